@@ -29,6 +29,7 @@ async fn main() {
                 .unwrap()
                 .as_secs()
                 + 3600,
+            ..Default::default()
         };
 
         // Store in cache
@@ -42,6 +42,9 @@ async fn main() {
     println!("\n📏 Size Limits:");
     test_size_limits(&cache).await;
 
+    // Demonstrate host extraction
+    test_host_extraction();
+
     println!("\n✅ All demonstrations completed!");
 }
 
@@ -137,6 +140,7 @@ async fn test_cache_operations(cache: &ProxyCache) {
                 .unwrap()
                 .as_secs()
                 + 3600,
+            ..Default::default()
         };
 
         if cache.put(key, response).await {
@@ -176,6 +180,7 @@ async fn test_cache_expiration(cache: &ProxyCache) {
             .unwrap()
             .as_secs()
             + 1,
+        ..Default::default()
     };
 
     cache.put(key, response).await;
@@ -207,6 +212,7 @@ async fn test_size_limits(cache: &ProxyCache) {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     };
 
     if !cache.put(key, oversized).await {
@@ -226,6 +232,7 @@ async fn test_size_limits(cache: &ProxyCache) {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     };
 
     if cache.put(key, normal).await {
@@ -101,9 +101,9 @@ async fn handle_client(
 
     // Parse request
     let (method, path, headers) = match parse_request(&buffer) {
-        Some(parsed) => parsed,
-        None => {
-            eprintln!("Failed to parse request");
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Failed to parse request: {}", e);
             let _ = client.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
             return;
         }
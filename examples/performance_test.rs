@@ -177,5 +177,6 @@ fn create_test_response(id: usize, size: usize) -> CachedResponse {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     }
 }
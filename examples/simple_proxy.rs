@@ -49,6 +49,7 @@ async fn main() {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     };
 
     if cache.put(key, response.clone()).await {
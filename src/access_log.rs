@@ -0,0 +1,261 @@
+use std::fmt;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Where the response to a logged request came from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// Served from cache without asking upstream, stale copies included
+    Hit,
+    /// Fetched from upstream
+    #[default]
+    Miss,
+    /// Served from cache after upstream answered `304 Not Modified`
+    Revalidated,
+}
+
+impl fmt::Display for CacheStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Hit => "HIT",
+            Self::Miss => "MISS",
+            Self::Revalidated => "REVALIDATED",
+        })
+    }
+}
+
+/// One request as it appears in the access log
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessRecord {
+    /// Client address, None if the socket was already gone
+    pub client: Option<IpAddr>,
+    /// When the request was read
+    pub time: SystemTime,
+    pub method: String,
+    /// Request target as the client sent it
+    pub target: String,
+    /// Status sent to the client, 0 until a response is chosen
+    pub status: u16,
+    /// Body bytes sent, headers excluded
+    pub bytes: usize,
+    pub cache_status: CacheStatus,
+    /// Time spent on the upstream connection and response, None when
+    /// upstream wasn't contacted
+    pub upstream_latency: Option<Duration>,
+}
+
+impl AccessRecord {
+    /// A record for a request read at `time`, a cache miss until told otherwise
+    pub fn new(client: Option<IpAddr>, time: SystemTime, method: &str, target: &str) -> Self {
+        Self {
+            client,
+            time,
+            method: method.to_string(),
+            target: target.to_string(),
+            status: 0,
+            bytes: 0,
+            cache_status: CacheStatus::default(),
+            upstream_latency: None,
+        }
+    }
+}
+
+/// Format a time as Common Log Format does, in UTC, e.g. `10/Oct/2000:13:55:36 +0000`
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+
+    // Days since the epoch to a proleptic Gregorian date, counting eras of
+    // 400 years from 0000-03-01 so leap days fall at the end of each year
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+/// Format a record as one Common Log Format line, without the newline
+///
+/// The cache status and upstream latency in milliseconds follow the
+/// standard fields. Unknown values are written as `-`, as is a zero size.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::access_log::{format_line, AccessRecord, CacheStatus};
+/// use std::time::{Duration, UNIX_EPOCH};
+///
+/// let mut record = AccessRecord::new(
+///     Some([192, 168, 1, 20].into()),
+///     UNIX_EPOCH + Duration::from_secs(971_186_136),
+///     "GET",
+///     "/app.js",
+/// );
+/// record.status = 200;
+/// record.bytes = 2326;
+/// record.cache_status = CacheStatus::Hit;
+/// assert_eq!(
+///     format_line(&record),
+///     "192.168.1.20 - - [10/Oct/2000:13:55:36 +0000] \"GET /app.js HTTP/1.1\" 200 2326 HIT -"
+/// );
+/// ```
+pub fn format_line(record: &AccessRecord) -> String {
+    let client = record
+        .client
+        .map_or_else(|| "-".to_string(), |ip| ip.to_string());
+    let bytes = match record.bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+    let latency = record.upstream_latency.map_or_else(
+        || "-".to_string(),
+        |latency| latency.as_millis().to_string(),
+    );
+    format!(
+        "{} - - [{}] \"{} {} HTTP/1.1\" {} {} {} {}",
+        client,
+        format_time(record.time),
+        record.method,
+        record.target,
+        record.status,
+        bytes,
+        record.cache_status,
+        latency
+    )
+}
+
+/// Access log writing one line per request to a shared sink
+///
+/// Clones write to the same sink. Each line is written and flushed under a
+/// lock, so lines from concurrent connections never interleave.
+#[derive(Clone)]
+pub struct AccessLog {
+    sink: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl AccessLog {
+    /// Log to `sink`, wrap files in a `BufWriter` only if losing the tail
+    /// of the log on a crash is acceptable
+    pub fn new<W: Write + Send + 'static>(sink: W) -> Self {
+        Self {
+            sink: Arc::new(Mutex::new(Box::new(sink))),
+        }
+    }
+
+    /// Write `record` as one line, failures are logged and otherwise ignored
+    pub fn log(&self, record: &AccessRecord) {
+        let mut line = format_line(record);
+        line.push('\n');
+        // A panic mid-write leaves at worst a torn line, keep logging
+        let mut sink = self.sink.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = sink.write_all(line.as_bytes()).and_then(|()| sink.flush()) {
+            debug!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sink whose contents stay readable after it is handed to the log
+    #[derive(Clone, Default)]
+    struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(UNIX_EPOCH), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(format_time(at(971_186_136)), "10/Oct/2000:13:55:36 +0000");
+        // Leap day and the last second of a leap year
+        assert_eq!(format_time(at(951_782_400)), "29/Feb/2000:00:00:00 +0000");
+        assert_eq!(format_time(at(1_735_689_599)), "31/Dec/2024:23:59:59 +0000");
+    }
+
+    #[test]
+    fn test_format_line_fields() {
+        let mut record = AccessRecord::new(
+            Some("::1".parse().unwrap()),
+            at(1_700_000_000),
+            "GET",
+            "/a.css?v=2",
+        );
+        record.status = 304;
+        record.cache_status = CacheStatus::Revalidated;
+        record.upstream_latency = Some(Duration::from_micros(12_900));
+        assert_eq!(
+            format_line(&record),
+            "::1 - - [14/Nov/2023:22:13:20 +0000] \"GET /a.css?v=2 HTTP/1.1\" 304 - REVALIDATED 12"
+        );
+
+        let record = AccessRecord {
+            status: 502,
+            bytes: 7,
+            ..AccessRecord::new(None, at(0), "HEAD", "/")
+        };
+        assert_eq!(
+            format_line(&record),
+            "- - - [01/Jan/1970:00:00:00 +0000] \"HEAD / HTTP/1.1\" 502 7 MISS -"
+        );
+    }
+
+    #[test]
+    fn test_log_writes_one_line_per_record() {
+        let sink = SharedSink::default();
+        let log = AccessLog::new(sink.clone());
+        let record = AccessRecord {
+            status: 200,
+            ..AccessRecord::new(None, at(0), "GET", "/")
+        };
+        log.log(&record);
+        log.clone().log(&record);
+
+        let written = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines, vec![format_line(&record); 2]);
+        assert!(written.ends_with('\n'));
+    }
+}
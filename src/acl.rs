@@ -0,0 +1,212 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A network in CIDR notation, e.g. `192.168.1.0/24` or `fd00::/8`
+///
+/// A bare address is a network of just that address. Bits past the prefix
+/// are ignored, so `192.168.1.7/24` is the same network as `192.168.1.0/24`.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::acl::Cidr;
+///
+/// let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+/// assert!(lan.contains("192.168.1.200".parse().unwrap()));
+/// assert!(!lan.contains("192.168.2.1".parse().unwrap()));
+/// assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether `ip` is in this network
+    ///
+    /// IPv4-mapped IPv6 addresses, as a dual-stack listener reports IPv4
+    /// peers, match IPv4 networks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| CidrError::Address(s.to_string()))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max)
+                .ok_or_else(|| CidrError::Prefix(s.to_string()))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Unparseable [`Cidr`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CidrError {
+    /// The part before the `/` isn't an IP address
+    Address(String),
+    /// The prefix isn't a number up to 32 for IPv4 or 128 for IPv6
+    Prefix(String),
+}
+
+impl fmt::Display for CidrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(cidr) => write!(f, "{:?}: not an IP address", cidr),
+            Self::Prefix(cidr) => write!(f, "{:?}: invalid prefix length", cidr),
+        }
+    }
+}
+
+impl std::error::Error for CidrError {}
+
+/// Which client addresses may use the proxy
+///
+/// An address in a `deny` network is refused even when an `allow` network
+/// holds it too. An empty `allow` list lets in every address not denied.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::acl::ClientAcl;
+///
+/// let acl = ClientAcl {
+///     allow: ClientAcl::parse_list("192.168.1.0/24, 10.0.0.0/8").unwrap(),
+///     deny: ClientAcl::parse_list("192.168.1.13").unwrap(),
+/// };
+/// assert!(acl.permits("10.1.2.3".parse().unwrap()));
+/// assert!(!acl.permits("192.168.1.13".parse().unwrap()));
+/// assert!(!acl.permits("203.0.113.9".parse().unwrap()));
+/// assert!(ClientAcl::default().permits("203.0.113.9".parse().unwrap()));
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClientAcl {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl ClientAcl {
+    /// Whether a client connecting from `ip` may use the proxy
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Parse a comma-separated list of networks, empty entries are skipped
+    pub fn parse_list(list: &str) -> Result<Vec<Cidr>, CidrError> {
+        list.split(',')
+            .filter(|cidr| !cidr.trim().is_empty())
+            .map(str::parse)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_boundaries() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(ip("192.168.1.0")));
+        assert!(lan.contains(ip("192.168.1.255")));
+        assert!(!lan.contains(ip("192.168.0.255")));
+        assert!(!lan.contains(ip("192.168.2.0")));
+        // Seen through a dual-stack listener
+        assert!(lan.contains(ip("::ffff:192.168.1.20")));
+
+        let host: Cidr = "10.0.0.1".parse().unwrap();
+        assert_eq!(host.to_string(), "10.0.0.1/32");
+        assert!(host.contains(ip("10.0.0.1")));
+        assert!(!host.contains(ip("10.0.0.2")));
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+        assert!(!everything.contains(ip("::1")));
+
+        let ula: Cidr = "fd00::/8".parse().unwrap();
+        assert!(ula.contains(ip("fdab::1")));
+        assert!(!ula.contains(ip("fe80::1")));
+        assert!(!ula.contains(ip("10.0.0.1")));
+        let all_v6: Cidr = "::/0".parse().unwrap();
+        assert!(all_v6.contains(ip("2001:db8::1")));
+
+        assert_eq!(
+            "10.0.0.0/33".parse::<Cidr>(),
+            Err(CidrError::Prefix("10.0.0.0/33".to_string()))
+        );
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert_eq!(
+            "lan/24".parse::<Cidr>(),
+            Err(CidrError::Address("lan/24".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_client_acl() {
+        let acl = ClientAcl {
+            allow: ClientAcl::parse_list("192.168.1.0/24,").unwrap(),
+            deny: ClientAcl::parse_list("192.168.1.128/25").unwrap(),
+        };
+        assert!(acl.permits(ip("192.168.1.127")));
+        // Deny wins over allow
+        assert!(!acl.permits(ip("192.168.1.128")));
+        assert!(!acl.permits(ip("192.168.2.1")));
+
+        let deny_only = ClientAcl {
+            deny: ClientAcl::parse_list("203.0.113.0/24").unwrap(),
+            ..ClientAcl::default()
+        };
+        assert!(deny_only.permits(ip("198.51.100.1")));
+        assert!(!deny_only.permits(ip("203.0.113.1")));
+        assert!(ClientAcl::parse_list("192.168.1.0/24, nope").is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use crate::config::ProxyCacheConfig;
+use crate::cors::Preflight;
+use crate::query::QueryPolicy;
+use crate::single_flight::SingleFlight;
+use crate::vary::VaryPolicy;
+use crate::{CacheLookup, CachedResponse, ProxyCache};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Boxed future returned by [`Cache`] methods
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Operations a cache backend provides to the request path, implemented in
+/// memory by [`ProxyCache`]
+///
+/// Besides storage that is the `Vary` bookkeeping behind the keys, miss
+/// coalescing, hit counters and the caching policies, so the proxy can
+/// serve requests from any backend, see
+/// [`CachingProxy`](crate::proxy::CachingProxy).
+///
+/// Methods return boxed futures rather than being `async fn`s, which keeps
+/// the trait object-safe (and within the crate's MSRV), so backends can be
+/// chosen at runtime behind a `dyn Cache`.
+///
+/// # Examples
+///
+/// ```
+/// # tokio_test::block_on(async {
+/// use rustysquid::backend::Cache;
+/// use rustysquid::{CachedResponse, ProxyCache};
+///
+/// let cache: Box<dyn Cache> = Box::new(ProxyCache::new());
+/// let entry = CachedResponse {
+///     expires: u64::MAX,
+///     ..Default::default()
+/// };
+/// assert!(cache.put(7, entry).await);
+/// assert!(cache.get(7).await.is_some());
+/// assert_eq!(cache.len().await, 1);
+/// # })
+/// ```
+pub trait Cache: Send + Sync {
+    /// Fresh entry stored under `key`
+    fn get(&self, key: u64) -> BoxFuture<'_, Option<Arc<CachedResponse>>>;
+
+    /// Store `entry` under `key`, false if the backend refused it
+    fn put(&self, key: u64, entry: CachedResponse) -> BoxFuture<'_, bool>;
+
+    /// Remove every entry
+    fn clear(&self) -> BoxFuture<'_, ()>;
+
+    /// Number of stored entries
+    fn len(&self) -> BoxFuture<'_, usize>;
+
+    /// Whether nothing is stored
+    fn is_empty(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async move { self.len().await == 0 })
+    }
+
+    /// Bytes taken by stored entries
+    fn total_size(&self) -> usize;
+
+    /// Entry stored under `key`, stale ones included while they carry
+    /// validators to revalidate them with
+    fn lookup(&self, key: u64) -> BoxFuture<'_, CacheLookup>;
+
+    /// Entry stored under `key` whether fresh or not, without counting a hit
+    fn peek(&self, key: u64) -> BoxFuture<'_, Option<Arc<CachedResponse>>>;
+
+    /// Extend the entry under `key` to `expires`, false if it is gone
+    fn refresh_expiry(&self, key: u64, expires: u64) -> BoxFuture<'_, bool>;
+
+    /// Key for a URL before any `Vary` headers are folded in
+    fn base_key(&self, host: &str, port: u16, path: &str) -> u64;
+
+    /// Key for a request, folding in the `Vary` headers recorded for its URL
+    fn lookup_key<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        path: &'a str,
+        request_headers: &'a [String],
+    ) -> BoxFuture<'a, u64>;
+
+    /// Key for the answer to a CORS preflight, distinct from the URL's key
+    fn preflight_key(&self, host: &str, port: u16, path: &str, preflight: &Preflight<'_>) -> u64;
+
+    /// Store `entry` under the variant key its `Vary` list selects and
+    /// record that list for the URL, returns the key or None if refused
+    fn put_variant<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        path: &'a str,
+        request_headers: &'a [String],
+        entry: CachedResponse,
+    ) -> BoxFuture<'a, Option<u64>>;
+
+    /// Upstream fetches in flight, shared by concurrent misses for one key
+    fn single_flight(&self) -> &SingleFlight;
+
+    /// Size and TTL limits
+    fn config(&self) -> &ProxyCacheConfig;
+
+    /// TTL for a response to `path` on `host`
+    fn ttl_for(&self, headers: &[String], host: &str, path: &str) -> u64;
+
+    /// Which `Vary` request headers may be cached on
+    fn vary_policy(&self) -> &VaryPolicy;
+
+    /// How query strings are keyed
+    fn query_policy(&self) -> Arc<QueryPolicy>;
+
+    /// Snippet inserted into cached HTML, if any
+    fn html_injection(&self) -> Option<&str>;
+
+    /// Count a failed write to a client
+    fn record_client_write_error(&self, error: &io::Error);
+
+    /// Count `body_bytes` of `cached` sent to a client without asking upstream
+    fn record_hit_bytes(&self, cached: &CachedResponse, body_bytes: usize);
+}
+
+impl Cache for ProxyCache {
+    fn get(&self, key: u64) -> BoxFuture<'_, Option<Arc<CachedResponse>>> {
+        Box::pin(ProxyCache::get(self, key))
+    }
+
+    fn put(&self, key: u64, entry: CachedResponse) -> BoxFuture<'_, bool> {
+        Box::pin(ProxyCache::put(self, key, entry))
+    }
+
+    fn clear(&self) -> BoxFuture<'_, ()> {
+        Box::pin(ProxyCache::clear(self))
+    }
+
+    fn len(&self) -> BoxFuture<'_, usize> {
+        Box::pin(ProxyCache::len(self))
+    }
+
+    fn total_size(&self) -> usize {
+        ProxyCache::total_size(self)
+    }
+
+    fn lookup(&self, key: u64) -> BoxFuture<'_, CacheLookup> {
+        Box::pin(ProxyCache::lookup(self, key))
+    }
+
+    fn peek(&self, key: u64) -> BoxFuture<'_, Option<Arc<CachedResponse>>> {
+        Box::pin(ProxyCache::peek(self, key))
+    }
+
+    fn refresh_expiry(&self, key: u64, expires: u64) -> BoxFuture<'_, bool> {
+        Box::pin(ProxyCache::refresh_expiry(self, key, expires))
+    }
+
+    fn base_key(&self, host: &str, port: u16, path: &str) -> u64 {
+        ProxyCache::base_key(self, host, port, path)
+    }
+
+    fn lookup_key<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        path: &'a str,
+        request_headers: &'a [String],
+    ) -> BoxFuture<'a, u64> {
+        Box::pin(ProxyCache::lookup_key(
+            self,
+            host,
+            port,
+            path,
+            request_headers,
+        ))
+    }
+
+    fn preflight_key(&self, host: &str, port: u16, path: &str, preflight: &Preflight<'_>) -> u64 {
+        ProxyCache::preflight_key(self, host, port, path, preflight)
+    }
+
+    fn put_variant<'a>(
+        &'a self,
+        host: &'a str,
+        port: u16,
+        path: &'a str,
+        request_headers: &'a [String],
+        entry: CachedResponse,
+    ) -> BoxFuture<'a, Option<u64>> {
+        Box::pin(ProxyCache::put_variant(
+            self,
+            host,
+            port,
+            path,
+            request_headers,
+            entry,
+        ))
+    }
+
+    fn single_flight(&self) -> &SingleFlight {
+        ProxyCache::single_flight(self)
+    }
+
+    fn config(&self) -> &ProxyCacheConfig {
+        ProxyCache::config(self)
+    }
+
+    fn ttl_for(&self, headers: &[String], host: &str, path: &str) -> u64 {
+        ProxyCache::ttl_for(self, headers, host, path)
+    }
+
+    fn vary_policy(&self) -> &VaryPolicy {
+        ProxyCache::vary_policy(self)
+    }
+
+    fn query_policy(&self) -> Arc<QueryPolicy> {
+        ProxyCache::query_policy(self)
+    }
+
+    fn html_injection(&self) -> Option<&str> {
+        ProxyCache::html_injection(self)
+    }
+
+    fn record_client_write_error(&self, error: &io::Error) {
+        ProxyCache::record_client_write_error(self, error);
+    }
+
+    fn record_hit_bytes(&self, cached: &CachedResponse, body_bytes: usize) {
+        ProxyCache::record_hit_bytes(self, cached, body_bytes);
+    }
+}
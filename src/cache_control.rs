@@ -0,0 +1,114 @@
+/// Typed view of the `Cache-Control` directives this proxy acts on
+///
+/// Directives from every `Cache-Control` header are merged, unknown ones are
+/// ignored.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::cache_control::CacheControl;
+///
+/// let headers = vec!["Cache-Control: public, max-age=600, proxy-revalidate".to_string()];
+/// let cc = CacheControl::parse(&headers);
+/// assert_eq!(cc.max_age, Some(600));
+/// assert!(cc.proxy_revalidate);
+/// assert!(cc.forbids_stale());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    pub no_cache: bool,
+    pub no_store: bool,
+    pub private: bool,
+    pub public: bool,
+    pub must_revalidate: bool,
+    pub proxy_revalidate: bool,
+    pub no_transform: bool,
+    pub max_age: Option<u64>,
+    pub s_maxage: Option<u64>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    /// Parse the `Cache-Control` headers out of a response's header lines
+    pub fn parse(headers: &[String]) -> Self {
+        let mut cc = Self::default();
+        let values = headers.iter().filter_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("cache-control")
+                .then_some(value)
+        });
+
+        for directive in values.flat_map(|value| value.split(',')) {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = argument.and_then(|a| a.parse::<u64>().ok());
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-cache" => cc.no_cache = true,
+                "no-store" => cc.no_store = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "must-revalidate" => cc.must_revalidate = true,
+                "proxy-revalidate" => cc.proxy_revalidate = true,
+                "no-transform" => cc.no_transform = true,
+                "max-age" => cc.max_age = cc.max_age.or(seconds),
+                "s-maxage" => cc.s_maxage = cc.s_maxage.or(seconds),
+                "stale-while-revalidate" => {
+                    cc.stale_while_revalidate = cc.stale_while_revalidate.or(seconds);
+                }
+                "stale-if-error" => cc.stale_if_error = cc.stale_if_error.or(seconds),
+                _ => {}
+            }
+        }
+        cc
+    }
+
+    /// Whether a shared cache must revalidate instead of serving this stale
+    ///
+    /// `s-maxage` implies `proxy-revalidate` (RFC 7234 §5.2.2.9).
+    pub fn forbids_stale(&self) -> bool {
+        self.must_revalidate || self.proxy_revalidate || self.s_maxage.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_merges_headers() {
+        let headers = vec![
+            "Cache-Control: public, max-age=60".to_string(),
+            "cache-control: stale-while-revalidate=30, stale-if-error=\"600\"".to_string(),
+            "Content-Type: text/css".to_string(),
+        ];
+        let cc = CacheControl::parse(&headers);
+        assert!(cc.public);
+        assert_eq!(cc.max_age, Some(60));
+        assert_eq!(cc.stale_while_revalidate, Some(30));
+        assert_eq!(cc.stale_if_error, Some(600));
+        assert!(!cc.forbids_stale());
+    }
+
+    #[test]
+    fn test_revalidation_directives_forbid_stale() {
+        for value in ["proxy-revalidate", "Must-Revalidate", "s-maxage=10"] {
+            let cc = CacheControl::parse(&[format!("Cache-Control: {}", value)]);
+            assert!(cc.forbids_stale(), "{} should forbid stale", value);
+        }
+        let cc = CacheControl::parse(&["Cache-Control: PROXY-REVALIDATE".to_string()]);
+        assert!(cc.proxy_revalidate);
+        assert!(!cc.must_revalidate);
+    }
+
+    #[test]
+    fn test_parse_ignores_unknown_and_malformed() {
+        let cc =
+            CacheControl::parse(&["Cache-Control: max-age=abc, community=\"UCI\"".to_string()]);
+        assert_eq!(cc, CacheControl::default());
+        assert_eq!(CacheControl::parse(&[]), CacheControl::default());
+    }
+}
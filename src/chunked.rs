@@ -0,0 +1,139 @@
+/// Decode a complete `Transfer-Encoding: chunked` body
+///
+/// Chunk extensions and the trailer section are discarded. Returns None if
+/// the stream is malformed or ends before the terminating zero-size chunk.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::chunked::decode_chunked;
+///
+/// let body = b"5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+/// assert_eq!(decode_chunked(body).unwrap(), b"hello world");
+/// assert!(decode_chunked(b"5\r\nhel").is_none());
+/// ```
+pub fn decode_chunked(body: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(body.len());
+    walk_chunks(body, |chunk| decoded.extend_from_slice(chunk)).map(|_| decoded)
+}
+
+/// Length of the chunked message at the start of `data`, trailers included
+///
+/// Returns None until the terminating empty line has arrived, or if the
+/// framing is malformed. Chunk data is skipped, not copied, so this is cheap
+/// to call again as more bytes are read.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::chunked::chunked_length;
+///
+/// assert_eq!(chunked_length(b"2\r\nhi\r\n0\r\n\r\nNEXT"), Some(12));
+/// assert_eq!(chunked_length(b"2\r\nhi\r\n0\r\n"), None);
+/// ```
+pub fn chunked_length(data: &[u8]) -> Option<usize> {
+    walk_chunks(data, |_| {})
+}
+
+/// Walk the chunks of a chunked message, returning the bytes it spans
+fn walk_chunks<'a>(body: &'a [u8], mut on_chunk: impl FnMut(&'a [u8])) -> Option<usize> {
+    let mut rest = body;
+
+    loop {
+        let (size_line, after_size) = split_line(rest)?;
+        let size_str = std::str::from_utf8(size_line).ok()?;
+        // Ignore chunk extensions after ';'
+        let size_hex = size_str.split(';').next()?.trim();
+        let size = usize::from_str_radix(size_hex, 16).ok()?;
+
+        if size == 0 {
+            let after_trailers = skip_trailers(after_size)?;
+            return Some(body.len() - after_trailers.len());
+        }
+
+        let chunk_end = size.checked_add(2)?;
+        if after_size.len() < chunk_end || &after_size[size..chunk_end] != b"\r\n" {
+            return None;
+        }
+        on_chunk(&after_size[..size]);
+        rest = &after_size[chunk_end..];
+    }
+}
+
+/// Whether a `Transfer-Encoding` value ends with the `chunked` coding
+pub fn is_chunked(transfer_encoding: &str) -> bool {
+    transfer_encoding
+        .rsplit(',')
+        .next()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Split off one CRLF-terminated line
+fn split_line(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = data.windows(2).position(|w| w == b"\r\n")?;
+    Some((&data[..end], &data[end + 2..]))
+}
+
+/// Consume trailer fields up to the empty line that ends the message
+fn skip_trailers(mut data: &[u8]) -> Option<&[u8]> {
+    loop {
+        let (line, rest) = split_line(data)?;
+        if line.is_empty() {
+            return Some(rest);
+        }
+        data = rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_multi_chunk_body() {
+        let body = b"4\r\nWiki\r\n5;name=value\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\n\r\n";
+        assert_eq!(
+            decode_chunked(body).unwrap(),
+            b"Wikipedia in\r\n\r\nchunks.".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_decode_with_trailers() {
+        let body =
+            b"3\r\nabc\r\n0\r\nExpires: Sun, 06 Nov 1994 08:49:37 GMT\r\nX-Checksum: 1\r\n\r\n";
+        assert_eq!(decode_chunked(body).unwrap(), b"abc".to_vec());
+    }
+
+    #[test]
+    fn test_decode_rejects_incomplete_or_malformed() {
+        // Missing the terminating chunk
+        assert!(decode_chunked(b"3\r\nabc\r\n").is_none());
+        // Trailer section never ends
+        assert!(decode_chunked(b"0\r\nX-Trailer: 1\r\n").is_none());
+        // Chunk shorter than its declared size
+        assert!(decode_chunked(b"5\r\nabc\r\n0\r\n\r\n").is_none());
+        // Non-hex or overflowing size
+        assert!(decode_chunked(b"zz\r\nabc\r\n0\r\n\r\n").is_none());
+        assert!(decode_chunked(b"ffffffffffffffff\r\nabc\r\n").is_none());
+    }
+
+    #[test]
+    fn test_chunked_length_stops_at_message_end() {
+        let message = b"3\r\nabc\r\n0\r\nX-Checksum: 1\r\n\r\n";
+        let mut stream = message.to_vec();
+        stream.extend_from_slice(b"HTTP/1.1 200 OK\r\n");
+        assert_eq!(chunked_length(&stream), Some(message.len()));
+        for end in 0..message.len() {
+            assert!(chunked_length(&message[..end]).is_none(), "{}", end);
+        }
+    }
+
+    #[test]
+    fn test_is_chunked() {
+        assert!(is_chunked("chunked"));
+        assert!(is_chunked("gzip, Chunked"));
+        assert!(!is_chunked("chunked, gzip"));
+        assert!(!is_chunked("identity"));
+    }
+}
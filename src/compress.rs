@@ -0,0 +1,375 @@
+use crate::header_value;
+use bytes::Bytes;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+/// Bodies smaller than this are stored as-is, gzip overhead eats the savings
+pub const MIN_COMPRESS_SIZE: usize = 1024;
+
+/// Text-like content types worth compressing
+const COMPRESSIBLE_TYPES: &[&str] = &[
+    "text/",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "image/svg+xml",
+];
+
+/// Extensions used when the response has no `Content-Type`
+const COMPRESSIBLE_EXTENSIONS: &[&str] = &[".css", ".js", ".svg", ".json"];
+
+/// Content codings [`encode_for_client`] can produce, most preferred first
+pub const ENCODINGS: &[&str] = &["br", "gzip"];
+
+/// Brotli quality for bodies encoded per request, well below the maximum
+/// of 11 that is too slow to run on every cache hit
+const BROTLI_QUALITY: u32 = 5;
+
+/// Brotli window size, as a power of two
+const BROTLI_WINDOW: u32 = 22;
+
+/// Whether a response body is text worth compressing
+///
+/// Uses the `Content-Type` when present, the path extension otherwise.
+pub fn is_compressible(headers: &[String], path: &str) -> bool {
+    match header_value(headers, "content-type") {
+        Some(content_type) => {
+            let content_type = content_type.to_ascii_lowercase();
+            COMPRESSIBLE_TYPES
+                .iter()
+                .any(|prefix| content_type.starts_with(prefix))
+                || content_type.contains("+json")
+        }
+        None => {
+            let path = path.to_ascii_lowercase();
+            COMPRESSIBLE_EXTENSIONS
+                .iter()
+                .any(|ext| path.ends_with(ext))
+        }
+    }
+}
+
+/// Gzip `body` at the default compression level
+pub fn gzip(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 2), Compression::default());
+    // Writing into a Vec can't fail
+    encoder.write_all(body).expect("in-memory gzip write");
+    encoder.finish().expect("in-memory gzip finish")
+}
+
+/// Decompress a gzip body, None if it is corrupt or inflates past `limit` bytes
+pub fn gunzip(body: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(body.len() * 2);
+    GzDecoder::new(body)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decoded)
+        .ok()?;
+    (decoded.len() <= limit).then_some(decoded)
+}
+
+/// Brotli-encode `body` at [`BROTLI_QUALITY`]
+pub fn brotli(body: &[u8]) -> Vec<u8> {
+    let mut encoder = brotli::CompressorWriter::new(
+        Vec::with_capacity(body.len() / 2),
+        4096,
+        BROTLI_QUALITY,
+        BROTLI_WINDOW,
+    );
+    // Writing into a Vec can't fail
+    encoder.write_all(body).expect("in-memory brotli write");
+    encoder.into_inner()
+}
+
+/// Decompress a brotli body, None if it is corrupt or inflates past `limit` bytes
+pub fn unbrotli(body: &[u8], limit: usize) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(body.len() * 2);
+    brotli::Decompressor::new(body, 4096)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut decoded)
+        .ok()?;
+    (decoded.len() <= limit).then_some(decoded)
+}
+
+/// Gzip an unencoded text body of at least [`MIN_COMPRESS_SIZE`] bytes
+///
+/// Adds `Content-Encoding: gzip` (and `Vary: Accept-Encoding`, since clients
+/// that don't accept gzip get it decoded) and replaces `Content-Length`. The response
+/// is returned unchanged if it is already encoded, too small, not text, or
+/// if compressing it would not make it smaller.
+pub fn compress_response(headers: Vec<String>, body: Bytes, path: &str) -> (Vec<String>, Bytes) {
+    if body.len() < MIN_COMPRESS_SIZE
+        || header_value(&headers, "content-encoding").is_some()
+        || !is_compressible(&headers, path)
+    {
+        return (headers, body);
+    }
+
+    let compressed = gzip(&body);
+    if compressed.len() >= body.len() {
+        return (headers, body);
+    }
+    let mut headers = mark_gzip_encoded(without_headers(headers, &["content-length"]));
+    headers.push(format!("Content-Length: {}", compressed.len()));
+    (headers, Bytes::from(compressed))
+}
+
+/// Label a gzipped body that carried no `Content-Encoding` as gzip-encoded
+///
+/// Adds `Content-Encoding: gzip`, and `Vary: Accept-Encoding` since clients
+/// that don't accept gzip get it decoded. Used for bodies gzipped here and
+/// for those upstream sent with a `gzip` transfer coding.
+pub fn mark_gzip_encoded(mut headers: Vec<String>) -> Vec<String> {
+    let varies_on_encoding = headers.iter().any(|header| {
+        header.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("vary")
+                && value.to_ascii_lowercase().contains("accept-encoding")
+        })
+    });
+    if !varies_on_encoding {
+        headers.push("Vary: Accept-Encoding".to_string());
+    }
+    headers.push("Content-Encoding: gzip".to_string());
+    headers
+}
+
+/// Codings and their quality values listed in an `Accept-Encoding` value,
+/// lowercased, with a missing `q` counting as 1
+pub(crate) fn accepted_codings(accept_encoding: &str) -> impl Iterator<Item = (String, f64)> + '_ {
+    accept_encoding.split(',').filter_map(|item| {
+        let mut params = item.split(';');
+        let coding = params.next()?.trim().to_ascii_lowercase();
+        let quality = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        (!coding.is_empty()).then_some((coding, quality))
+    })
+}
+
+/// The coding a client's `Accept-Encoding` ranks highest among `available`
+/// and `identity`
+///
+/// Ties go to the coding listed first in `available`. `identity` is
+/// acceptable unless refused, but when the client doesn't name it (or `*`)
+/// any coding it does accept is preferred. None if the client refuses
+/// everything on offer.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::compress::preferred_encoding;
+///
+/// let available = ["br", "gzip"];
+/// assert_eq!(preferred_encoding("gzip, br", &available), Some("br"));
+/// assert_eq!(preferred_encoding("br;q=0.5, gzip", &available), Some("gzip"));
+/// assert_eq!(preferred_encoding("deflate", &available), Some("identity"));
+/// assert_eq!(preferred_encoding("", &available), Some("identity"));
+/// assert_eq!(preferred_encoding("identity;q=0", &available), None);
+/// ```
+pub fn preferred_encoding<'a>(accept_encoding: &str, available: &[&'a str]) -> Option<&'a str> {
+    let accepted: Vec<(String, f64)> = accepted_codings(accept_encoding).collect();
+    let quality = |name: &str| accepted.iter().find(|(c, _)| c == name).map(|(_, q)| *q);
+    let wildcard = quality("*");
+
+    let mut best: Option<(&'a str, f64)> = None;
+    for &coding in available {
+        let q = quality(coding).or(wildcard).unwrap_or(0.0);
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((coding, q));
+        }
+    }
+    match quality("identity").or(wildcard) {
+        Some(q) if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) => Some("identity"),
+        Some(_) => best.map(|(coding, _)| coding),
+        None => Some(best.map_or("identity", |(coding, _)| coding)),
+    }
+}
+
+/// Encode an unencoded `body` in the coding the client prefers among
+/// [`ENCODINGS`], returning the bytes and the `Content-Encoding` to send
+///
+/// Bodies go out as they are, with no `Content-Encoding`, when the client
+/// prefers `identity` or accepts nothing this build can produce.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::compress::{encode_for_client, gunzip, unbrotli};
+///
+/// let body = b"body { color: red }".repeat(100);
+/// let (encoded, encoding) = encode_for_client(&body, "br;q=1.0, gzip;q=0.8");
+/// assert_eq!(encoding.as_deref(), Some("br"));
+/// assert_eq!(unbrotli(&encoded, body.len()).unwrap(), body);
+///
+/// let (encoded, encoding) = encode_for_client(&body, "zstd, gzip;q=0.8");
+/// assert_eq!(encoding.as_deref(), Some("gzip"));
+/// assert_eq!(gunzip(&encoded, body.len()).unwrap(), body);
+///
+/// let (plain, encoding) = encode_for_client(&body, "identity");
+/// assert_eq!((plain.len(), encoding), (body.len(), None));
+/// ```
+pub fn encode_for_client(body: &[u8], accept_encoding: &str) -> (Bytes, Option<String>) {
+    let encoded = preferred_encoding(accept_encoding, ENCODINGS)
+        .and_then(|coding| Some((encode(body, coding)?, coding)));
+    match encoded {
+        Some((encoded, coding)) => (encoded, Some(coding.to_string())),
+        None => (Bytes::copy_from_slice(body), None),
+    }
+}
+
+/// `body` encoded in `coding`, None for codings not in [`ENCODINGS`]
+pub fn encode(body: &[u8], coding: &str) -> Option<Bytes> {
+    match coding {
+        "br" => Some(Bytes::from(brotli(body))),
+        "gzip" => Some(Bytes::from(gzip(body))),
+        _ => None,
+    }
+}
+
+/// Undo a gzip `Content-Encoding` for a client that doesn't accept it
+///
+/// Returns the decoded headers and body, None if the body isn't gzip or
+/// can't be decoded within `limit` bytes.
+pub fn decompress_response(
+    headers: &[String],
+    body: &[u8],
+    limit: usize,
+) -> Option<(Vec<String>, Bytes)> {
+    let encoding = header_value(headers, "content-encoding")?;
+    if !encoding.trim().eq_ignore_ascii_case("gzip") {
+        return None;
+    }
+    let decoded = gunzip(body, limit)?;
+    let mut headers = without_headers(headers.to_vec(), &["content-encoding", "content-length"]);
+    headers.push(format!("Content-Length: {}", decoded.len()));
+    Some((headers, Bytes::from(decoded)))
+}
+
+/// Drop every header line whose name is in `names` (lowercase)
+pub(crate) fn without_headers(headers: Vec<String>, names: &[&str]) -> Vec<String> {
+    headers
+        .into_iter()
+        .filter(|header| {
+            header.split_once(':').map_or(true, |(name, _)| {
+                let name = name.trim().to_ascii_lowercase();
+                !names.contains(&name.as_str())
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stylesheet(size: usize) -> Bytes {
+        let rule = ".button { color: #333; padding: 4px 8px; }\n";
+        Bytes::from(rule.repeat(size / rule.len() + 1)[..size].to_string())
+    }
+
+    #[test]
+    fn test_compress_text_above_threshold() {
+        let headers = vec![
+            "Content-Type: text/css".to_string(),
+            "Content-Length: 8192".to_string(),
+        ];
+        let body = stylesheet(8192);
+        let (headers, compressed) = compress_response(headers, body.clone(), "/site.css");
+
+        assert_eq!(header_value(&headers, "content-encoding"), Some("gzip"));
+        let length = compressed.len().to_string();
+        assert_eq!(
+            header_value(&headers, "content-length"),
+            Some(length.as_str())
+        );
+        // Repetitive CSS should shrink to well under a tenth of its size
+        assert!(compressed.len() * 10 < body.len(), "{}", compressed.len());
+        assert_eq!(gunzip(&compressed, body.len()).unwrap(), body);
+    }
+
+    #[test]
+    fn test_compress_skips_small_encoded_and_binary() {
+        let small = stylesheet(MIN_COMPRESS_SIZE - 1);
+        let css = vec!["Content-Type: text/css".to_string()];
+        assert_eq!(
+            compress_response(css.clone(), small.clone(), "/a.css").1,
+            small
+        );
+        let at_threshold = stylesheet(MIN_COMPRESS_SIZE);
+        assert_ne!(
+            compress_response(css, at_threshold.clone(), "/a.css").1,
+            at_threshold
+        );
+
+        let body = stylesheet(4096);
+        let encoded = vec![
+            "Content-Type: text/css".to_string(),
+            "Content-Encoding: br".to_string(),
+        ];
+        assert_eq!(compress_response(encoded, body.clone(), "/a.css").1, body);
+        let png = vec!["Content-Type: image/png".to_string()];
+        assert_eq!(compress_response(png, body.clone(), "/a.png").1, body);
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        let content_type = |value: &str| vec![format!("Content-Type: {}", value)];
+        assert!(is_compressible(
+            &content_type("application/json; charset=utf-8"),
+            "/"
+        ));
+        assert!(is_compressible(&content_type("application/ld+json"), "/"));
+        assert!(is_compressible(&content_type("image/svg+xml"), "/"));
+        assert!(!is_compressible(&content_type("image/webp"), "/a.svg"));
+        assert!(is_compressible(&[], "/app.JS"));
+        assert!(!is_compressible(&[], "/font.woff2"));
+    }
+
+    #[test]
+    fn test_preferred_encoding_by_quality() {
+        let available = ["br", "gzip"];
+        let prefer = |accept: &str| preferred_encoding(accept, &available);
+
+        // Highest q wins whatever the order listed, ties go to br
+        assert_eq!(prefer("br;q=0.9, gzip;q=0.8"), Some("br"));
+        assert_eq!(prefer("gzip;q=0.8, br;q=0.9"), Some("br"));
+        assert_eq!(prefer("br;q=0.8, gzip;q=0.9"), Some("gzip"));
+        assert_eq!(prefer("gzip, br"), Some("br"));
+        assert_eq!(prefer("BR;Q=0.9, gzip;q=0.8"), Some("br"));
+        // Refused codings are skipped, wildcards fill in the rest
+        assert_eq!(prefer("br;q=0, *;q=0.5"), Some("gzip"));
+        assert_eq!(prefer("gzip;q=0.5, identity"), Some("identity"));
+        assert_eq!(prefer("gzip;q=0.5, identity;q=0.4"), Some("gzip"));
+        assert_eq!(prefer("*;q=0"), None);
+
+        // Codings this build can't produce fall through to the next choice
+        assert_eq!(preferred_encoding("br, gzip;q=0.8", ENCODINGS), Some("br"));
+        assert_eq!(
+            preferred_encoding("zstd, gzip;q=0.8", ENCODINGS),
+            Some("gzip")
+        );
+        assert_eq!(preferred_encoding("zstd", ENCODINGS), Some("identity"));
+    }
+
+    #[test]
+    fn test_decompress_response() {
+        let body = stylesheet(4096);
+        let (headers, compressed) = compress_response(
+            vec!["Content-Type: text/css".to_string()],
+            body.clone(),
+            "/a.css",
+        );
+        let (headers, decoded) = decompress_response(&headers, &compressed, 1 << 20).unwrap();
+        assert_eq!(decoded, body);
+        assert_eq!(header_value(&headers, "content-encoding"), None);
+        assert_eq!(header_value(&headers, "content-length"), Some("4096"));
+
+        // Inflating past the limit or corrupt input is refused
+        let gzip_headers = vec!["Content-Encoding: gzip".to_string()];
+        assert!(decompress_response(&gzip_headers, &compressed, 4095).is_none());
+        assert!(decompress_response(&gzip_headers, b"not gzip", 1 << 20).is_none());
+        assert!(decompress_response(&[], &compressed, 1 << 20).is_none());
+    }
+}
@@ -0,0 +1,1096 @@
+use crate::access_log::AccessLog;
+use crate::acl::ClientAcl;
+use crate::connection_pool::MAX_CONNECTIONS_PER_HOST;
+use crate::proxy::parse_refresh_url;
+use crate::query::QueryPolicy;
+use crate::rate_limit::RateLimiter;
+use crate::tasks::TaskLimiter;
+use crate::{
+    ExtensionTtls, HostTtlMultipliers, CACHE_SIZE, CACHE_TTL, MAX_CACHE_BYTES, MAX_ENTRY_SIZE,
+    MAX_TTL,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Default [`ProxyConfig::max_request_line`], the 8000 octets RFC 9112
+/// asks servers to support, rounded up
+pub const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+
+/// Default [`ProxyConfig::listen_addr`], every interface on the usual
+/// Squid port
+pub const DEFAULT_LISTEN_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 3128);
+
+/// Default [`ProxyConfig::retry_backoff`]
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Default [`ProxyConfig::head_timeout`]
+pub const DEFAULT_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default [`RefreshSchedule::interval`] for `RUSTYSQUID_REFRESH_URLS`
+pub const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Default [`ProxyCacheConfig::large_entry_size`]
+pub const DEFAULT_LARGE_ENTRY_SIZE: usize = 1024 * 1024;
+
+/// Default [`ProxyCacheConfig::max_large_inserts`]
+pub const DEFAULT_MAX_LARGE_INSERTS: usize = 2;
+
+/// Size and freshness limits for a [`crate::ProxyCache`]
+///
+/// Defaults to the crate constants.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::config::ProxyCacheConfig;
+/// use rustysquid::ProxyCache;
+///
+/// // A small cache for a memory-constrained router
+/// let config = ProxyCacheConfig {
+///     max_entries: 500,
+///     max_cache_bytes: 8 * 1024 * 1024,
+///     max_entry_size: 1024 * 1024,
+///     default_ttl: 600,
+///     max_ttl: 3600,
+///     compress: true,
+///     gzip_transfer_coding: false,
+///     synthesize_etags: false,
+///     large_entry_size: 256 * 1024,
+///     max_large_inserts: 1,
+/// };
+/// let cache = ProxyCache::with_config(config).unwrap();
+/// assert_eq!(cache.config().max_entries, 500);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProxyCacheConfig {
+    /// Maximum number of cached entries
+    pub max_entries: usize,
+    /// Maximum total size of all cached entries in bytes
+    pub max_cache_bytes: usize,
+    /// Maximum size of a single cached entry in bytes
+    pub max_entry_size: usize,
+    /// TTL in seconds for responses without freshness headers
+    pub default_ttl: u64,
+    /// Longest TTL in seconds any response is cached for, whatever its
+    /// `Cache-Control`, `Expires` or `Retry-After` headers ask
+    pub max_ttl: u64,
+    /// Gzip text bodies before storing them, see [`crate::compress`]
+    pub compress: bool,
+    /// Cache responses sent with `Transfer-Encoding: gzip, chunked` by
+    /// storing the gzip layer as `Content-Encoding: gzip`, instead of
+    /// passing them through uncached
+    pub gzip_transfer_coding: bool,
+    /// Give responses stored without an `ETag` one hashed from the body, so
+    /// clients' `If-None-Match` can be answered without asking upstream
+    pub synthesize_etags: bool,
+    /// Entries above this many bytes count as large for `max_large_inserts`
+    pub large_entry_size: usize,
+    /// Large entries being inserted at once, more wait their turn before
+    /// taking the cache lock so small puts don't queue behind them
+    pub max_large_inserts: usize,
+}
+
+impl ProxyCacheConfig {
+    /// Check the limits are usable together
+    pub fn validate(&self) -> Result<(), CacheConfigError> {
+        if self.max_entries == 0 {
+            return Err(CacheConfigError::ZeroEntries);
+        }
+        if self.max_large_inserts == 0 {
+            return Err(CacheConfigError::ZeroLargeInserts);
+        }
+        if self.max_entry_size > self.max_cache_bytes {
+            return Err(CacheConfigError::EntryLargerThanCache {
+                max_entry_size: self.max_entry_size,
+                max_cache_bytes: self.max_cache_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Default for ProxyCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: CACHE_SIZE,
+            max_cache_bytes: MAX_CACHE_BYTES,
+            max_entry_size: MAX_ENTRY_SIZE,
+            default_ttl: CACHE_TTL,
+            max_ttl: MAX_TTL,
+            compress: false,
+            gzip_transfer_coding: false,
+            synthesize_etags: false,
+            large_entry_size: DEFAULT_LARGE_ENTRY_SIZE,
+            max_large_inserts: DEFAULT_MAX_LARGE_INSERTS,
+        }
+    }
+}
+
+/// Invalid [`ProxyCacheConfig`] limits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheConfigError {
+    /// `max_entries` must be at least 1
+    ZeroEntries,
+    /// `max_large_inserts` must be at least 1
+    ZeroLargeInserts,
+    /// A single entry may not be allowed to exceed the whole cache
+    EntryLargerThanCache {
+        max_entry_size: usize,
+        max_cache_bytes: usize,
+    },
+}
+
+impl fmt::Display for CacheConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroEntries => write!(f, "max_entries must be greater than 0"),
+            Self::ZeroLargeInserts => write!(f, "max_large_inserts must be greater than 0"),
+            Self::EntryLargerThanCache {
+                max_entry_size,
+                max_cache_bytes,
+            } => write!(
+                f,
+                "max_entry_size ({}) exceeds max_cache_bytes ({})",
+                max_entry_size, max_cache_bytes
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CacheConfigError {}
+
+/// How upstream responses that won't be cached are relayed to clients
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResponseMode {
+    /// Read the whole response before writing it to the client
+    #[default]
+    Buffered,
+    /// Relay bytes as they arrive, so a slow client stalls the upstream read
+    /// instead of growing a buffer
+    Streaming,
+    /// Relay every response as it arrives, keeping a copy of those that may
+    /// be cached and caching it once complete. Copies outgrowing the size
+    /// limit are dropped and the rest is relayed without one
+    Tee,
+}
+
+/// What to do when a cached entry's `Content-Length` disagrees with its body
+///
+/// Sent as stored, the header would leave clients waiting for bytes that
+/// never come, or reading the next response as part of this one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LengthMismatch {
+    /// Serve the body with a `Content-Length` giving its actual size
+    #[default]
+    Recompute,
+    /// Drop `Content-Length` and send the body chunked
+    Chunked,
+    /// Treat the entry as missing and fetch a fresh copy. Entries served
+    /// without a lookup, e.g. as a stale fallback, are recomputed instead
+    Refetch,
+}
+
+/// What a [`ForceCacheRule`] does with a response that sets cookies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SetCookiePolicy {
+    /// Leave the response to the normal rules, which never cache it
+    #[default]
+    Refuse,
+    /// Drop `Set-Cookie` from the cached copy and cache it anyway. Only
+    /// safe when the cookies carry nothing specific to one user
+    Strip,
+}
+
+/// Cache responses for matching URLs for `ttl` seconds, whatever their
+/// `Cache-Control`, `Expires` or `Pragma` headers say
+///
+/// Only `GET` responses the proxy would otherwise cache apart from their
+/// freshness headers are stored, host TTL multipliers still apply.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::config::{ForceCacheRule, SetCookiePolicy};
+///
+/// let rule = ForceCacheRule {
+///     host: "cdn.example.com".to_string(),
+///     path_prefix: "/static/".to_string(),
+///     ttl: 3600,
+///     set_cookie: SetCookiePolicy::default(),
+/// };
+/// assert!(rule.matches("CDN.example.com", "/static/app.js"));
+/// assert!(!rule.matches("cdn.example.com", "/api/user"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ForceCacheRule {
+    /// Hostname the rule applies to, compared case-insensitively
+    pub host: String,
+    /// Paths starting with this are matched, `/` matches the whole host
+    pub path_prefix: String,
+    /// Freshness lifetime given to matched responses
+    pub ttl: u64,
+    /// Handling of matched responses carrying `Set-Cookie`
+    pub set_cookie: SetCookiePolicy,
+}
+
+impl ForceCacheRule {
+    /// Whether a request for `path` on `host` falls under this rule
+    pub fn matches(&self, host: &str, path: &str) -> bool {
+        self.host.eq_ignore_ascii_case(host) && path.starts_with(&self.path_prefix)
+    }
+}
+
+/// URLs refetched in the background so they never expire from the cache
+#[derive(Clone, Debug, PartialEq)]
+pub struct RefreshSchedule {
+    /// `[http://]host[:port]/path` URLs to keep fresh
+    pub urls: Vec<String>,
+    /// How often the list is checked
+    pub interval: Duration,
+    /// Only refetch an entry once less than this fraction of its lifetime
+    /// remains, None refetches on every tick
+    pub remaining_fraction: Option<f64>,
+    /// Seconds of lifetime a cached entry must have been stored with to be
+    /// refetched, shorter-lived entries are left to expire. None refetches
+    /// entries of any lifetime
+    pub min_ttl: Option<u64>,
+}
+
+/// Proxy-level settings that sit outside the cache itself
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    /// Address the proxy listens on
+    pub listen_addr: SocketAddr,
+    /// Stop accepting connections while fewer than this many descriptors
+    /// remain below the soft limit, None disables the check
+    pub min_free_fds: Option<usize>,
+    /// Before revalidating a stale entry, check that its `Content-Encoding`
+    /// is acceptable to the client, fetching it in full otherwise
+    pub check_encoding_on_revalidate: bool,
+    /// Relay strategy for responses that are passed through uncached
+    pub response_mode: ResponseMode,
+    /// Directory the cache is reloaded from on startup and saved to on
+    /// shutdown, None keeps the cache in memory only
+    pub cache_dir: Option<PathBuf>,
+    /// Answer a 5xx from upstream with the stale cached copy when its
+    /// `stale-if-error` window (or `default_stale_if_error`) still covers it
+    pub serve_stale_on_server_error: bool,
+    /// `stale-if-error` window in seconds for entries that don't carry the
+    /// directive, None only honours the origin's directive
+    pub default_stale_if_error: Option<u64>,
+    /// URLs kept warm by a background refresher, None disables it
+    pub refresh: Option<RefreshSchedule>,
+    /// Idle upstream connections pooled per host
+    pub pool_connections_per_host: usize,
+    /// Per-hostname overrides of `pool_connections_per_host`
+    pub pool_host_limits: HashMap<String, usize>,
+    /// Admin listener serving Prometheus metrics on `GET /metrics` and the
+    /// version and counters on `GET /__rustysquid/stats`, None disables it
+    pub metrics_addr: Option<SocketAddr>,
+    /// Requests served on one client connection before it is closed with
+    /// `Connection: close`, 1 keeps the one-request-per-connection behaviour
+    pub max_requests_per_connection: usize,
+    /// Longest request line (method, target and version) accepted, longer
+    /// ones get a `414 URI Too Long`
+    pub max_request_line: usize,
+    /// How long shutdown waits for in-flight connections before closing them
+    pub drain_timeout: Duration,
+    /// Common Log Format access log written after each response, None
+    /// disables it
+    pub access_log: Option<AccessLog>,
+    /// Request methods clients may use, CONNECT included, anything else gets
+    /// a `405` listing these in `Allow`. None allows every method
+    pub allowed_methods: Option<Vec<String>>,
+    /// Client addresses allowed to connect, others are closed as soon as
+    /// they are accepted
+    pub client_acl: ClientAcl,
+    /// Requests each client address may make, beyond which they get a
+    /// `429 Too Many Requests`. None leaves clients unlimited
+    pub rate_limiter: Option<RateLimiter>,
+    /// Request header that skips the cache read and fetches from upstream,
+    /// e.g. `X-Bypass-Cache`, None disables bypassing
+    pub bypass_header: Option<String>,
+    /// Store the fresh response fetched for a bypassing request
+    pub store_bypassed: bool,
+    /// How long upstream may take to send the first byte of its response
+    /// before the request fails with a `504`, or a stale copy. None waits
+    /// as long as for any other read
+    pub first_byte_timeout: Option<Duration>,
+    /// How long a request or response head may take to finish once its
+    /// first byte has arrived, however steadily it trickles in. Slow
+    /// requests get a `408`, slow upstreams a `504`
+    pub head_timeout: Duration,
+    /// Lowest average rate in bytes per second a request or upstream
+    /// response may arrive at after its first second, None disables it
+    pub min_transfer_rate: Option<u64>,
+    /// Rules caching matching URLs regardless of their freshness headers,
+    /// the first match wins
+    pub force_cache: Vec<ForceCacheRule>,
+    /// Tell clients how the cache answered with `X-Cache: HIT`, `MISS` or
+    /// `REVALIDATED`, plus `X-Cache-Age` on cached copies
+    pub cache_status_headers: bool,
+    /// Name the proxy and its version in a `Server` header on every
+    /// response, in place of the upstream's, see
+    /// [`crate::metrics::server_header`]
+    pub server_header: bool,
+    /// Caps the background work spawned while serving clients, access log
+    /// writes, and the refresher's fetches. Connections aren't limited by
+    /// it, and access log writes without a free slot are dropped rather
+    /// than waited for. None spawns that work unthrottled
+    pub task_limiter: Option<TaskLimiter>,
+    /// Handling of cached entries whose `Content-Length` doesn't match
+    /// their body
+    pub length_mismatch: LengthMismatch,
+    /// Further attempts for a `GET` or `HEAD` whose upstream connect or
+    /// fetch fails before any of the response reached the client, each on
+    /// a new connection. Refused connections and timeouts aren't retried
+    pub upstream_retries: u32,
+    /// Wait before the first retry, doubled for each one after it
+    pub retry_backoff: Duration,
+    /// Cache answers to CORS preflights per origin, requested method and
+    /// URL, for as long as their `Access-Control-Max-Age` allows
+    pub cache_preflights: bool,
+    /// TTL multipliers for hosts that under-specify freshness, given to the
+    /// proxy's cache at startup and when a reload changes them
+    pub host_ttl_multipliers: HostTtlMultipliers,
+    /// TTLs by extension for responses without freshness headers, given to
+    /// the proxy's cache like `host_ttl_multipliers`
+    pub extension_ttls: ExtensionTtls,
+    /// Query string handling, given to the proxy's cache like
+    /// `host_ttl_multipliers`. A reload changing how it keys entries
+    /// empties the cache
+    pub query_policy: QueryPolicy,
+    /// Size and TTL limits of the cache the proxy builds at startup
+    pub cache: ProxyCacheConfig,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: DEFAULT_LISTEN_ADDR,
+            min_free_fds: None,
+            check_encoding_on_revalidate: true,
+            response_mode: ResponseMode::default(),
+            cache_dir: None,
+            serve_stale_on_server_error: true,
+            default_stale_if_error: None,
+            refresh: None,
+            pool_connections_per_host: MAX_CONNECTIONS_PER_HOST,
+            pool_host_limits: HashMap::new(),
+            metrics_addr: None,
+            max_requests_per_connection: 1,
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            drain_timeout: Duration::from_secs(30),
+            access_log: None,
+            allowed_methods: None,
+            client_acl: ClientAcl::default(),
+            rate_limiter: None,
+            bypass_header: None,
+            store_bypassed: true,
+            first_byte_timeout: None,
+            head_timeout: DEFAULT_HEAD_TIMEOUT,
+            min_transfer_rate: None,
+            force_cache: Vec::new(),
+            cache_status_headers: false,
+            server_header: false,
+            task_limiter: None,
+            length_mismatch: LengthMismatch::default(),
+            upstream_retries: 2,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
+            cache_preflights: false,
+            host_ttl_multipliers: HostTtlMultipliers::default(),
+            extension_ttls: ExtensionTtls::default(),
+            query_policy: QueryPolicy::default(),
+            cache: ProxyCacheConfig::default(),
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// Override settings from the environment
+    ///
+    /// `RUSTYSQUID_BIND` and `RUSTYSQUID_PORT` set the proxy's address and
+    /// port. `RUSTYSQUID_METRICS_PORT` enables the admin listener, on
+    /// `RUSTYSQUID_METRICS_BIND` if given and the proxy's address otherwise.
+    /// `RUSTYSQUID_ALLOWED_METHODS` is a comma-separated list of the request
+    /// methods clients may use. `RUSTYSQUID_ALLOW_CLIENTS` and
+    /// `RUSTYSQUID_DENY_CLIENTS` are comma-separated networks, like
+    /// `192.168.1.0/24`, for [`ProxyConfig::client_acl`].
+    /// `RUSTYSQUID_RATE_LIMIT` limits each client to that many requests per
+    /// second, in bursts of up to `RUSTYSQUID_RATE_BURST` (by default the
+    /// rate rounded up). `RUSTYSQUID_HOST_TTL_MULTIPLIERS` lists
+    /// `host=multiplier` pairs, like `cdn.example.com=2`, for
+    /// [`ProxyConfig::host_ttl_multipliers`], and
+    /// `RUSTYSQUID_EXTENSION_TTLS` `extension=seconds` pairs, like `mp4=86400`,
+    /// for [`ProxyConfig::extension_ttls`]. `RUSTYSQUID_FORCE_CACHE` lists
+    /// `host/path-prefix=seconds` rules for [`ProxyConfig::force_cache`].
+    /// `RUSTYSQUID_IGNORE_QUERY` (`true` or `false`) and
+    /// `RUSTYSQUID_PRIVATE_QUERY_PARAMS`, a list of parameter names, set the
+    /// [`ProxyConfig::query_policy`]. `RUSTYSQUID_SERVER_HEADER` (`true` or
+    /// `false`) sets [`ProxyConfig::server_header`]. `RUSTYSQUID_CACHE_DIR` names
+    /// the directory the cache is saved to and reloaded from.
+    /// `RUSTYSQUID_CACHE_ENTRIES`, `RUSTYSQUID_CACHE_BYTES`,
+    /// `RUSTYSQUID_MAX_ENTRY_SIZE` and `RUSTYSQUID_MAX_TTL` (in seconds) set
+    /// the [`ProxyConfig::cache`] limits. `RUSTYSQUID_REFRESH_URLS` lists
+    /// URLs to keep fresh, checked every `RUSTYSQUID_REFRESH_INTERVAL`
+    /// seconds (by default [`DEFAULT_REFRESH_INTERVAL`]), an empty list
+    /// turning [`ProxyConfig::refresh`] off. `RUSTYSQUID_MAX_TASKS` sets up
+    /// the [`ProxyConfig::task_limiter`] for that many background tasks.
+    /// Unset variables keep the current values.
+    /// When `RUSTYSQUID_CONFIG` names a file it is read as well, see
+    /// [`ProxyConfig::with_file`].
+    pub fn with_env(self) -> Result<Self, EnvConfigError> {
+        match std::env::var_os("RUSTYSQUID_CONFIG") {
+            Some(path) => self.with_file(path),
+            None => self.with_vars(|name| std::env::var(name).ok()),
+        }
+    }
+
+    /// [`ProxyConfig::with_env`] with the variables a file sets taking
+    /// precedence over the environment
+    ///
+    /// The file holds one `NAME=value` line per variable, blank lines and
+    /// lines starting with `#` are skipped.
+    pub fn with_file(self, path: impl AsRef<Path>) -> Result<Self, EnvConfigError> {
+        let path = path.as_ref();
+        let file_error = |reason: String| EnvConfigError::File {
+            path: path.to_path_buf(),
+            reason,
+        };
+        let contents = std::fs::read_to_string(path).map_err(|e| file_error(e.to_string()))?;
+        let mut vars = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(file_error(format!(
+                    "line {}: expected NAME=value",
+                    number + 1
+                )));
+            };
+            vars.insert(name.trim().to_string(), value.trim().to_string());
+        }
+        self.with_vars(|name| vars.get(name).cloned().or_else(|| std::env::var(name).ok()))
+    }
+
+    /// Carry over the settings only read at startup from the `running`
+    /// config, for a config reloaded while the proxy runs
+    ///
+    /// Listeners are already bound, the cache built and its directory
+    /// loaded, and the pool, task and rate limiters and refresher built, so
+    /// changes to those need a restart.
+    #[must_use]
+    pub fn with_startup_settings(self, running: &ProxyConfig) -> Self {
+        Self {
+            listen_addr: running.listen_addr,
+            metrics_addr: running.metrics_addr,
+            cache_dir: running.cache_dir.clone(),
+            refresh: running.refresh.clone(),
+            pool_connections_per_host: running.pool_connections_per_host,
+            pool_host_limits: running.pool_host_limits.clone(),
+            cache: running.cache,
+            task_limiter: running.task_limiter.clone(),
+            rate_limiter: running.rate_limiter.clone(),
+            ..self
+        }
+    }
+
+    /// [`ProxyConfig::with_env`] with variables looked up through `var`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rustysquid::config::{EnvConfigError, ProxyConfig};
+    ///
+    /// let config = ProxyConfig::default()
+    ///     .with_vars(|name| (name == "RUSTYSQUID_PORT").then(|| "8080".to_string()))
+    ///     .unwrap();
+    /// assert_eq!(config.listen_addr.to_string(), "0.0.0.0:8080");
+    ///
+    /// let err = ProxyConfig::default()
+    ///     .with_vars(|name| (name == "RUSTYSQUID_BIND").then(|| "localhost".to_string()))
+    ///     .unwrap_err();
+    /// assert!(matches!(err, EnvConfigError::Invalid { .. }));
+    /// ```
+    pub fn with_vars(
+        mut self,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, EnvConfigError> {
+        let ip = |name: &'static str| {
+            var(name)
+                .map(|value| {
+                    value
+                        .trim()
+                        .parse::<IpAddr>()
+                        .map_err(|_| EnvConfigError::Invalid { var: name, value })
+                })
+                .transpose()
+        };
+        let port = |name: &'static str| {
+            var(name)
+                .map(|value| match value.trim().parse::<u16>() {
+                    Ok(0) | Err(_) => Err(EnvConfigError::Invalid { var: name, value }),
+                    Ok(port) => Ok(port),
+                })
+                .transpose()
+        };
+
+        if let Some(bind) = ip("RUSTYSQUID_BIND")? {
+            self.listen_addr.set_ip(bind);
+        }
+        if let Some(port) = port("RUSTYSQUID_PORT")? {
+            self.listen_addr.set_port(port);
+        }
+        let metrics_bind = ip("RUSTYSQUID_METRICS_BIND")?;
+        match (port("RUSTYSQUID_METRICS_PORT")?, self.metrics_addr.as_mut()) {
+            (Some(port), _) => {
+                let bind = metrics_bind.unwrap_or_else(|| self.listen_addr.ip());
+                self.metrics_addr = Some(SocketAddr::new(bind, port));
+            }
+            (None, Some(addr)) => {
+                if let Some(bind) = metrics_bind {
+                    addr.set_ip(bind);
+                }
+            }
+            (None, None) if metrics_bind.is_some() => {
+                return Err(EnvConfigError::MetricsBindWithoutPort);
+            }
+            (None, None) => {}
+        }
+
+        if let Some(value) = var("RUSTYSQUID_ALLOWED_METHODS") {
+            let methods: Vec<String> = value
+                .split(',')
+                .map(|method| method.trim().to_ascii_uppercase())
+                .collect();
+            let valid = |method: &String| {
+                !method.is_empty() && method.bytes().all(|b| b.is_ascii_alphabetic() || b == b'-')
+            };
+            if !methods.iter().all(valid) {
+                return Err(EnvConfigError::Invalid {
+                    var: "RUSTYSQUID_ALLOWED_METHODS",
+                    value,
+                });
+            }
+            self.allowed_methods = Some(methods);
+        }
+        let cidrs = |name: &'static str| {
+            var(name)
+                .map(|value| {
+                    ClientAcl::parse_list(&value)
+                        .map_err(|_| EnvConfigError::Invalid { var: name, value })
+                })
+                .transpose()
+        };
+        if let Some(allow) = cidrs("RUSTYSQUID_ALLOW_CLIENTS")? {
+            self.client_acl.allow = allow;
+        }
+        if let Some(deny) = cidrs("RUSTYSQUID_DENY_CLIENTS")? {
+            self.client_acl.deny = deny;
+        }
+        let rate = var("RUSTYSQUID_RATE_LIMIT")
+            .map(|value| match value.trim().parse::<f64>() {
+                Ok(rate) if rate.is_finite() && rate > 0.0 => Ok(rate),
+                _ => Err(EnvConfigError::Invalid {
+                    var: "RUSTYSQUID_RATE_LIMIT",
+                    value,
+                }),
+            })
+            .transpose()?;
+        let burst = var("RUSTYSQUID_RATE_BURST")
+            .map(|value| match value.trim().parse::<u32>() {
+                Ok(0) | Err(_) => Err(EnvConfigError::Invalid {
+                    var: "RUSTYSQUID_RATE_BURST",
+                    value,
+                }),
+                Ok(burst) => Ok(burst),
+            })
+            .transpose()?;
+        match (rate, burst) {
+            (Some(rate), burst) => {
+                let burst = burst.unwrap_or_else(|| rate.ceil().min(f64::from(u32::MAX)) as u32);
+                self.rate_limiter = Some(RateLimiter::new(rate, burst));
+            }
+            (None, Some(_)) => return Err(EnvConfigError::RateBurstWithoutLimit),
+            (None, None) => {}
+        }
+        if let Some(value) = var("RUSTYSQUID_CACHE_DIR") {
+            if value.trim().is_empty() {
+                return Err(EnvConfigError::Invalid {
+                    var: "RUSTYSQUID_CACHE_DIR",
+                    value,
+                });
+            }
+            self.cache_dir = Some(PathBuf::from(value.trim()));
+        }
+        let positive = |name: &'static str| {
+            var(name)
+                .map(|value| match value.trim().parse::<u64>() {
+                    Ok(0) | Err(_) => Err(EnvConfigError::Invalid { var: name, value }),
+                    Ok(number) => Ok(number),
+                })
+                .transpose()
+        };
+        let size = |number: u64| usize::try_from(number).unwrap_or(usize::MAX);
+        if let Some(entries) = positive("RUSTYSQUID_CACHE_ENTRIES")? {
+            self.cache.max_entries = size(entries);
+        }
+        if let Some(bytes) = positive("RUSTYSQUID_CACHE_BYTES")? {
+            self.cache.max_cache_bytes = size(bytes);
+        }
+        if let Some(bytes) = positive("RUSTYSQUID_MAX_ENTRY_SIZE")? {
+            self.cache.max_entry_size = size(bytes);
+        }
+        if let Some(ttl) = positive("RUSTYSQUID_MAX_TTL")? {
+            self.cache.max_ttl = ttl;
+        }
+        self.cache.validate().map_err(EnvConfigError::Cache)?;
+        if let Some(value) = var("RUSTYSQUID_REFRESH_URLS") {
+            let urls: Vec<String> = value
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .map(str::to_string)
+                .collect();
+            if !urls.iter().all(|url| parse_refresh_url(url).is_some()) {
+                return Err(EnvConfigError::Invalid {
+                    var: "RUSTYSQUID_REFRESH_URLS",
+                    value,
+                });
+            }
+            self.refresh = (!urls.is_empty()).then_some(RefreshSchedule {
+                urls,
+                interval: DEFAULT_REFRESH_INTERVAL,
+                remaining_fraction: None,
+                min_ttl: None,
+            });
+        }
+        if let Some(tasks) = positive("RUSTYSQUID_MAX_TASKS")? {
+            self.task_limiter = Some(TaskLimiter::new(size(tasks)));
+        }
+        match (
+            positive("RUSTYSQUID_REFRESH_INTERVAL")?,
+            self.refresh.as_mut(),
+        ) {
+            (Some(secs), Some(schedule)) => schedule.interval = Duration::from_secs(secs),
+            (Some(_), None) => return Err(EnvConfigError::RefreshIntervalWithoutUrls),
+            (None, _) => {}
+        }
+        if let Some(multipliers) = var("RUSTYSQUID_HOST_TTL_MULTIPLIERS")
+            .map(|value| {
+                parse_host_multipliers(&value).ok_or(EnvConfigError::Invalid {
+                    var: "RUSTYSQUID_HOST_TTL_MULTIPLIERS",
+                    value,
+                })
+            })
+            .transpose()?
+        {
+            self.host_ttl_multipliers = multipliers;
+        }
+        if let Some(value) = var("RUSTYSQUID_EXTENSION_TTLS") {
+            self.extension_ttls = parse_extension_ttls(&value).ok_or(EnvConfigError::Invalid {
+                var: "RUSTYSQUID_EXTENSION_TTLS",
+                value,
+            })?;
+        }
+        if let Some(value) = var("RUSTYSQUID_FORCE_CACHE") {
+            self.force_cache = parse_force_cache(&value).ok_or(EnvConfigError::Invalid {
+                var: "RUSTYSQUID_FORCE_CACHE",
+                value,
+            })?;
+        }
+        let flag = |name: &'static str| {
+            var(name)
+                .map(|value| match value.trim().to_ascii_lowercase().as_str() {
+                    "true" | "1" => Ok(true),
+                    "false" | "0" => Ok(false),
+                    _ => Err(EnvConfigError::Invalid { var: name, value }),
+                })
+                .transpose()
+        };
+        if let Some(ignore_query) = flag("RUSTYSQUID_IGNORE_QUERY")? {
+            self.query_policy.ignore_query = ignore_query;
+        }
+        if let Some(server_header) = flag("RUSTYSQUID_SERVER_HEADER")? {
+            self.server_header = server_header;
+        }
+        if let Some(value) = var("RUSTYSQUID_PRIVATE_QUERY_PARAMS") {
+            self.query_policy.private_params = value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        if let Some(metrics) = self.metrics_addr {
+            let overlapping = metrics.ip() == self.listen_addr.ip()
+                || metrics.ip().is_unspecified()
+                || self.listen_addr.ip().is_unspecified();
+            if metrics.port() == self.listen_addr.port() && overlapping {
+                return Err(EnvConfigError::SharedPort(metrics.port()));
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// Comma-separated `host=multiplier` pairs, None if any isn't one with a
+/// positive multiplier
+fn parse_host_multipliers(list: &str) -> Option<HostTtlMultipliers> {
+    let mut multipliers = HostTtlMultipliers::new();
+    for pair in list.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let (host, multiplier) = pair.split_once('=')?;
+        let multiplier = multiplier.trim().parse::<f64>().ok()?;
+        if host.trim().is_empty() || !multiplier.is_finite() || multiplier <= 0.0 {
+            return None;
+        }
+        multipliers.insert(host.trim(), multiplier);
+    }
+    Some(multipliers)
+}
+
+/// Comma-separated `extension=seconds` pairs, None if any isn't one
+fn parse_extension_ttls(list: &str) -> Option<ExtensionTtls> {
+    let mut ttls = ExtensionTtls::new();
+    for pair in list.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let (extension, ttl) = pair.split_once('=')?;
+        let extension = extension.trim();
+        if extension.trim_start_matches('.').is_empty() {
+            return None;
+        }
+        ttls.insert(extension, ttl.trim().parse().ok()?);
+    }
+    Some(ttls)
+}
+
+/// Comma-separated `host/path-prefix=seconds` rules, the prefix defaulting
+/// to `/`, None if any isn't one with a positive TTL
+fn parse_force_cache(list: &str) -> Option<Vec<ForceCacheRule>> {
+    list.split(',')
+        .filter(|rule| !rule.trim().is_empty())
+        .map(|rule| {
+            let (url, ttl) = rule.rsplit_once('=')?;
+            let url = url.trim();
+            let (host, path_prefix) = url
+                .find('/')
+                .map_or((url, "/"), |slash| url.split_at(slash));
+            let ttl = ttl.trim().parse::<u64>().ok().filter(|&ttl| ttl > 0)?;
+            (!host.is_empty()).then(|| ForceCacheRule {
+                host: host.to_string(),
+                path_prefix: path_prefix.to_string(),
+                ttl,
+                set_cookie: SetCookiePolicy::default(),
+            })
+        })
+        .collect()
+}
+
+/// Unusable settings from the environment, see [`ProxyConfig::with_env`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EnvConfigError {
+    /// Not an IP address, a port from 1 to 65535, a directory, a flag, a
+    /// list of methods, networks, URLs, TTLs or rules, or a positive number
+    Invalid { var: &'static str, value: String },
+    /// The config file couldn't be read or has a line that isn't `NAME=value`
+    File { path: PathBuf, reason: String },
+    /// `RUSTYSQUID_METRICS_BIND` was set but the admin listener isn't enabled
+    MetricsBindWithoutPort,
+    /// `RUSTYSQUID_RATE_BURST` was set but `RUSTYSQUID_RATE_LIMIT` wasn't
+    RateBurstWithoutLimit,
+    /// `RUSTYSQUID_REFRESH_INTERVAL` was set but there are no URLs to refresh
+    RefreshIntervalWithoutUrls,
+    /// The proxy and admin listeners would both bind this port
+    SharedPort(u16),
+    /// The cache limits don't fit together
+    Cache(CacheConfigError),
+}
+
+impl fmt::Display for EnvConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid { var, value } => write!(f, "{}: invalid value {:?}", var, value),
+            Self::File { path, reason } => write!(f, "{}: {}", path.display(), reason),
+            Self::MetricsBindWithoutPort => write!(
+                f,
+                "RUSTYSQUID_METRICS_BIND needs RUSTYSQUID_METRICS_PORT to be set"
+            ),
+            Self::RateBurstWithoutLimit => write!(
+                f,
+                "RUSTYSQUID_RATE_BURST needs RUSTYSQUID_RATE_LIMIT to be set"
+            ),
+            Self::RefreshIntervalWithoutUrls => write!(
+                f,
+                "RUSTYSQUID_REFRESH_INTERVAL needs RUSTYSQUID_REFRESH_URLS to be set"
+            ),
+            Self::SharedPort(port) => write!(
+                f,
+                "the proxy and metrics listeners can't both use port {}",
+                port
+            ),
+            Self::Cache(e) => write!(f, "cache limits: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for EnvConfigError {}
+
+/// A [`ProxyConfig`] that can be replaced while the proxy runs
+///
+/// Readers take a snapshot with [`SharedConfig::load`] and keep using it
+/// after a [`SharedConfig::store`], so work that started under the old
+/// config finishes with it while anything started later sees the new one.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::config::{ProxyConfig, SharedConfig};
+///
+/// let shared = SharedConfig::new(ProxyConfig::default());
+/// let before = shared.load();
+/// shared.store(ProxyConfig {
+///     cache_status_headers: true,
+///     ..ProxyConfig::default()
+/// });
+/// assert!(!before.cache_status_headers);
+/// assert!(shared.load().cache_status_headers);
+/// ```
+#[derive(Clone, Debug)]
+pub struct SharedConfig(Arc<RwLock<Arc<ProxyConfig>>>);
+
+impl SharedConfig {
+    pub fn new(config: ProxyConfig) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// The current config
+    pub fn load(&self) -> Arc<ProxyConfig> {
+        // Swaps can't leave the slot half written, a poisoned lock is still usable
+        let config = self
+            .0
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Arc::clone(&config)
+    }
+
+    /// Replace the config for everything that loads it from now on
+    pub fn store(&self, config: ProxyConfig) {
+        let mut current = self
+            .0
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *current = Arc::new(config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(vars: &[(&str, &str)]) -> Result<ProxyConfig, EnvConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        ProxyConfig::default().with_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn test_cache_dir_from_env() {
+        assert_eq!(from(&[]).unwrap().cache_dir, None);
+        let config = from(&[("RUSTYSQUID_CACHE_DIR", " /var/cache/rustysquid ")]).unwrap();
+        assert_eq!(
+            config.cache_dir,
+            Some(PathBuf::from("/var/cache/rustysquid"))
+        );
+        assert_eq!(
+            from(&[("RUSTYSQUID_CACHE_DIR", " ")]).unwrap_err(),
+            EnvConfigError::Invalid {
+                var: "RUSTYSQUID_CACHE_DIR",
+                value: " ".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cache_limits_from_env() {
+        let config = from(&[
+            ("RUSTYSQUID_CACHE_ENTRIES", "500"),
+            ("RUSTYSQUID_CACHE_BYTES", "8388608"),
+            ("RUSTYSQUID_MAX_ENTRY_SIZE", " 1048576 "),
+            ("RUSTYSQUID_MAX_TTL", "3600"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.cache,
+            ProxyCacheConfig {
+                max_entries: 500,
+                max_cache_bytes: 8 * 1024 * 1024,
+                max_entry_size: 1024 * 1024,
+                max_ttl: 3600,
+                ..ProxyCacheConfig::default()
+            }
+        );
+        let cache = crate::ProxyCache::with_config(config.cache).unwrap();
+        assert_eq!(cache.config().max_entries, 500);
+
+        for var in [
+            "RUSTYSQUID_CACHE_ENTRIES",
+            "RUSTYSQUID_CACHE_BYTES",
+            "RUSTYSQUID_MAX_ENTRY_SIZE",
+            "RUSTYSQUID_MAX_TTL",
+        ] {
+            for value in ["0", "-1", "big"] {
+                assert_eq!(
+                    from(&[(var, value)]).unwrap_err(),
+                    EnvConfigError::Invalid {
+                        var,
+                        value: value.to_string()
+                    }
+                );
+            }
+        }
+        assert_eq!(
+            from(&[("RUSTYSQUID_CACHE_BYTES", "1024")]).unwrap_err(),
+            EnvConfigError::Cache(CacheConfigError::EntryLargerThanCache {
+                max_entry_size: MAX_ENTRY_SIZE,
+                max_cache_bytes: 1024,
+            })
+        );
+    }
+
+    #[test]
+    fn test_refresh_schedule_from_env() {
+        assert_eq!(from(&[]).unwrap().refresh, None);
+        let config = from(&[
+            (
+                "RUSTYSQUID_REFRESH_URLS",
+                "http://cdn.example.com/app.js, [::1]:8080/status.json,",
+            ),
+            ("RUSTYSQUID_REFRESH_INTERVAL", "60"),
+        ])
+        .unwrap();
+        assert_eq!(
+            config.refresh,
+            Some(RefreshSchedule {
+                urls: vec![
+                    "http://cdn.example.com/app.js".to_string(),
+                    "[::1]:8080/status.json".to_string()
+                ],
+                interval: Duration::from_secs(60),
+                remaining_fraction: None,
+                min_ttl: None,
+            })
+        );
+        let config = from(&[("RUSTYSQUID_REFRESH_URLS", "example.com/")]).unwrap();
+        assert_eq!(config.refresh.unwrap().interval, DEFAULT_REFRESH_INTERVAL);
+        // An empty list turns refreshing off again
+        let config = ProxyConfig {
+            refresh: from(&[("RUSTYSQUID_REFRESH_URLS", "example.com/")])
+                .unwrap()
+                .refresh,
+            ..ProxyConfig::default()
+        }
+        .with_vars(|name| (name == "RUSTYSQUID_REFRESH_URLS").then(String::new))
+        .unwrap();
+        assert_eq!(config.refresh, None);
+
+        for (var, value) in [
+            ("RUSTYSQUID_REFRESH_URLS", "example.com:http/"),
+            ("RUSTYSQUID_REFRESH_URLS", "example.com/, http:///x"),
+        ] {
+            assert_eq!(
+                from(&[("RUSTYSQUID_REFRESH_INTERVAL", "60"), (var, value)]).unwrap_err(),
+                EnvConfigError::Invalid {
+                    var,
+                    value: value.to_string()
+                }
+            );
+        }
+        assert_eq!(
+            from(&[
+                ("RUSTYSQUID_REFRESH_URLS", "example.com/"),
+                ("RUSTYSQUID_REFRESH_INTERVAL", "0")
+            ])
+            .unwrap_err(),
+            EnvConfigError::Invalid {
+                var: "RUSTYSQUID_REFRESH_INTERVAL",
+                value: "0".to_string()
+            }
+        );
+        assert_eq!(
+            from(&[("RUSTYSQUID_REFRESH_INTERVAL", "60")]).unwrap_err(),
+            EnvConfigError::RefreshIntervalWithoutUrls
+        );
+    }
+
+    #[test]
+    fn test_task_limit_from_env() {
+        assert!(from(&[]).unwrap().task_limiter.is_none());
+        let limiter = from(&[("RUSTYSQUID_MAX_TASKS", "16")])
+            .unwrap()
+            .task_limiter
+            .unwrap();
+        assert_eq!(limiter.limit(), 16);
+        assert_eq!(
+            from(&[("RUSTYSQUID_MAX_TASKS", "0")]).unwrap_err(),
+            EnvConfigError::Invalid {
+                var: "RUSTYSQUID_MAX_TASKS",
+                value: "0".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_cache_policies_from_env() {
+        let config = from(&[
+            ("RUSTYSQUID_EXTENSION_TTLS", "mp4=86400, .HTML=60"),
+            (
+                "RUSTYSQUID_FORCE_CACHE",
+                "cdn.example.com/static/=3600, example.com=60",
+            ),
+            ("RUSTYSQUID_IGNORE_QUERY", "true"),
+            ("RUSTYSQUID_PRIVATE_QUERY_PARAMS", "session, sig"),
+            ("RUSTYSQUID_SERVER_HEADER", "1"),
+        ])
+        .unwrap();
+        assert!(config.server_header);
+        assert_eq!(config.extension_ttls.get("/intro.mp4"), Some(86400));
+        assert_eq!(config.extension_ttls.get("/index.html"), Some(60));
+        assert_eq!(config.force_cache.len(), 2);
+        assert!(config.force_cache[0].matches("cdn.example.com", "/static/app.js"));
+        assert!(!config.force_cache[0].matches("cdn.example.com", "/api"));
+        assert_eq!(config.force_cache[1].path_prefix, "/");
+        assert_eq!(config.force_cache[1].ttl, 60);
+        assert!(config.query_policy.ignore_query);
+        assert_eq!(config.query_policy.private_params, vec!["session", "sig"]);
+        // Settings left unset keep their values
+        assert!(!config.query_policy.collapse_identical_variants);
+        assert!(from(&[]).unwrap().force_cache.is_empty());
+
+        for (var, value) in [
+            ("RUSTYSQUID_EXTENSION_TTLS", "mp4"),
+            ("RUSTYSQUID_EXTENSION_TTLS", ".=60"),
+            ("RUSTYSQUID_EXTENSION_TTLS", "mp4=day"),
+            ("RUSTYSQUID_FORCE_CACHE", "/static/=60"),
+            ("RUSTYSQUID_FORCE_CACHE", "example.com/=0"),
+            ("RUSTYSQUID_IGNORE_QUERY", "sometimes"),
+            ("RUSTYSQUID_SERVER_HEADER", "yes"),
+        ] {
+            assert_eq!(
+                from(&[(var, value)]).unwrap_err(),
+                EnvConfigError::Invalid {
+                    var,
+                    value: value.to_string()
+                }
+            );
+        }
+    }
+}
@@ -1,4 +1,9 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::net::TcpStream;
@@ -6,9 +11,51 @@ use tokio::sync::Mutex;
 use tokio::time::timeout;
 use tracing::debug;
 
-const MAX_CONNECTIONS_PER_HOST: usize = 4;
+/// Default number of idle connections kept per upstream host
+pub const MAX_CONNECTIONS_PER_HOST: usize = 4;
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
 const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Independently locked slices of the pool, hosts are spread across them
+const POOL_SHARDS: usize = 16;
+
+/// Why a new upstream connection could not be opened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectError {
+    /// The origin actively refused, it is down and retrying right away won't help
+    Refused,
+    /// No answer within the connect timeout, possibly transient
+    TimedOut,
+    /// Any other connect failure (unreachable network, DNS, ...)
+    Failed(io::ErrorKind),
+}
+
+impl ConnectError {
+    /// Classify the error returned by `TcpStream::connect`
+    pub fn from_io(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Self::Refused,
+            io::ErrorKind::TimedOut => Self::TimedOut,
+            kind => Self::Failed(kind),
+        }
+    }
+
+    /// Whether a later attempt might succeed, refusals are definitive
+    pub fn is_transient(&self) -> bool {
+        !matches!(self, Self::Refused)
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Refused => write!(f, "Connection refused"),
+            Self::TimedOut => write!(f, "Connection timeout"),
+            Self::Failed(kind) => write!(f, "Connection failed: {}", kind),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
 
 #[derive(Debug)]
 struct PooledConnection {
@@ -21,26 +68,115 @@ type HostKey = (String, u16);
 type ConnectionVec = Vec<PooledConnection>;
 type PoolMap = HashMap<HostKey, ConnectionVec>;
 
+/// Snapshot of lifetime connection pool counters
+///
+/// Reuse effectiveness is `reused / (reused + created)`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolMetrics {
+    /// New upstream connections opened
+    pub created: u64,
+    /// Pooled connections handed out again after passing the liveness check
+    pub reused: u64,
+    /// Pooled connections discarded as idle too long, closed or dirty
+    pub dropped_stale: u64,
+    /// Returned connections closed because their host's pool was full
+    pub dropped_full: u64,
+}
+
+/// Lifetime counters behind [`PoolMetrics`]
+#[derive(Default)]
+struct PoolCounters {
+    created: AtomicU64,
+    reused: AtomicU64,
+    dropped_stale: AtomicU64,
+    dropped_full: AtomicU64,
+}
+
+impl PoolCounters {
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> PoolMetrics {
+        PoolMetrics {
+            created: self.created.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            dropped_stale: self.dropped_stale.load(Ordering::Relaxed),
+            dropped_full: self.dropped_full.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// Connection pool for upstream servers
+///
+/// Hosts are spread over a fixed set of separately locked maps, so getting
+/// and returning connections for unrelated hosts rarely waits on a lock.
 #[derive(Clone)]
 pub struct ConnectionPool {
-    pools: Arc<Mutex<PoolMap>>,
+    shards: Arc<[Mutex<PoolMap>]>,
+    default_per_host: usize,
+    per_host_overrides: Arc<HashMap<String, usize>>,
+    counters: Arc<PoolCounters>,
 }
 
 impl ConnectionPool {
     pub fn new() -> Self {
+        Self::with_limits(MAX_CONNECTIONS_PER_HOST, HashMap::new())
+    }
+
+    /// Pool keeping up to `default_per_host` idle connections per upstream,
+    /// with per-hostname overrides
+    ///
+    /// A limit of 0 disables pooling for that host.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rustysquid::connection_pool::ConnectionPool;
+    /// use std::collections::HashMap;
+    ///
+    /// let overrides = HashMap::from([("slow.example.com".to_string(), 16)]);
+    /// let pool = ConnectionPool::with_limits(2, overrides);
+    /// assert_eq!(pool.limit_for("slow.example.com"), 16);
+    /// assert_eq!(pool.limit_for("cdn.example.com"), 2);
+    /// ```
+    pub fn with_limits(default_per_host: usize, overrides: HashMap<String, usize>) -> Self {
+        let overrides = overrides
+            .into_iter()
+            .map(|(host, limit)| (host.to_ascii_lowercase(), limit))
+            .collect();
         Self {
-            pools: Arc::new(Mutex::new(HashMap::new())),
+            shards: (0..POOL_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            default_per_host,
+            per_host_overrides: Arc::new(overrides),
+            counters: Arc::new(PoolCounters::default()),
         }
     }
 
+    /// Maximum idle connections kept for `host`
+    pub fn limit_for(&self, host: &str) -> usize {
+        self.per_host_overrides
+            .get(&host.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(self.default_per_host)
+    }
+
+    /// Shard holding the connections for `key`
+    fn shard(&self, key: &HostKey) -> &Mutex<PoolMap> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
     /// Get a connection from the pool or create a new one
-    pub async fn get_connection(&self, host: &str, port: u16) -> Result<TcpStream, &'static str> {
+    pub async fn get_connection(&self, host: &str, port: u16) -> Result<TcpStream, ConnectError> {
         let key = (host.to_string(), port);
 
         // Try to get an existing connection
         {
-            let mut pools = self.pools.lock().await;
+            let mut pools = self.shard(&key).lock().await;
             if let Some(pool) = pools.get_mut(&key) {
                 while let Some(mut conn) = pool.pop() {
                     // Check if connection is still fresh
@@ -48,32 +184,43 @@ impl ConnectionPool {
                         // Test if connection is still alive
                         if Self::is_connection_alive(&mut conn.stream).await {
                             debug!("Reusing connection to {}:{}", host, port);
+                            PoolCounters::bump(&self.counters.reused);
                             return Ok(conn.stream);
                         }
                     }
                     // Connection is stale or dead, continue to next
                     debug!("Dropping stale connection to {}:{}", host, port);
+                    PoolCounters::bump(&self.counters.dropped_stale);
                 }
             }
         }
 
         // No suitable connection found, create new one
+        self.connect_fresh(host, port).await
+    }
+
+    /// Open a new connection, bypassing idle pooled ones
+    ///
+    /// For retries, where a pooled connection may be what just failed.
+    pub async fn connect_fresh(&self, host: &str, port: u16) -> Result<TcpStream, ConnectError> {
         debug!("Creating new connection to {}:{}", host, port);
-        timeout(CONNECTION_TIMEOUT, TcpStream::connect((host, port)))
+        let stream = timeout(CONNECTION_TIMEOUT, TcpStream::connect((host, port)))
             .await
-            .map_err(|_| "Connection timeout")?
-            .map_err(|_| "Connection failed")
+            .map_err(|_| ConnectError::TimedOut)?
+            .map_err(|e| ConnectError::from_io(&e))?;
+        PoolCounters::bump(&self.counters.created);
+        Ok(stream)
     }
 
     /// Return a connection to the pool
     pub async fn return_connection(&self, host: String, port: u16, stream: TcpStream) {
         let key = (host.clone(), port);
-        let mut pools = self.pools.lock().await;
+        let mut pools = self.shard(&key).lock().await;
 
         let pool = pools.entry(key).or_insert_with(Vec::new);
 
-        // Only keep up to MAX_CONNECTIONS_PER_HOST
-        if pool.len() < MAX_CONNECTIONS_PER_HOST {
+        // Only keep up to the host's limit
+        if pool.len() < self.limit_for(&host) {
             debug!("Returning connection to pool for {}:{}", host, port);
             pool.push(PooledConnection {
                 stream,
@@ -81,43 +228,62 @@ impl ConnectionPool {
             });
         } else {
             debug!("Pool full for {}:{}, dropping connection", host, port);
+            PoolCounters::bump(&self.counters.dropped_full);
             // Connection will be dropped automatically
         }
     }
 
-    /// Test if a connection is still alive
+    /// Test if a connection is still alive without waiting on it
+    ///
+    /// `readable()` also fires on EOF, so it can't tell a closed peer from
+    /// an open one. A non-blocking read can: `WouldBlock` is an idle live
+    /// connection, `Ok(0)` a closed one, and buffered bytes are left over
+    /// from an undrained response, which makes it unusable too.
     async fn is_connection_alive(stream: &mut TcpStream) -> bool {
-        // Try to read with zero timeout to check if connection is closed
-        // This is a non-blocking peek to see if the connection is still valid
-        stream.readable().await.is_ok()
+        let mut probe = [0u8; 1];
+        matches!(
+            stream.try_read(&mut probe),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock
+        )
     }
 
-    /// Clean up stale connections
+    /// Clean up stale connections, one shard at a time
     pub async fn cleanup_stale_connections(&self) {
-        let mut pools = self.pools.lock().await;
         let now = Instant::now();
+        for shard in self.shards.iter() {
+            let mut pools = shard.lock().await;
+            for ((host, port), pool) in pools.iter_mut() {
+                pool.retain(|conn| {
+                    let is_fresh = now.duration_since(conn.last_used) < IDLE_TIMEOUT;
+                    if !is_fresh {
+                        debug!("Removing stale connection to {}:{}", host, port);
+                        PoolCounters::bump(&self.counters.dropped_stale);
+                    }
+                    is_fresh
+                });
+            }
 
-        for ((host, port), pool) in pools.iter_mut() {
-            pool.retain(|conn| {
-                let is_fresh = now.duration_since(conn.last_used) < IDLE_TIMEOUT;
-                if !is_fresh {
-                    debug!("Removing stale connection to {}:{}", host, port);
-                }
-                is_fresh
-            });
+            // Remove empty pools
+            pools.retain(|_, pool| !pool.is_empty());
         }
-
-        // Remove empty pools
-        pools.retain(|_, pool| !pool.is_empty());
     }
 
     /// Get statistics about the connection pool
+    ///
+    /// Shards are read one after another, so the counts aren't a single
+    /// snapshot while connections are being used.
     pub async fn stats(&self) -> HashMap<HostKey, usize> {
-        let pools = self.pools.lock().await;
-        pools
-            .iter()
-            .map(|(key, pool)| (key.clone(), pool.len()))
-            .collect()
+        let mut stats = HashMap::new();
+        for shard in self.shards.iter() {
+            let pools = shard.lock().await;
+            stats.extend(pools.iter().map(|(key, pool)| (key.clone(), pool.len())));
+        }
+        stats
+    }
+
+    /// Lifetime counts of connections opened, reused and discarded
+    pub fn metrics(&self) -> PoolMetrics {
+        self.counters.snapshot()
     }
 }
 
@@ -140,6 +306,164 @@ mod tests {
         assert!(stats.is_empty());
     }
 
+    #[test]
+    fn test_connect_error_from_io_kind() {
+        let error = |kind| ConnectError::from_io(&io::Error::from(kind));
+        assert_eq!(
+            error(io::ErrorKind::ConnectionRefused),
+            ConnectError::Refused
+        );
+        assert_eq!(error(io::ErrorKind::TimedOut), ConnectError::TimedOut);
+        assert_eq!(
+            error(io::ErrorKind::AddrNotAvailable),
+            ConnectError::Failed(io::ErrorKind::AddrNotAvailable)
+        );
+        assert!(!ConnectError::Refused.is_transient());
+        assert!(ConnectError::TimedOut.is_transient());
+    }
+
+    #[tokio::test]
+    async fn test_get_connection_reports_refusal() {
+        // Bind then drop to find a local port with nothing listening
+        let port = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let result = ConnectionPool::new()
+            .get_connection("127.0.0.1", port)
+            .await;
+        assert_eq!(result.unwrap_err(), ConnectError::Refused);
+    }
+
+    #[tokio::test]
+    async fn test_closed_or_dirty_connections_are_discarded() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pool = ConnectionPool::new();
+        let key = ("127.0.0.1".to_string(), port);
+
+        // Peer closed: the pooled socket is dropped and a new one opened
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let pooled_addr = stream.local_addr().unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        pool.return_connection("127.0.0.1".to_string(), port, stream)
+            .await;
+        drop(server);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let fresh = pool.get_connection("127.0.0.1", port).await.unwrap();
+        assert_ne!(fresh.local_addr().unwrap(), pooled_addr);
+        assert_eq!(pool.stats().await.get(&key), Some(&0));
+
+        // Undrained bytes: also unusable
+        let (mut server, _) = listener.accept().await.unwrap();
+        let pooled_addr = fresh.local_addr().unwrap();
+        pool.return_connection("127.0.0.1".to_string(), port, fresh)
+            .await;
+        tokio::io::AsyncWriteExt::write_all(&mut server, b"leftover")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let fresh = pool.get_connection("127.0.0.1", port).await.unwrap();
+        assert_ne!(fresh.local_addr().unwrap(), pooled_addr);
+
+        // Idle and open: reused
+        let (_server, _) = listener.accept().await.unwrap();
+        let pooled_addr = fresh.local_addr().unwrap();
+        pool.return_connection("127.0.0.1".to_string(), port, fresh)
+            .await;
+        let reused = pool.get_connection("127.0.0.1", port).await.unwrap();
+        assert_eq!(reused.local_addr().unwrap(), pooled_addr);
+
+        // Only the connection that passed the liveness check counts as reused
+        assert_eq!(
+            pool.metrics(),
+            PoolMetrics {
+                created: 2,
+                reused: 1,
+                dropped_stale: 2,
+                dropped_full: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_per_host_limits() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let overrides = HashMap::from([("throttled.test".to_string(), 1)]);
+        let pool = ConnectionPool::with_limits(MAX_CONNECTIONS_PER_HOST, overrides);
+
+        for host in ["throttled.test", "default.test"] {
+            for _ in 0..6 {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                pool.return_connection(host.to_string(), 80, stream).await;
+            }
+        }
+        let stats = pool.stats().await;
+        assert_eq!(stats.get(&("throttled.test".to_string(), 80)), Some(&1));
+        assert_eq!(
+            stats.get(&("default.test".to_string(), 80)),
+            Some(&MAX_CONNECTIONS_PER_HOST)
+        );
+        assert_eq!(pool.limit_for("THROTTLED.test"), 1);
+        let dropped = (6 - 1) + (6 - MAX_CONNECTIONS_PER_HOST);
+        assert_eq!(pool.metrics().dropped_full, dropped as u64);
+        // Connections opened outside the pool aren't counted as created
+        assert_eq!(pool.metrics().created, 0);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_hosts_stay_consistent() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Keep the server ends open so pooled connections stay alive
+        tokio::spawn(async move {
+            let mut accepted = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.push(stream);
+            }
+        });
+
+        let pool = ConnectionPool::with_limits(2, HashMap::new());
+        let tasks: Vec<_> = (0..64)
+            .map(|i| {
+                let pool = pool.clone();
+                tokio::spawn(async move {
+                    let host = format!("host{}.test", i);
+                    for _ in 0..3 {
+                        let stream = TcpStream::connect(addr).await.unwrap();
+                        pool.return_connection(host.clone(), addr.port(), stream)
+                            .await;
+                        tokio::task::yield_now().await;
+                    }
+                    // Reuse one while other tasks hit the neighbouring shards
+                    let stream = pool.get_connection(&host, addr.port()).await.unwrap();
+                    pool.return_connection(host, addr.port(), stream).await;
+                })
+            })
+            .collect();
+        timeout(Duration::from_secs(10), async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        })
+        .await
+        .expect("pool operations deadlocked");
+
+        let stats = pool.stats().await;
+        assert_eq!(stats.len(), 64);
+        assert!(stats.values().all(|&idle| idle == 2), "{:?}", stats);
+        // Hosts landed on more than one shard
+        let mut used = 0;
+        for shard in pool.shards.iter() {
+            used += usize::from(!shard.lock().await.is_empty());
+        }
+        assert!(used > 1);
+    }
+
     #[tokio::test]
     async fn test_connection_pool_return() {
         let pool = ConnectionPool::new();
@@ -0,0 +1,110 @@
+use crate::header_value;
+
+/// The parts of a CORS preflight request its answer depends on
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::cors::preflight;
+///
+/// let headers = vec![
+///     "Origin: https://app.example".to_string(),
+///     "Access-Control-Request-Method: PUT".to_string(),
+/// ];
+/// let request = preflight("OPTIONS", &headers).unwrap();
+/// assert_eq!(request.method, "PUT");
+/// assert!(preflight("GET", &headers).is_none());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Preflight<'a> {
+    /// `Origin` the browser is asking for
+    pub origin: &'a str,
+    /// `Access-Control-Request-Method`, the method of the actual request
+    pub method: &'a str,
+    /// `Access-Control-Request-Headers` lowercased and sorted, so the same
+    /// set of headers always gives the same key
+    pub request_headers: String,
+}
+
+/// The preflight an `OPTIONS` request carries, None for any other request
+///
+/// A preflight names both an `Origin` and the method it asks about in
+/// `Access-Control-Request-Method`, a plain `OPTIONS` request does not.
+pub fn preflight<'a>(method: &str, headers: &'a [String]) -> Option<Preflight<'a>> {
+    if method != "OPTIONS" {
+        return None;
+    }
+    let origin = header_value(headers, "origin")?.trim();
+    let requested = header_value(headers, "access-control-request-method")?.trim();
+    if origin.is_empty() || requested.is_empty() {
+        return None;
+    }
+    let mut request_headers: Vec<String> = header_value(headers, "access-control-request-headers")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    request_headers.sort_unstable();
+    request_headers.dedup();
+    Some(Preflight {
+        origin,
+        method: requested,
+        request_headers: request_headers.join(","),
+    })
+}
+
+/// Seconds a preflight answer may be reused, from `Access-Control-Max-Age`
+///
+/// None when the header is missing, malformed or zero, which all mean the
+/// answer must not be reused.
+pub fn max_age(response_headers: &[String]) -> Option<u64> {
+    header_value(response_headers, "access-control-max-age")?
+        .trim()
+        .parse()
+        .ok()
+        .filter(|&secs| secs > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_preflight_detection() {
+        let request = headers(&[
+            "Origin: https://app.example",
+            "Access-Control-Request-Method: DELETE",
+            "Access-Control-Request-Headers: X-Token, content-type,x-token",
+        ]);
+        let found = preflight("OPTIONS", &request).unwrap();
+        assert_eq!(found.origin, "https://app.example");
+        assert_eq!(found.method, "DELETE");
+        assert_eq!(found.request_headers, "content-type,x-token");
+
+        // Plain OPTIONS requests aren't preflights
+        let plain = headers(&["Origin: https://app.example"]);
+        assert!(preflight("OPTIONS", &plain).is_none());
+        let no_origin = headers(&["Access-Control-Request-Method: PUT"]);
+        assert!(preflight("OPTIONS", &no_origin).is_none());
+    }
+
+    #[test]
+    fn test_max_age() {
+        assert_eq!(
+            max_age(&headers(&["Access-Control-Max-Age: 600"])),
+            Some(600)
+        );
+        assert_eq!(
+            max_age(&headers(&["access-control-max-age:  30 "])),
+            Some(30)
+        );
+        assert_eq!(max_age(&headers(&["Access-Control-Max-Age: 0"])), None);
+        assert_eq!(max_age(&headers(&["Access-Control-Max-Age: -1"])), None);
+        assert_eq!(max_age(&[]), None);
+    }
+}
@@ -0,0 +1,726 @@
+use crate::{CachedResponse, CachedUrl, ProxyCache};
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Default interval between coalesced disk flushes
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Default number of dirty entries that forces an early flush
+pub const DEFAULT_FLUSH_BATCH: usize = 256;
+
+/// Batches dirty cache keys so repeated writes to the same entry reach disk
+/// at most once per flush
+///
+/// A cache persisting its changes marks keys here as entries are stored and
+/// removed, see [`crate::ProxyCache::persist_changes`]. The dirty set is
+/// only locked briefly and never across an await.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::disk::WriteCoalescer;
+/// use std::time::Duration;
+///
+/// let coalescer = WriteCoalescer::new(Duration::from_secs(5), 128);
+/// coalescer.mark_dirty(1);
+/// coalescer.mark_dirty(1);
+///
+/// let mut writes = 0;
+/// coalescer.flush(|_key| writes += 1);
+/// assert_eq!(writes, 1);
+/// ```
+#[derive(Clone)]
+pub struct WriteCoalescer {
+    dirty: Arc<Mutex<HashSet<u64>>>,
+    batch_ready: Arc<Notify>,
+    flush_interval: Duration,
+    max_batch: usize,
+}
+
+impl WriteCoalescer {
+    /// Creates a coalescer that flushes every `flush_interval`, or sooner once
+    /// `max_batch` distinct keys are dirty
+    pub fn new(flush_interval: Duration, max_batch: usize) -> Self {
+        Self {
+            dirty: Arc::new(Mutex::new(HashSet::new())),
+            batch_ready: Arc::new(Notify::new()),
+            flush_interval,
+            max_batch: max_batch.max(1),
+        }
+    }
+
+    /// Record that `key` changed, returns true if the batch limit was reached
+    pub fn mark_dirty(&self, key: u64) -> bool {
+        let mut dirty = self.dirty();
+        dirty.insert(key);
+        let full = dirty.len() >= self.max_batch;
+        if full {
+            self.batch_ready.notify_one();
+        }
+        full
+    }
+
+    /// Number of keys waiting to be flushed
+    pub fn pending(&self) -> usize {
+        self.dirty().len()
+    }
+
+    /// Drain the dirty set, calling `write` once per key, returns keys written
+    pub fn flush<F>(&self, mut write: F) -> usize
+    where
+        F: FnMut(u64),
+    {
+        let batch: Vec<u64> = self.dirty().drain().collect();
+
+        for key in &batch {
+            write(*key);
+        }
+        if !batch.is_empty() {
+            debug!("Flushed {} dirty cache entries", batch.len());
+        }
+        batch.len()
+    }
+
+    /// Spawn a task flushing on every interval tick or when a batch fills up
+    ///
+    /// Abort the returned handle and call [`WriteCoalescer::flush`] once more
+    /// on shutdown so the last batch is not lost.
+    pub fn spawn_flusher<F>(self, mut write: F) -> JoinHandle<()>
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        tokio::spawn(async move {
+            loop {
+                self.next_flush().await;
+                self.flush(&mut write);
+            }
+        })
+    }
+
+    /// Wait until the flush interval has passed or a batch has filled up
+    pub async fn next_flush(&self) {
+        tokio::select! {
+            () = tokio::time::sleep(self.flush_interval) => {},
+            () = self.batch_ready.notified() => {},
+        }
+    }
+
+    fn dirty(&self) -> std::sync::MutexGuard<'_, HashSet<u64>> {
+        // A set of keys can't be left half updated, a poisoned lock is still usable
+        self.dirty
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Default for WriteCoalescer {
+    fn default() -> Self {
+        Self::new(DEFAULT_FLUSH_INTERVAL, DEFAULT_FLUSH_BATCH)
+    }
+}
+
+/// Background task writing the entries a [`WriteCoalescer`] marks dirty to
+/// a [`DiskCache`], started by [`crate::ProxyCache::persist_changes`]
+///
+/// Call [`Persister::shutdown`] once the cache stops changing, so the last
+/// batch is written rather than lost with the task.
+#[derive(Debug)]
+pub struct Persister {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<usize>,
+}
+
+impl Persister {
+    pub(crate) fn spawn(cache: ProxyCache, disk: DiskCache, coalescer: WriteCoalescer) -> Self {
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    () = coalescer.next_flush() => {},
+                    _ = &mut stopped => break,
+                }
+                cache.write_dirty(&disk, &coalescer).await;
+            }
+            cache.write_dirty(&disk, &coalescer).await
+        });
+        Self { stop, task }
+    }
+
+    /// Write the entries still dirty and stop, returns how many were written
+    pub async fn shutdown(self) -> usize {
+        let _ = self.stop.send(());
+        self.task.await.unwrap_or(0)
+    }
+}
+
+/// Leading bytes of every persisted entry file
+const ENTRY_MAGIC: &[u8; 4] = b"RSQC";
+
+/// Version of the entry layout, written after the magic and bumped
+/// whenever [`encode_entry`] changes
+pub const FORMAT_VERSION: u8 = 3;
+
+/// Magic, version byte and payload checksum
+const HEADER_LEN: usize = ENTRY_MAGIC.len() + 1 + 8;
+
+/// File holding the key seed the entries in a directory were hashed with
+const SEED_FILE: &str = "key_seed";
+
+/// Directory of persisted cache entries, one file per key
+///
+/// Entry files are named after the hex cache key. Keys depend on the hash
+/// seed, so the seed is stored alongside and entries are only reloaded into
+/// a cache using the same seed.
+#[derive(Clone, Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Seed recorded by the last [`DiskCache::store`], if any
+    pub fn key_seed(&self) -> Option<u64> {
+        let bytes = fs::read(self.dir.join(SEED_FILE)).ok()?;
+        Some(u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?))
+    }
+
+    /// Record the seed the entries written from now on are hashed with
+    pub fn store_key_seed(&self, key_seed: u64) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        write_atomic(&self.dir.join(SEED_FILE), &key_seed.to_le_bytes())
+    }
+
+    /// Write one entry and the URL it was fetched from, if known, replacing
+    /// the file of an earlier version
+    pub fn store_entry(
+        &self,
+        key: u64,
+        entry: &CachedResponse,
+        url: Option<&CachedUrl>,
+    ) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        write_atomic(
+            &self.dir.join(entry_file_name(key)),
+            &encode_entry(entry, url),
+        )
+    }
+
+    /// Delete one entry's file, which may already be gone
+    pub fn remove_entry(&self, key: u64) -> io::Result<()> {
+        match fs::remove_file(self.dir.join(entry_file_name(key))) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the directory contents with `entries` and their URLs,
+    /// returns entries written
+    pub fn store(&self, key_seed: u64, entries: &[StoredEntry]) -> io::Result<usize> {
+        self.store_key_seed(key_seed)?;
+
+        let mut keep = HashSet::with_capacity(entries.len());
+        for (key, entry, url) in entries {
+            let name = entry_file_name(*key);
+            write_atomic(&self.dir.join(&name), &encode_entry(entry, url.as_ref()))?;
+            keep.insert(name);
+        }
+
+        // Drop files for entries that have since been evicted or expired
+        for file in fs::read_dir(&self.dir)?.flatten() {
+            let name = file.file_name().to_string_lossy().to_string();
+            if parse_entry_file_name(&name).is_some() && !keep.contains(&name) {
+                fs::remove_file(file.path())?;
+            }
+        }
+        Ok(entries.len())
+    }
+
+    /// Read back entries still fresh at `now`, nothing if the directory is missing
+    ///
+    /// Every file is validated on its own: expired entries are skipped
+    /// quietly, unreadable, corrupt and other-version files with a warning,
+    /// and none of them fail the load. Nothing is returned if the directory
+    /// was written with a different key seed.
+    pub fn load(&self, key_seed: u64, now: u64) -> io::Result<LoadedEntries> {
+        let mut loaded = LoadedEntries::default();
+        if !self.dir.exists() {
+            return Ok(loaded);
+        }
+        if let Some(stored_seed) = self.key_seed() {
+            if stored_seed != key_seed {
+                warn!(
+                    "Ignoring cache in {}: written with a different key seed",
+                    self.dir.display()
+                );
+                return Ok(loaded);
+            }
+        }
+
+        for file in fs::read_dir(&self.dir)?.flatten() {
+            let name = file.file_name().to_string_lossy().to_string();
+            let Some(key) = parse_entry_file_name(&name) else {
+                continue;
+            };
+            let path = file.path();
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("Skipping unreadable cache file {}: {}", path.display(), e);
+                    loaded.stats.corrupt += 1;
+                    continue;
+                }
+            };
+            match decode_entry(&data) {
+                Ok((entry, url)) if entry.expires > now => loaded.entries.push((key, entry, url)),
+                Ok(_) => {
+                    debug!("Skipping expired cache file {}", path.display());
+                    loaded.stats.expired += 1;
+                }
+                Err(e) => {
+                    warn!("Skipping cache file {}: {}", path.display(), e);
+                    match e {
+                        DecodeError::UnsupportedVersion(_) => loaded.stats.incompatible += 1,
+                        _ => loaded.stats.corrupt += 1,
+                    }
+                }
+            }
+        }
+        loaded.stats.loaded = loaded.entries.len();
+        Ok(loaded)
+    }
+}
+
+/// A cache key with its entry and the URL the entry was fetched from,
+/// None for entries stored by key alone
+pub type StoredEntry<E = Arc<CachedResponse>> = (u64, E, Option<CachedUrl>);
+
+/// Entries read by [`DiskCache::load`] along with what was skipped
+#[derive(Debug, Default, PartialEq)]
+pub struct LoadedEntries {
+    pub entries: Vec<StoredEntry<CachedResponse>>,
+    pub stats: LoadStats,
+}
+
+/// Counts from reloading a cache directory
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadStats {
+    /// Entries accepted
+    pub loaded: usize,
+    /// Valid entries the cache turned away, e.g. over its size limits
+    pub refused: usize,
+    /// Entries no longer fresh
+    pub expired: usize,
+    /// Files that couldn't be read, failed their checksum or didn't parse
+    pub corrupt: usize,
+    /// Files written in another format version
+    pub incompatible: usize,
+}
+
+impl LoadStats {
+    /// Files found but not loaded, for whatever reason
+    pub fn skipped(&self) -> usize {
+        self.refused + self.expired + self.corrupt + self.incompatible
+    }
+}
+
+/// Why an entry file couldn't be decoded, see [`decode_entry`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Too short for the header or not starting with the entry magic
+    NotAnEntry,
+    /// Written in a format version this build doesn't read
+    UnsupportedVersion(u8),
+    /// The payload doesn't hash to the checksum in the header
+    ChecksumMismatch,
+    /// The checksum matched but the payload didn't parse
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAnEntry => write!(f, "not a cache entry"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "format version {} is not supported (expected {})",
+                version, FORMAT_VERSION
+            ),
+            Self::ChecksumMismatch => write!(f, "checksum mismatch"),
+            Self::Malformed => write!(f, "malformed entry"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn entry_file_name(key: u64) -> String {
+    format!("{:016x}", key)
+}
+
+fn parse_entry_file_name(name: &str) -> Option<u64> {
+    if name.len() != 16 {
+        return None;
+    }
+    u64::from_str_radix(name, 16).ok()
+}
+
+/// Write through a temporary file so a crash never leaves a partial entry
+fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(tmp, path)
+}
+
+/// Serialize an entry into the compact on-disk format
+///
+/// A header of the magic, [`FORMAT_VERSION`] and the xxh64 checksum of the
+/// payload, body included, comes first. In the payload integers are
+/// little-endian, strings and the body are prefixed with their `u32`
+/// length, optional validators and the optional `url` with a presence byte.
+pub fn encode_entry(entry: &CachedResponse, url: Option<&CachedUrl>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64 + entry.body.len());
+    out.extend_from_slice(ENTRY_MAGIC);
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&entry.expires.to_le_bytes());
+    out.extend_from_slice(&entry.stored_at.to_le_bytes());
+    put_bytes(&mut out, entry.status_line.as_bytes());
+    put_strings(&mut out, &entry.headers);
+    put_bytes(&mut out, &entry.body);
+    put_strings(&mut out, &entry.vary);
+    for validator in [&entry.etag, &entry.last_modified] {
+        match validator {
+            Some(value) => {
+                out.push(1);
+                put_bytes(&mut out, value.as_bytes());
+            }
+            None => out.push(0),
+        }
+    }
+    out.push(u8::from(entry.must_revalidate));
+    out.push(u8::from(entry.pinned));
+    out.push(u8::from(entry.always_revalidate));
+    out.push(u8::from(entry.etag_synthesized));
+    match url {
+        Some(url) => {
+            out.push(1);
+            put_bytes(&mut out, url.host.as_bytes());
+            out.extend_from_slice(&u32::from(url.port).to_le_bytes());
+            put_bytes(&mut out, url.path.as_bytes());
+        }
+        None => out.push(0),
+    }
+    let checksum = xxhash_rust::xxh64::xxh64(&out[HEADER_LEN..], 0);
+    out[HEADER_LEN - 8..HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+/// Parse an entry and its URL written by [`encode_entry`], checking its
+/// version and checksum
+pub fn decode_entry(data: &[u8]) -> Result<(CachedResponse, Option<CachedUrl>), DecodeError> {
+    if data.len() < HEADER_LEN || !data.starts_with(ENTRY_MAGIC) {
+        return Err(DecodeError::NotAnEntry);
+    }
+    let version = data[ENTRY_MAGIC.len()];
+    if version != FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let (header, payload) = data.split_at(HEADER_LEN);
+    let checksum = u64::from_le_bytes(header[HEADER_LEN - 8..].try_into().unwrap_or_default());
+    if xxhash_rust::xxh64::xxh64(payload, 0) != checksum {
+        return Err(DecodeError::ChecksumMismatch);
+    }
+    decode_payload(payload).ok_or(DecodeError::Malformed)
+}
+
+fn decode_payload(payload: &[u8]) -> Option<(CachedResponse, Option<CachedUrl>)> {
+    let mut reader = Reader(payload);
+    let expires = reader.u64()?;
+    let stored_at = reader.u64()?;
+    let status_line = reader.string()?;
+    let headers = reader.strings()?;
+    let body = Bytes::copy_from_slice(reader.bytes()?);
+    let vary = reader.strings()?;
+    let etag = reader.optional_string()?;
+    let last_modified = reader.optional_string()?;
+    let must_revalidate = reader.flag()?;
+    let pinned = reader.flag()?;
+    let always_revalidate = reader.flag()?;
+    let etag_synthesized = reader.flag()?;
+    let url = match reader.take(1)?[0] {
+        0 => None,
+        1 => Some(CachedUrl {
+            host: reader.string()?,
+            port: u16::try_from(reader.u32()?).ok()?,
+            path: reader.string()?,
+        }),
+        _ => return None,
+    };
+    if !reader.0.is_empty() {
+        return None;
+    }
+
+    let entry = CachedResponse {
+        status_line,
+        headers,
+        body,
+        expires,
+        vary,
+        etag,
+        etag_synthesized,
+        last_modified,
+        stored_at,
+        must_revalidate,
+        always_revalidate,
+        pinned,
+    };
+    Some((entry, url))
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+fn put_strings(out: &mut Vec<u8>, strings: &[String]) {
+    out.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+    for s in strings {
+        put_bytes(out, s.as_bytes());
+    }
+}
+
+/// Bounds-checked cursor over an encoded entry
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    fn strings(&mut self) -> Option<Vec<String>> {
+        let count = self.u32()?;
+        (0..count).map(|_| self.string()).collect()
+    }
+
+    fn flag(&mut self) -> Option<bool> {
+        match self.take(1)?[0] {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        }
+    }
+
+    fn optional_string(&mut self) -> Option<Option<String>> {
+        match self.take(1)?[0] {
+            0 => Some(None),
+            1 => self.string().map(Some),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn sample_entry(expires: u64) -> CachedResponse {
+        CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec![
+                "Content-Type: text/css".to_string(),
+                "ETag: \"v1\"".to_string(),
+            ],
+            body: Bytes::from("body { color: red }"),
+            expires,
+            vary: vec!["accept-encoding".to_string()],
+            etag: Some("\"v1\"".to_string()),
+            etag_synthesized: true,
+            last_modified: None,
+            stored_at: 100,
+            must_revalidate: true,
+            always_revalidate: true,
+            pinned: true,
+        }
+    }
+
+    fn body_offset(encoded: &[u8]) -> usize {
+        encoded
+            .windows(4)
+            .position(|window| window == b"body")
+            .unwrap()
+    }
+
+    fn sample_url() -> CachedUrl {
+        CachedUrl {
+            host: "cdn.example.com".to_string(),
+            port: 8080,
+            path: "/site.css?v=2".to_string(),
+        }
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustysquid-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let entry = sample_entry(12345);
+        let encoded = encode_entry(&entry, Some(&sample_url()));
+        assert_eq!(
+            decode_entry(&encoded),
+            Ok((entry.clone(), Some(sample_url())))
+        );
+        let unindexed = encode_entry(&entry, None);
+        assert_eq!(decode_entry(&unindexed), Ok((entry, None)));
+
+        // Truncation anywhere is detected
+        for len in 0..encoded.len() {
+            assert!(decode_entry(&encoded[..len]).is_err());
+        }
+
+        // So is a flipped bit in the body, via the checksum
+        let mut flipped = encoded.clone();
+        flipped[body_offset(&encoded)] ^= 1;
+        assert_eq!(decode_entry(&flipped), Err(DecodeError::ChecksumMismatch));
+
+        let mut future = encoded;
+        future[ENTRY_MAGIC.len()] = FORMAT_VERSION + 1;
+        assert_eq!(
+            decode_entry(&future),
+            Err(DecodeError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn test_disk_cache_skips_expired_and_corrupt_files() {
+        let dir = scratch_dir("disk-load");
+        let disk = DiskCache::new(&dir);
+        let entries = vec![
+            (1, Arc::new(sample_entry(2_000)), Some(sample_url())),
+            (2, Arc::new(sample_entry(500)), None),
+        ];
+        assert_eq!(disk.store(7, &entries).unwrap(), 2);
+        fs::write(dir.join(entry_file_name(3)), b"RSQCgarbage").unwrap();
+        let mut damaged = encode_entry(&sample_entry(3_000), None);
+        let body_at = body_offset(&damaged);
+        damaged[body_at] ^= 0x20;
+        fs::write(dir.join(entry_file_name(4)), damaged).unwrap();
+        let mut older = encode_entry(&sample_entry(3_000), None);
+        older[ENTRY_MAGIC.len()] = 1;
+        fs::write(dir.join(entry_file_name(5)), older).unwrap();
+
+        let loaded = disk.load(7, 1_000).unwrap();
+        assert_eq!(
+            loaded.entries,
+            vec![(1, sample_entry(2_000), Some(sample_url()))]
+        );
+        assert_eq!(
+            loaded.stats,
+            LoadStats {
+                loaded: 1,
+                expired: 1,
+                corrupt: 2,
+                incompatible: 1,
+                ..LoadStats::default()
+            }
+        );
+        assert_eq!(loaded.stats.skipped(), 4);
+
+        // Keys hashed with another seed would be unreachable
+        assert_eq!(disk.load(8, 1_000).unwrap(), LoadedEntries::default());
+
+        // Storing again drops files for entries no longer present
+        disk.store(7, &entries[..1]).unwrap();
+        assert!(!dir.join(entry_file_name(2)).exists());
+        assert!(!dir.join(entry_file_name(3)).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rapid_puts_coalesce_into_single_write() {
+        let coalescer = WriteCoalescer::new(Duration::from_secs(60), 16);
+
+        for _ in 0..100 {
+            coalescer.mark_dirty(42);
+        }
+        assert_eq!(coalescer.pending(), 1);
+
+        let mut written = Vec::new();
+        assert_eq!(coalescer.flush(|key| written.push(key)), 1);
+        assert_eq!(written, vec![42]);
+
+        // Nothing left until the key is dirtied again
+        assert_eq!(coalescer.flush(|_| panic!("unexpected write")), 0);
+    }
+
+    #[test]
+    fn test_batch_limit_signals_flush() {
+        let coalescer = WriteCoalescer::new(Duration::from_secs(60), 3);
+
+        assert!(!coalescer.mark_dirty(1));
+        assert!(!coalescer.mark_dirty(2));
+        assert!(!coalescer.mark_dirty(2));
+        assert!(coalescer.mark_dirty(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flusher_writes_once_per_interval() {
+        let coalescer = WriteCoalescer::new(Duration::from_secs(5), 100);
+        let writes = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&writes);
+        let handle = coalescer.clone().spawn_flusher(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        for _ in 0..10 {
+            coalescer.mark_dirty(7);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+
+        handle.abort();
+    }
+}
@@ -0,0 +1,84 @@
+use std::fs;
+
+/// Number of descriptors still available before hitting the soft limit
+pub fn fd_headroom(open: usize, soft_limit: usize) -> usize {
+    soft_limit.saturating_sub(open)
+}
+
+/// Read the current descriptor headroom of this process
+/// Returns None if it can't be determined or the limit is unlimited
+pub fn current_fd_headroom() -> Option<usize> {
+    Some(fd_headroom(open_fd_count()?, fd_soft_limit()?))
+}
+
+/// Count open descriptors by listing `/proc/self/fd`
+pub fn open_fd_count() -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(entries) = fs::read_dir("/proc/self/fd") {
+            // The listing itself holds one descriptor, which is fine to count
+            return Some(entries.count());
+        }
+    }
+
+    None
+}
+
+/// Soft `RLIMIT_NOFILE` for this process, read from `/proc/self/limits`
+pub fn fd_soft_limit() -> Option<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Ok(limits) = fs::read_to_string("/proc/self/limits") {
+            return parse_soft_limit(&limits);
+        }
+    }
+
+    None
+}
+
+/// Extract the "Max open files" soft limit from `/proc/<pid>/limits` content
+pub fn parse_soft_limit(limits: &str) -> Option<usize> {
+    let line = limits
+        .lines()
+        .find(|line| line.starts_with("Max open files"))?;
+    line["Max open files".len()..]
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fd_headroom_simulated_counts() {
+        assert_eq!(fd_headroom(10, 1024), 1014);
+        assert_eq!(fd_headroom(1000, 1024), 24);
+        assert_eq!(fd_headroom(1024, 1024), 0);
+        // Descriptors inherited above a lowered limit must not underflow
+        assert_eq!(fd_headroom(2000, 1024), 0);
+    }
+
+    #[test]
+    fn test_parse_soft_limit() {
+        let limits = "Limit                     Soft Limit           Hard Limit           Units     \n\
+                      Max processes             63459                63459                processes \n\
+                      Max open files            1024                 524288               files     \n";
+        assert_eq!(parse_soft_limit(limits), Some(1024));
+
+        let unlimited =
+            "Max open files            unlimited            unlimited            files     \n";
+        assert_eq!(parse_soft_limit(unlimited), None);
+        assert_eq!(parse_soft_limit(""), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_current_fd_headroom() {
+        let open = open_fd_count().unwrap();
+        assert!(open > 0);
+        assert!(current_fd_headroom().unwrap() < fd_soft_limit().unwrap());
+    }
+}
@@ -0,0 +1,120 @@
+use crate::cache_control::CacheControl;
+use crate::compress::without_headers;
+use crate::header_value;
+use bytes::Bytes;
+
+/// Insert `snippet` right after the opening `<head>` tag of an HTML body
+///
+/// Only unencoded `text/html` responses are touched, and never ones marked
+/// `Cache-Control: no-transform`. `Content-Length` is replaced to match the
+/// new body. Responses without a `<head>` tag are returned unchanged.
+///
+/// # Examples
+///
+/// ```
+/// use bytes::Bytes;
+/// use rustysquid::html::inject_after_head;
+///
+/// let headers = vec!["Content-Type: text/html".to_string()];
+/// let body = Bytes::from_static(b"<html><head><title>a</title></head></html>");
+/// let (_, body) = inject_after_head(headers, body, r#"<base href="/app/">"#);
+/// assert!(body.starts_with(br#"<html><head><base href="/app/"><title>"#));
+/// ```
+pub fn inject_after_head(headers: Vec<String>, body: Bytes, snippet: &str) -> (Vec<String>, Bytes) {
+    let is_html = header_value(&headers, "content-type").is_some_and(|content_type| {
+        content_type
+            .trim()
+            .to_ascii_lowercase()
+            .starts_with("text/html")
+    });
+    if !is_html
+        || header_value(&headers, "content-encoding").is_some()
+        || CacheControl::parse(&headers).no_transform
+    {
+        return (headers, body);
+    }
+    let Some(at) = head_tag_end(&body) else {
+        return (headers, body);
+    };
+
+    let mut injected = Vec::with_capacity(body.len() + snippet.len());
+    injected.extend_from_slice(&body[..at]);
+    injected.extend_from_slice(snippet.as_bytes());
+    injected.extend_from_slice(&body[at..]);
+    let mut headers = without_headers(headers, &["content-length"]);
+    headers.push(format!("Content-Length: {}", injected.len()));
+    (headers, Bytes::from(injected))
+}
+
+/// Offset just past the `>` of the first `<head>` tag, attributes allowed
+fn head_tag_end(body: &[u8]) -> Option<usize> {
+    let mut from = 0;
+    while let Some(offset) = body[from..]
+        .windows(5)
+        .position(|window| window.eq_ignore_ascii_case(b"<head"))
+    {
+        let after = from + offset + 5;
+        // `<header>` and friends are other elements
+        match body.get(after) {
+            Some(b'>') => return Some(after + 1),
+            Some(b) if b.is_ascii_whitespace() => {
+                return body[after..]
+                    .iter()
+                    .position(|&b| b == b'>')
+                    .map(|end| after + end + 1);
+            }
+            _ => from = after,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SNIPPET: &str = r#"<base href="/app/">"#;
+
+    fn html(extra: &str) -> Vec<String> {
+        let mut headers = vec!["Content-Type: text/html; charset=utf-8".to_string()];
+        if !extra.is_empty() {
+            headers.push(extra.to_string());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_snippet_follows_head_tag() {
+        let body = Bytes::from_static(b"<header></header><HEAD lang=\"en\">\n<title>x</title>");
+        let (headers, injected) =
+            inject_after_head(html("Content-Length: 50"), body.clone(), SNIPPET);
+        assert_eq!(
+            &injected[..],
+            &b"<header></header><HEAD lang=\"en\"><base href=\"/app/\">\n<title>x</title>"[..]
+        );
+        let length = injected.len().to_string();
+        assert_eq!(
+            header_value(&headers, "content-length"),
+            Some(length.as_str())
+        );
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn test_untouched_without_html_head() {
+        let body = Bytes::from_static(b"<html><head></head></html>");
+        let unchanged = |headers: Vec<String>, body: Bytes| {
+            inject_after_head(headers.clone(), body.clone(), SNIPPET) == (headers, body)
+        };
+        assert!(unchanged(
+            html("Cache-Control: max-age=60, no-transform"),
+            body.clone()
+        ));
+        assert!(unchanged(html("Content-Encoding: gzip"), body.clone()));
+        assert!(unchanged(
+            vec!["Content-Type: text/plain".to_string()],
+            body.clone()
+        ));
+        assert!(unchanged(html(""), Bytes::from_static(b"<p>no head</p>")));
+    }
+}
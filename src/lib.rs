@@ -1,13 +1,50 @@
 use bytes::Bytes;
+use config::{CacheConfigError, ProxyCacheConfig};
+use cors::Preflight;
+use disk::{DiskCache, LoadStats, Persister, StoredEntry, WriteCoalescer};
 use lru::LruCache;
+use memory::MemoryMonitor;
+use query::{QueryPolicy, QueryVariants};
+use single_flight::SingleFlight;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
 use std::num::NonZeroUsize;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::sync::Mutex;
-
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+use vary::{create_vary_cache_key_with_seed, VaryPolicy};
+use xxhash_rust::xxh64::Xxh64;
+
+pub mod access_log;
+pub mod acl;
+pub mod backend;
+pub mod cache_control;
+pub mod chunked;
+pub mod compress;
+pub mod config;
 pub mod connection_pool;
+pub mod cors;
+pub mod disk;
+pub mod fd;
+pub mod html;
 pub mod memory;
+pub mod metrics;
+pub mod proxy;
+pub mod query;
+pub mod rate_limit;
+pub mod single_flight;
+pub mod tasks;
+pub mod vary;
+
+/// Crate version, reported in logs and by the admin listener
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 /// Maximum number of cache entries
 pub const CACHE_SIZE: usize = 10000;
@@ -24,12 +61,38 @@ pub const MAX_ENTRY_SIZE: usize = 5 * 1024 * 1024;
 /// Default cache TTL in seconds (1 hour)
 pub const CACHE_TTL: u64 = 3600;
 
+/// Maximum TTL for any cached response in seconds (24 hours)
+pub const MAX_TTL: u64 = 86400;
+
+/// Difference in seconds between `max-age` and `Expires` worth logging
+const TTL_CONFLICT_THRESHOLD: u64 = 60;
+
 /// Maximum number of concurrent connections
 pub const MAX_CONNECTIONS: usize = 100;
 
 /// Maximum size of request headers (64KB)
 pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
 
+/// Most header lines [`parse_request`] accepts in one request
+pub const MAX_REQUEST_HEADERS: usize = 64;
+
+/// Headers that describe one connection and are never forwarded or cached
+/// (RFC 7230 §6.1), `Trailers` as RFC 2616 spelled it included
+pub const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "trailers",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Expired entries removed per lock acquisition by `evict_expired`
+const EXPIRE_BATCH: usize = 256;
+
 /// A cached HTTP response
 ///
 /// # Examples
@@ -43,22 +106,404 @@ pub const MAX_REQUEST_SIZE: usize = 64 * 1024;
 ///     headers: vec!["Content-Type: text/html".to_string()],
 ///     body: Bytes::from("<html>Hello</html>"),
 ///     expires: 1234567890,
+///     ..Default::default()
 /// };
 /// assert_eq!(response.status_line, "HTTP/1.1 200 OK");
 /// ```
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct CachedResponse {
     pub status_line: String,
     pub headers: Vec<String>,
     pub body: Bytes,
     pub expires: u64,
+    /// Lowercased request header names from the response's `Vary` headers
+    pub vary: Vec<String>,
+    /// `ETag` validator, used for `If-None-Match` revalidation and to
+    /// answer clients' conditional requests
+    pub etag: Option<String>,
+    /// `etag` was made from the body here, upstream has never seen it
+    pub etag_synthesized: bool,
+    /// Upstream `Last-Modified` validator, used for `If-Modified-Since` revalidation
+    pub last_modified: Option<String>,
+    /// Unix time the entry was stored, 0 if unknown
+    pub stored_at: u64,
+    /// Upstream forbade serving this stale (`must-revalidate`, `proxy-revalidate`)
+    pub must_revalidate: bool,
+    /// Stored stale (`no-cache`, `max-age=0`), every reuse needs a successful
+    /// revalidation first. `expires` then only bounds how long it is kept
+    pub always_revalidate: bool,
+    /// Never chosen as an eviction victim, see [`ProxyCache::put_pinned`]
+    pub pinned: bool,
+}
+
+impl CachedResponse {
+    /// Whether the entry can be served at `now` without asking upstream
+    pub fn is_fresh_at(&self, now: u64) -> bool {
+        self.expires > now && !self.always_revalidate
+    }
+
+    /// Whether the entry can be revalidated with a conditional request
+    pub fn has_validators(&self) -> bool {
+        self.upstream_etag().is_some() || self.last_modified.is_some()
+    }
+
+    /// The `ETag` upstream sent, not one synthesized from the body
+    pub fn upstream_etag(&self) -> Option<&str> {
+        self.etag.as_deref().filter(|_| !self.etag_synthesized)
+    }
+
+    /// Whether the entry may be served after expiry, e.g. when upstream is down
+    pub fn may_serve_stale(&self) -> bool {
+        !self.must_revalidate
+    }
+
+    /// Check that replaying this response cannot split it or inject headers
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rustysquid::CachedResponse;
+    ///
+    /// let mut response = CachedResponse {
+    ///     status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+    ///     headers: vec!["X-Note: fine".to_string()],
+    ///     body: Default::default(),
+    ///     expires: 0,
+    ///     ..Default::default()
+    /// };
+    /// assert!(response.has_safe_headers());
+    ///
+    /// response.headers.push("X-Note: a\r\nInjected: evil".to_string());
+    /// assert!(!response.has_safe_headers());
+    /// ```
+    pub fn has_safe_headers(&self) -> bool {
+        let status_line = self
+            .status_line
+            .strip_suffix("\r\n")
+            .unwrap_or(&self.status_line);
+        is_safe_header_line(status_line)
+            && self
+                .headers
+                .iter()
+                .all(|header| is_safe_header_line(header))
+    }
+}
+
+/// Check a single header line for CR, LF or NUL characters
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::is_safe_header_line;
+///
+/// assert!(is_safe_header_line("Content-Type: text/html"));
+/// assert!(!is_safe_header_line("Location: /\r\nSet-Cookie: x=1"));
+/// ```
+pub fn is_safe_header_line(line: &str) -> bool {
+    !line.bytes().any(|b| matches!(b, b'\r' | b'\n' | 0))
+}
+
+/// Outcome of [`ProxyCache::lookup`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheLookup {
+    /// Entry is fresh and can be served as-is
+    Fresh(Arc<CachedResponse>),
+    /// Entry has expired but carries validators for a conditional request
+    Stale(Arc<CachedResponse>),
+    /// Nothing usable is cached
+    Miss,
+}
+
+/// Age spread of the cached entries, in seconds since they were stored
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AgeStats {
+    pub oldest_secs: u64,
+    pub newest_secs: u64,
+    pub count: usize,
+}
+
+/// Snapshot of lifetime cache counters
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub insertions: u64,
+    pub evictions: u64,
+    pub rejected_too_large: u64,
+    /// Writes to clients that failed because the client went away
+    pub client_disconnects: u64,
+    /// Writes to clients that failed for any other reason
+    pub client_write_errors: u64,
+    /// Body bytes sent to clients from cached entries
+    pub hit_bytes: u64,
+    /// Estimate of what those hits would have cost in origin traffic: the
+    /// stored status line and headers plus the body bytes sent
+    pub origin_bytes_saved: u64,
+}
+
+impl CacheStats {
+    /// Fraction of lookups served from cache, 0.0 before any lookup
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+/// Lifetime counters behind [`CacheStats`]
+#[derive(Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    insertions: AtomicU64,
+    evictions: AtomicU64,
+    rejected_too_large: AtomicU64,
+    client_disconnects: AtomicU64,
+    client_write_errors: AtomicU64,
+    hit_bytes: AtomicU64,
+    origin_bytes_saved: AtomicU64,
+}
+
+impl CacheCounters {
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            insertions: self.insertions.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            rejected_too_large: self.rejected_too_large.load(Ordering::Relaxed),
+            client_disconnects: self.client_disconnects.load(Ordering::Relaxed),
+            client_write_errors: self.client_write_errors.load(Ordering::Relaxed),
+            hit_bytes: self.hit_bytes.load(Ordering::Relaxed),
+            origin_bytes_saved: self.origin_bytes_saved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Cached entries and the bytes they hold, kept in step under the cache lock
+///
+/// Every insertion and removal goes through here, so the byte count can't
+/// drift from the entries. `total_size` mirrors it for lock-free reads and
+/// is only ever stored, never adjusted, so it can lag but not drift. The
+/// same goes for `changes`, which learns of every key that changed.
+struct Entries {
+    lru: LruCache<u64, Arc<CachedResponse>>,
+    bytes: usize,
+    total_size: Arc<AtomicUsize>,
+    /// Keys to write to disk, set by [`ProxyCache::persist_changes`]
+    changes: Option<WriteCoalescer>,
+}
+
+impl Entries {
+    fn new(capacity: NonZeroUsize, total_size: Arc<AtomicUsize>) -> Self {
+        Self {
+            lru: LruCache::new(capacity),
+            bytes: 0,
+            total_size,
+            changes: None,
+        }
+    }
+
+    fn changed(&self, key: u64) {
+        if let Some(changes) = &self.changes {
+            changes.mark_dirty(key);
+        }
+    }
+
+    fn get(&mut self, key: &u64) -> Option<&Arc<CachedResponse>> {
+        self.lru.get(key)
+    }
+
+    /// Mutable access for changes that keep the entry's size, such as `expires`
+    fn get_mut(&mut self, key: &u64) -> Option<&mut Arc<CachedResponse>> {
+        self.changed(*key);
+        self.lru.get_mut(key)
+    }
+
+    fn push(&mut self, key: u64, entry: Arc<CachedResponse>) {
+        self.bytes += ProxyCache::calculate_entry_size(&entry);
+        self.changed(key);
+        if let Some((displaced_key, displaced)) = self.lru.push(key, entry) {
+            self.bytes -= ProxyCache::calculate_entry_size(&displaced);
+            self.changed(displaced_key);
+        }
+        self.total_size.store(self.bytes, Ordering::Relaxed);
+    }
+
+    fn pop_entry(&mut self, key: &u64) -> Option<(u64, Arc<CachedResponse>)> {
+        let (key, entry) = self.lru.pop_entry(key)?;
+        self.bytes -= ProxyCache::calculate_entry_size(&entry);
+        self.changed(key);
+        self.total_size.store(self.bytes, Ordering::Relaxed);
+        Some((key, entry))
+    }
+
+    fn pop(&mut self, key: &u64) -> Option<Arc<CachedResponse>> {
+        self.pop_entry(key).map(|(_, entry)| entry)
+    }
+
+    fn clear(&mut self) {
+        for (key, _) in self.lru.iter() {
+            self.changed(*key);
+        }
+        self.lru.clear();
+        self.bytes = 0;
+        self.total_size.store(0, Ordering::Relaxed);
+    }
+}
+
+impl std::ops::Deref for Entries {
+    type Target = LruCache<u64, Arc<CachedResponse>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.lru
+    }
+}
+
+/// Result of [`ProxyCache::audit`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheAudit {
+    pub entries: usize,
+    /// Size reported by [`ProxyCache::total_size`]
+    pub tracked_bytes: usize,
+    /// Size recomputed from the stored entries
+    pub actual_bytes: usize,
+}
+
+impl CacheAudit {
+    /// Whether the tracked size matches the entries
+    pub fn is_consistent(&self) -> bool {
+        self.tracked_bytes == self.actual_bytes
+    }
+}
+
+/// Which cached keys belong to each upstream (host, port)
+///
+/// Only entries stored with a known host are indexed. Locked after the
+/// cache mutex and never held across an await.
+#[derive(Default)]
+struct HostIndex {
+    by_host: HashMap<(String, u16), HashSet<u64>>,
+    url_of: HashMap<u64, CachedUrl>,
+}
+
+impl HostIndex {
+    fn insert(&mut self, key: u64, url: CachedUrl) {
+        self.remove(key);
+        self.by_host
+            .entry((url.host.clone(), url.port))
+            .or_default()
+            .insert(key);
+        self.url_of.insert(key, url);
+    }
+
+    fn remove(&mut self, key: u64) {
+        let Some(url) = self.url_of.remove(&key) else {
+            return;
+        };
+        let origin = (url.host, url.port);
+        if let Some(keys) = self.by_host.get_mut(&origin) {
+            keys.remove(&key);
+            if keys.is_empty() {
+                self.by_host.remove(&origin);
+            }
+        }
+    }
+
+    fn take_host(&mut self, host: &str, port: u16) -> HashSet<u64> {
+        let keys = self
+            .by_host
+            .remove(&(host.to_ascii_lowercase(), port))
+            .unwrap_or_default();
+        for key in &keys {
+            self.url_of.remove(key);
+        }
+        keys
+    }
+}
+
+/// Where a cached entry was fetched from, as recorded by [`ProxyCache::put_variant`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CachedUrl {
+    /// Lowercased host name
+    pub host: String,
+    pub port: u16,
+    /// Path as keyed, after the query policy was applied
+    pub path: String,
+}
+
+/// Description of one cached entry, without its body, see
+/// [`ProxyCache::describe_entries`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub key: u64,
+    /// None for entries stored by key alone, e.g. with [`ProxyCache::put`]
+    pub url: Option<CachedUrl>,
+    pub body_bytes: usize,
+    /// Bytes the entry counts against the cache size, headers included
+    pub size: usize,
+    pub expires: u64,
+    /// Seconds since the entry was stored, None if that isn't known
+    pub age_secs: Option<u64>,
+}
+
+/// Policy shared by a cache and its clones that can be replaced while they
+/// are in use
+///
+/// Readers keep the snapshot they loaded, as with [`config::SharedConfig`].
+#[derive(Debug, Default)]
+struct SharedPolicy<T>(std::sync::RwLock<Arc<T>>);
+
+impl<T> SharedPolicy<T> {
+    fn new(policy: T) -> Arc<Self> {
+        Arc::new(Self(std::sync::RwLock::new(Arc::new(policy))))
+    }
+
+    fn load(&self) -> Arc<T> {
+        // Swaps can't leave the slot half written, a poisoned lock is still usable
+        let policy = self
+            .0
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        Arc::clone(&policy)
+    }
+
+    fn store(&self, policy: T) {
+        let mut current = self
+            .0
+            .write()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        *current = Arc::new(policy);
+    }
 }
 
 /// Thread-safe LRU cache for HTTP responses
 #[derive(Clone)]
 pub struct ProxyCache {
-    cache: Arc<Mutex<LruCache<u64, Arc<CachedResponse>>>>,
+    cache: Arc<Mutex<Entries>>,
     total_size: Arc<AtomicUsize>,
+    /// `Vary` header names last seen for each base (host, port, path) key
+    vary_specs: Arc<Mutex<LruCache<u64, Vec<String>>>>,
+    host_ttl_multipliers: Arc<SharedPolicy<HostTtlMultipliers>>,
+    extension_ttls: Arc<SharedPolicy<ExtensionTtls>>,
+    vary_policy: Arc<VaryPolicy>,
+    query_policy: Arc<SharedPolicy<QueryPolicy>>,
+    query_variants: Arc<std::sync::Mutex<QueryVariants>>,
+    html_injection: Option<Arc<str>>,
+    single_flight: SingleFlight,
+    counters: Arc<CacheCounters>,
+    key_seed: u64,
+    config: ProxyCacheConfig,
+    host_index: Arc<std::sync::Mutex<HostIndex>>,
+    memory: Arc<MemoryMonitor>,
+    /// Permits for inserting entries above `large_entry_size`
+    large_inserts: Arc<Semaphore>,
 }
 
 impl ProxyCache {
@@ -75,14 +520,341 @@ impl ProxyCache {
     ///
     /// # Panics
     ///
-    /// Panics if `CACHE_SIZE` is 0, which should never happen in normal operation.
+    /// Panics if the default limits are invalid, which should never happen in normal operation.
     pub fn new() -> Self {
-        Self {
-            cache: Arc::new(Mutex::new(LruCache::new(
-                NonZeroUsize::new(CACHE_SIZE).expect("CACHE_SIZE must be non-zero"),
-            ))),
-            total_size: Arc::new(AtomicUsize::new(0)),
+        Self::with_config(ProxyCacheConfig::default()).expect("default cache limits must be valid")
+    }
+
+    /// Creates a `ProxyCache` with custom size and TTL limits
+    ///
+    /// Returns an error if `config` fails [`ProxyCacheConfig::validate`].
+    pub fn with_config(config: ProxyCacheConfig) -> Result<Self, CacheConfigError> {
+        config.validate()?;
+        let capacity =
+            NonZeroUsize::new(config.max_entries).ok_or(CacheConfigError::ZeroEntries)?;
+        let total_size = Arc::new(AtomicUsize::new(0));
+        Ok(Self {
+            cache: Arc::new(Mutex::new(Entries::new(capacity, Arc::clone(&total_size)))),
+            total_size,
+            vary_specs: Arc::new(Mutex::new(LruCache::new(capacity))),
+            host_ttl_multipliers: SharedPolicy::new(HostTtlMultipliers::default()),
+            extension_ttls: SharedPolicy::new(ExtensionTtls::default()),
+            vary_policy: Arc::new(VaryPolicy::default()),
+            query_policy: SharedPolicy::new(QueryPolicy::default()),
+            query_variants: Arc::new(std::sync::Mutex::new(QueryVariants::default())),
+            html_injection: None,
+            single_flight: SingleFlight::default(),
+            counters: Arc::new(CacheCounters::default()),
+            key_seed: process_key_seed(),
+            config,
+            host_index: Arc::new(std::sync::Mutex::new(HostIndex::default())),
+            memory: Arc::new(MemoryMonitor::default()),
+            large_inserts: Arc::new(Semaphore::new(config.max_large_inserts)),
+        })
+    }
+
+    /// Size and TTL limits this cache was created with
+    pub fn config(&self) -> &ProxyCacheConfig {
+        &self.config
+    }
+
+    /// TTL for a response to `path` on `host`, using this cache's default
+    /// TTL and host multipliers, capped at the configured `max_ttl`
+    ///
+    /// Without freshness headers the TTL configured for the path's extension
+    /// is used in place of the default.
+    pub fn ttl_for(&self, headers: &[String], host: &str, path: &str) -> u64 {
+        let default_ttl = self
+            .extension_ttls
+            .load()
+            .get(path)
+            .unwrap_or(self.config.default_ttl);
+        let ttl = uncapped_ttl_at(headers, unix_now(), default_ttl);
+        self.host_ttl_multipliers
+            .load()
+            .apply(host, ttl)
+            .min(self.config.max_ttl)
+    }
+
+    /// Use `multipliers` to stretch TTLs for trusted hosts
+    #[must_use]
+    pub fn with_host_ttl_multipliers(mut self, multipliers: HostTtlMultipliers) -> Self {
+        self.host_ttl_multipliers = SharedPolicy::new(multipliers);
+        self
+    }
+
+    /// Replace the host TTL multipliers of this cache and its clones, for
+    /// responses stored from now on
+    pub fn set_host_ttl_multipliers(&self, multipliers: HostTtlMultipliers) {
+        self.host_ttl_multipliers.store(multipliers);
+    }
+
+    /// Per-host TTL multipliers applied when caching responses
+    pub fn host_ttl_multipliers(&self) -> Arc<HostTtlMultipliers> {
+        self.host_ttl_multipliers.load()
+    }
+
+    /// Use `ttls` for responses that carry no freshness headers of their own
+    #[must_use]
+    pub fn with_extension_ttls(mut self, ttls: ExtensionTtls) -> Self {
+        self.extension_ttls = SharedPolicy::new(ttls);
+        self
+    }
+
+    /// Replace the extension TTLs of this cache and its clones, for
+    /// responses stored from now on
+    pub fn set_extension_ttls(&self, ttls: ExtensionTtls) {
+        self.extension_ttls.store(ttls);
+    }
+
+    /// Per-extension TTLs used in place of the default TTL
+    pub fn extension_ttls(&self) -> Arc<ExtensionTtls> {
+        self.extension_ttls.load()
+    }
+
+    /// Use `policy` to decide which `Vary` responses may be cached
+    #[must_use]
+    pub fn with_vary_policy(mut self, policy: VaryPolicy) -> Self {
+        self.vary_policy = Arc::new(policy);
+        self
+    }
+
+    /// Policy for responses carrying a `Vary` header
+    pub fn vary_policy(&self) -> &VaryPolicy {
+        &self.vary_policy
+    }
+
+    /// Use `policy` for query-string keys and private parameters
+    ///
+    /// Set this before caching anything, changing `ignore_query` changes
+    /// the keys entries are stored under.
+    #[must_use]
+    pub fn with_query_policy(mut self, policy: QueryPolicy) -> Self {
+        self.query_policy = SharedPolicy::new(policy);
+        self
+    }
+
+    /// Replace the query policy of this cache and its clones
+    ///
+    /// When `ignore_query` or `collapse_identical_variants` change, entries
+    /// are keyed differently from now on, so the cache is emptied. Returns
+    /// how many entries that dropped.
+    pub async fn set_query_policy(&self, policy: QueryPolicy) -> usize {
+        let rekeyed = policy.ignore_query != self.query_policy.load().ignore_query
+            || policy.collapse_identical_variants
+                != self.query_policy.load().collapse_identical_variants;
+        self.query_policy.store(policy);
+        if !rekeyed {
+            return 0;
+        }
+        *self.variants() = QueryVariants::default();
+        let cleared = self.len().await;
+        self.clear().await;
+        cleared
+    }
+
+    /// Policy for request paths carrying a query string
+    pub fn query_policy(&self) -> Arc<QueryPolicy> {
+        self.query_policy.load()
+    }
+
+    /// Insert `snippet`, e.g. a `<base href>`, after `<head>` in cached HTML
+    ///
+    /// Applied when a response is stored, see [`html::inject_after_head`],
+    /// so the client whose request fetched it gets it as upstream sent it.
+    #[must_use]
+    pub fn with_html_injection(mut self, snippet: &str) -> Self {
+        self.html_injection = Some(Arc::from(snippet));
+        self
+    }
+
+    /// Snippet inserted into cached HTML, if any
+    pub fn html_injection(&self) -> Option<&str> {
+        self.html_injection.as_deref()
+    }
+
+    /// Coalesce misses for at most `max_in_flight` distinct keys at a time
+    #[must_use]
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.single_flight = SingleFlight::new(max_in_flight);
+        self
+    }
+
+    /// Upstream fetches in flight, shared by concurrent misses for one key
+    pub fn single_flight(&self) -> &SingleFlight {
+        &self.single_flight
+    }
+
+    /// Hash cache keys with `seed` instead of the per-process random seed
+    ///
+    /// Set this before caching anything, keys stored under another seed
+    /// become unreachable.
+    #[must_use]
+    pub fn with_key_seed(mut self, seed: u64) -> Self {
+        self.key_seed = seed;
+        self
+    }
+
+    /// Seed used when hashing cache keys
+    pub fn key_seed(&self) -> u64 {
+        self.key_seed
+    }
+
+    /// Refuse puts while `monitor` reports memory pressure, instead of the
+    /// system memory checked once a second
+    #[must_use]
+    pub fn with_memory_monitor(mut self, monitor: MemoryMonitor) -> Self {
+        self.memory = Arc::new(monitor);
+        self
+    }
+
+    /// Key for a URL before any `Vary` headers are folded in
+    pub fn base_key(&self, host: &str, port: u16, path: &str) -> u64 {
+        create_cache_key_with_seed(self.key_seed, host, port, self.key_path(host, port, path))
+    }
+
+    /// Path keys for `path` are built from, without its query when the
+    /// query policy ignores it or the path was collapsed
+    fn key_path<'a>(&self, host: &str, port: u16, path: &'a str) -> &'a str {
+        let policy = self.query_policy.load();
+        let key_path = policy.key_path(path);
+        let bare = query::split_query(key_path).0;
+        if bare.len() < key_path.len()
+            && policy.collapse_identical_variants
+            && self.variants().is_collapsed(create_cache_key_with_seed(
+                self.key_seed,
+                host,
+                port,
+                bare,
+            ))
+        {
+            return bare;
+        }
+        key_path
+    }
+
+    /// Note a query variant about to be cached, collapsing its path once
+    /// two variants turn out to share a body
+    fn record_query_variant(&self, host: &str, port: u16, path: &str, body: &[u8]) {
+        let (bare, Some(query)) = query::split_query(path) else {
+            return;
+        };
+        let path_key = create_cache_key_with_seed(self.key_seed, host, port, bare);
+        let body_hash = xxhash_rust::xxh64::xxh64(body, 0);
+        if self.variants().record(path_key, query, body_hash) {
+            info!(
+                "Query variants of {}:{}{} have identical bodies, caching it without its query",
+                host, port, bare
+            );
+        }
+    }
+
+    fn variants(&self) -> std::sync::MutexGuard<'_, QueryVariants> {
+        // Losing track of a variant only delays a collapse
+        self.query_variants
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Key for the answer to a CORS preflight, distinct from the URL's `GET` key
+    pub fn preflight_key(
+        &self,
+        host: &str,
+        port: u16,
+        path: &str,
+        preflight: &Preflight<'_>,
+    ) -> u64 {
+        let mut hasher =
+            cache_key_hasher(self.key_seed, host, port, self.key_path(host, port, path));
+        for part in [
+            "OPTIONS",
+            preflight.origin,
+            preflight.method,
+            &preflight.request_headers,
+        ] {
+            hasher.update(b"\0");
+            hasher.update(part.as_bytes());
+        }
+        hasher.digest()
+    }
+
+    /// Cache key for a request, folding in any `Vary` headers recorded for its URL
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # tokio_test::block_on(async {
+    /// use rustysquid::{create_cache_key, ProxyCache};
+    ///
+    /// let cache = ProxyCache::new();
+    /// let key = cache.lookup_key("example.com", 80, "/", &[]).await;
+    /// assert_eq!(key, create_cache_key("example.com", 80, "/"));
+    /// # })
+    /// ```
+    pub async fn lookup_key(
+        &self,
+        host: &str,
+        port: u16,
+        path: &str,
+        request_headers: &[String],
+    ) -> u64 {
+        let base_key = self.base_key(host, port, path);
+        let path = self.key_path(host, port, path);
+        let vary_specs = self.vary_specs.lock().await;
+        match vary_specs.peek(&base_key) {
+            Some(vary) => create_vary_cache_key_with_seed(
+                self.key_seed,
+                host,
+                port,
+                path,
+                request_headers,
+                vary,
+            ),
+            None => base_key,
+        }
+    }
+
+    /// Store `response` under the variant key selected by its `vary` list
+    ///
+    /// Records the `Vary` set for the URL so [`ProxyCache::lookup_key`] can
+    /// rebuild the key for later requests. Returns the key used, or `None`
+    /// if the entry was rejected.
+    pub async fn put_variant(
+        &self,
+        host: &str,
+        port: u16,
+        path: &str,
+        request_headers: &[String],
+        response: CachedResponse,
+    ) -> Option<u64> {
+        let policy = self.query_policy.load();
+        if policy.collapse_identical_variants && !policy.ignore_query {
+            self.record_query_variant(host, port, path, &response.body);
+        }
+        let path = self.key_path(host, port, path);
+        let base_key = create_cache_key_with_seed(self.key_seed, host, port, path);
+        let key = create_vary_cache_key_with_seed(
+            self.key_seed,
+            host,
+            port,
+            path,
+            request_headers,
+            &response.vary,
+        );
+        {
+            let mut vary_specs = self.vary_specs.lock().await;
+            if response.vary.is_empty() {
+                vary_specs.pop(&base_key);
+            } else {
+                vary_specs.put(base_key, response.vary.clone());
+            }
         }
+        let url = CachedUrl {
+            host: host.to_ascii_lowercase(),
+            port,
+            path: path.to_string(),
+        };
+        self.insert(key, response, Some(url)).await.then_some(key)
     }
 
     /// Check if the cache is empty
@@ -102,7 +874,10 @@ impl ProxyCache {
         cache.is_empty()
     }
 
-    /// Get a cached response by key, returns None if not found or expired
+    /// Get a cached response by key, returns None if not found or not fresh
+    ///
+    /// Entries that need revalidation on every use are kept for
+    /// [`ProxyCache::lookup`] to revalidate, expired ones are removed.
     pub async fn get(&self, key: u64) -> Option<Arc<CachedResponse>> {
         let mut cache = self.cache.lock().await;
         let now = SystemTime::now()
@@ -111,70 +886,529 @@ impl ProxyCache {
             .as_secs();
 
         if let Some(entry) = cache.get(&key) {
-            if entry.expires > now {
+            if entry.is_fresh_at(now) {
+                CacheCounters::bump(&self.counters.hits);
                 return Some(Arc::clone(entry));
             }
             // Remove expired entry and update size
-            if let Some(expired) = cache.pop(&key) {
-                let size = Self::calculate_entry_size(&expired);
-                self.total_size.fetch_sub(size, Ordering::Relaxed);
+            if entry.expires <= now && cache.pop(&key).is_some() {
+                self.forget(key);
             }
         }
+        CacheCounters::bump(&self.counters.misses);
         None
     }
 
+    /// Entry stored under `key`, expired or not, without touching LRU order or stats
+    pub async fn peek(&self, key: u64) -> Option<Arc<CachedResponse>> {
+        self.cache.lock().await.peek(&key).map(Arc::clone)
+    }
+
+    /// Look up a response, keeping expired entries with validators for revalidation
+    ///
+    /// Entries marked `always_revalidate` are never fresh. Expired entries
+    /// without an `ETag` or `Last-Modified` are removed just like
+    /// [`ProxyCache::get`] does.
+    pub async fn lookup(&self, key: u64) -> CacheLookup {
+        let mut cache = self.cache.lock().await;
+        let Some(entry) = cache.get(&key) else {
+            CacheCounters::bump(&self.counters.misses);
+            return CacheLookup::Miss;
+        };
+        if entry.is_fresh_at(unix_now()) {
+            CacheCounters::bump(&self.counters.hits);
+            return CacheLookup::Fresh(Arc::clone(entry));
+        }
+        // A stale entry still needs an upstream round trip
+        CacheCounters::bump(&self.counters.misses);
+        if entry.has_validators() {
+            return CacheLookup::Stale(Arc::clone(entry));
+        }
+        if cache.pop(&key).is_some() {
+            self.forget(key);
+        }
+        CacheLookup::Miss
+    }
+
+    /// Extend the lifetime of an entry after a successful revalidation
+    ///
+    /// Returns false if the entry is no longer cached.
+    pub async fn refresh_expiry(&self, key: u64, expires: u64) -> bool {
+        let mut cache = self.cache.lock().await;
+        match cache.get_mut(&key) {
+            Some(entry) => {
+                Arc::make_mut(entry).expires = expires;
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Store a response in the cache, returns false if rejected (too large, memory pressure, etc)
+    ///
+    /// The entry isn't tied to a host, so [`ProxyCache::purge_host`] won't
+    /// find it. [`ProxyCache::put_variant`] records the host.
     pub async fn put(&self, key: u64, response: CachedResponse) -> bool {
-        // Check memory pressure
-        if !memory::has_sufficient_memory() {
+        self.insert(key, response, None).await
+    }
+
+    /// [`ProxyCache::put`], indexing the entry under `origin` when known
+    async fn insert(&self, key: u64, response: CachedResponse, url: Option<CachedUrl>) -> bool {
+        // Under memory pressure, drop what has expired and refuse new entries
+        if !self.memory.has_sufficient_memory() {
+            let purged = self.evict_expired().await;
+            debug!(
+                "Rejecting cache entry under memory pressure, purged {} expired",
+                purged
+            );
+            return false;
+        }
+
+        // Refuse entries that would inject headers when replayed
+        if !response.has_safe_headers() {
+            warn!("Rejecting cache entry with CR/LF in headers");
             return false;
         }
 
         let entry_size = Self::calculate_entry_size(&response);
 
         // Reject entries that are too large
-        if entry_size > MAX_ENTRY_SIZE {
+        if entry_size > self.config.max_entry_size {
+            CacheCounters::bump(&self.counters.rejected_too_large);
             return false;
         }
 
+        // Large entries take turns, the rest go straight for the lock
+        let _permit = if entry_size > self.config.large_entry_size {
+            self.large_inserts.acquire().await.ok()
+        } else {
+            None
+        };
         let mut cache = self.cache.lock().await;
 
-        // Check if we need to evict entries to make room
-        let mut current_size = self.total_size.load(Ordering::Relaxed);
-        while current_size + entry_size > MAX_CACHE_BYTES && !cache.is_empty() {
-            // Evict LRU entry
-            if let Some((_, evicted)) = cache.pop_lru() {
-                let evicted_size = Self::calculate_entry_size(&evicted);
-                self.total_size.fetch_sub(evicted_size, Ordering::Relaxed);
-                current_size = self.total_size.load(Ordering::Relaxed);
-            } else {
-                break;
-            }
+        // Remove old entry if it exists, it is being replaced anyway
+        if cache.pop(&key).is_some() {
+            self.forget(key);
         }
 
-        // Remove old entry if it exists
-        if let Some(old) = cache.get(&key) {
-            let old_size = Self::calculate_entry_size(old);
-            self.total_size.fetch_sub(old_size, Ordering::Relaxed);
+        // Evict unpinned LRU entries until the new one fits in both budgets
+        while cache.bytes + entry_size > self.config.max_cache_bytes
+            || cache.len() >= self.config.max_entries
+        {
+            let Some((evicted_key, _)) = Self::pop_unpinned_lru(&mut cache) else {
+                debug!("Rejecting cache entry, only pinned entries left to evict");
+                return false;
+            };
+            self.forget(evicted_key);
+            CacheCounters::bump(&self.counters.evictions);
         }
 
-        // Add new entry wrapped in Arc
-        cache.put(key, Arc::new(response));
-        self.total_size.fetch_add(entry_size, Ordering::Relaxed);
+        // Add new entry wrapped in Arc, there is room so nothing is pushed out
+        cache.push(key, Arc::new(response));
+        if let Some(url) = url {
+            self.index().insert(key, url);
+        }
+        CacheCounters::bump(&self.counters.insertions);
         true
     }
 
-    pub async fn clear(&self) {
-        let mut cache = self.cache.lock().await;
-        cache.clear();
-        self.total_size.store(0, Ordering::Relaxed);
+    /// Store a response that eviction never removes, only expiry or [`ProxyCache::remove`]
+    ///
+    /// For critical assets such as error pages. Once the cache holds nothing
+    /// but pinned entries, puts that don't fit are rejected.
+    pub async fn put_pinned(&self, key: u64, response: CachedResponse) -> bool {
+        self.put(
+            key,
+            CachedResponse {
+                pinned: true,
+                ..response
+            },
+        )
+        .await
     }
 
-    /// Get the number of entries in the cache
+    /// Remove an entry, pinned or not
+    pub async fn remove(&self, key: u64) -> Option<Arc<CachedResponse>> {
+        let removed = self.cache.lock().await.pop(&key)?;
+        self.forget(key);
+        Some(removed)
+    }
+
+    /// Remove every entry stored for `host:port`, returns how many were removed
     ///
-    /// # Examples
+    /// Covers all paths and `Vary` variants stored through
+    /// [`ProxyCache::put_variant`]. Pinned entries are removed too.
+    pub async fn purge_host(&self, host: &str, port: u16) -> usize {
+        let mut cache = self.cache.lock().await;
+        let keys = self.index().take_host(host, port);
+        let mut purged = 0;
+        for key in keys {
+            if cache.pop(&key).is_some() {
+                purged += 1;
+            }
+        }
+        purged
+    }
+
+    /// [`ProxyCache::clear`], returning how many entries were removed
+    pub async fn purge_all(&self) -> usize {
+        let removed = self.len().await;
+        self.clear().await;
+        removed
+    }
+
+    /// Drop the index entry of a key that has left the cache
+    fn forget(&self, key: u64) {
+        self.index().remove(key);
+    }
+
+    fn index(&self) -> std::sync::MutexGuard<'_, HostIndex> {
+        // The index holds no invariants a panic could break halfway
+        self.host_index
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Pop the least recently used entry that isn't pinned
+    fn pop_unpinned_lru(cache: &mut Entries) -> Option<(u64, Arc<CachedResponse>)> {
+        let key = cache
+            .iter()
+            .rev()
+            .find(|(_, entry)| !entry.pinned)
+            .map(|(key, _)| *key)?;
+        cache.pop_entry(&key)
+    }
+
+    /// Ages of the oldest and newest cached entries
     ///
-    /// ```
+    /// Entries without a `stored_at` timestamp are left out of the stats.
+    pub async fn age_stats(&self) -> AgeStats {
+        self.age_stats_at(unix_now()).await
+    }
+
+    /// [`ProxyCache::age_stats`] relative to an explicit `now`
+    pub async fn age_stats_at(&self, now: u64) -> AgeStats {
+        let cache = self.cache.lock().await;
+        let mut stats = AgeStats {
+            newest_secs: u64::MAX,
+            ..AgeStats::default()
+        };
+        for (_, entry) in cache.iter().filter(|(_, entry)| entry.stored_at > 0) {
+            let age = now.saturating_sub(entry.stored_at);
+            stats.oldest_secs = stats.oldest_secs.max(age);
+            stats.newest_secs = stats.newest_secs.min(age);
+            stats.count += 1;
+        }
+        if stats.count == 0 {
+            stats.newest_secs = 0;
+        }
+        stats
+    }
+
+    /// Every cached entry with its URL, size, expiry and age, largest first
+    ///
+    /// The cache lock is only held to take a snapshot of the entries, which
+    /// are described after it is released.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # tokio_test::block_on(async {
+    /// use bytes::Bytes;
+    /// use rustysquid::{CachedResponse, ProxyCache};
+    ///
+    /// let cache = ProxyCache::new();
+    /// let entry = |body: &'static str| CachedResponse {
+    ///     body: Bytes::from(body),
+    ///     expires: u64::MAX,
+    ///     ..Default::default()
+    /// };
+    /// cache.put(1, entry("small")).await;
+    /// cache.put_variant("example.com", 80, "/big", &[], entry("much larger")).await;
+    ///
+    /// let entries = cache.describe_entries().await;
+    /// assert_eq!(entries[0].url.as_ref().unwrap().path, "/big");
+    /// assert_eq!(entries[0].body_bytes, 11);
+    /// assert_eq!(entries[1].key, 1);
+    /// assert!(entries[1].url.is_none());
+    /// # })
+    /// ```
+    pub async fn describe_entries(&self) -> Vec<EntryInfo> {
+        self.describe_entries_at(unix_now()).await
+    }
+
+    /// [`ProxyCache::describe_entries`] with ages relative to an explicit `now`
+    pub async fn describe_entries_at(&self, now: u64) -> Vec<EntryInfo> {
+        let snapshot: Vec<(u64, Arc<CachedResponse>)> = {
+            let cache = self.cache.lock().await;
+            cache
+                .iter()
+                .map(|(key, entry)| (*key, Arc::clone(entry)))
+                .collect()
+        };
+        let urls: Vec<Option<CachedUrl>> = {
+            let index = self.index();
+            snapshot
+                .iter()
+                .map(|(key, _)| index.url_of.get(key).cloned())
+                .collect()
+        };
+        let mut entries: Vec<EntryInfo> = snapshot
+            .into_iter()
+            .zip(urls)
+            .map(|((key, entry), url)| EntryInfo {
+                key,
+                url,
+                body_bytes: entry.body.len(),
+                size: Self::calculate_entry_size(&entry),
+                expires: entry.expires,
+                age_secs: (entry.stored_at > 0).then(|| now.saturating_sub(entry.stored_at)),
+            })
+            .collect();
+        entries.sort_by(|a, b| b.size.cmp(&a.size).then(a.key.cmp(&b.key)));
+        entries
+    }
+
+    /// Remove every entry whose `expires <= now`, returns how many were purged
+    ///
+    /// Unlike [`ProxyCache::lookup`] this also drops stale entries that could
+    /// still have been revalidated. Expired keys are collected in one pass
+    /// and then removed in batches, so the lock is released between batches
+    /// on a large cache.
+    pub async fn evict_expired(&self) -> usize {
+        self.evict_expired_at(unix_now()).await
+    }
+
+    /// [`ProxyCache::evict_expired`] relative to an explicit `now`
+    pub async fn evict_expired_at(&self, now: u64) -> usize {
+        let expired: Vec<u64> = {
+            let cache = self.cache.lock().await;
+            cache
+                .iter()
+                .filter(|(_, entry)| entry.expires <= now)
+                .map(|(key, _)| *key)
+                .collect()
+        };
+
+        let mut purged = 0;
+        for batch in expired.chunks(EXPIRE_BATCH) {
+            let mut cache = self.cache.lock().await;
+            for key in batch {
+                // Skip entries refreshed or replaced since the scan
+                if cache.peek(key).is_some_and(|entry| entry.expires <= now)
+                    && cache.pop(key).is_some()
+                {
+                    self.forget(*key);
+                    purged += 1;
+                }
+            }
+        }
+        if purged > 0 {
+            debug!("Purged {} expired cache entries", purged);
+        }
+        purged
+    }
+
+    /// Spawn a task calling [`ProxyCache::evict_expired`] every `interval`
+    ///
+    /// Abort the returned handle to stop it.
+    pub fn spawn_janitor(self, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                self.evict_expired().await;
+            }
+        })
+    }
+
+    /// Count a failed write to a client, split into disconnects and other errors
+    pub fn record_client_write_error(&self, error: &io::Error) {
+        let counter = match error.kind() {
+            io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::WriteZero => &self.counters.client_disconnects,
+            _ => &self.counters.client_write_errors,
+        };
+        CacheCounters::bump(counter);
+    }
+
+    /// Count `body_bytes` of `cached` sent to a client without asking upstream
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use bytes::Bytes;
+    /// use rustysquid::{CachedResponse, ProxyCache};
+    ///
+    /// let cache = ProxyCache::new();
+    /// let cached = CachedResponse {
+    ///     status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+    ///     headers: vec!["Content-Length: 5".to_string()],
+    ///     body: Bytes::from("hello"),
+    ///     ..Default::default()
+    /// };
+    /// cache.record_hit_bytes(&cached, 5);
+    /// let stats = cache.stats();
+    /// assert_eq!(stats.hit_bytes, 5);
+    /// // Status line, header and blank line, then the body
+    /// assert_eq!(stats.origin_bytes_saved, 17 + 19 + 2 + 5);
+    /// ```
+    pub fn record_hit_bytes(&self, cached: &CachedResponse, body_bytes: usize) {
+        // `status_line` is stored with its CRLF
+        let head_bytes = cached.status_line.len()
+            + cached
+                .headers
+                .iter()
+                .map(|header| header.len() + 2)
+                .sum::<usize>()
+            + 2;
+        self.counters
+            .hit_bytes
+            .fetch_add(body_bytes as u64, Ordering::Relaxed);
+        self.counters
+            .origin_bytes_saved
+            .fetch_add((head_bytes + body_bytes) as u64, Ordering::Relaxed);
+    }
+
+    /// Snapshot of lifetime hit/miss/insertion counters, not reset by [`ProxyCache::clear`]
+    pub fn stats(&self) -> CacheStats {
+        self.counters.snapshot()
+    }
+
+    /// Write every fresh entry to `dir`, replacing what was there
+    ///
+    /// Returns the number of entries written. See [`DiskCache`] for the layout.
+    /// The files are written on the blocking pool, so requests are served
+    /// meanwhile.
+    pub async fn persist_to_dir(&self, dir: impl AsRef<Path>) -> io::Result<usize> {
+        let now = unix_now();
+        let entries: Vec<StoredEntry> = {
+            let cache = self.cache.lock().await;
+            let index = self.index();
+            cache
+                .iter()
+                .filter(|(_, entry)| entry.expires > now)
+                .map(|(key, entry)| (*key, Arc::clone(entry), index.url_of.get(key).cloned()))
+                .collect()
+        };
+        let (disk, key_seed) = (DiskCache::new(dir.as_ref()), self.key_seed);
+        run_blocking(move || disk.store(key_seed, &entries)).await?
+    }
+
+    /// Keep `dir` in step with the cache from now on, writing changed
+    /// entries in the background
+    ///
+    /// Every store, update and removal marks its key in `coalescer`, and the
+    /// returned [`Persister`] writes or deletes the marked entries' files on
+    /// each flush, so an entry changed many times in between is written
+    /// once. Entries already cached aren't written until they change, use
+    /// [`ProxyCache::persist_to_dir`] for a full snapshot.
+    pub async fn persist_changes(
+        &self,
+        dir: impl Into<PathBuf>,
+        coalescer: WriteCoalescer,
+    ) -> io::Result<Persister> {
+        let disk = DiskCache::new(dir);
+        disk.store_key_seed(self.key_seed)?;
+        self.cache.lock().await.changes = Some(coalescer.clone());
+        Ok(Persister::spawn(self.clone(), disk, coalescer))
+    }
+
+    /// Write the entries `coalescer` marks dirty to `disk`, deleting the
+    /// files of those no longer cached, returns how many were written
+    ///
+    /// The batch is written on the blocking pool, off the request path.
+    pub(crate) async fn write_dirty(&self, disk: &DiskCache, coalescer: &WriteCoalescer) -> usize {
+        let mut keys = Vec::new();
+        coalescer.flush(|key| keys.push(key));
+        let now = unix_now();
+        let entries: Vec<(u64, Option<Arc<CachedResponse>>, Option<CachedUrl>)> = {
+            let cache = self.cache.lock().await;
+            let index = self.index();
+            keys.into_iter()
+                .map(|key| {
+                    let entry = cache.peek(&key).filter(|entry| entry.expires > now);
+                    (key, entry.cloned(), index.url_of.get(&key).cloned())
+                })
+                .collect()
+        };
+        if entries.is_empty() {
+            return 0;
+        }
+        let disk = disk.clone();
+        let flushed = run_blocking(move || {
+            let mut written = 0;
+            for (key, entry, url) in entries {
+                let result = match entry {
+                    Some(entry) => disk
+                        .store_entry(key, &entry, url.as_ref())
+                        .map(|()| written += 1),
+                    None => disk.remove_entry(key),
+                };
+                if let Err(e) = result {
+                    warn!("Failed to persist cache entry {:016x}: {}", key, e);
+                }
+            }
+            written
+        });
+        flushed.await.unwrap_or_else(|e| {
+            warn!("Failed to persist cache entries: {}", e);
+            0
+        })
+    }
+
+    /// Reload entries written by [`ProxyCache::persist_to_dir`], returns
+    /// how many were loaded and skipped
+    ///
+    /// Entries are indexed under the URL stored with them, so
+    /// [`ProxyCache::purge_host`] and [`ProxyCache::describe_entries`] see
+    /// them as before the restart, and the `Vary` headers of variants are
+    /// recorded for [`ProxyCache::lookup_key`] again.
+    /// Expired, corrupt and other-version entries are skipped, see
+    /// [`DiskCache::load`]. Entries are only reachable if this cache uses
+    /// the key seed they were stored with, see [`DiskCache::key_seed`]. The
+    /// files are read on the blocking pool.
+    pub async fn load_from_dir(&self, dir: impl AsRef<Path>) -> io::Result<LoadStats> {
+        let (disk, key_seed) = (DiskCache::new(dir.as_ref()), self.key_seed);
+        let loaded = run_blocking(move || disk.load(key_seed, unix_now())).await??;
+        let mut stats = LoadStats {
+            loaded: 0,
+            ..loaded.stats
+        };
+        for (key, entry, url) in loaded.entries {
+            let vary = url.clone().filter(|_| !entry.vary.is_empty()).map(|url| {
+                let base_key =
+                    create_cache_key_with_seed(self.key_seed, &url.host, url.port, &url.path);
+                (base_key, entry.vary.clone())
+            });
+            if self.insert(key, entry, url).await {
+                if let Some((base_key, vary)) = vary {
+                    self.vary_specs.lock().await.put(base_key, vary);
+                }
+                stats.loaded += 1;
+            } else {
+                stats.refused += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    pub async fn clear(&self) {
+        let mut cache = self.cache.lock().await;
+        cache.clear();
+        *self.index() = HostIndex::default();
+        drop(cache);
+        self.vary_specs.lock().await.clear();
+    }
+
+    /// Get the number of entries in the cache
+    ///
+    /// # Examples
+    ///
+    /// ```
     /// # tokio_test::block_on(async {
     /// use rustysquid::{ProxyCache, CachedResponse};
     /// use bytes::Bytes;
@@ -187,6 +1421,7 @@ impl ProxyCache {
     ///     headers: vec![],
     ///     body: Bytes::from("test"),
     ///     expires: u64::MAX,
+    ///     ..Default::default()
     /// };
     /// cache.put(12345, response).await;
     /// assert_eq!(cache.len().await, 1);
@@ -197,6 +1432,21 @@ impl ProxyCache {
         cache.len()
     }
 
+    /// Recompute the size of every entry and compare it with [`ProxyCache::total_size`]
+    ///
+    /// Takes the cache lock, so the two always describe the same entries.
+    pub async fn audit(&self) -> CacheAudit {
+        let cache = self.cache.lock().await;
+        CacheAudit {
+            entries: cache.len(),
+            tracked_bytes: self.total_size.load(Ordering::Relaxed),
+            actual_bytes: cache
+                .iter()
+                .map(|(_, entry)| Self::calculate_entry_size(entry))
+                .sum(),
+        }
+    }
+
     /// Get the total size of all cached entries in bytes
     ///
     /// # Examples
@@ -219,7 +1469,10 @@ impl ProxyCache {
                 .map(std::string::String::len)
                 .sum::<usize>()
             + entry.body.len()
-            + std::mem::size_of::<u64>() // expires field
+            + entry.vary.iter().map(std::string::String::len).sum::<usize>()
+            + entry.etag.as_ref().map_or(0, String::len)
+            + entry.last_modified.as_ref().map_or(0, String::len)
+            + 2 * std::mem::size_of::<u64>() // expires and stored_at fields
             + std::mem::size_of::<Arc<CachedResponse>>() // Arc overhead
     }
 }
@@ -230,37 +1483,62 @@ impl Default for ProxyCache {
     }
 }
 
-/// Parse an HTTP request, returns (method, path, headers) or None if invalid
+/// Why [`parse_request`] rejected a request head
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// More than [`MAX_REQUEST_HEADERS`] header lines
+    TooManyHeaders,
+    /// The head stops before its closing blank line
+    Incomplete,
+    /// Not an HTTP/1.x request head
+    Malformed,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyHeaders => write!(f, "more than {} headers", MAX_REQUEST_HEADERS),
+            Self::Incomplete => write!(f, "incomplete request head"),
+            Self::Malformed => write!(f, "malformed request head"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse an HTTP request, returns (method, path, headers)
 ///
 /// # Examples
 ///
 /// ```
-/// use rustysquid::parse_request;
+/// use rustysquid::{parse_request, ParseError};
 ///
 /// let request = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n";
-/// let result = parse_request(request);
-/// assert!(result.is_some());
-/// let (method, path, headers) = result.unwrap();
+/// let (method, path, headers) = parse_request(request).unwrap();
 /// assert_eq!(method, "GET");
 /// assert_eq!(path, "/index.html");
 /// assert_eq!(headers[0], "Host: example.com");
+///
+/// assert_eq!(parse_request(b"GET / HTTP/1.1\r\nHost: exa"), Err(ParseError::Incomplete));
 /// ```
-pub fn parse_request(data: &[u8]) -> Option<(String, String, Vec<String>)> {
-    let mut headers = [httparse::EMPTY_HEADER; 64];
+pub fn parse_request(data: &[u8]) -> Result<(String, String, Vec<String>), ParseError> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
 
     match req.parse(data) {
         Ok(httparse::Status::Complete(_)) => {
-            let method = req.method?.to_string();
-            let path = req.path?.to_string();
+            let method = req.method.ok_or(ParseError::Malformed)?.to_string();
+            let path = req.path.ok_or(ParseError::Malformed)?.to_string();
             let headers: Vec<String> = req
                 .headers
                 .iter()
                 .map(|h| format!("{}: {}", h.name, String::from_utf8_lossy(h.value)))
                 .collect();
-            Some((method, path, headers))
+            Ok((method, path, headers))
         }
-        _ => None,
+        Ok(httparse::Status::Partial) => Err(ParseError::Incomplete),
+        Err(httparse::Error::TooManyHeaders) => Err(ParseError::TooManyHeaders),
+        Err(_) => Err(ParseError::Malformed),
     }
 }
 
@@ -277,24 +1555,95 @@ pub fn parse_request(data: &[u8]) -> Option<(String, String, Vec<String>)> {
 /// let headers = vec!["Host: example.com".to_string()];
 /// assert_eq!(extract_host(&headers), Some(("example.com".to_string(), 80)));
 ///
+/// // IPv6 literals lose their brackets
+/// let headers = vec!["Host: [2001:db8::1]:443".to_string()];
+/// assert_eq!(extract_host(&headers), Some(("2001:db8::1".to_string(), 443)));
+///
 /// let headers = vec!["Content-Type: text/html".to_string()];
 /// assert_eq!(extract_host(&headers), None);
 /// ```
 pub fn extract_host(headers: &[String]) -> Option<(String, u16)> {
     for header in headers {
         if header.to_lowercase().starts_with("host:") {
-            let host_value = header[5..].trim();
-            if let Some(colon_pos) = host_value.rfind(':') {
-                let host = host_value[..colon_pos].to_string();
-                let port = host_value[colon_pos + 1..].parse::<u16>().unwrap_or(80);
-                return Some((host, port));
-            }
-            return Some((host_value.to_string(), 80));
+            return Some(parse_authority(header[5..].trim()));
         }
     }
     None
 }
 
+/// Split a `host[:port]` authority, defaulting to port 80
+fn parse_authority(authority: &str) -> (String, u16) {
+    let (host, port) = split_authority(authority);
+    let port = port.and_then(|port| port.parse().ok());
+    (host.to_string(), port.unwrap_or(80))
+}
+
+/// Split a `host[:port]` authority into the host and the unparsed port
+///
+/// A bracketed IPv6 literal like `[::1]:8080` is returned without its
+/// brackets, the port being whatever follows a colon after the `]`.
+pub(crate) fn split_authority(authority: &str) -> (&str, Option<&str>) {
+    if let Some((host, rest)) = authority
+        .strip_prefix('[')
+        .and_then(|literal| literal.split_once(']'))
+    {
+        return (host, rest.strip_prefix(':'));
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    }
+}
+
+/// Resolve a request target to the host, port and origin-form path it addresses
+///
+/// Absolute-form targets (`http://host[:port]/path`, as sent to a forward
+/// proxy) name the origin themselves and take precedence over the `Host`
+/// header, as RFC 9112 requires. Origin-form targets (`/path`) use `Host`.
+/// Returns None for other schemes, other target forms, or no host.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::normalize_target;
+///
+/// let headers = vec!["Host: example.com".to_string()];
+/// assert_eq!(
+///     normalize_target("/a.js", &headers),
+///     Some(("example.com".to_string(), 80, "/a.js".to_string()))
+/// );
+/// assert_eq!(
+///     normalize_target("http://cdn.example.com:8080/a.js?v=2", &headers),
+///     Some(("cdn.example.com".to_string(), 8080, "/a.js?v=2".to_string()))
+/// );
+/// assert_eq!(normalize_target("ftp://example.com/a.js", &headers), None);
+/// ```
+pub fn normalize_target(target: &str, headers: &[String]) -> Option<(String, u16, String)> {
+    let (host, port, path) = if target.starts_with('/') || target == "*" {
+        let (host, port) = extract_host(headers)?;
+        (host, port, target.to_string())
+    } else {
+        let scheme_end = "http://".len();
+        if !target.get(..scheme_end)?.eq_ignore_ascii_case("http://") {
+            return None;
+        }
+        let rest = &target[scheme_end..];
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        // Credentials in the authority are deprecated and never forwarded
+        let authority = rest[..authority_end]
+            .rsplit_once('@')
+            .map_or(&rest[..authority_end], |(_, authority)| authority);
+        let (host, port) = parse_authority(authority);
+        let path = match &rest[authority_end..] {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_string(),
+        };
+        (host, port, path)
+    };
+    (!host.is_empty()).then_some((host, port, path))
+}
+
 /// Determine if a response should be cached based on method, path, and headers
 ///
 /// # Examples
@@ -309,12 +1658,21 @@ pub fn extract_host(headers: &[String]) -> Option<(String, u16)> {
 /// assert!(!is_cacheable("POST", "/api", &[]));
 ///
 /// // Respect Cache-Control headers
-/// let headers = vec!["Cache-Control: no-cache".to_string()];
+/// let headers = vec!["Cache-Control: no-store".to_string()];
 /// assert!(!is_cacheable("GET", "/index.html", &headers));
 ///
+/// // no-cache responses are stored, to be revalidated on every use
+/// let headers = vec!["Cache-Control: no-cache".to_string()];
+/// assert!(is_cacheable("GET", "/api/items", &headers));
+///
 /// // Private responses are not cached
 /// let headers = vec!["Cache-Control: private".to_string()];
 /// assert!(!is_cacheable("GET", "/user", &headers));
+///
+/// // Extensions are matched without the query string, private query
+/// // parameters are left to `query::QueryPolicy`
+/// assert!(is_cacheable("GET", "/app.js?v=3", &[]));
+/// assert!(!is_cacheable("GET", "/search?q=app.js", &[]));
 /// ```
 pub fn is_cacheable(method: &str, path: &str, response_headers: &[String]) -> bool {
     if method != "GET" {
@@ -325,128 +1683,1012 @@ pub fn is_cacheable(method: &str, path: &str, response_headers: &[String]) -> bo
     for header in response_headers {
         let header_lower = header.to_lowercase();
         if header_lower.starts_with("cache-control:") {
-            if header_lower.contains("no-cache")
-                || header_lower.contains("no-store")
-                || header_lower.contains("private")
-            {
+            // no-cache and max-age=0 only mean revalidate before reuse
+            if header_lower.contains("no-store") || header_lower.contains("private") {
+                return false;
+            }
+            // s-maxage=0 forbids shared caches from reusing the response
+            if directive_seconds(&header_lower, "s-maxage=") == Some(0) {
                 return false;
             }
-            if header_lower.contains("max-age=") {
+            // no-cache explicitly allows storing, as long as every use is revalidated
+            if header_lower.contains("max-age=")
+                || header_lower.contains("s-maxage=")
+                || header_lower.contains("no-cache")
+            {
                 return true;
             }
         }
     }
 
-    // Check for static content extensions
+    // Check for static content extensions, ignoring any query string
     let cacheable_extensions = [
-        ".jpg", ".jpeg", ".png", ".gif", ".ico", ".css", ".js", ".woff", ".woff2", ".ttf", ".svg",
-        ".webp", ".mp4", ".webm", ".html", ".htm", ".xml", ".json", ".txt",
+        "jpg", "jpeg", "png", "gif", "ico", "css", "js", "woff", "woff2", "ttf", "svg", "webp",
+        "mp4", "webm", "html", "htm", "xml", "json", "txt",
     ];
 
-    let path_lower = path.to_lowercase();
     // Cache if it has a cacheable extension or is the root path
-    path == "/"
-        || cacheable_extensions
-            .iter()
-            .any(|ext| path_lower.ends_with(ext))
+    query::split_query(path).0 == "/"
+        || url_extension(path).is_some_and(|ext| cacheable_extensions.contains(&ext.as_str()))
+}
+
+/// Lowercased extension of the last segment of a request path, ignoring
+/// any query string
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::url_extension;
+///
+/// assert_eq!(url_extension("/static/App.JS?v=3").as_deref(), Some("js"));
+/// assert_eq!(url_extension("/v1.2/download"), None);
+/// assert_eq!(url_extension("/"), None);
+/// ```
+pub fn url_extension(path: &str) -> Option<String> {
+    let path = query::split_query(path).0;
+    let segment = path.rsplit('/').next().unwrap_or(path);
+    let (_, extension) = segment.rsplit_once('.')?;
+    (!extension.is_empty()).then(|| extension.to_ascii_lowercase())
 }
 
-/// Calculate TTL from Cache-Control headers, defaults to `CACHE_TTL`
+/// Calculate TTL from `Cache-Control` and `Expires` headers, defaults to `CACHE_TTL`
+///
+/// `s-maxage` takes precedence over `max-age`, which takes precedence over
+/// `Expires` when both are present (RFC 7234 section 5.3). An `Expires` date
+/// in the past yields a TTL of 0.
 pub fn calculate_ttl(headers: &[String]) -> u64 {
-    for header in headers {
-        let header_lower = header.to_lowercase();
-        if header_lower.starts_with("cache-control:") {
-            if let Some(max_age_pos) = header_lower.find("max-age=") {
-                let start = max_age_pos + 8;
-                let value_str = &header_lower[start..];
-                if let Some(end) = value_str.find(|c: char| !c.is_ascii_digit()) {
-                    if let Ok(seconds) = value_str[..end].parse::<u64>() {
-                        return seconds.min(86400);
-                    }
-                } else if let Ok(seconds) = value_str.parse::<u64>() {
-                    return seconds.min(86400);
-                }
-            }
-        }
-    }
-    CACHE_TTL
+    calculate_ttl_at(headers, unix_now())
 }
 
-/// Create a cache key from request parameters without allocation
-pub fn create_cache_key(host: &str, port: u16, path: &str) -> u64 {
-    use xxhash_rust::xxh64::Xxh64;
+/// Calculate TTL relative to `now` (Unix seconds), see [`calculate_ttl`]
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::calculate_ttl_at;
+///
+/// // Sun, 06 Nov 1994 08:49:37 GMT
+/// let now = 784_111_777;
+/// let headers = vec!["Expires: Sun, 06 Nov 1994 09:49:37 GMT".to_string()];
+/// assert_eq!(calculate_ttl_at(&headers, now), 3600);
+/// ```
+pub fn calculate_ttl_at(headers: &[String], now: u64) -> u64 {
+    uncapped_ttl_at(headers, now, CACHE_TTL).min(MAX_TTL)
+}
 
-    let mut hasher = Xxh64::new(0);
-    hasher.update(host.as_bytes());
-    hasher.update(b":");
-    hasher.update(&port.to_le_bytes());
-    hasher.update(path.as_bytes());
-    hasher.digest()
+/// Calculate TTL with the per-host multiplier applied before the `MAX_TTL` cap
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::{calculate_ttl_for_host, HostTtlMultipliers};
+///
+/// let mut multipliers = HostTtlMultipliers::new();
+/// multipliers.insert("cdn.example.com", 2.0);
+///
+/// let headers = vec!["Cache-Control: max-age=600".to_string()];
+/// assert_eq!(calculate_ttl_for_host(&headers, "cdn.example.com", &multipliers), 1200);
+/// assert_eq!(calculate_ttl_for_host(&headers, "example.com", &multipliers), 600);
+/// ```
+pub fn calculate_ttl_for_host(
+    headers: &[String],
+    host: &str,
+    multipliers: &HostTtlMultipliers,
+) -> u64 {
+    multipliers
+        .apply(host, uncapped_ttl_at(headers, unix_now(), CACHE_TTL))
+        .min(MAX_TTL)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// TTL of a `429` or `503` response from its `Retry-After`, see [`retry_after_ttl_at`]
+pub fn retry_after_ttl(status: u16, headers: &[String]) -> Option<u64> {
+    retry_after_ttl_at(status, headers, unix_now())
+}
 
-    #[test]
-    fn test_extract_host() {
-        let headers = vec![
-            "Host: example.com".to_string(),
-            "User-Agent: test".to_string(),
-        ];
-        assert_eq!(
-            extract_host(&headers),
-            Some(("example.com".to_string(), 80))
-        );
+/// TTL of a `429` or `503` response relative to `now` (Unix seconds)
+///
+/// An overloaded origin says in `Retry-After` how long clients should stay
+/// away, as delta-seconds or an HTTP-date, and the response is cached for
+/// exactly that long. None for other statuses and for a missing or
+/// malformed `Retry-After`. A date in the past yields 0.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::retry_after_ttl_at;
+///
+/// // Sun, 06 Nov 1994 08:49:37 GMT
+/// let now = 784_111_777;
+/// let seconds = vec!["Retry-After: 120".to_string()];
+/// assert_eq!(retry_after_ttl_at(503, &seconds, now), Some(120));
+/// let date = vec!["Retry-After: Sun, 06 Nov 1994 08:59:37 GMT".to_string()];
+/// assert_eq!(retry_after_ttl_at(429, &date, now), Some(600));
+/// assert_eq!(retry_after_ttl_at(200, &seconds, now), None);
+/// ```
+pub fn retry_after_ttl_at(status: u16, headers: &[String], now: u64) -> Option<u64> {
+    if status != 429 && status != 503 {
+        return None;
+    }
+    let value = header_value(headers, "retry-after")?.trim();
+    let ttl = match value.parse::<u64>() {
+        Ok(seconds) => seconds,
+        Err(_) => parse_http_date(value)?.saturating_sub(now),
+    };
+    Some(ttl.min(MAX_TTL))
+}
 
-        let headers_with_port = vec!["Host: example.com:8080".to_string()];
-        assert_eq!(
-            extract_host(&headers_with_port),
-            Some(("example.com".to_string(), 8080))
-        );
+/// TTL derived from headers before any cap is applied, `default_ttl` without any
+fn uncapped_ttl_at(headers: &[String], now: u64, default_ttl: u64) -> u64 {
+    // s-maxage applies to shared caches like this one and overrides max-age
+    let max_age =
+        find_directive(headers, "s-maxage=").or_else(|| find_directive(headers, "max-age="));
+    let expires_ttl = header_value(headers, "expires")
+        .and_then(parse_http_date)
+        .map(|expires| expires.saturating_sub(now));
+
+    match (max_age, expires_ttl) {
+        (Some(max_age), Some(expires_ttl)) => {
+            if max_age.abs_diff(expires_ttl) > TTL_CONFLICT_THRESHOLD {
+                debug!(
+                    "max-age={} conflicts with Expires ({}s), using max-age",
+                    max_age, expires_ttl
+                );
+            }
+            max_age
+        }
+        (Some(max_age), None) => max_age,
+        (None, Some(expires_ttl)) => expires_ttl,
+        (None, None) => default_ttl,
     }
+}
 
-    #[test]
-    fn test_is_cacheable() {
-        // Static content should be cacheable
-        assert!(is_cacheable("GET", "/image.jpg", &[]));
-        assert!(is_cacheable("GET", "/style.css", &[]));
-        assert!(is_cacheable("GET", "/script.js", &[]));
+/// Per-host TTL multipliers for origins that under-specify freshness
+///
+/// Multipliers only stretch the computed TTL; they never make an uncacheable
+/// response (`no-store`, `private`, ...) cacheable.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HostTtlMultipliers {
+    multipliers: HashMap<String, f64>,
+}
 
-        // POST requests should not be cacheable
-        assert!(!is_cacheable("POST", "/image.jpg", &[]));
+impl HostTtlMultipliers {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // Respect no-cache headers
-        let no_cache_headers = vec!["Cache-Control: no-cache".to_string()];
-        assert!(!is_cacheable("GET", "/image.jpg", &no_cache_headers));
+    /// Set the multiplier for `host`, ignoring non-finite or non-positive values
+    pub fn insert(&mut self, host: impl Into<String>, multiplier: f64) {
+        if multiplier.is_finite() && multiplier > 0.0 {
+            self.multipliers
+                .insert(host.into().to_ascii_lowercase(), multiplier);
+        }
+    }
 
-        // Respect max-age headers
-        let max_age_headers = vec!["Cache-Control: max-age=3600".to_string()];
-        assert!(is_cacheable("GET", "/api/data", &max_age_headers));
+    /// Multiplier for `host`, 1.0 when none is configured
+    pub fn get(&self, host: &str) -> f64 {
+        self.multipliers
+            .get(&host.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(1.0)
     }
 
-    #[test]
-    fn test_calculate_ttl() {
-        let headers_with_max_age = vec!["Cache-Control: max-age=7200".to_string()];
-        assert_eq!(calculate_ttl(&headers_with_max_age), 7200);
+    /// Scale `ttl` by the multiplier configured for `host`
+    pub fn apply(&self, host: &str, ttl: u64) -> u64 {
+        let multiplier = self.get(host);
+        if (multiplier - 1.0).abs() < f64::EPSILON {
+            return ttl;
+        }
+        (ttl as f64 * multiplier) as u64
+    }
+}
 
-        let headers_with_large_max_age = vec!["Cache-Control: max-age=999999".to_string()];
-        assert_eq!(calculate_ttl(&headers_with_large_max_age), 86400); // Capped at 24 hours
+/// Per-extension TTLs for responses without freshness headers, e.g. a day
+/// for `.mp4` and a minute for `.html`
+///
+/// Only the default is replaced, `Cache-Control` and `Expires` still win.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::ExtensionTtls;
+///
+/// let mut ttls = ExtensionTtls::new();
+/// ttls.insert(".mp4", 86_400);
+/// ttls.insert("JS", 3_600);
+///
+/// assert_eq!(ttls.get("/media/intro.MP4"), Some(86_400));
+/// assert_eq!(ttls.get("/app.js?v=2"), Some(3_600));
+/// assert_eq!(ttls.get("/index.html"), None);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ExtensionTtls {
+    ttls: HashMap<String, u64>,
+}
 
-        let headers_without_cache = vec!["Content-Type: text/html".to_string()];
-        assert_eq!(calculate_ttl(&headers_without_cache), CACHE_TTL);
+impl ExtensionTtls {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    #[test]
-    fn test_cache_key_generation() {
-        let key1 = create_cache_key("example.com", 80, "/index.html");
-        let key2 = create_cache_key("example.com", 80, "/index.html");
-        let key3 = create_cache_key("example.com", 80, "/other.html");
+    /// Set the TTL in seconds for `extension`, with or without its leading dot
+    pub fn insert(&mut self, extension: &str, ttl: u64) {
+        let extension = extension.strip_prefix('.').unwrap_or(extension);
+        self.ttls.insert(extension.to_ascii_lowercase(), ttl);
+    }
 
-        assert_eq!(key1, key2); // Same input should produce same key
+    /// TTL configured for the extension of `path`, if any
+    pub fn get(&self, path: &str) -> Option<u64> {
+        self.ttls.get(&url_extension(path)?).copied()
+    }
+}
+
+/// Find the first parseable `directive=` (e.g. `max-age=`) in `Cache-Control` headers
+fn find_directive(headers: &[String], directive: &str) -> Option<u64> {
+    headers.iter().find_map(|header| {
+        let header_lower = header.to_lowercase();
+        if header_lower.starts_with("cache-control:") {
+            directive_seconds(&header_lower, directive)
+        } else {
+            None
+        }
+    })
+}
+
+/// Parse the delta-seconds value following `directive=` in a lowercased header
+fn directive_seconds(header_lower: &str, directive: &str) -> Option<u64> {
+    let pos = header_lower.find(directive)?;
+    let value_str = &header_lower[pos + directive.len()..];
+    let end = value_str
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value_str.len());
+    value_str[..end].parse::<u64>().ok()
+}
+
+/// Get the trimmed value of the first header named `name` (case-insensitive)
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::header_value;
+///
+/// let headers = vec!["Content-Type: text/html".to_string()];
+/// assert_eq!(header_value(&headers, "content-type"), Some("text/html"));
+/// assert_eq!(header_value(&headers, "etag"), None);
+/// ```
+pub fn header_value<'a>(headers: &'a [String], name: &str) -> Option<&'a str> {
+    headers.iter().find_map(|header| {
+        let (header_name, value) = header.split_once(':')?;
+        header_name
+            .trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim())
+    })
+}
+
+/// Copy of `headers` without [`HOP_BY_HOP_HEADERS`] or the headers their
+/// `Connection` header names
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::strip_hop_by_hop;
+///
+/// let headers = vec![
+///     "Connection: keep-alive, X-Trace".to_string(),
+///     "X-Trace: 42".to_string(),
+///     "Content-Type: text/css".to_string(),
+/// ];
+/// assert_eq!(strip_hop_by_hop(&headers), vec!["Content-Type: text/css"]);
+/// ```
+pub fn strip_hop_by_hop(headers: &[String]) -> Vec<String> {
+    strip_hop_by_hop_except(headers, &[])
+}
+
+/// [`strip_hop_by_hop`], keeping headers named in `keep` even when they are
+/// hop-by-hop
+///
+/// For relaying a message whose body is passed on untouched, which must
+/// keep the `Transfer-Encoding` it was framed with.
+pub fn strip_hop_by_hop_except(headers: &[String], keep: &[&str]) -> Vec<String> {
+    let listed: Vec<String> = headers
+        .iter()
+        .filter_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("connection")
+                .then_some(value)
+        })
+        .flat_map(|value| value.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty())
+        .collect();
+
+    headers
+        .iter()
+        .filter(|header| {
+            let name = header
+                .split_once(':')
+                .map_or(header.as_str(), |(name, _)| name)
+                .trim()
+                .to_ascii_lowercase();
+            keep.iter().any(|kept| kept.eq_ignore_ascii_case(&name))
+                || !(HOP_BY_HOP_HEADERS.contains(&name.as_str()) || listed.contains(&name))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Whether a request's `Accept-Encoding` allows a response with `content_encoding`
+///
+/// Every coding in a comma-separated `Content-Encoding` must be acceptable.
+/// A request without `Accept-Encoding` accepts anything (RFC 7231 §5.3.4).
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::accepts_encoding;
+///
+/// let gzip = vec!["Accept-Encoding: gzip, deflate".to_string()];
+/// let identity = vec!["Accept-Encoding: identity".to_string()];
+/// assert!(accepts_encoding(&gzip, "gzip"));
+/// assert!(!accepts_encoding(&identity, "gzip"));
+/// assert!(accepts_encoding(&identity, "identity"));
+/// ```
+pub fn accepts_encoding(request_headers: &[String], content_encoding: &str) -> bool {
+    let accepted: Vec<(String, f64)> = request_headers
+        .iter()
+        .filter_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            name.trim()
+                .eq_ignore_ascii_case("accept-encoding")
+                .then_some(value)
+        })
+        .flat_map(compress::accepted_codings)
+        .collect();
+
+    if accepted.is_empty() {
+        return true;
+    }
+
+    content_encoding
+        .split(',')
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .filter(|coding| !coding.is_empty())
+        .all(|coding| {
+            let quality = |name: &str| accepted.iter().find(|(c, _)| c == name).map(|(_, q)| *q);
+            match quality(&coding).or_else(|| quality("*")) {
+                Some(q) => q > 0.0,
+                // Identity is acceptable unless explicitly refused
+                None => coding == "identity",
+            }
+        })
+}
+
+/// Parse an RFC 7231 IMF-fixdate (`Sun, 06 Nov 1994 08:49:37 GMT`) into Unix seconds
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::parse_http_date;
+///
+/// assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784_111_777));
+/// assert_eq!(parse_http_date("not a date"), None);
+/// ```
+pub fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.split_ascii_whitespace();
+    parts.next()?.strip_suffix(',')?;
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = match parts.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(str::parse::<u64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if parts.next()? != "GMT" || parts.next().is_some() || time.next().is_some() {
+        return None;
+    }
+    if !(1..=31).contains(&day) || year < 1970 || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    Some(days_since_epoch(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// Format Unix seconds as an RFC 7231 IMF-fixdate
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::format_http_date;
+///
+/// assert_eq!(format_http_date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+/// ```
+pub fn format_http_date(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let days = secs / 86400;
+    let seconds_of_day = secs % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// Civil date (year, month, day) for a day count since 1970-01-01
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    (year_of_era + era * 400 + u64::from(month <= 2), month, day)
+}
+
+/// Days between 1970-01-01 and the given civil date (proleptic Gregorian)
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    let (y, m) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * m + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Run blocking file IO on tokio's blocking pool, keeping the runtime
+/// thread free to serve requests
+async fn run_blocking<T, F>(work: F) -> io::Result<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+/// Current Unix time in seconds
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Create a cache key from request parameters without allocation
+///
+/// Uses the per-process [`process_key_seed`], so keys are stable within a
+/// run but colliding URLs can't be precomputed.
+pub fn create_cache_key(host: &str, port: u16, path: &str) -> u64 {
+    create_cache_key_with_seed(process_key_seed(), host, port, path)
+}
+
+/// Strong `ETag` for a body, quoted and derived from its xxh64 hash
+///
+/// The hash is unseeded so the same body gets the same tag across restarts.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::body_etag;
+///
+/// assert_eq!(body_etag(b"ok"), body_etag(b"ok"));
+/// assert_ne!(body_etag(b"ok"), body_etag(b"ko"));
+/// assert!(body_etag(b"ok").starts_with('"'));
+/// ```
+pub fn body_etag(body: &[u8]) -> String {
+    format!("\"{:016x}\"", xxhash_rust::xxh64::xxh64(body, 0))
+}
+
+/// Whether an `If-None-Match` value names the `stored` entity tag
+///
+/// Uses the weak comparison RFC 7232 prescribes for `If-None-Match`, so a
+/// `W/` prefix on either side is ignored. `client_value` may list several
+/// tags separated by commas, and `*` matches any stored tag.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::etag_matches;
+///
+/// assert!(etag_matches(r#"W/"abc""#, r#""abc""#));
+/// assert!(etag_matches(r#""x", "abc""#, r#"W/"abc""#));
+/// assert!(etag_matches("*", r#""abc""#));
+/// assert!(!etag_matches(r#""abcd""#, r#""abc""#));
+/// ```
+pub fn etag_matches(client_value: &str, stored: &str) -> bool {
+    fn opaque(tag: &str) -> &str {
+        let tag = tag.trim();
+        tag.strip_prefix("W/").unwrap_or(tag)
+    }
+    let stored = opaque(stored);
+    client_value
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || opaque(tag) == stored)
+}
+
+/// [`create_cache_key`] with an explicit hash seed
+pub fn create_cache_key_with_seed(seed: u64, host: &str, port: u16, path: &str) -> u64 {
+    cache_key_hasher(seed, host, port, path).digest()
+}
+
+/// Random cache key seed, chosen once per process
+pub fn process_key_seed() -> u64 {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    *SEED.get_or_init(|| RandomState::new().build_hasher().finish())
+}
+
+/// Hasher primed with the host, port and path, shared by all cache key builders
+pub(crate) fn cache_key_hasher(seed: u64, host: &str, port: u16, path: &str) -> Xxh64 {
+    let mut hasher = Xxh64::new(seed);
+    hasher.update(host.as_bytes());
+    hasher.update(b":");
+    hasher.update(&port.to_le_bytes());
+    hasher.update(path.as_bytes());
+    hasher
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_host() {
+        let headers = vec![
+            "Host: example.com".to_string(),
+            "User-Agent: test".to_string(),
+        ];
+        assert_eq!(
+            extract_host(&headers),
+            Some(("example.com".to_string(), 80))
+        );
+
+        let headers_with_port = vec!["Host: example.com:8080".to_string()];
+        assert_eq!(
+            extract_host(&headers_with_port),
+            Some(("example.com".to_string(), 8080))
+        );
+    }
+
+    #[test]
+    fn test_strip_hop_by_hop() {
+        let headers: Vec<String> = [
+            "Host: example.com",
+            "connection: Keep-Alive, X-Custom",
+            "Keep-Alive: timeout=5",
+            "X-Custom: dropped",
+            "x-custom: dropped too",
+            "Proxy-Authorization: Basic Zm9vOmJhcg==",
+            "TE: trailers",
+            "Transfer-Encoding: chunked",
+            "Upgrade: websocket",
+            "X-Custom-Other: kept",
+            "Accept: */*",
+        ]
+        .iter()
+        .map(|h| h.to_string())
+        .collect();
+
+        assert_eq!(
+            strip_hop_by_hop(&headers),
+            vec!["Host: example.com", "X-Custom-Other: kept", "Accept: */*"]
+        );
+        assert_eq!(
+            strip_hop_by_hop_except(&headers, &["transfer-encoding"]),
+            vec![
+                "Host: example.com",
+                "Transfer-Encoding: chunked",
+                "X-Custom-Other: kept",
+                "Accept: */*"
+            ]
+        );
+        // Without a Connection header only the fixed set goes
+        assert_eq!(
+            strip_hop_by_hop(&headers[3..]),
+            vec![
+                "X-Custom: dropped",
+                "x-custom: dropped too",
+                "X-Custom-Other: kept",
+                "Accept: */*"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_normalize_absolute_form_targets() {
+        let target = |target: &str, headers: &[&str]| {
+            let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+            normalize_target(target, &headers)
+        };
+        let resolved =
+            |host: &str, port, path: &str| Some((host.to_string(), port, path.to_string()));
+
+        assert_eq!(
+            target("http://example.com/a/b.js", &[]),
+            resolved("example.com", 80, "/a/b.js")
+        );
+        assert_eq!(
+            target("HTTP://example.com:8080/a?x=1", &[]),
+            resolved("example.com", 8080, "/a?x=1")
+        );
+        assert_eq!(
+            target("http://example.com", &[]),
+            resolved("example.com", 80, "/")
+        );
+        assert_eq!(
+            target("http://example.com?x=1", &[]),
+            resolved("example.com", 80, "/?x=1")
+        );
+        assert_eq!(
+            target("http://user:pw@example.com/", &[]),
+            resolved("example.com", 80, "/")
+        );
+
+        // The URI authority wins over a mismatched Host header
+        assert_eq!(
+            target("http://origin.example/a.js", &["Host: other.example:81"]),
+            resolved("origin.example", 80, "/a.js")
+        );
+        assert_eq!(
+            target("/a.js", &["Host: other.example:81"]),
+            resolved("other.example", 81, "/a.js")
+        );
+
+        assert_eq!(target("https://example.com/", &[]), None);
+        assert_eq!(target("http:///a.js", &["Host: example.com"]), None);
+        assert_eq!(target("/a.js", &[]), None);
+        assert_eq!(target("example.com/a.js", &["Host: example.com"]), None);
+    }
+
+    #[test]
+    fn test_is_cacheable() {
+        // Static content should be cacheable
+        assert!(is_cacheable("GET", "/image.jpg", &[]));
+        assert!(is_cacheable("GET", "/style.css", &[]));
+        assert!(is_cacheable("GET", "/script.js", &[]));
+
+        // POST requests should not be cacheable
+        assert!(!is_cacheable("POST", "/image.jpg", &[]));
+
+        // Respect no-store headers, no-cache ones are stored for revalidation
+        let no_store_headers = vec!["Cache-Control: no-store".to_string()];
+        assert!(!is_cacheable("GET", "/image.jpg", &no_store_headers));
+        let no_cache_headers = vec!["Cache-Control: no-cache".to_string()];
+        assert!(is_cacheable("GET", "/image.jpg", &no_cache_headers));
+
+        // Respect max-age headers
+        let max_age_headers = vec!["Cache-Control: max-age=3600".to_string()];
+        assert!(is_cacheable("GET", "/api/data", &max_age_headers));
+    }
+
+    #[test]
+    fn test_calculate_ttl() {
+        let headers_with_max_age = vec!["Cache-Control: max-age=7200".to_string()];
+        assert_eq!(calculate_ttl(&headers_with_max_age), 7200);
+
+        let headers_with_large_max_age = vec!["Cache-Control: max-age=999999".to_string()];
+        assert_eq!(calculate_ttl(&headers_with_large_max_age), 86400); // Capped at 24 hours
+
+        let headers_without_cache = vec!["Content-Type: text/html".to_string()];
+        assert_eq!(calculate_ttl(&headers_without_cache), CACHE_TTL);
+    }
+
+    #[test]
+    fn test_s_maxage_overrides_max_age() {
+        let headers = vec!["Cache-Control: max-age=60, s-maxage=600".to_string()];
+        assert_eq!(calculate_ttl(&headers), 600);
+
+        let headers = vec!["Cache-Control: s-maxage=7200, max-age=60".to_string()];
+        assert_eq!(calculate_ttl(&headers), 7200);
+
+        // Still capped at the ceiling
+        let headers = vec!["Cache-Control: s-maxage=999999".to_string()];
+        assert_eq!(calculate_ttl(&headers), MAX_TTL);
+
+        // Falls back to max-age when absent
+        let headers = vec!["Cache-Control: public, max-age=120".to_string()];
+        assert_eq!(calculate_ttl(&headers), 120);
+    }
+
+    #[test]
+    fn test_s_maxage_cacheability() {
+        let headers = vec!["Cache-Control: s-maxage=0, max-age=3600".to_string()];
+        assert!(!is_cacheable("GET", "/image.jpg", &headers));
+
+        let headers = vec!["Cache-Control: s-maxage=600".to_string()];
+        assert!(is_cacheable("GET", "/api/data", &headers));
+    }
+
+    #[test]
+    fn test_calculate_ttl_max_age_overrides_expires() {
+        let now = 784_111_777; // Sun, 06 Nov 1994 08:49:37 GMT
+        let headers = vec![
+            "Cache-Control: max-age=60".to_string(),
+            "Expires: Sun, 06 Nov 1994 10:49:37 GMT".to_string(),
+        ];
+        assert_eq!(calculate_ttl_at(&headers, now), 60);
+    }
+
+    #[test]
+    fn test_calculate_ttl_expires_only() {
+        let now = 784_111_777;
+        let headers = vec!["Expires: Sun, 06 Nov 1994 10:49:37 GMT".to_string()];
+        assert_eq!(calculate_ttl_at(&headers, now), 7200);
+    }
+
+    #[test]
+    fn test_calculate_ttl_expires_in_past() {
+        let now = 784_111_777;
+        let headers = vec!["Expires: Sat, 05 Nov 1994 08:49:37 GMT".to_string()];
+        assert_eq!(calculate_ttl_at(&headers, now), 0);
+
+        // max-age still wins over a past Expires
+        let headers = vec![
+            "Expires: Sat, 05 Nov 1994 08:49:37 GMT".to_string(),
+            "Cache-Control: max-age=300".to_string(),
+        ];
+        assert_eq!(calculate_ttl_at(&headers, now), 300);
+    }
+
+    #[test]
+    fn test_calculate_ttl_expires_header() {
+        // Well-formed future date relative to the real clock
+        let expires = format!("Expires: {}", format_http_date(unix_now() + 600));
+        let ttl = calculate_ttl(&[expires]);
+        assert!((599..=600).contains(&ttl), "unexpected TTL {ttl}");
+
+        // Past dates floor at 0
+        let expires = "Expires: Thu, 01 Jan 1970 00:00:00 GMT".to_string();
+        assert_eq!(calculate_ttl(&[expires]), 0);
+
+        // Far-future dates are clamped to the ceiling
+        let expires = format!("Expires: {}", format_http_date(unix_now() + 7 * 86400));
+        assert_eq!(calculate_ttl(&[expires]), MAX_TTL);
+
+        // Malformed dates fall back to the default TTL
+        for malformed in ["0", "-1", "tomorrow", "Sun, 32 Nov 1994 08:49:37 GMT"] {
+            let expires = format!("Expires: {malformed}");
+            assert_eq!(calculate_ttl(&[expires]), CACHE_TTL, "{malformed}");
+        }
+    }
+
+    #[test]
+    fn test_accepts_encoding() {
+        let gzip = vec!["Accept-Encoding: gzip;q=0.8, br".to_string()];
+        assert!(accepts_encoding(&gzip, "gzip"));
+        assert!(accepts_encoding(&gzip, "br"));
+        assert!(accepts_encoding(&gzip, "identity"));
+        assert!(!accepts_encoding(&gzip, "deflate"));
+        assert!(!accepts_encoding(&gzip, "gzip, deflate"));
+
+        let refused = vec!["Accept-Encoding: gzip;q=0, *;q=0".to_string()];
+        assert!(!accepts_encoding(&refused, "gzip"));
+        assert!(!accepts_encoding(&refused, "identity"));
+
+        let wildcard = vec!["accept-encoding: *".to_string()];
+        assert!(accepts_encoding(&wildcard, "zstd"));
+
+        // No Accept-Encoding means any coding is acceptable
+        assert!(accepts_encoding(&[], "gzip"));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        // Weak and strong tags compare equal by their opaque part
+        assert!(etag_matches(r#""v1""#, r#""v1""#));
+        assert!(etag_matches(r#"W/"v1""#, r#""v1""#));
+        assert!(etag_matches(r#""v1""#, r#"W/"v1""#));
+        assert!(etag_matches(r#"W/"v1""#, r#"W/"v1""#));
+        assert!(!etag_matches(r#"W/"v2""#, r#""v1""#));
+        // The quotes are part of the tag
+        assert!(!etag_matches("v1", r#""v1""#));
+
+        // Any tag in a list may match
+        assert!(etag_matches(r#""a", W/"v1" , "b""#, r#""v1""#));
+        assert!(!etag_matches(r#""a", "b""#, r#""v1""#));
+        assert!(!etag_matches("", r#""v1""#));
+
+        assert!(etag_matches("*", r#"W/"anything""#));
+        assert!(etag_matches(" * ", r#""v1""#));
+    }
+
+    #[test]
+    fn test_http_date_round_trip() {
+        for secs in [0, 784_111_777, 951_782_400, 4_102_444_799] {
+            assert_eq!(parse_http_date(&format_http_date(secs)), Some(secs));
+        }
+        assert_eq!(
+            format_http_date(951_782_400),
+            "Tue, 29 Feb 2000 00:00:00 GMT"
+        );
+    }
+
+    #[test]
+    fn test_retry_after_ttl() {
+        let now = 784_111_777;
+        let headers = |value: &str| vec![format!("Retry-After: {}", value)];
+
+        assert_eq!(retry_after_ttl_at(503, &headers("120"), now), Some(120));
+        assert_eq!(retry_after_ttl_at(429, &headers(" 120 "), now), Some(120));
+        let date = headers(&format_http_date(now + 120));
+        assert_eq!(retry_after_ttl_at(503, &date, now), Some(120));
+        assert_eq!(retry_after_ttl_at(429, &date, now), Some(120));
+        let past = headers(&format_http_date(now - 60));
+        assert_eq!(retry_after_ttl_at(503, &past, now), Some(0));
+        assert_eq!(
+            retry_after_ttl_at(503, &headers("999999999"), now),
+            Some(MAX_TTL)
+        );
+
+        // Only overloaded origins, with a usable header
+        assert_eq!(retry_after_ttl_at(500, &headers("120"), now), None);
+        assert_eq!(retry_after_ttl_at(503, &headers("soon"), now), None);
+        assert_eq!(retry_after_ttl_at(503, &[], now), None);
+    }
+
+    #[test]
+    fn test_extension_ttls() {
+        let mut ttls = ExtensionTtls::new();
+        ttls.insert(".mp4", 86_400);
+        ttls.insert("HTML", 60);
+        let cache = ProxyCache::new().with_extension_ttls(ttls);
+
+        assert_eq!(cache.ttl_for(&[], "example.com", "/intro.mp4"), 86_400);
+        assert_eq!(cache.ttl_for(&[], "example.com", "/index.html?lang=en"), 60);
+        assert_eq!(cache.ttl_for(&[], "example.com", "/app.js"), CACHE_TTL);
+        // A directory named like a file doesn't match
+        assert_eq!(cache.ttl_for(&[], "example.com", "/a.mp4/"), CACHE_TTL);
+        let headers = vec!["Cache-Control: max-age=300".to_string()];
+        assert_eq!(cache.ttl_for(&headers, "example.com", "/intro.mp4"), 300);
+    }
+
+    #[test]
+    fn test_host_ttl_multiplier() {
+        let mut multipliers = HostTtlMultipliers::new();
+        multipliers.insert("CDN.example.com", 2.0);
+
+        let headers = vec!["Cache-Control: max-age=1800".to_string()];
+        assert_eq!(
+            calculate_ttl_for_host(&headers, "cdn.example.com", &multipliers),
+            3600
+        );
+        assert_eq!(
+            calculate_ttl_for_host(&headers, "other.example.com", &multipliers),
+            1800
+        );
+
+        // The multiplier is applied before the cap
+        let headers = vec!["Cache-Control: max-age=80000".to_string()];
+        assert_eq!(
+            calculate_ttl_for_host(&headers, "cdn.example.com", &multipliers),
+            MAX_TTL
+        );
+    }
+
+    #[tokio::test]
+    async fn test_host_ttl_multiplier_never_overrides_no_store() {
+        use crate::config::ProxyConfig;
+        use crate::connection_pool::ConnectionPool;
+        use crate::proxy::CachingProxy;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let upstream = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let _ = stream.read(&mut request).await;
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nCache-Control: no-store, max-age=600\r\n\
+                      Content-Length: 4\r\n\r\nbody",
+                )
+                .await
+                .unwrap();
+        });
+
+        let config = ProxyConfig::default()
+            .with_vars(|name| {
+                (name == "RUSTYSQUID_HOST_TTL_MULTIPLIERS").then(|| "127.0.0.1=2".to_string())
+            })
+            .unwrap();
+        let cache = ProxyCache::new().with_host_ttl_multipliers(config.host_ttl_multipliers);
+        let fresh = vec!["Cache-Control: max-age=600".to_string()];
+        assert_eq!(cache.ttl_for(&fresh, "127.0.0.1", "/app.js"), 1200);
+
+        let proxy = CachingProxy::new(cache.clone(), ConnectionPool::new(), ProxyConfig::default());
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let handler = tokio::spawn(async move { proxy.handle(server).await });
+        let request = format!(
+            "GET http://{}/app.js HTTP/1.1\r\nHost: {}\r\n\r\n",
+            addr, addr
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        handler.await.unwrap();
+
+        assert!(response.ends_with(b"\r\n\r\nbody"));
+        assert!(cache.is_empty().await);
+    }
+
+    #[test]
+    fn test_host_ttl_multiplier_ignores_invalid_values() {
+        let mut multipliers = HostTtlMultipliers::new();
+        multipliers.insert("bad.example.com", f64::NAN);
+        multipliers.insert("zero.example.com", 0.0);
+        assert!((multipliers.get("bad.example.com") - 1.0).abs() < f64::EPSILON);
+        assert!((multipliers.get("zero.example.com") - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_cache_key_generation() {
+        let key1 = create_cache_key("example.com", 80, "/index.html");
+        let key2 = create_cache_key("example.com", 80, "/index.html");
+        let key3 = create_cache_key("example.com", 80, "/other.html");
+
+        assert_eq!(key1, key2); // Same input should produce same key
         assert_ne!(key1, key3); // Different input should produce different key
     }
 
+    #[tokio::test]
+    async fn test_key_seed_changes_keys() {
+        let seeded = ProxyCache::new().with_key_seed(1);
+        let first = seeded.lookup_key("example.com", 80, "/a.js", &[]).await;
+        assert_eq!(
+            first,
+            seeded.lookup_key("example.com", 80, "/a.js", &[]).await
+        );
+        assert_eq!(
+            first,
+            create_cache_key_with_seed(1, "example.com", 80, "/a.js")
+        );
+
+        let reseeded = ProxyCache::new().with_key_seed(2);
+        assert_ne!(
+            first,
+            reseeded.lookup_key("example.com", 80, "/a.js", &[]).await
+        );
+
+        // The default seed is fixed for the life of the process
+        assert_eq!(ProxyCache::new().key_seed(), process_key_seed());
+        assert_eq!(ProxyCache::new().key_seed(), ProxyCache::new().key_seed());
+    }
+
     #[tokio::test]
     async fn test_proxy_cache_operations() {
         let cache = ProxyCache::new();
@@ -468,6 +2710,7 @@ mod tests {
                 .unwrap()
                 .as_secs()
                 + 3600,
+            ..Default::default()
         };
 
         cache.put(key, response.clone()).await;
@@ -497,6 +2740,7 @@ mod tests {
                 .unwrap()
                 .as_secs()
                 + 3600,
+            ..Default::default()
         };
 
         // Add entries until we exceed the limit
@@ -526,6 +2770,7 @@ mod tests {
                 .unwrap()
                 .as_secs()
                 + 3600,
+            ..Default::default()
         };
 
         let key = create_cache_key("test.com", 80, "/large");
@@ -537,6 +2782,920 @@ mod tests {
         assert_eq!(cache.total_size(), 0);
     }
 
+    #[tokio::test]
+    async fn test_age_stats_tracks_oldest_and_newest() {
+        let cache = ProxyCache::new();
+        assert_eq!(cache.age_stats_at(1_000).await, AgeStats::default());
+
+        for (key, stored_at) in [(1, 400), (2, 900), (3, 700)] {
+            let response = CachedResponse {
+                status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+                expires: u64::MAX,
+                stored_at,
+                ..Default::default()
+            };
+            assert!(cache.put(key, response).await);
+        }
+
+        let stats = cache.age_stats_at(1_000).await;
+        assert_eq!(stats.oldest_secs, 600);
+        assert_eq!(stats.newest_secs, 100);
+        assert_eq!(stats.count, 3);
+
+        // Entries without a timestamp don't skew the spread
+        let unstamped = CachedResponse {
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        cache.put(4, unstamped).await;
+        assert_eq!(cache.age_stats_at(1_000).await, stats);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_entries_survive_eviction() {
+        let config = ProxyCacheConfig {
+            max_entries: 3,
+            max_cache_bytes: 4096,
+            max_entry_size: 2048,
+            ..ProxyCacheConfig::default()
+        };
+        let cache = ProxyCache::with_config(config).unwrap();
+        let entry = |size: usize| CachedResponse {
+            body: Bytes::from(vec![0u8; size]),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+
+        assert!(cache.put_pinned(1, entry(1000)).await);
+        for key in 2..20 {
+            assert!(cache.put(key, entry(1000)).await);
+        }
+        assert!(cache.get(1).await.is_some_and(|e| e.pinned));
+        assert_eq!(cache.len().await, 3);
+
+        // With only pinned entries left there's no victim, so the put is refused
+        assert!(cache.put_pinned(20, entry(1000)).await);
+        assert!(cache.put_pinned(21, entry(1000)).await);
+        assert!(!cache.put(22, entry(1000)).await);
+        assert_eq!(cache.len().await, 3);
+
+        // Explicit removal and expiry still apply
+        assert!(cache.remove(20).await.is_some());
+        assert!(cache.put(22, entry(1000)).await);
+        assert!(
+            cache
+                .put_pinned(
+                    21,
+                    CachedResponse {
+                        expires: 1,
+                        ..entry(10)
+                    }
+                )
+                .await
+        );
+        assert_eq!(cache.evict_expired().await, 1);
+        assert!(cache.get(1).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ignore_query_shares_one_entry() {
+        let response = CachedResponse {
+            body: Bytes::from("body"),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        let cache = ProxyCache::new();
+        cache
+            .put_variant("a.example", 80, "/app.js?v=1", &[], response.clone())
+            .await
+            .unwrap();
+        let other = cache.lookup_key("a.example", 80, "/app.js?v=2", &[]).await;
+        assert!(cache.get(other).await.is_none());
+
+        let cache = ProxyCache::new().with_query_policy(QueryPolicy {
+            ignore_query: true,
+            ..QueryPolicy::default()
+        });
+        cache
+            .put_variant("a.example", 80, "/app.js?v=1", &[], response)
+            .await
+            .unwrap();
+        let other = cache.lookup_key("a.example", 80, "/app.js?v=2", &[]).await;
+        assert!(cache.get(other).await.is_some());
+        assert_eq!(
+            other,
+            cache.lookup_key("a.example", 80, "/app.js", &[]).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_host_removes_every_path_and_variant() {
+        let cache = ProxyCache::new();
+        let response = |expires: u64| CachedResponse {
+            body: Bytes::from("body"),
+            expires,
+            ..Default::default()
+        };
+        for path in ["/a.js", "/b.css", "/c.png"] {
+            cache
+                .put_variant("Site.example", 80, path, &[], response(u64::MAX))
+                .await
+                .unwrap();
+        }
+        let gzip = vec!["Accept-Encoding: gzip".to_string()];
+        let variant = CachedResponse {
+            vary: vec!["accept-encoding".to_string()],
+            ..response(u64::MAX)
+        };
+        cache
+            .put_variant("site.example", 80, "/a.js", &gzip, variant)
+            .await
+            .unwrap();
+        let other = cache
+            .put_variant("site.example", 8080, "/a.js", &[], response(u64::MAX))
+            .await
+            .unwrap();
+        let expired = cache
+            .put_variant("site.example", 80, "/old.js", &[], response(1))
+            .await
+            .unwrap();
+
+        // Expiry drops the key from the index, so it isn't counted again
+        assert!(cache.get(expired).await.is_none());
+        assert_eq!(cache.purge_host("site.example", 80).await, 4);
+        assert_eq!(cache.len().await, 1);
+        assert!(cache.get(other).await.is_some());
+        assert_eq!(cache.purge_host("site.example", 80).await, 0);
+        assert_eq!(cache.purge_all().await, 1);
+        assert_eq!(cache.total_size(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_host_index_follows_eviction() {
+        let cache = ProxyCache::with_config(ProxyCacheConfig {
+            max_entries: 2,
+            ..ProxyCacheConfig::default()
+        })
+        .unwrap();
+        for path in ["/1.js", "/2.js", "/3.js"] {
+            cache
+                .put_variant("evict.example", 80, path, &[], CachedResponse::default())
+                .await
+                .unwrap();
+        }
+        assert_eq!(cache.index().url_of.len(), 2);
+        assert_eq!(cache.purge_host("evict.example", 80).await, 2);
+        assert!(cache.index().by_host.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_describe_entries_largest_first() {
+        let cache = ProxyCache::new();
+        let entry = |len: usize, stored_at| CachedResponse {
+            body: Bytes::from(vec![b'x'; len]),
+            expires: 5_000,
+            stored_at,
+            ..Default::default()
+        };
+        cache
+            .put_variant("Media.example", 8080, "/intro.mp4", &[], entry(4096, 900))
+            .await
+            .unwrap();
+        cache
+            .put_variant("media.example", 8080, "/app.js", &[], entry(64, 0))
+            .await
+            .unwrap();
+        cache.put(42, entry(512, 990)).await;
+
+        let entries = cache.describe_entries_at(1_000).await;
+        let sizes: Vec<usize> = entries.iter().map(|e| e.body_bytes).collect();
+        assert_eq!(sizes, [4096, 512, 64]);
+        assert_eq!(
+            entries[0].url,
+            Some(CachedUrl {
+                host: "media.example".to_string(),
+                port: 8080,
+                path: "/intro.mp4".to_string(),
+            })
+        );
+        assert_eq!(entries[0].age_secs, Some(100));
+        assert_eq!(entries[0].expires, 5_000);
+        assert!(entries[0].size > 4096);
+        assert_eq!((entries[1].key, entries[1].url.clone()), (42, None));
+        assert_eq!(entries[2].age_secs, None);
+
+        // Purged hosts drop out of the listing
+        cache.purge_host("media.example", 8080).await;
+        let remaining = cache.describe_entries_at(1_000).await;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].key, 42);
+    }
+
+    #[tokio::test]
+    async fn test_evict_expired_purges_only_expired() {
+        let cache = ProxyCache::new();
+        for (key, expires) in (0..600).map(|key| (key, if key % 2 == 0 { 500 } else { 1_500 })) {
+            let response = CachedResponse {
+                body: Bytes::from("body"),
+                expires,
+                etag: Some("\"v1\"".to_string()),
+                ..Default::default()
+            };
+            assert!(cache.put(key, response).await);
+        }
+        let size = cache.total_size();
+
+        assert_eq!(cache.evict_expired_at(1_000).await, 300);
+        assert_eq!(cache.len().await, 300);
+        assert_eq!(cache.total_size(), size / 2);
+        assert_eq!(cache.evict_expired_at(1_000).await, 0);
+        assert_eq!(cache.evict_expired_at(1_500).await, 300);
+        assert_eq!(cache.total_size(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_racing_puts_and_gets_keep_size_consistent() {
+        // Small budgets so most puts evict or replace something
+        let cache = ProxyCache::with_config(ProxyCacheConfig {
+            max_entries: 32,
+            max_cache_bytes: 16 * 1024,
+            max_entry_size: 4 * 1024,
+            ..ProxyCacheConfig::default()
+        })
+        .unwrap();
+
+        let workers: Vec<_> = (0..16u64)
+            .map(|worker| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    for i in 0..500u64 {
+                        let key = (worker * 7 + i) % 64;
+                        match i % 5 {
+                            0 | 1 => {
+                                let response = CachedResponse {
+                                    body: Bytes::from(vec![
+                                        b'x';
+                                        ((worker + i) % 40 * 100) as usize
+                                    ]),
+                                    expires: if i % 3 == 0 { 1 } else { u64::MAX },
+                                    pinned: key % 16 == 0,
+                                    ..Default::default()
+                                };
+                                cache.put(key, response).await;
+                            }
+                            2 => {
+                                cache.get(key).await;
+                            }
+                            3 => {
+                                cache.remove(key).await;
+                            }
+                            _ => {
+                                cache.evict_expired().await;
+                            }
+                        }
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+
+        let audit = cache.audit().await;
+        assert!(audit.is_consistent(), "{:?}", audit);
+        assert!(audit.actual_bytes <= 16 * 1024);
+        assert!(audit.entries <= 32);
+        cache.clear().await;
+        assert_eq!(cache.audit().await.tracked_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_janitor_purges_in_background() {
+        let cache = ProxyCache::new();
+        let expired = CachedResponse {
+            expires: 1,
+            ..Default::default()
+        };
+        assert!(cache.put(1, expired).await);
+
+        let janitor = cache.clone().spawn_janitor(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        janitor.abort();
+        assert_eq!(cache.len().await, 0);
+    }
+
+    /// Memory source whose pressure tests switch on and off
+    struct SwitchedMemory(Arc<std::sync::atomic::AtomicBool>);
+
+    impl memory::MemorySource for SwitchedMemory {
+        fn read(&self) -> Option<memory::MemoryReading> {
+            let available_kb = if self.0.load(Ordering::Relaxed) {
+                10 * 1024
+            } else {
+                4 * 1024 * 1024
+            };
+            Some(memory::MemoryReading {
+                available_kb,
+                total_kb: 8 * 1024 * 1024,
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_put_refused_under_memory_pressure() {
+        let pressure = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let monitor = MemoryMonitor::new(SwitchedMemory(Arc::clone(&pressure)), Duration::ZERO);
+        let cache = ProxyCache::new().with_memory_monitor(monitor);
+        let fresh = CachedResponse {
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        let expired = CachedResponse {
+            expires: 1,
+            ..Default::default()
+        };
+        assert!(cache.put(1, fresh.clone()).await);
+        assert!(cache.put(2, expired).await);
+
+        // Low memory refuses the put and reclaims the expired entry
+        pressure.store(true, Ordering::Relaxed);
+        assert!(!cache.put(3, fresh.clone()).await);
+        assert_eq!(cache.len().await, 1);
+        assert!(cache.peek(1).await.is_some());
+
+        pressure.store(false, Ordering::Relaxed);
+        assert!(cache.put(3, fresh).await);
+        assert_eq!(cache.len().await, 2);
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_hits_misses_and_rejections() {
+        let cache = ProxyCache::new();
+        assert_eq!(cache.stats().hit_rate(), 0.0);
+
+        let response = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            body: Bytes::from("body"),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        assert!(cache.put(1, response).await);
+        let oversized = CachedResponse {
+            body: Bytes::from(vec![0u8; MAX_ENTRY_SIZE + 1]),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        assert!(!cache.put(2, oversized).await);
+
+        assert!(cache.get(1).await.is_some());
+        assert!(cache.get(1).await.is_some());
+        assert!(cache.get(1).await.is_some());
+        assert!(cache.get(3).await.is_none());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 3);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.insertions, 1);
+        assert_eq!(stats.rejected_too_large, 1);
+        assert_eq!(stats.evictions, 0);
+        assert_eq!(stats.hit_rate(), 0.75);
+
+        // Lifetime counters survive a clear
+        cache.clear().await;
+        assert_eq!(cache.stats(), stats);
+    }
+
+    #[test]
+    fn test_proxy_config_from_env_vars() {
+        use crate::config::{EnvConfigError, ProxyConfig, DEFAULT_LISTEN_ADDR};
+        let from = |vars: &[(&str, &str)]| {
+            let vars: HashMap<String, String> = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            ProxyConfig::default().with_vars(|name| vars.get(name).cloned())
+        };
+
+        let unset = from(&[]).unwrap();
+        assert_eq!(unset.listen_addr, DEFAULT_LISTEN_ADDR);
+        assert_eq!(unset.metrics_addr, None);
+
+        let config = from(&[
+            ("RUSTYSQUID_BIND", "127.0.0.1"),
+            ("RUSTYSQUID_PORT", " 3129 "),
+            ("RUSTYSQUID_METRICS_PORT", "9090"),
+        ])
+        .unwrap();
+        assert_eq!(config.listen_addr.to_string(), "127.0.0.1:3129");
+        assert_eq!(config.metrics_addr.unwrap().to_string(), "127.0.0.1:9090");
+        let config = from(&[
+            ("RUSTYSQUID_BIND", "::1"),
+            ("RUSTYSQUID_METRICS_BIND", "10.0.0.1"),
+            ("RUSTYSQUID_METRICS_PORT", "3128"),
+        ])
+        .unwrap();
+        assert_eq!(config.listen_addr.to_string(), "[::1]:3128");
+        assert_eq!(config.metrics_addr.unwrap().to_string(), "10.0.0.1:3128");
+        let config = from(&[
+            ("RUSTYSQUID_ALLOW_CLIENTS", "192.168.1.0/24, fd00::/8"),
+            ("RUSTYSQUID_DENY_CLIENTS", "192.168.1.13"),
+        ])
+        .unwrap();
+        assert_eq!(config.client_acl.allow.len(), 2);
+        assert!(config.client_acl.permits("192.168.1.12".parse().unwrap()));
+        assert!(!config.client_acl.permits("192.168.1.13".parse().unwrap()));
+        assert!(unset.rate_limiter.is_none());
+        let client = "192.168.1.12".parse().unwrap();
+        let limiter = from(&[("RUSTYSQUID_RATE_LIMIT", "1.5")])
+            .unwrap()
+            .rate_limiter
+            .unwrap();
+        assert!(limiter.check(client) && limiter.check(client));
+        assert!(!limiter.check(client));
+        let limiter = from(&[
+            ("RUSTYSQUID_RATE_LIMIT", "1"),
+            ("RUSTYSQUID_RATE_BURST", "3"),
+        ])
+        .unwrap()
+        .rate_limiter
+        .unwrap();
+        assert!((0..3).all(|_| limiter.check(client)));
+        assert!(!limiter.check(client));
+        let multipliers = from(&[(
+            "RUSTYSQUID_HOST_TTL_MULTIPLIERS",
+            "CDN.example.com=2, static.example.com = 1.5,",
+        )])
+        .unwrap()
+        .host_ttl_multipliers;
+        assert!((multipliers.get("cdn.example.com") - 2.0).abs() < f64::EPSILON);
+        assert!((multipliers.get("static.example.com") - 1.5).abs() < f64::EPSILON);
+        assert!((multipliers.get("example.com") - 1.0).abs() < f64::EPSILON);
+
+        for (var, value) in [
+            ("RUSTYSQUID_PORT", "0"),
+            ("RUSTYSQUID_PORT", "65536"),
+            ("RUSTYSQUID_BIND", "router.lan"),
+            ("RUSTYSQUID_METRICS_PORT", "metrics"),
+            ("RUSTYSQUID_ALLOW_CLIENTS", "192.168.1.0/24, lan"),
+            ("RUSTYSQUID_RATE_LIMIT", "0"),
+            ("RUSTYSQUID_RATE_LIMIT", "fast"),
+            ("RUSTYSQUID_HOST_TTL_MULTIPLIERS", "cdn.example.com"),
+            ("RUSTYSQUID_HOST_TTL_MULTIPLIERS", "cdn.example.com=0"),
+            ("RUSTYSQUID_HOST_TTL_MULTIPLIERS", "=2"),
+        ] {
+            assert_eq!(
+                from(&[(var, value)]).unwrap_err(),
+                EnvConfigError::Invalid {
+                    var,
+                    value: value.to_string()
+                }
+            );
+        }
+        assert_eq!(
+            from(&[("RUSTYSQUID_METRICS_BIND", "127.0.0.1")]).unwrap_err(),
+            EnvConfigError::MetricsBindWithoutPort
+        );
+        assert_eq!(
+            from(&[("RUSTYSQUID_RATE_BURST", "10")]).unwrap_err(),
+            EnvConfigError::RateBurstWithoutLimit
+        );
+        assert_eq!(
+            from(&[("RUSTYSQUID_METRICS_PORT", "3128")]).unwrap_err(),
+            EnvConfigError::SharedPort(3128)
+        );
+    }
+
+    #[test]
+    fn test_proxy_config_from_file() {
+        use crate::config::{EnvConfigError, ProxyConfig};
+        let path = std::env::temp_dir().join(format!("rustysquid-config-{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "# proxy policy\n\nRUSTYSQUID_PORT = 8080\nRUSTYSQUID_ALLOWED_METHODS=get, head\n",
+        )
+        .unwrap();
+        let config = ProxyConfig::default().with_file(&path).unwrap();
+        assert_eq!(config.listen_addr.port(), 8080);
+        assert_eq!(
+            config.allowed_methods,
+            Some(vec!["GET".to_string(), "HEAD".to_string()])
+        );
+
+        std::fs::write(&path, "RUSTYSQUID_ALLOWED_METHODS=GET,\n").unwrap();
+        assert!(matches!(
+            ProxyConfig::default().with_file(&path),
+            Err(EnvConfigError::Invalid { .. })
+        ));
+        std::fs::write(&path, "RUSTYSQUID_PORT\n").unwrap();
+        assert!(matches!(
+            ProxyConfig::default().with_file(&path),
+            Err(EnvConfigError::File { .. })
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            ProxyConfig::default().with_file(&path),
+            Err(EnvConfigError::File { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_with_config_limits() {
+        let config = ProxyCacheConfig {
+            max_entries: 2,
+            max_cache_bytes: 4096,
+            max_entry_size: 1024,
+            default_ttl: 60,
+            max_ttl: 600,
+            compress: false,
+            gzip_transfer_coding: false,
+            synthesize_etags: false,
+            large_entry_size: 512,
+            max_large_inserts: 1,
+        };
+        let cache = ProxyCache::with_config(config).unwrap();
+        let entry = |size: usize| CachedResponse {
+            body: Bytes::from(vec![0u8; size]),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+
+        assert!(!cache.put(1, entry(2048)).await);
+        for key in 1..=3 {
+            assert!(cache.put(key, entry(100)).await);
+        }
+        assert_eq!(cache.len().await, 2);
+        assert!(cache.get(1).await.is_none());
+        assert_eq!(cache.ttl_for(&[], "example.com", "/"), 60);
+
+        assert_eq!(
+            ProxyCache::with_config(ProxyCacheConfig {
+                max_entries: 0,
+                ..config
+            })
+            .err(),
+            Some(CacheConfigError::ZeroEntries)
+        );
+        assert_eq!(
+            ProxyCache::with_config(ProxyCacheConfig {
+                max_large_inserts: 0,
+                ..config
+            })
+            .err(),
+            Some(CacheConfigError::ZeroLargeInserts)
+        );
+        assert!(matches!(
+            ProxyCache::with_config(ProxyCacheConfig {
+                max_entry_size: 8192,
+                ..config
+            }),
+            Err(CacheConfigError::EntryLargerThanCache { .. })
+        ));
+    }
+
+    #[test]
+    fn test_configured_max_ttl() {
+        let cache = ProxyCache::with_config(ProxyCacheConfig {
+            max_ttl: 600,
+            ..ProxyCacheConfig::default()
+        })
+        .unwrap();
+        let max_age = vec!["Cache-Control: max-age=86400".to_string()];
+        assert_eq!(cache.ttl_for(&max_age, "example.com", "/app.js"), 600);
+        let expires = vec![format!("Expires: {}", format_http_date(unix_now() + 86400))];
+        assert_eq!(cache.ttl_for(&expires, "example.com", "/app.js"), 600);
+        let short = vec!["Cache-Control: max-age=60".to_string()];
+        assert_eq!(cache.ttl_for(&short, "example.com", "/app.js"), 60);
+        // Without freshness headers the default TTL is capped too
+        assert_eq!(cache.ttl_for(&[], "example.com", "/app.js"), 600);
+
+        let mut multipliers = HostTtlMultipliers::new();
+        multipliers.insert("cdn.example.com", 4.0);
+        let cache = cache.with_host_ttl_multipliers(multipliers);
+        assert_eq!(cache.ttl_for(&short, "cdn.example.com", "/app.js"), 240);
+        assert_eq!(cache.ttl_for(&max_age, "cdn.example.com", "/app.js"), 600);
+    }
+
+    #[tokio::test]
+    async fn test_small_puts_not_queued_behind_large_ones() {
+        let cache = ProxyCache::with_config(ProxyCacheConfig {
+            large_entry_size: 1024,
+            max_large_inserts: 1,
+            ..ProxyCacheConfig::default()
+        })
+        .unwrap();
+        let finished = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let put = |key: u64, size: usize| {
+            let (cache, finished) = (cache.clone(), Arc::clone(&finished));
+            tokio::spawn(async move {
+                let entry = CachedResponse {
+                    body: Bytes::from(vec![0u8; size]),
+                    expires: u64::MAX,
+                    ..Default::default()
+                };
+                assert!(cache.put(key, entry).await);
+                finished.lock().unwrap().push(key);
+            })
+        };
+
+        // Every put waits while the lock is held, large ones in turn
+        let held = cache.cache.lock().await;
+        let mut puts: Vec<_> = (1..=4).map(|key| put(key, 4096)).collect();
+        tokio::task::yield_now().await;
+        puts.push(put(100, 16));
+        tokio::task::yield_now().await;
+        drop(held);
+        for put in puts {
+            put.await.unwrap();
+        }
+
+        // Only the large put already holding a permit went first
+        assert_eq!(*finished.lock().unwrap(), vec![1, 100, 2, 3, 4]);
+        assert_eq!(cache.len().await, 5);
+    }
+
+    #[tokio::test]
+    async fn test_stats_count_byte_budget_evictions() {
+        let cache = ProxyCache::new();
+        let big = || CachedResponse {
+            body: Bytes::from(vec![0u8; MAX_ENTRY_SIZE - 1024]),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        let fits = MAX_CACHE_BYTES / MAX_ENTRY_SIZE;
+        for key in 0..=fits as u64 {
+            assert!(cache.put(key, big()).await);
+        }
+        assert_eq!(cache.stats().evictions, 1);
+        assert!(cache.total_size() <= MAX_CACHE_BYTES);
+    }
+
+    #[tokio::test]
+    async fn test_persist_and_reload_from_dir() {
+        let dir = std::env::temp_dir().join(format!("rustysquid-persist-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let cache = ProxyCache::new().with_key_seed(42);
+        let key = cache.lookup_key("example.com", 80, "/logo.png", &[]).await;
+        let fresh = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec!["Content-Type: image/png".to_string()],
+            body: Bytes::from("png"),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        cache.put(key, fresh.clone()).await;
+        cache
+            .put(
+                key + 1,
+                CachedResponse {
+                    expires: 1,
+                    ..fresh.clone()
+                },
+            )
+            .await;
+        assert_eq!(cache.persist_to_dir(&dir).await.unwrap(), 1);
+
+        let restarted = ProxyCache::new().with_key_seed(42);
+        assert_eq!(restarted.load_from_dir(&dir).await.unwrap().loaded, 1);
+        let key = restarted
+            .lookup_key("example.com", 80, "/logo.png", &[])
+            .await;
+        assert_eq!(*restarted.get(key).await.unwrap(), fresh);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_entries_purged_by_host() {
+        let dir = std::env::temp_dir().join(format!("rustysquid-reindex-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let entry = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            body: Bytes::from("ok"),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+
+        let cache = ProxyCache::new().with_key_seed(42);
+        for (host, path) in [
+            ("a.example", "/one"),
+            ("a.example", "/two"),
+            ("b.example", "/"),
+        ] {
+            cache
+                .put_variant(host, 80, path, &[], entry.clone())
+                .await
+                .unwrap();
+        }
+        assert_eq!(cache.persist_to_dir(&dir).await.unwrap(), 3);
+
+        let restarted = ProxyCache::new().with_key_seed(42);
+        assert_eq!(restarted.load_from_dir(&dir).await.unwrap().loaded, 3);
+        let mut urls: Vec<String> = restarted
+            .describe_entries()
+            .await
+            .into_iter()
+            .map(|info| {
+                let url = info.url.unwrap();
+                format!("{}:{}{}", url.host, url.port, url.path)
+            })
+            .collect();
+        urls.sort();
+        assert_eq!(
+            urls,
+            ["a.example:80/one", "a.example:80/two", "b.example:80/"]
+        );
+
+        assert_eq!(restarted.purge_host("a.example", 80).await, 2);
+        assert_eq!(restarted.len().await, 1);
+        let key = restarted.lookup_key("b.example", 80, "/", &[]).await;
+        assert!(restarted.get(key).await.is_some());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_vary_variants_reachable() {
+        let dir = std::env::temp_dir().join(format!("rustysquid-vary-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let gzip = vec!["Accept-Encoding: gzip".to_string()];
+        let variant = |body: &'static str| CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec!["Vary: Accept-Encoding".to_string()],
+            body: Bytes::from(body),
+            expires: u64::MAX,
+            vary: vec!["accept-encoding".to_string()],
+            ..Default::default()
+        };
+
+        let cache = ProxyCache::new().with_key_seed(42);
+        let gzip_key = cache
+            .put_variant("example.com", 80, "/app.js", &gzip, variant("gzipped"))
+            .await
+            .unwrap();
+        cache
+            .put_variant("example.com", 80, "/app.js", &[], variant("plain"))
+            .await
+            .unwrap();
+        assert_eq!(cache.persist_to_dir(&dir).await.unwrap(), 2);
+
+        let restarted = ProxyCache::new().with_key_seed(42);
+        assert_eq!(restarted.load_from_dir(&dir).await.unwrap().loaded, 2);
+        let key = restarted
+            .lookup_key("example.com", 80, "/app.js", &gzip)
+            .await;
+        assert_eq!(key, gzip_key);
+        assert_eq!(
+            restarted.get(key).await.unwrap().body,
+            Bytes::from("gzipped")
+        );
+        let key = restarted
+            .lookup_key("example.com", 80, "/app.js", &[])
+            .await;
+        assert_eq!(restarted.get(key).await.unwrap().body, Bytes::from("plain"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_persist_changes_writes_coalesced_batches() {
+        use crate::disk::WriteCoalescer;
+        let dir = std::env::temp_dir().join(format!("rustysquid-changes-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let files = || {
+            let mut names: Vec<String> = std::fs::read_dir(&dir)
+                .unwrap()
+                .flatten()
+                .map(|file| file.file_name().to_string_lossy().to_string())
+                .filter(|name| name != "key_seed")
+                .collect();
+            names.sort();
+            names
+        };
+        let entry = |body: &'static str| CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            body: Bytes::from(body),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+
+        let cache = ProxyCache::new().with_key_seed(42);
+        cache.put(1, entry("loaded before")).await;
+        let coalescer = WriteCoalescer::new(Duration::from_millis(50), 100);
+        let persister = cache
+            .persist_changes(&dir, coalescer.clone())
+            .await
+            .unwrap();
+        // Rewritten many times, flushed once
+        for _ in 0..10 {
+            cache.put(2, entry("v1")).await;
+        }
+        cache.put(3, entry("removed")).await;
+        cache.remove(3).await;
+        assert_eq!(coalescer.pending(), 2);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(coalescer.pending(), 0);
+        assert_eq!(files(), vec![format!("{:016x}", 2)]);
+
+        // The last batch is written on shutdown, removals included
+        cache.put(4, entry("late")).await;
+        cache.remove(2).await;
+        assert_eq!(persister.shutdown().await, 1);
+        assert_eq!(files(), vec![format!("{:016x}", 4)]);
+        let restarted = ProxyCache::new().with_key_seed(42);
+        assert_eq!(restarted.load_from_dir(&dir).await.unwrap().loaded, 1);
+        assert_eq!(restarted.get(4).await.unwrap().body, Bytes::from("late"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_vary_accept_encoding_variants() {
+        let cache = ProxyCache::new();
+        let gzip = vec!["Accept-Encoding: gzip".to_string()];
+        let identity: Vec<String> = vec![];
+
+        let response = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec![
+                "Content-Encoding: gzip".to_string(),
+                "Vary: Accept-Encoding".to_string(),
+            ],
+            body: Bytes::from("gzip body"),
+            expires: u64::MAX,
+            vary: vec!["accept-encoding".to_string()],
+            ..Default::default()
+        };
+        let stored = cache
+            .put_variant("test.com", 80, "/app.js", &gzip, response)
+            .await
+            .unwrap();
+
+        let gzip_key = cache.lookup_key("test.com", 80, "/app.js", &gzip).await;
+        let identity_key = cache.lookup_key("test.com", 80, "/app.js", &identity).await;
+        assert_eq!(gzip_key, stored);
+        assert_ne!(gzip_key, identity_key);
+        assert_ne!(identity_key, create_cache_key("test.com", 80, "/app.js"));
+
+        assert!(cache.get(gzip_key).await.is_some());
+        assert!(cache.get(identity_key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_lookup_keeps_stale_entries_with_validators() {
+        let cache = ProxyCache::new();
+        let expired = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec!["ETag: \"v1\"".to_string()],
+            body: Bytes::from("cached"),
+            expires: 1,
+            etag: Some("\"v1\"".to_string()),
+            ..Default::default()
+        };
+        cache.put(1, expired.clone()).await;
+        cache
+            .put(
+                2,
+                CachedResponse {
+                    etag: None,
+                    ..expired
+                },
+            )
+            .await;
+
+        assert!(matches!(cache.lookup(1).await, CacheLookup::Stale(_)));
+        assert_eq!(cache.lookup(2).await, CacheLookup::Miss);
+        assert_eq!(cache.len().await, 1);
+
+        // Revalidation makes the entry fresh again
+        assert!(cache.refresh_expiry(1, u64::MAX).await);
+        match cache.lookup(1).await {
+            CacheLookup::Fresh(entry) => assert_eq!(entry.body, Bytes::from("cached")),
+            other => panic!("expected fresh entry, got {other:?}"),
+        }
+        assert!(!cache.refresh_expiry(2, u64::MAX).await);
+    }
+
+    #[tokio::test]
+    async fn test_put_rejects_header_injection() {
+        let cache = ProxyCache::new();
+        let key = create_cache_key("test.com", 80, "/split");
+
+        let response = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec!["X-Forwarded: value\r\nInjected: evil".to_string()],
+            body: Bytes::from("body"),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+
+        assert!(!cache.put(key, response).await);
+        assert!(cache.get(key).await.is_none());
+        assert_eq!(cache.total_size(), 0);
+    }
+
     #[tokio::test]
     async fn test_cache_expiration() {
         let cache = ProxyCache::new();
@@ -552,6 +3711,7 @@ mod tests {
                 .unwrap()
                 .as_secs()
                 - 1, // Already expired
+            ..Default::default()
         };
 
         cache.put(key, expired_response).await;
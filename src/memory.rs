@@ -1,55 +1,416 @@
+use std::fmt;
+#[cfg(target_os = "linux")]
 use std::fs;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tracing::debug;
 
+/// Minimum available memory in KB regardless of total size
+const MIN_AVAILABLE_KB: usize = 100 * 1024;
+
+/// How long a [`MemoryMonitor`] reuses a reading
+pub const MEMORY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// `checked_at` value before the first reading
+const NEVER_CHECKED: u64 = u64::MAX;
+
+/// A snapshot of system memory, in KB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryReading {
+    /// Memory that can be handed out without swapping
+    pub available_kb: usize,
+    /// Physical memory installed
+    pub total_kb: usize,
+}
+
+/// Somewhere memory readings come from, lets tests simulate pressure
+pub trait MemorySource {
+    /// Current reading, None if the platform can't report one
+    fn read(&self) -> Option<MemoryReading>;
+}
+
+/// Reads memory from the operating system
+///
+/// `/proc/meminfo` on Linux, `sysctl` and the Mach VM statistics on macOS,
+/// `GlobalMemoryStatusEx` on Windows. Other platforms report nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemMemory;
+
+impl MemorySource for SystemMemory {
+    fn read(&self) -> Option<MemoryReading> {
+        read_system_memory()
+    }
+}
+
 /// Check if system has enough memory for caching
 /// Returns true if caching should proceed, false if memory is low
 pub fn has_sufficient_memory() -> bool {
-    #[cfg(target_os = "linux")]
-    {
-        if let Ok(meminfo) = fs::read_to_string("/proc/meminfo") {
-            let mut mem_available = 0;
-            let mut mem_total = 0;
-
-            for line in meminfo.lines() {
-                if line.starts_with("MemAvailable:") {
-                    if let Some(kb_str) = line.split_whitespace().nth(1) {
-                        mem_available = kb_str.parse::<usize>().unwrap_or(0);
-                    }
-                } else if line.starts_with("MemTotal:") {
-                    if let Some(kb_str) = line.split_whitespace().nth(1) {
-                        mem_total = kb_str.parse::<usize>().unwrap_or(0);
-                    }
-                }
-            }
+    has_sufficient_memory_with(&SystemMemory)
+}
+
+/// [`has_sufficient_memory`] against any [`MemorySource`]
+///
+/// Needs more than 100MB or 10% of total memory available, whichever is
+/// larger. Defaults to true when the source can't be read.
+pub fn has_sufficient_memory_with<S: MemorySource + ?Sized>(source: &S) -> bool {
+    let Some(reading) = source.read() else {
+        // Default to true if we can't check
+        return true;
+    };
+
+    let required = MIN_AVAILABLE_KB.max(reading.total_kb / 10);
+    let sufficient = reading.available_kb > required;
+    debug!(
+        "Memory check: available={}MB, required={}MB, sufficient={}",
+        reading.available_kb / 1024,
+        required / 1024,
+        sufficient
+    );
+    sufficient
+}
+
+/// Memory check that reads its source at most once per interval
+///
+/// Puts happen on every cacheable response, so the verdict is kept in
+/// atomics and shared until it is `interval` old. Concurrent callers
+/// racing past the deadline may each read the source once.
+pub struct MemoryMonitor {
+    source: Arc<dyn MemorySource + Send + Sync>,
+    interval: Duration,
+    started: Instant,
+    /// Milliseconds after `started` of the last reading
+    checked_at: AtomicU64,
+    sufficient: AtomicBool,
+}
+
+impl MemoryMonitor {
+    /// Check `source`, reusing each verdict for `interval`
+    pub fn new<S: MemorySource + Send + Sync + 'static>(source: S, interval: Duration) -> Self {
+        Self {
+            source: Arc::new(source),
+            interval,
+            started: Instant::now(),
+            checked_at: AtomicU64::new(NEVER_CHECKED),
+            sufficient: AtomicBool::new(true),
+        }
+    }
+
+    /// [`has_sufficient_memory_with`] the source, read again only once the
+    /// last verdict is older than the interval
+    pub fn has_sufficient_memory(&self) -> bool {
+        let now = self.started.elapsed().as_millis() as u64;
+        let checked_at = self.checked_at.load(Ordering::Acquire);
+        if checked_at != NEVER_CHECKED
+            && now.saturating_sub(checked_at) < self.interval.as_millis() as u64
+        {
+            return self.sufficient.load(Ordering::Relaxed);
+        }
+
+        let sufficient = has_sufficient_memory_with(self.source.as_ref());
+        self.sufficient.store(sufficient, Ordering::Relaxed);
+        self.checked_at.store(now, Ordering::Release);
+        sufficient
+    }
+}
+
+impl Default for MemoryMonitor {
+    /// [`SystemMemory`] checked every [`MEMORY_CHECK_INTERVAL`]
+    fn default() -> Self {
+        Self::new(SystemMemory, MEMORY_CHECK_INTERVAL)
+    }
+}
+
+impl fmt::Debug for MemoryMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryMonitor")
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Extract `MemAvailable` and `MemTotal` from `/proc/meminfo` content
+/// Missing fields read as 0
+pub fn parse_meminfo(meminfo: &str) -> MemoryReading {
+    let mut reading = MemoryReading {
+        available_kb: 0,
+        total_kb: 0,
+    };
+    for line in meminfo.lines() {
+        let field = if line.starts_with("MemAvailable:") {
+            &mut reading.available_kb
+        } else if line.starts_with("MemTotal:") {
+            &mut reading.total_kb
+        } else {
+            continue;
+        };
+        if let Some(kb_str) = line.split_whitespace().nth(1) {
+            *field = kb_str.parse::<usize>().unwrap_or(0);
+        }
+    }
+    reading
+}
+
+#[cfg(target_os = "linux")]
+fn read_system_memory() -> Option<MemoryReading> {
+    fs::read_to_string("/proc/meminfo")
+        .ok()
+        .map(|meminfo| parse_meminfo(&meminfo))
+}
+
+#[cfg(target_os = "macos")]
+fn read_system_memory() -> Option<MemoryReading> {
+    macos::read()
+}
+
+#[cfg(windows)]
+fn read_system_memory() -> Option<MemoryReading> {
+    windows::read()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn read_system_memory() -> Option<MemoryReading> {
+    None
+}
+
+#[cfg(target_os = "macos")]
+mod macos {
+    use super::MemoryReading;
+    use std::ffi::c_void;
+    use std::mem;
+    use std::os::raw::{c_char, c_int, c_uint};
 
-            // Need at least 100MB available or 10% of total memory
-            let min_available = 100 * 1024; // 100MB in KB
-            let min_percent = mem_total / 10; // 10% of total
-            let required = min_available.max(min_percent);
+    const HOST_VM_INFO64: c_int = 4;
+    const KERN_SUCCESS: c_int = 0;
 
-            let sufficient = mem_available > required;
-            debug!(
-                "Memory check: available={}MB, required={}MB, sufficient={}",
-                mem_available / 1024,
-                required / 1024,
-                sufficient
-            );
-            return sufficient;
+    /// `vm_statistics64` from `<mach/vm_statistics.h>`, filled in by the kernel
+    #[allow(dead_code)]
+    #[repr(C, align(8))]
+    #[derive(Default)]
+    struct VmStatistics64 {
+        free_count: u32,
+        active_count: u32,
+        inactive_count: u32,
+        wire_count: u32,
+        zero_fill_count: u64,
+        reactivations: u64,
+        pageins: u64,
+        pageouts: u64,
+        faults: u64,
+        cow_faults: u64,
+        lookups: u64,
+        hits: u64,
+        purges: u64,
+        purgeable_count: u32,
+        speculative_count: u32,
+        decompressions: u64,
+        compressions: u64,
+        swapins: u64,
+        swapouts: u64,
+        compressor_page_count: u32,
+        throttled_count: u32,
+        external_page_count: u32,
+        internal_page_count: u32,
+        total_uncompressed_pages_in_compressor: u64,
+    }
+
+    extern "C" {
+        fn sysctlbyname(
+            name: *const c_char,
+            oldp: *mut c_void,
+            oldlenp: *mut usize,
+            newp: *mut c_void,
+            newlen: usize,
+        ) -> c_int;
+        fn mach_host_self() -> c_uint;
+        fn host_statistics64(
+            host: c_uint,
+            flavor: c_int,
+            info: *mut c_int,
+            count: *mut c_uint,
+        ) -> c_int;
+    }
+
+    /// Read an integer sysctl, `name` must be NUL-terminated
+    fn sysctl_u64(name: &[u8]) -> Option<u64> {
+        let mut value = [0u8; 8];
+        let mut len = value.len();
+        // SAFETY: name is NUL-terminated and len matches the buffer
+        let rc = unsafe {
+            sysctlbyname(
+                name.as_ptr().cast(),
+                value.as_mut_ptr().cast(),
+                &mut len,
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        // Some values, hw.pagesize on older releases, are 32-bit
+        match (rc, len) {
+            (0, 8) => Some(u64::from_ne_bytes(value)),
+            (0, 4) => Some(u64::from(u32::from_ne_bytes([
+                value[0], value[1], value[2], value[3],
+            ]))),
+            _ => None,
         }
     }
 
-    // Default to true if we can't check
-    true
+    pub(super) fn read() -> Option<MemoryReading> {
+        let total = sysctl_u64(b"hw.memsize\0")?;
+        let page_size = sysctl_u64(b"hw.pagesize\0")?;
+
+        let mut stats = VmStatistics64::default();
+        let mut count = (mem::size_of::<VmStatistics64>() / mem::size_of::<c_int>()) as c_uint;
+        // SAFETY: stats is a vm_statistics64 and count is its size in integers
+        let rc = unsafe {
+            host_statistics64(
+                mach_host_self(),
+                HOST_VM_INFO64,
+                (&mut stats as *mut VmStatistics64).cast(),
+                &mut count,
+            )
+        };
+        if rc != KERN_SUCCESS {
+            return None;
+        }
+
+        // Inactive pages are reclaimed before anything is swapped, as
+        // MemAvailable counts reclaimable page cache on Linux
+        let pages = u64::from(stats.free_count) + u64::from(stats.inactive_count);
+        Some(MemoryReading {
+            available_kb: (pages * page_size / 1024) as usize,
+            total_kb: (total / 1024) as usize,
+        })
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::MemoryReading;
+    use std::mem;
+
+    /// `MEMORYSTATUSEX` from `<sysinfoapi.h>`, filled in by the kernel
+    #[allow(dead_code)]
+    #[repr(C)]
+    #[derive(Default)]
+    struct MemoryStatusEx {
+        length: u32,
+        memory_load: u32,
+        total_phys: u64,
+        avail_phys: u64,
+        total_page_file: u64,
+        avail_page_file: u64,
+        total_virtual: u64,
+        avail_virtual: u64,
+        avail_extended_virtual: u64,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GlobalMemoryStatusEx(buffer: *mut MemoryStatusEx) -> i32;
+    }
+
+    pub(super) fn read() -> Option<MemoryReading> {
+        let mut status = MemoryStatusEx {
+            length: mem::size_of::<MemoryStatusEx>() as u32,
+            ..MemoryStatusEx::default()
+        };
+        // SAFETY: status is a MEMORYSTATUSEX with its length set
+        if unsafe { GlobalMemoryStatusEx(&mut status) } == 0 {
+            return None;
+        }
+        Some(MemoryReading {
+            available_kb: (status.avail_phys / 1024) as usize,
+            total_kb: (status.total_phys / 1024) as usize,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    struct FakeMemory(Option<MemoryReading>);
+
+    impl MemorySource for FakeMemory {
+        fn read(&self) -> Option<MemoryReading> {
+            self.0
+        }
+    }
+
+    fn mb(available: usize, total: usize) -> FakeMemory {
+        FakeMemory(Some(MemoryReading {
+            available_kb: available * 1024,
+            total_kb: total * 1024,
+        }))
+    }
+
     #[test]
     fn test_memory_check() {
         // Should always return a boolean
         let _result = has_sufficient_memory();
         // Function always returns a boolean by definition
     }
+
+    #[test]
+    fn test_small_hosts_need_100mb() {
+        // 10% of 512MB is below the 100MB floor
+        assert!(!has_sufficient_memory_with(&mb(100, 512)));
+        assert!(has_sufficient_memory_with(&mb(101, 512)));
+    }
+
+    #[test]
+    fn test_large_hosts_need_ten_percent() {
+        assert!(!has_sufficient_memory_with(&mb(1600, 16384)));
+        assert!(has_sufficient_memory_with(&mb(1700, 16384)));
+    }
+
+    #[test]
+    fn test_unreadable_memory_allows_caching() {
+        assert!(has_sufficient_memory_with(&FakeMemory(None)));
+    }
+
+    /// Source counting how often it is read
+    struct CountingMemory(Arc<AtomicU64>);
+
+    impl MemorySource for CountingMemory {
+        fn read(&self) -> Option<MemoryReading> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            mb(50, 1024).0
+        }
+    }
+
+    #[test]
+    fn test_monitor_reuses_recent_reading() {
+        let reads = Arc::new(AtomicU64::new(0));
+        let monitor =
+            MemoryMonitor::new(CountingMemory(Arc::clone(&reads)), Duration::from_secs(60));
+        for _ in 0..100 {
+            assert!(!monitor.has_sufficient_memory());
+        }
+        assert_eq!(reads.load(Ordering::Relaxed), 1);
+
+        // A zero interval reads every time
+        let monitor = MemoryMonitor::new(CountingMemory(Arc::clone(&reads)), Duration::ZERO);
+        monitor.has_sufficient_memory();
+        monitor.has_sufficient_memory();
+        assert_eq!(reads.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_parse_meminfo() {
+        let meminfo = "MemTotal:       16318452 kB\n\
+                       MemFree:         1022060 kB\n\
+                       MemAvailable:    9312008 kB\n\
+                       Buffers:          512344 kB\n";
+        assert_eq!(
+            parse_meminfo(meminfo),
+            MemoryReading {
+                available_kb: 9_312_008,
+                total_kb: 16_318_452,
+            }
+        );
+        // Kernels before 3.14 have no MemAvailable
+        assert_eq!(parse_meminfo("MemTotal: 2048 kB\n").available_kb, 0);
+    }
 }
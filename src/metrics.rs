@@ -0,0 +1,534 @@
+use crate::config::SharedConfig;
+use crate::connection_pool::{ConnectionPool, PoolMetrics};
+use crate::{parse_request, CacheStats, EntryInfo, ProxyCache, VERSION};
+use bytes::BytesMut;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{debug, error};
+
+/// Path of the JSON version and stats endpoint
+pub const STATS_PATH: &str = "/__rustysquid/stats";
+
+/// Path of the JSON listing of cached entries
+pub const ENTRIES_PATH: &str = "/__rustysquid/entries";
+
+/// Largest admin request head read before giving up
+const MAX_ADMIN_REQUEST: usize = 8 * 1024;
+const ADMIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Render the Prometheus text exposition (format 0.0.4)
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::metrics::render;
+/// use rustysquid::CacheStats;
+///
+/// let stats = CacheStats { hits: 3, ..CacheStats::default() };
+/// let text = render(&stats, 1024, 2, 1);
+/// assert!(text.contains("\nrustysquid_cache_hits_total 3\n"));
+/// ```
+pub fn render(
+    stats: &CacheStats,
+    cache_bytes: usize,
+    cache_entries: usize,
+    active_connections: usize,
+) -> String {
+    let metrics: [(&str, &str, &str, u64); 7] = [
+        (
+            "rustysquid_cache_hits_total",
+            "counter",
+            "Requests served from cache",
+            stats.hits,
+        ),
+        (
+            "rustysquid_cache_misses_total",
+            "counter",
+            "Requests not served from cache",
+            stats.misses,
+        ),
+        (
+            "rustysquid_cache_hit_bytes_total",
+            "counter",
+            "Body bytes sent to clients from cache",
+            stats.hit_bytes,
+        ),
+        (
+            "rustysquid_origin_bytes_saved_total",
+            "counter",
+            "Estimated origin traffic avoided by cache hits, headers included",
+            stats.origin_bytes_saved,
+        ),
+        (
+            "rustysquid_cache_bytes",
+            "gauge",
+            "Bytes held by cached entries",
+            cache_bytes as u64,
+        ),
+        (
+            "rustysquid_cache_entries",
+            "gauge",
+            "Number of cached entries",
+            cache_entries as u64,
+        ),
+        (
+            "rustysquid_active_connections",
+            "gauge",
+            "Client connections being handled",
+            active_connections as u64,
+        ),
+    ];
+
+    let mut out = String::with_capacity(1024);
+    write_metrics(&mut out, &metrics);
+    out
+}
+
+/// Render the upstream connection pool counters, in the same format as [`render`]
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::connection_pool::PoolMetrics;
+/// use rustysquid::metrics::render_pool;
+///
+/// let text = render_pool(&PoolMetrics { reused: 4, ..PoolMetrics::default() });
+/// assert!(text.contains("\nrustysquid_pool_connections_reused_total 4\n"));
+/// ```
+pub fn render_pool(metrics: &PoolMetrics) -> String {
+    let metrics: [(&str, &str, &str, u64); 4] = [
+        (
+            "rustysquid_pool_connections_created_total",
+            "counter",
+            "Upstream connections opened",
+            metrics.created,
+        ),
+        (
+            "rustysquid_pool_connections_reused_total",
+            "counter",
+            "Pooled upstream connections reused",
+            metrics.reused,
+        ),
+        (
+            "rustysquid_pool_connections_dropped_stale_total",
+            "counter",
+            "Pooled upstream connections discarded as stale or dead",
+            metrics.dropped_stale,
+        ),
+        (
+            "rustysquid_pool_connections_dropped_full_total",
+            "counter",
+            "Upstream connections closed because the host's pool was full",
+            metrics.dropped_full,
+        ),
+    ];
+    let mut out = String::with_capacity(1024);
+    write_metrics(&mut out, &metrics);
+    out
+}
+
+/// Append `(name, type, help, value)` metrics with their `HELP` and `TYPE` lines
+fn write_metrics(out: &mut String, metrics: &[(&str, &str, &str, u64)]) {
+    for (name, kind, help, value) in metrics {
+        // Writing to a String can't fail
+        let _ = write!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n",
+            name = name,
+            help = help,
+            kind = kind,
+            value = value
+        );
+    }
+}
+
+/// `Server` header value identifying this build, e.g. `rustysquid/1.2.0`
+pub fn server_header() -> String {
+    format!("rustysquid/{}", VERSION)
+}
+
+/// Render the version and counters as a JSON object for [`STATS_PATH`]
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::metrics::render_stats;
+/// use rustysquid::CacheStats;
+///
+/// let json = render_stats(&CacheStats::default(), 0, 0, 0);
+/// assert!(json.starts_with(&format!("{{\"version\":\"{}\"", env!("CARGO_PKG_VERSION"))));
+/// ```
+pub fn render_stats(
+    stats: &CacheStats,
+    cache_bytes: usize,
+    cache_entries: usize,
+    active_connections: usize,
+) -> String {
+    format!(
+        "{{\"version\":\"{}\",\"hits\":{},\"misses\":{},\"hit_rate\":{:.4},\"insertions\":{},\"evictions\":{},\"hit_bytes\":{},\"origin_bytes_saved\":{},\"cache_bytes\":{},\"cache_entries\":{},\"active_connections\":{}}}\n",
+        VERSION,
+        stats.hits,
+        stats.misses,
+        stats.hit_rate(),
+        stats.insertions,
+        stats.evictions,
+        stats.hit_bytes,
+        stats.origin_bytes_saved,
+        cache_bytes,
+        cache_entries,
+        active_connections
+    )
+}
+
+/// Render cached entries as a JSON array for [`ENTRIES_PATH`], in the order given
+///
+/// Entries stored without a URL have `null` host, port and path.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::metrics::render_entries;
+/// use rustysquid::{CachedUrl, EntryInfo};
+///
+/// let entry = EntryInfo {
+///     key: 7,
+///     url: Some(CachedUrl { host: "example.com".into(), port: 80, path: "/a".into() }),
+///     body_bytes: 5,
+///     size: 40,
+///     expires: 100,
+///     age_secs: None,
+/// };
+/// assert_eq!(
+///     render_entries(&[entry]),
+///     "[{\"key\":7,\"host\":\"example.com\",\"port\":80,\"path\":\"/a\",\
+///      \"body_bytes\":5,\"size\":40,\"expires\":100,\"age_secs\":null}]\n"
+/// );
+/// ```
+pub fn render_entries(entries: &[EntryInfo]) -> String {
+    let mut out = String::with_capacity(64 + entries.len() * 160);
+    out.push('[');
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{{\"key\":{},", entry.key);
+        match &entry.url {
+            Some(url) => {
+                out.push_str("\"host\":");
+                write_json_string(&mut out, &url.host);
+                let _ = write!(out, ",\"port\":{},\"path\":", url.port);
+                write_json_string(&mut out, &url.path);
+            }
+            None => out.push_str("\"host\":null,\"port\":null,\"path\":null"),
+        }
+        let _ = write!(
+            out,
+            ",\"body_bytes\":{},\"size\":{},\"expires\":{},\"age_secs\":",
+            entry.body_bytes, entry.size, entry.expires
+        );
+        match entry.age_secs {
+            Some(age) => {
+                let _ = write!(out, "{}}}", age);
+            }
+            None => out.push_str("null}"),
+        }
+    }
+    out.push_str("]\n");
+    out
+}
+
+/// Append `value` as a quoted JSON string
+fn write_json_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Answer `GET /metrics`, `GET` [`STATS_PATH`] and `GET` [`ENTRIES_PATH`]
+/// on an admin listener until the task is dropped
+///
+/// Anything else gets a `404`. Every response names the running version
+/// in its `Server` header. Each connection serves one request. Clients the
+/// proxy's [`ClientAcl`](crate::acl::ClientAcl) refuses, as currently
+/// configured, are closed on accept, the entries listing shows every
+/// cached URL.
+pub async fn serve(
+    listener: TcpListener,
+    cache: ProxyCache,
+    pool: ConnectionPool,
+    active_connections: Arc<AtomicUsize>,
+    shared_config: SharedConfig,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Failed to accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        if !shared_config.load().client_acl.permits(addr.ip()) {
+            debug!("Refusing metrics connection from {}", addr);
+            continue;
+        }
+        let cache = cache.clone();
+        let pool = pool.clone();
+        let active_connections = Arc::clone(&active_connections);
+        tokio::spawn(async move {
+            handle(stream, &cache, &pool, &active_connections).await;
+        });
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    cache: &ProxyCache,
+    pool: &ConnectionPool,
+    active_connections: &AtomicUsize,
+) {
+    let mut buffer = BytesMut::with_capacity(1024);
+    while !buffer.windows(4).any(|w| w == b"\r\n\r\n") {
+        match timeout(ADMIN_TIMEOUT, stream.read_buf(&mut buffer)).await {
+            Ok(Ok(n)) if n > 0 && buffer.len() <= MAX_ADMIN_REQUEST => {}
+            _ => return,
+        }
+    }
+
+    let (status, content_type, body) = match parse_request(&buffer) {
+        Ok((method, path, _)) if method == "GET" && path == "/metrics" => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            render(
+                &cache.stats(),
+                cache.total_size(),
+                cache.len().await,
+                active_connections.load(Ordering::Relaxed),
+            ) + &render_pool(&pool.metrics()),
+        ),
+        Ok((method, path, _)) if method == "GET" && path == STATS_PATH => (
+            "200 OK",
+            "application/json",
+            render_stats(
+                &cache.stats(),
+                cache.total_size(),
+                cache.len().await,
+                active_connections.load(Ordering::Relaxed),
+            ),
+        ),
+        Ok((method, path, _)) if method == "GET" && path == ENTRIES_PATH => (
+            "200 OK",
+            "application/json",
+            render_entries(&cache.describe_entries().await),
+        ),
+        _ => ("404 Not Found", "text/plain", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nServer: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        server_header(),
+        content_type,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("Failed to send metrics response: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::ClientAcl;
+    use crate::config::ProxyConfig;
+
+    #[test]
+    fn test_render_format() {
+        let stats = CacheStats {
+            hits: 7,
+            misses: 2,
+            hit_bytes: 300,
+            origin_bytes_saved: 420,
+            ..CacheStats::default()
+        };
+        let text = render(&stats, 4096, 3, 5);
+        for line in [
+            "# TYPE rustysquid_cache_hits_total counter",
+            "rustysquid_cache_hits_total 7",
+            "rustysquid_cache_misses_total 2",
+            "rustysquid_cache_hit_bytes_total 300",
+            "rustysquid_origin_bytes_saved_total 420",
+            "# TYPE rustysquid_cache_bytes gauge",
+            "rustysquid_cache_bytes 4096",
+            "rustysquid_cache_entries 3",
+            "rustysquid_active_connections 5",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {:?}", line);
+        }
+        assert!(text.ends_with('\n'));
+    }
+
+    async fn fetch(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(format!("GET {} HTTP/1.1\r\nHost: admin\r\n\r\n", path).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn test_serve_metrics_endpoint() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cache = ProxyCache::new();
+        cache.get(1).await;
+        let pool = ConnectionPool::new();
+        let server = tokio::spawn(serve(
+            listener,
+            cache,
+            pool.clone(),
+            Arc::new(AtomicUsize::new(2)),
+            SharedConfig::new(ProxyConfig::default()),
+        ));
+        // Any listener serves as an upstream to count a connection against
+        pool.get_connection("127.0.0.1", addr.port()).await.unwrap();
+
+        let response = fetch(addr, "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\nrustysquid_cache_misses_total 1\n"));
+        assert!(response.contains("\nrustysquid_active_connections 2\n"));
+        assert!(response.contains("\nrustysquid_pool_connections_created_total 1\n"));
+        assert!(response.contains("\n# TYPE rustysquid_pool_connections_reused_total counter\n"));
+        assert!(fetch(addr, "/").await.starts_with("HTTP/1.1 404"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stats_endpoint_reports_version() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(serve(
+            listener,
+            ProxyCache::new(),
+            ConnectionPool::new(),
+            Arc::new(AtomicUsize::new(0)),
+            SharedConfig::new(ProxyConfig::default()),
+        ));
+
+        let version = env!("CARGO_PKG_VERSION");
+        let response = fetch(addr, STATS_PATH).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(&format!("\r\nServer: rustysquid/{}\r\n", version)));
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+        assert!(response.contains(&format!("\r\n\r\n{{\"version\":\"{}\",", version)));
+        // The 404 for unknown paths still identifies the build
+        let missing = fetch(addr, "/__rustysquid/nope").await;
+        assert!(missing.starts_with("HTTP/1.1 404"));
+        assert!(missing.contains(&format!("\r\nServer: rustysquid/{}\r\n", version)));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_entries_endpoint_lists_cache() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cache = ProxyCache::new();
+        let entry = |body: &'static str| crate::CachedResponse {
+            body: bytes::Bytes::from(body),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        cache
+            .put_variant("example.com", 80, "/a \"quoted\" path", &[], entry("hello"))
+            .await
+            .unwrap();
+        let server = tokio::spawn(serve(
+            listener,
+            cache,
+            ConnectionPool::new(),
+            Arc::new(AtomicUsize::new(0)),
+            SharedConfig::new(ProxyConfig::default()),
+        ));
+
+        let response = fetch(addr, ENTRIES_PATH).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Type: application/json\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert!(body.starts_with("[{\"key\":"), "{}", body);
+        assert!(body.contains(
+            ",\"host\":\"example.com\",\"port\":80,\"path\":\"/a \\\"quoted\\\" path\",\"body_bytes\":5,"
+        ));
+        assert!(!body.contains("hello"));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_admin_clients_outside_acl_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cache = ProxyCache::new();
+        cache
+            .put_variant(
+                "example.com",
+                80,
+                "/download?token=secret",
+                &[],
+                crate::CachedResponse {
+                    expires: u64::MAX,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let shared = SharedConfig::new(ProxyConfig {
+            client_acl: ClientAcl {
+                deny: ClientAcl::parse_list("127.0.0.0/8").unwrap(),
+                ..ClientAcl::default()
+            },
+            ..ProxyConfig::default()
+        });
+        let server = tokio::spawn(serve(
+            listener,
+            cache,
+            ConnectionPool::new(),
+            Arc::new(AtomicUsize::new(0)),
+            shared.clone(),
+        ));
+
+        // Closed without an answer, the listing never leaves the proxy
+        for path in [ENTRIES_PATH, "/metrics"] {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!("GET {} HTTP/1.1\r\nHost: admin\r\n\r\n", path);
+            let _ = stream.write_all(request.as_bytes()).await;
+            let mut response = Vec::new();
+            let _ = stream.read_to_end(&mut response).await;
+            assert!(
+                response.is_empty(),
+                "{}",
+                String::from_utf8_lossy(&response)
+            );
+        }
+
+        // The ACL is read per connection, a reload lets the client in
+        shared.store(ProxyConfig::default());
+        let response = fetch(addr, ENTRIES_PATH).await;
+        assert!(response.contains("token=secret"), "{}", response);
+        server.abort();
+    }
+}
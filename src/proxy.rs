@@ -0,0 +1,7236 @@
+use bytes::{Bytes, BytesMut};
+use std::net::IpAddr;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    accepts_encoding,
+    access_log::{AccessRecord, CacheStatus},
+    backend::Cache,
+    body_etag,
+    cache_control::CacheControl,
+    chunked::{chunked_length, decode_chunked, is_chunked},
+    compress::{
+        compress_response, decompress_response, encode, mark_gzip_encoded, preferred_encoding,
+        without_headers, ENCODINGS,
+    },
+    config::{
+        EnvConfigError, ForceCacheRule, LengthMismatch, ProxyConfig, RefreshSchedule, ResponseMode,
+        SetCookiePolicy, SharedConfig,
+    },
+    connection_pool::{ConnectError, ConnectionPool},
+    cors,
+    disk::DiskCache,
+    etag_matches, extract_host, fd, header_value,
+    html::inject_after_head,
+    is_cacheable, is_safe_header_line,
+    metrics::server_header,
+    normalize_target, parse_request, process_key_seed, retry_after_ttl,
+    single_flight::{Flight, FlightGuard, FlightOutcome},
+    split_authority, strip_hop_by_hop, strip_hop_by_hop_except,
+    tasks::TaskLimiter,
+    vary::parse_vary,
+    CacheLookup, CachedResponse, ParseError, ProxyCache, MAX_CONNECTIONS, MAX_REQUEST_SIZE,
+    MAX_RESPONSE_SIZE,
+};
+
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Target of the per-request cache key event, enabled with
+/// `RUST_LOG=rustysquid::cache_key=trace`
+const CACHE_KEY_TARGET: &str = "rustysquid::cache_key";
+/// Error for an upstream that took the request but sent nothing in time
+const FIRST_BYTE_TIMED_OUT: &str = "No response byte before the first-byte deadline";
+/// Read error for a head still incomplete at [`ProxyConfig::head_timeout`]
+const HEAD_TIMED_OUT: &str = "Head not complete before the head deadline";
+/// Read error for a transfer below [`ProxyConfig::min_transfer_rate`]
+const TRANSFER_TOO_SLOW: &str = "Transfer below the minimum rate";
+/// How long a transfer runs before its average rate is checked
+const MIN_RATE_GRACE: Duration = Duration::from_secs(1);
+/// Per-connection buffer for streamed passthrough responses
+const STREAM_CHUNK_SIZE: usize = 16 * 1024;
+
+// Refactored with reduced complexity - each function has cyclomatic complexity <= 10
+
+/// Wall-clock limits on a read spanning many `read_buf` calls
+///
+/// Per-read timeouts alone let a peer hold a connection forever by sending
+/// a byte just before each one expires. Once the first byte has arrived
+/// the head must complete within `head_timeout`, and past
+/// [`MIN_RATE_GRACE`] the average rate must stay above `min_rate`.
+#[derive(Clone, Copy, Debug)]
+struct ReadBudget {
+    /// Wait for the first byte
+    first_byte: Duration,
+    head_timeout: Duration,
+    /// Bytes per second, None disables the check
+    min_rate: Option<u64>,
+}
+
+impl ReadBudget {
+    fn new(config: &ProxyConfig, first_byte: Duration) -> Self {
+        Self {
+            first_byte,
+            head_timeout: config.head_timeout,
+            min_rate: config.min_transfer_rate,
+        }
+    }
+
+    /// Fail a read that started at `started` with `received` bytes so far
+    /// once it has run out of time or fallen below the minimum rate
+    fn check(
+        &self,
+        started: Option<Instant>,
+        received: usize,
+        head_done: bool,
+    ) -> Result<(), &'static str> {
+        let Some(elapsed) = started.map(|started| started.elapsed()) else {
+            return Ok(());
+        };
+        if !head_done && elapsed >= self.head_timeout {
+            return Err(HEAD_TIMED_OUT);
+        }
+        match self.min_rate {
+            Some(rate)
+                if elapsed >= MIN_RATE_GRACE
+                    && (received as f64) < rate as f64 * elapsed.as_secs_f64() =>
+            {
+                Err(TRANSFER_TOO_SLOW)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Timeout for the next read, cut short by the head deadline
+    fn next_read(&self, started: Option<Instant>, head_done: bool) -> Duration {
+        match started {
+            None => self.first_byte,
+            Some(_) if head_done => CONNECTION_TIMEOUT,
+            Some(started) => self
+                .head_timeout
+                .saturating_sub(started.elapsed())
+                .min(CONNECTION_TIMEOUT),
+        }
+    }
+}
+
+/// Read the next request from a client connection with size limits
+///
+/// Bytes past the end of the request (a pipelined follow-up) stay in
+/// `pending` for the next call. A request body is included when framed by
+/// `Content-Length` or chunked encoding. Returns None once the client has
+/// closed between requests. Requests that break the `budget` once their
+/// first byte is in fail with [`HEAD_TIMED_OUT`] or [`TRANSFER_TOO_SLOW`].
+///
+/// A client sending `Expect: 100-continue` gets a `100 Continue` once its
+/// head is in, since the whole request is read here before going upstream.
+async fn read_next_request<C: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut C,
+    pending: &mut BytesMut,
+    budget: ReadBudget,
+) -> Result<Option<BytesMut>, &'static str> {
+    // Pipelined bytes already waiting start the clock
+    let mut started = (!pending.is_empty()).then(Instant::now);
+    let mut continued = false;
+    loop {
+        if let Some(length) = request_length(pending) {
+            if length > MAX_REQUEST_SIZE {
+                return Err("Request too large");
+            }
+            return Ok(Some(pending.split_to(length)));
+        }
+        if pending.len() > MAX_REQUEST_SIZE {
+            return Err("Request too large");
+        }
+
+        let head_done = find_headers_end(pending).is_some();
+        // The client holds its body back until told to go ahead
+        if head_done && !continued && expects_continue(pending) {
+            continued = true;
+            client
+                .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .map_err(|_| "Failed to send 100 Continue")?;
+        }
+        budget.check(started, pending.len(), head_done)?;
+        match timeout(
+            budget.next_read(started, head_done),
+            client.read_buf(pending),
+        )
+        .await
+        {
+            Ok(Ok(0)) if pending.is_empty() => return Ok(None),
+            // A truncated request is passed on and rejected by validation
+            Ok(Ok(0)) => return Ok(Some(pending.split())),
+            Ok(Ok(_)) => {
+                started.get_or_insert_with(Instant::now);
+            }
+            // The check above reports the head deadline
+            Err(_) if started.is_some() && !head_done => {}
+            _ => return Err("Read timeout or error"),
+        }
+    }
+}
+
+/// Length of the first complete request in `data`, None until it has all arrived
+fn request_length(data: &[u8]) -> Option<usize> {
+    let head_end = find_headers_end(data)?;
+    let Ok((_, _, headers)) = parse_request(&data[..head_end]) else {
+        // Malformed, validation answers it with a 400
+        return Some(head_end);
+    };
+    if header_value(&headers, "transfer-encoding").is_some_and(is_chunked) {
+        return chunked_length(&data[head_end..]).map(|length| head_end + length);
+    }
+    let body = header_value(&headers, "content-length")
+        .and_then(|length| length.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    let end = head_end.saturating_add(body);
+    // Report oversized bodies right away instead of buffering them
+    (data.len() >= end || end > MAX_REQUEST_SIZE).then_some(end)
+}
+
+/// Whether an HTTP/1.1 request head asks for `100 Continue` before its body
+fn expects_continue(request: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Request::new(&mut headers);
+    if parsed.parse(request).is_err() || parsed.version != Some(1) {
+        return false;
+    }
+    parsed.headers.iter().any(|h| {
+        h.name.eq_ignore_ascii_case("expect") && h.value.eq_ignore_ascii_case(b"100-continue")
+    })
+}
+
+/// Whether the client allows another request on this connection
+fn client_keeps_alive(request: &[u8]) -> bool {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Request::new(&mut headers);
+    if parsed.parse(request).is_err() {
+        return false;
+    }
+    let connection_has = |token: &str| {
+        parsed
+            .headers
+            .iter()
+            .filter(|h| h.name.eq_ignore_ascii_case("connection"))
+            .filter_map(|h| std::str::from_utf8(h.value).ok())
+            .flat_map(|value| value.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    };
+    match parsed.version {
+        Some(1) => !connection_has("close"),
+        _ => connection_has("keep-alive"),
+    }
+}
+
+/// Whether `header` is a `Connection` or `Keep-Alive` line
+fn is_connection_header(header: &[u8]) -> bool {
+    let name = header.split(|&b| b == b':').next().unwrap_or_default();
+    std::str::from_utf8(name).is_ok_and(|name| {
+        let name = name.trim();
+        name.eq_ignore_ascii_case("connection") || name.eq_ignore_ascii_case("keep-alive")
+    })
+}
+
+/// Whether `header` is a `Server` line
+fn is_server_header(header: &[u8]) -> bool {
+    let name = header.split(|&b| b == b':').next().unwrap_or_default();
+    std::str::from_utf8(name).is_ok_and(|name| name.trim().eq_ignore_ascii_case("server"))
+}
+
+/// Whether `header` is an `X-Cache` or `X-Cache-Age` line
+fn is_x_cache_header(header: &[u8]) -> bool {
+    let name = header.split(|&b| b == b':').next().unwrap_or_default();
+    std::str::from_utf8(name).is_ok_and(|name| {
+        let name = name.trim();
+        name.eq_ignore_ascii_case("x-cache") || name.eq_ignore_ascii_case("x-cache-age")
+    })
+}
+
+/// Headers the proxy sets on a response it sends, replacing any the
+/// response already carries
+#[derive(Clone, Copy, Debug, Default)]
+struct ReplyHeaders<'a> {
+    /// `Connection` value, None leaves the response's own headers
+    connection: Option<&'a str>,
+    /// `X-Cache` value, None leaves the header out
+    x_cache: Option<CacheStatus>,
+    /// Framing for cached bodies whose stored `Content-Length` is wrong
+    length_mismatch: LengthMismatch,
+    /// `Server` naming this build, see [`ProxyConfig::server_header`]
+    server: bool,
+}
+
+impl ReplyHeaders<'_> {
+    fn is_empty(&self) -> bool {
+        self.connection.is_none() && self.x_cache.is_none() && !self.server
+    }
+
+    /// Whether a stored `header` line gives way to one of these
+    fn replaces(&self, header: &[u8]) -> bool {
+        (self.connection.is_some() && is_connection_header(header))
+            || (self.x_cache.is_some() && is_x_cache_header(header))
+            || (self.server && is_server_header(header))
+    }
+
+    /// Header lines to append, `age` is how long a cached copy has been stored
+    fn render(&self, age: Option<u64>) -> String {
+        let mut out = String::new();
+        if self.server {
+            out.push_str(&format!("Server: {}\r\n", server_header()));
+        }
+        if let Some(connection) = self.connection {
+            out.push_str(&format!("Connection: {}\r\n", connection));
+        }
+        if let Some(status) = self.x_cache {
+            out.push_str(&format!("X-Cache: {}\r\n", status));
+            if let Some(age) = age {
+                out.push_str(&format!("X-Cache-Age: {}\r\n", age));
+            }
+        }
+        out
+    }
+}
+
+/// Whether `header` is a line for the header called `name`
+fn is_named(header: &str, name: &str) -> bool {
+    header
+        .split_once(':')
+        .is_some_and(|(line_name, _)| line_name.trim().eq_ignore_ascii_case(name))
+}
+
+/// Whether the entry's `Content-Length` is something other than its body size
+fn length_mismatched(cached: &CachedResponse) -> bool {
+    header_value(&cached.headers, "content-length")
+        .is_some_and(|value| value.trim().parse::<usize>().ok() != Some(cached.body.len()))
+}
+
+/// Seconds since a cached copy was stored
+fn cached_age(cached: &CachedResponse) -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .saturating_sub(cached.stored_at)
+}
+
+/// `Age` of a cached copy, the age upstream gave it plus the time it has
+/// been stored
+fn current_age(cached: &CachedResponse) -> u64 {
+    header_value(&cached.headers, "age")
+        .and_then(|age| age.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+        .saturating_add(cached_age(cached))
+}
+
+/// Copy of a response head with the headers `reply` sets replaced
+fn with_reply_headers(head: &[u8], reply: ReplyHeaders<'_>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(head.len() + 32);
+    let lines = head
+        .strip_suffix(b"\r\n\r\n")
+        .unwrap_or(head)
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    for (i, line) in lines.enumerate() {
+        if i > 0 && reply.replaces(line) {
+            continue;
+        }
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(reply.render(None).as_bytes());
+    out.extend_from_slice(b"\r\n");
+    out
+}
+
+/// Write a forwarded response, setting the headers `reply` carries
+async fn write_forwarded<C: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut C,
+    response: &[u8],
+    reply: ReplyHeaders<'_>,
+) -> std::io::Result<()> {
+    match find_headers_end(response) {
+        Some(head_end) if !reply.is_empty() => {
+            client
+                .write_all(&with_reply_headers(&response[..head_end], reply))
+                .await?;
+            client.write_all(&response[head_end..]).await
+        }
+        _ => client.write_all(response).await,
+    }
+}
+
+/// Send error response to client
+async fn send_error_response<C: AsyncRead + AsyncWrite + Unpin>(client: &mut C, status: &[u8]) {
+    if let Err(e) = client.write_all(status).await {
+        debug!("Failed to send error response: {}", e);
+    }
+}
+
+/// `405` response naming the methods that are allowed, in the order given
+fn method_not_allowed(allowed: &[String]) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 405 Method Not Allowed\r\nAllow: {}\r\n\r\n",
+        allowed.join(", ")
+    )
+    .into_bytes()
+}
+
+/// `429` response telling the client when it may try again
+fn too_many_requests(retry_after: u64) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 429 Too Many Requests\r\nRetry-After: {}\r\n\r\n",
+        retry_after
+    )
+    .into_bytes()
+}
+
+/// Methods listed in `Allow` when no `allowed_methods` are configured
+const SUPPORTED_METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS", "CONNECT",
+];
+
+/// Answer to a request meant for the proxy itself rather than an origin
+///
+/// `TRACE` is refused whatever its target, echoed back through the proxy
+/// it would hand scripts the request's headers, cookies included (cross-site
+/// tracing). `OPTIONS *` is answered with the methods the proxy accepts.
+/// Everything else, `OPTIONS` for an origin included, gets None.
+fn proxy_local_answer(method: &str, target: &str, allowed: Option<&[String]>) -> Option<Vec<u8>> {
+    let methods: Vec<String> = match allowed {
+        Some(allowed) => allowed.to_vec(),
+        None => SUPPORTED_METHODS.iter().map(|m| m.to_string()).collect(),
+    };
+    let methods: Vec<String> = methods.into_iter().filter(|m| m != "TRACE").collect();
+    match method {
+        "TRACE" => Some(method_not_allowed(&methods)),
+        "OPTIONS" if target == "*" => Some(
+            format!(
+                "HTTP/1.1 200 OK\r\nAllow: {}\r\nContent-Length: 0\r\n\r\n",
+                methods.join(", ")
+            )
+            .into_bytes(),
+        ),
+        _ => None,
+    }
+}
+
+/// Why a client request was rejected before being looked up or forwarded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RequestError {
+    /// The request line or headers don't parse
+    Malformed,
+    /// No `Host` header, or an empty one
+    MissingHost,
+    /// More than one `Host` header, which could target two origins
+    DuplicateHost,
+    /// A request `Transfer-Encoding` other than chunked
+    Unsupported,
+    /// The request line is longer than `max_request_line`
+    UriTooLong,
+    /// More header lines than the parser takes
+    TooManyHeaders,
+}
+
+impl RequestError {
+    /// Complete error response sent back to the client
+    fn status_line(&self) -> &'static [u8] {
+        match self {
+            Self::Malformed | Self::MissingHost | Self::DuplicateHost => {
+                b"HTTP/1.1 400 Bad Request\r\n\r\n"
+            }
+            Self::Unsupported => b"HTTP/1.1 501 Not Implemented\r\n\r\n",
+            Self::UriTooLong => b"HTTP/1.1 414 URI Too Long\r\n\r\n",
+            Self::TooManyHeaders => b"HTTP/1.1 431 Request Header Fields Too Large\r\n\r\n",
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "Invalid request"),
+            Self::MissingHost => write!(f, "Missing host header"),
+            Self::DuplicateHost => write!(f, "Duplicate host header"),
+            Self::Unsupported => write!(f, "Unsupported transfer encoding"),
+            Self::UriTooLong => write!(f, "Request line too long"),
+            Self::TooManyHeaders => write!(f, "Too many request headers"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// A request that passed [`validate_request`]
+#[derive(Debug)]
+struct ValidRequest {
+    method: String,
+    host: String,
+    port: u16,
+    /// Origin-form path and query
+    path: String,
+    /// Client headers, with `Host` set to the target authority
+    headers: Vec<String>,
+    /// The target was absolute-form and must be rewritten before forwarding
+    absolute_form: bool,
+}
+
+/// Parse and validate HTTP request
+///
+/// The request line is checked against `max_request_line` before parsing,
+/// so an oversized target is reported as such rather than as malformed.
+/// Absolute-form targets decide the origin, see [`normalize_target`].
+fn validate_request(buffer: &[u8], max_request_line: usize) -> Result<ValidRequest, RequestError> {
+    let line_len = buffer
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .unwrap_or(buffer.len());
+    if line_len > max_request_line {
+        return Err(RequestError::UriTooLong);
+    }
+    let (method, target, mut headers) = parse_request(buffer).map_err(|e| match e {
+        ParseError::TooManyHeaders => RequestError::TooManyHeaders,
+        ParseError::Incomplete | ParseError::Malformed => RequestError::Malformed,
+    })?;
+    let is_host = |h: &String| {
+        h.split_once(':')
+            .is_some_and(|(name, _)| name.eq_ignore_ascii_case("host"))
+    };
+    if headers.iter().filter(|h| is_host(h)).count() > 1 {
+        return Err(RequestError::DuplicateHost);
+    }
+    let (host, port, path) =
+        normalize_target(&target, &headers).ok_or_else(|| {
+            match extract_host(&headers).filter(|(host, _)| !host.is_empty()) {
+                Some(_) => RequestError::Malformed,
+                None => RequestError::MissingHost,
+            }
+        })?;
+    if header_value(&headers, "transfer-encoding").is_some_and(|te| !is_chunked(te)) {
+        return Err(RequestError::Unsupported);
+    }
+
+    let absolute_form = path != target;
+    if absolute_form {
+        headers.retain(|h| !is_host(h));
+        headers.push(format!("Host: {}", authority(&host, port)));
+    }
+    Ok(ValidRequest {
+        method,
+        host,
+        port,
+        path,
+        headers,
+        absolute_form,
+    })
+}
+
+/// `host[:port]` as written in a `Host` header, omitting the default port
+///
+/// IPv6 literals get back the brackets [`extract_host`] took off.
+fn authority(host: &str, port: u16) -> String {
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    if port == 80 {
+        host
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Rewrite an absolute-form request to origin-form for the upstream server
+///
+/// The request line gets `path` as its target and the client's `Host`
+/// headers are replaced by `host`. Any body is passed through as is.
+fn origin_form_request(buffer: &[u8], path: &str, host: &str) -> Vec<u8> {
+    let Some(head_end) = find_headers_end(buffer) else {
+        return buffer.to_vec();
+    };
+    let mut out = Vec::with_capacity(buffer.len());
+    let mut lines = buffer[..head_end - 4].split(|&b| b == b'\n');
+    let request_line = lines.next().unwrap_or_default();
+    let request_line = request_line.strip_suffix(b"\r").unwrap_or(request_line);
+    let mut parts = request_line.splitn(3, |&b| b == b' ');
+    out.extend_from_slice(parts.next().unwrap_or_default());
+    out.push(b' ');
+    out.extend_from_slice(path.as_bytes());
+    out.push(b' ');
+    parts.next();
+    out.extend_from_slice(parts.next().unwrap_or(b"HTTP/1.1"));
+    out.extend_from_slice(format!("\r\nHost: {}\r\n", host).as_bytes());
+    for line in lines {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let name = line.split(|&b| b == b':').next().unwrap_or_default();
+        if name.eq_ignore_ascii_case(b"host") {
+            continue;
+        }
+        out.extend_from_slice(line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(&buffer[head_end..]);
+    out
+}
+
+/// Result of forwarding a request in streaming mode
+enum Forwarded {
+    /// The response was relayed straight to the client, with its status
+    /// and the body bytes relayed
+    Streamed(u16, usize),
+    /// The response may be cacheable and was read in full
+    Buffered(BytesMut),
+    /// The response was relayed straight to the client, with its status,
+    /// the body bytes relayed and a complete copy of it if it may be cached
+    Teed(u16, usize, Option<BytesMut>),
+}
+
+/// Forward a request, streaming the response to the client unless it may be cached
+///
+/// Only the response head is buffered before deciding, and responses
+/// declaring or reaching more than [`MAX_RESPONSE_SIZE`] are streamed as
+/// well. The head is read within `budget`. With `forced` set, responses a force-cache rule
+/// may store are buffered despite carrying `no-store` or `Set-Cookie`.
+/// Streamed heads get the headers in `reply`.
+async fn forward_streaming<C: AsyncRead + AsyncWrite + Unpin>(
+    upstream: &mut TcpStream,
+    client: &mut C,
+    request: &[u8],
+    method: &str,
+    budget: ReadBudget,
+    forced: bool,
+    reply: ReplyHeaders<'_>,
+) -> Result<Forwarded, &'static str> {
+    let mut response = send_and_read_head(upstream, request, budget).await?;
+    let mut cache_candidate = may_cache(&response, method, forced);
+    if cache_candidate {
+        loop {
+            match timeout(CONNECTION_TIMEOUT, upstream.read_buf(&mut response)).await {
+                Ok(Ok(0)) => break,
+                // Too large to cache after all, relay it like any other
+                Ok(Ok(_)) if response.len() > MAX_RESPONSE_SIZE => {
+                    cache_candidate = false;
+                    break;
+                }
+                Ok(Ok(_)) => {}
+                _ => break,
+            }
+        }
+        if cache_candidate {
+            return Ok(Forwarded::Buffered(response));
+        }
+    }
+
+    let (status, relayed) = relay_response(upstream, client, &response, reply).await;
+    Ok(Forwarded::Streamed(status, relayed))
+}
+
+/// Forward a request, relaying the response as it arrives and keeping a
+/// copy of it for the cache
+///
+/// Responses that may be cached are copied while relayed, the copy ending
+/// at the response's framing boundary or at EOF. It is dropped as soon as
+/// it would outgrow [`MAX_RESPONSE_SIZE`], or when the transfer fails, and
+/// the relay carries on without it. Other responses are relayed as
+/// [`forward_streaming`] does. The head is read within `budget` and gets
+/// the headers in `reply`.
+async fn forward_tee<C: AsyncRead + AsyncWrite + Unpin>(
+    upstream: &mut TcpStream,
+    client: &mut C,
+    request: &[u8],
+    method: &str,
+    budget: ReadBudget,
+    forced: bool,
+    reply: ReplyHeaders<'_>,
+) -> Result<Forwarded, &'static str> {
+    let response = send_and_read_head(upstream, request, budget).await?;
+    if !may_cache(&response, method, forced) {
+        let (status, relayed) = relay_response(upstream, client, &response, reply).await;
+        return Ok(Forwarded::Streamed(status, relayed));
+    }
+
+    let status = response_status(&response).unwrap_or(502);
+    let framing = response_framing(&response, method);
+    let mut relayed = response.len() - find_headers_end(&response).unwrap_or(response.len());
+    if let Err(e) = write_forwarded(client, &response, reply).await {
+        debug!("Failed to send response to client: {}", e);
+        return Ok(Forwarded::Streamed(status, 0));
+    }
+    let mut copy = Some(response);
+    let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+    loop {
+        if let (Some(buffer), Some(framing)) = (&mut copy, framing) {
+            if let Some(end) = framed_end(buffer, framing) {
+                buffer.truncate(end);
+                break;
+            }
+        }
+        let n = match timeout(CONNECTION_TIMEOUT, upstream.read(&mut chunk)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => n,
+            Ok(Err(_)) | Err(_) => {
+                copy = None;
+                break;
+            }
+        };
+        if let Err(e) = client.write_all(&chunk[..n]).await {
+            debug!("Failed to stream response to client: {}", e);
+            copy = None;
+            break;
+        }
+        relayed += n;
+        if copy
+            .as_ref()
+            .is_some_and(|buffer| buffer.len() + n > MAX_RESPONSE_SIZE)
+        {
+            debug!("Response outgrew the cache size limit, relaying it without a copy");
+            copy = None;
+        }
+        if let Some(buffer) = &mut copy {
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+    Ok(Forwarded::Teed(status, relayed, copy))
+}
+
+/// Send `request` upstream and read until the response head is complete
+///
+/// The head is read within `budget`, bytes of the body read along with it
+/// are kept.
+async fn send_and_read_head(
+    upstream: &mut TcpStream,
+    request: &[u8],
+    budget: ReadBudget,
+) -> Result<BytesMut, &'static str> {
+    upstream
+        .write_all(request)
+        .await
+        .map_err(|_| "Failed to forward request")?;
+
+    let mut response = BytesMut::with_capacity(8192);
+    let mut started = None;
+    while find_headers_end(&response).is_none() && response.len() <= MAX_REQUEST_SIZE {
+        budget.check(started, response.len(), false)?;
+        match timeout(
+            budget.next_read(started, false),
+            upstream.read_buf(&mut response),
+        )
+        .await
+        {
+            Ok(Ok(0)) => break,
+            Ok(Ok(_)) => {
+                started.get_or_insert_with(Instant::now);
+                skip_interim_responses(&mut response);
+            }
+            Err(_) if response.is_empty() => return Err(FIRST_BYTE_TIMED_OUT),
+            // The check above reports the head deadline
+            Err(_) => {}
+            _ => return Err("Failed to read response head"),
+        }
+    }
+    if response.is_empty() {
+        return Err("Upstream closed without responding");
+    }
+    Ok(response)
+}
+
+/// Whether a response, from its head, is worth keeping for the cache
+///
+/// With `forced` set, responses a force-cache rule may store count despite
+/// carrying `no-store` or `Set-Cookie`.
+fn may_cache(response: &[u8], method: &str, forced: bool) -> bool {
+    method == "GET"
+        && !declared_too_large(response, method)
+        && find_headers_end(response)
+            .is_some_and(|end| forced || uncacheable_marker(&response[..end]).is_none())
+}
+
+/// Send the `response` read so far to the client, then stream the rest of
+/// it from `upstream` until upstream closes
+///
+/// Passthrough bodies go through a fixed-size buffer, so a slow client
+/// blocks the upstream read rather than growing memory. Returns the status
+/// and the body bytes relayed.
+async fn relay_response<C: AsyncRead + AsyncWrite + Unpin>(
+    upstream: &mut TcpStream,
+    client: &mut C,
+    response: &[u8],
+    reply: ReplyHeaders<'_>,
+) -> (u16, usize) {
+    let status = response_status(response).unwrap_or(502);
+    let mut relayed = response.len() - find_headers_end(response).unwrap_or(response.len());
+    if let Err(e) = write_forwarded(client, response, reply).await {
+        debug!("Failed to send response to client: {}", e);
+        return (status, 0);
+    }
+    let mut chunk = vec![0u8; STREAM_CHUNK_SIZE];
+    loop {
+        let n = match timeout(CONNECTION_TIMEOUT, upstream.read(&mut chunk)).await {
+            Ok(Ok(0)) | Ok(Err(_)) | Err(_) => break,
+            Ok(Ok(n)) => n,
+        };
+        // Waiting here is the backpressure, upstream isn't read until the client catches up
+        if let Err(e) = client.write_all(&chunk[..n]).await {
+            debug!("Failed to stream response to client: {}", e);
+            break;
+        }
+        relayed += n;
+    }
+    (status, relayed)
+}
+
+/// Whether a response head declares a body too large to ever be cached
+fn declared_too_large(response: &[u8], method: &str) -> bool {
+    matches!(
+        response_framing(response, method),
+        Some((head_len, BodyFraming::Length(length), _))
+            if head_len.saturating_add(length) > MAX_RESPONSE_SIZE
+    )
+}
+
+/// Copy of a request without the hop-by-hop headers meant for this proxy
+///
+/// Headers named by the client's `Connection` header go too. The body is
+/// relayed as is, so `Transfer-Encoding` is kept to frame it.
+fn without_hop_by_hop(buffer: &[u8]) -> Vec<u8> {
+    let Some(head_end) = find_headers_end(buffer) else {
+        return buffer.to_vec();
+    };
+    let lines: Vec<&[u8]> = buffer[..head_end - 4]
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .collect();
+    let Some((request_line, header_lines)) = lines.split_first() else {
+        return buffer.to_vec();
+    };
+    let headers: Vec<String> = header_lines
+        .iter()
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect();
+    // Kept headers come back in order, so match them up with the raw lines
+    // and copy those, leaving any non-UTF-8 bytes untouched
+    let mut kept = strip_hop_by_hop_except(&headers, &["transfer-encoding"]).into_iter();
+    let mut next_kept = kept.next();
+
+    let mut out = Vec::with_capacity(buffer.len());
+    out.extend_from_slice(request_line);
+    out.extend_from_slice(b"\r\n");
+    for (line, header) in header_lines.iter().zip(&headers) {
+        if next_kept.as_ref() == Some(header) {
+            out.extend_from_slice(line);
+            out.extend_from_slice(b"\r\n");
+            next_kept = kept.next();
+        }
+    }
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(&buffer[head_end..]);
+    out
+}
+
+/// Target host and port of a `CONNECT` request, None for other methods
+fn connect_target(buffer: &[u8]) -> Option<(String, u16)> {
+    let (method, authority, _) = parse_request(buffer).ok()?;
+    if method != "CONNECT" {
+        return None;
+    }
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None => Some((authority, 443)),
+    }
+}
+
+/// Open a raw tunnel to `host:port` and pipe bytes both ways until either side closes
+///
+/// Returns the status sent to the client and the bytes relayed to it.
+async fn tunnel_connect<C: AsyncRead + AsyncWrite + Unpin>(
+    mut client: C,
+    buffer: &[u8],
+    host: &str,
+    port: u16,
+) -> (u16, usize) {
+    let mut upstream = match timeout(CONNECTION_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(stream)) => stream,
+        _ => {
+            debug!("CONNECT to {}:{} failed", host, port);
+            send_error_response(&mut client, b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+            return (502, 0);
+        }
+    };
+
+    if client
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await
+        .is_err()
+    {
+        return (200, 0);
+    }
+
+    // Clients may send the start of the TLS handshake along with the request
+    let headers_end = find_headers_end(buffer).unwrap_or(buffer.len());
+    if upstream.write_all(&buffer[headers_end..]).await.is_err() {
+        return (200, 0);
+    }
+
+    info!("TUNNEL: {}:{}", host, port);
+    match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+        Ok((sent, received)) => {
+            debug!(
+                "Tunnel to {}:{} closed ({} bytes sent, {} received)",
+                host, port, sent, received
+            );
+            (200, received as usize)
+        }
+        Err(e) => {
+            debug!("Tunnel to {}:{} failed: {}", host, port, e);
+            (200, 0)
+        }
+    }
+}
+
+/// Serve response from cache
+///
+/// Gzip bodies are re-encoded for clients whose `Accept-Encoding` prefers
+/// another coding, see [`entry_for_client`], and a single `Range` is
+/// answered from the body, see [`serve_range`].
+/// Headers set in `reply` replace any stored ones, `X-Cache` comes with
+/// the entry's age in `X-Cache-Age`. A stored `Content-Length` that
+/// disagrees with the body is replaced as `reply.length_mismatch` says.
+/// With `head_only` the body is left out, as a `HEAD` request asks, while
+/// `Content-Length` still gives the full body size. Returns the status sent
+/// and the body bytes written.
+/// Write failures are counted in the cache stats, a client that goes away
+/// mid-response is left with a truncated one.
+async fn serve_cached_response<W: AsyncWrite + Unpin, B: Cache>(
+    client: &mut W,
+    cached: Arc<CachedResponse>,
+    request_headers: &[String],
+    cache: &B,
+    reply: ReplyHeaders<'_>,
+    head_only: bool,
+) -> Result<(u16, usize), &'static str> {
+    let cached = entry_for_client(cached, request_headers).await;
+    if let Some(range) = byte_range(&cached, request_headers) {
+        return serve_range(client, &cached, range, cache, reply, head_only).await;
+    }
+    let failed = |what: &'static str| {
+        move |e: std::io::Error| {
+            cache.record_client_write_error(&e);
+            what
+        }
+    };
+    let chunked = match length_mismatched(&cached).then_some(reply.length_mismatch) {
+        Some(mode) => {
+            warn!(
+                "Cached Content-Length doesn't match the {} byte body, reframing",
+                cached.body.len()
+            );
+            Some(mode == LengthMismatch::Chunked)
+        }
+        None => None,
+    };
+    client
+        .write_all(cached.status_line.as_bytes())
+        .await
+        .map_err(failed("Failed to write status"))?;
+
+    for header in &cached.headers {
+        if reply.replaces(header.as_bytes())
+            || is_named(header, "age")
+            || (chunked.is_some() && is_named(header, "content-length"))
+        {
+            continue;
+        }
+        client
+            .write_all(header.as_bytes())
+            .await
+            .map_err(failed("Failed to write header"))?;
+        client
+            .write_all(b"\r\n")
+            .await
+            .map_err(failed("Failed to write CRLF"))?;
+    }
+    client
+        .write_all(format!("Age: {}\r\n", current_age(&cached)).as_bytes())
+        .await
+        .map_err(failed("Failed to write header"))?;
+    if !reply.is_empty() {
+        client
+            .write_all(reply.render(Some(cached_age(&cached))).as_bytes())
+            .await
+            .map_err(failed("Failed to write header"))?;
+    }
+    if advertises_ranges(&cached) {
+        client
+            .write_all(b"Accept-Ranges: bytes\r\n")
+            .await
+            .map_err(failed("Failed to write header"))?;
+    }
+    if let Some(chunked) = chunked {
+        let framing = if chunked {
+            "Transfer-Encoding: chunked\r\n".to_string()
+        } else {
+            format!("Content-Length: {}\r\n", cached.body.len())
+        };
+        client
+            .write_all(framing.as_bytes())
+            .await
+            .map_err(failed("Failed to write header"))?;
+    }
+
+    client
+        .write_all(b"\r\n")
+        .await
+        .map_err(failed("Failed to write final CRLF"))?;
+    let status = response_status(cached.status_line.as_bytes()).unwrap_or(200);
+    if head_only {
+        return Ok((status, 0));
+    }
+    if chunked == Some(true) && !cached.body.is_empty() {
+        client
+            .write_all(format!("{:x}\r\n", cached.body.len()).as_bytes())
+            .await
+            .map_err(failed("Failed to write chunk size"))?;
+    }
+    client
+        .write_all(&cached.body)
+        .await
+        .map_err(failed("Failed to write body"))?;
+    if chunked == Some(true) {
+        let end: &[u8] = if cached.body.is_empty() {
+            b"0\r\n\r\n"
+        } else {
+            b"\r\n0\r\n\r\n"
+        };
+        client
+            .write_all(end)
+            .await
+            .map_err(failed("Failed to write last chunk"))?;
+    }
+
+    Ok((status, cached.body.len()))
+}
+
+/// Byte range of a cached body requested with `Range`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ByteRange {
+    /// First and last byte offsets, inclusive
+    Satisfiable(usize, usize),
+    /// The range starts past the end of the body
+    Unsatisfiable,
+}
+
+/// The single byte range a request asks for, None to serve the full body
+///
+/// Multiple ranges, unparseable ones, an `If-Range` that doesn't match the
+/// entry, and entries that aren't complete `200` bodies all get the full
+/// body, as a server that ignores `Range` would send.
+fn byte_range(cached: &CachedResponse, request_headers: &[String]) -> Option<ByteRange> {
+    let spec = header_value(request_headers, "range")?;
+    let refuses_ranges = header_value(&cached.headers, "accept-ranges")
+        .is_some_and(|value| value.trim().eq_ignore_ascii_case("none"));
+    if cached.status_line.split_whitespace().nth(1) != Some("200") || refuses_ranges {
+        return None;
+    }
+    if let Some(validator) = header_value(request_headers, "if-range") {
+        let validator = validator.trim();
+        let matches = ["etag", "last-modified"]
+            .iter()
+            .any(|name| header_value(&cached.headers, name).is_some_and(|v| v.trim() == validator));
+        // Weak validators can't vouch for byte-identical bodies
+        if !matches || validator.starts_with("W/") {
+            return None;
+        }
+    }
+
+    let (unit, set) = spec.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") || set.contains(',') {
+        return None;
+    }
+    let (first, last) = set.trim().split_once('-')?;
+    let len = cached.body.len();
+    let range = match (first.trim(), last.trim()) {
+        // Suffix range, the last `suffix` bytes
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            if suffix == 0 || len == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Satisfiable(len - suffix.min(len), len - 1)
+            }
+        }
+        (first, last) => {
+            let start: usize = first.parse().ok()?;
+            let end: usize = if last.is_empty() {
+                usize::MAX
+            } else {
+                last.parse().ok()?
+            };
+            if end < start {
+                return None;
+            }
+            if start >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Satisfiable(start, end.min(len - 1))
+            }
+        }
+    };
+    Some(range)
+}
+
+/// Answer a `Range` request from a cached body
+///
+/// Sends a `206` with the slice and its `Content-Range`, or a `416` naming
+/// the full length when the range starts past the end. `head_only` sends
+/// the head alone.
+async fn serve_range<W: AsyncWrite + Unpin, B: Cache>(
+    client: &mut W,
+    cached: &CachedResponse,
+    range: ByteRange,
+    cache: &B,
+    reply: ReplyHeaders<'_>,
+    head_only: bool,
+) -> Result<(u16, usize), &'static str> {
+    let total = cached.body.len();
+    let mut head = String::with_capacity(512);
+    let (status, body) = match range {
+        ByteRange::Satisfiable(start, end) => {
+            head.push_str("HTTP/1.1 206 Partial Content\r\n");
+            for header in &cached.headers {
+                if is_named(header, "content-length")
+                    || is_named(header, "age")
+                    || reply.replaces(header.as_bytes())
+                {
+                    continue;
+                }
+                head.push_str(header);
+                head.push_str("\r\n");
+            }
+            head.push_str(&format!(
+                "Age: {}\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n",
+                current_age(cached),
+                start,
+                end,
+                total,
+                end - start + 1
+            ));
+            (206, &cached.body[start..=end])
+        }
+        ByteRange::Unsatisfiable => {
+            head.push_str(&format!(
+                "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\n",
+                total
+            ));
+            (416, &[][..])
+        }
+    };
+    head.push_str(&reply.render(Some(cached_age(cached))));
+    head.push_str("\r\n");
+
+    let failed = |what: &'static str| {
+        move |e: std::io::Error| {
+            cache.record_client_write_error(&e);
+            what
+        }
+    };
+    client
+        .write_all(head.as_bytes())
+        .await
+        .map_err(failed("Failed to write header"))?;
+    if head_only {
+        return Ok((status, 0));
+    }
+    client
+        .write_all(body)
+        .await
+        .map_err(failed("Failed to write body"))?;
+    Ok((status, body.len()))
+}
+
+/// Response read by [`forward_to_upstream`]
+struct Fetched {
+    response: BytesMut,
+    /// The response ended at its framing boundary and upstream didn't ask
+    /// to close, so the connection can go back to the pool
+    reusable: bool,
+    /// The response ended at its framing boundary rather than at EOF, so the
+    /// client can tell where it ends without the connection closing
+    framed: bool,
+    /// The response is too large to cache and was cut short, `response`
+    /// holds its start and the rest is still to be read from upstream
+    oversized: bool,
+}
+
+impl Fetched {
+    fn oversized(response: BytesMut) -> Self {
+        Fetched {
+            response,
+            reusable: false,
+            framed: false,
+            oversized: true,
+        }
+    }
+}
+
+/// How the end of a response body is found
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BodyFraming {
+    Empty,
+    Length(usize),
+    Chunked,
+    UntilClose,
+}
+
+/// Parse a complete response head, returning its length, body framing and
+/// whether upstream allows the connection to be kept alive
+///
+/// None while the head is still incomplete. Heads httparse can't parse
+/// (e.g. too many headers) are read until close.
+fn response_framing(response: &[u8], method: &str) -> Option<(usize, BodyFraming, bool)> {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let head_len = match parsed.parse(response) {
+        Ok(httparse::Status::Complete(len)) => len,
+        Ok(httparse::Status::Partial) => return None,
+        Err(_) => {
+            let head_len = find_headers_end(response)?;
+            return Some((head_len, BodyFraming::UntilClose, false));
+        }
+    };
+
+    let value = |name: &str| {
+        parsed
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    };
+    let connection_has = |token: &str| {
+        value("connection")
+            .is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    let keep_alive = match parsed.version {
+        Some(1) => !connection_has("close"),
+        _ => connection_has("keep-alive"),
+    };
+
+    let code = parsed.code.unwrap_or(0);
+    let framing = if method == "HEAD" || code == 204 || code == 304 {
+        BodyFraming::Empty
+    } else if (100..200).contains(&code) {
+        // 101 and any interim response not yet skipped are read until close
+        BodyFraming::UntilClose
+    } else if let Some(te) = value("transfer-encoding") {
+        if is_chunked(te) {
+            BodyFraming::Chunked
+        } else {
+            BodyFraming::UntilClose
+        }
+    } else if let Some(length) = value("content-length") {
+        match length.trim().parse() {
+            Ok(length) => BodyFraming::Length(length),
+            Err(_) => BodyFraming::UntilClose,
+        }
+    } else {
+        BodyFraming::UntilClose
+    };
+    let keep_alive = keep_alive && framing != BodyFraming::UntilClose;
+    Some((head_len, framing, keep_alive))
+}
+
+/// Drop interim `1xx` responses from the front of `response`, up to the
+/// final one
+///
+/// `101 Switching Protocols` ends the exchange, so it is kept.
+fn skip_interim_responses(response: &mut BytesMut) {
+    while let (Some(status), Some(end)) = (response_status(response), find_headers_end(response)) {
+        if !(100..200).contains(&status) || status == 101 {
+            break;
+        }
+        debug!("Skipping interim {} response", status);
+        drop(response.split_to(end));
+    }
+}
+
+/// Where a response with the given head length and framing ends, None
+/// while its body is still incomplete or runs until close
+fn framed_end(response: &[u8], framing: (usize, BodyFraming, bool)) -> Option<usize> {
+    let (head_len, body, _) = framing;
+    match body {
+        BodyFraming::Empty => Some(head_len),
+        BodyFraming::Length(length) => head_len
+            .checked_add(length)
+            .filter(|&end| response.len() >= end),
+        BodyFraming::Chunked => {
+            chunked_length(&response[head_len..]).map(|length| head_len + length)
+        }
+        BodyFraming::UntilClose => None,
+    }
+}
+
+/// Forward request to upstream and read its response
+///
+/// Reading stops at the end of a `Content-Length` or chunked body rather
+/// than at EOF, so keep-alive connections can be pooled afterwards. An
+/// upstream that sends nothing within the `budget`'s first-byte wait fails
+/// with [`FIRST_BYTE_TIMED_OUT`], one that breaks its head deadline or
+/// minimum rate with [`HEAD_TIMED_OUT`] or [`TRANSFER_TOO_SLOW`].
+async fn forward_to_upstream(
+    upstream: &mut TcpStream,
+    request: &[u8],
+    method: &str,
+    budget: ReadBudget,
+) -> Result<Fetched, &'static str> {
+    let (mut upstream_read, mut upstream_write) = upstream.split();
+
+    // Send request
+    upstream_write
+        .write_all(request)
+        .await
+        .map_err(|_| "Failed to forward request")?;
+
+    // Read response
+    let mut response_buffer = BytesMut::with_capacity(8192);
+    let mut framing = None;
+    let mut started = None;
+
+    loop {
+        let head_done = framing.is_some();
+        budget.check(started, response_buffer.len(), head_done)?;
+        let deadline = budget.next_read(started, head_done);
+        match timeout(deadline, upstream_read.read_buf(&mut response_buffer)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(_)) => {
+                started.get_or_insert_with(Instant::now);
+                if response_buffer.len() > MAX_RESPONSE_SIZE {
+                    return Ok(Fetched::oversized(response_buffer));
+                }
+            }
+            Err(_) if response_buffer.is_empty() => return Err(FIRST_BYTE_TIMED_OUT),
+            // The check above reports the head deadline
+            Err(_) if !head_done => continue,
+            _ => break,
+        }
+
+        if framing.is_none() {
+            skip_interim_responses(&mut response_buffer);
+            framing = response_framing(&response_buffer, method);
+            // No point reading a body in full that can't be cached
+            if declared_too_large(&response_buffer, method) {
+                return Ok(Fetched::oversized(response_buffer));
+            }
+        }
+        let Some(found) = framing else {
+            continue;
+        };
+        if let Some(end) = framed_end(&response_buffer, found) {
+            let keep_alive = found.2;
+            // Bytes past the boundary mean upstream is out of step with us
+            let reusable = keep_alive && response_buffer.len() == end;
+            response_buffer.truncate(end);
+            return Ok(Fetched {
+                response: response_buffer,
+                reusable,
+                framed: true,
+                oversized: false,
+            });
+        }
+    }
+
+    // Nothing has reached the client yet, so this can still become a 502
+    if response_buffer.is_empty() {
+        return Err("Upstream closed without responding");
+    }
+    Ok(Fetched {
+        response: response_buffer,
+        reusable: false,
+        framed: false,
+        oversized: false,
+    })
+}
+
+/// Parse response headers for caching decision
+fn parse_response_for_cache<B: Cache>(
+    response: &[u8],
+    method: &str,
+    host: &str,
+    path: &str,
+    cache: &B,
+) -> Option<CachedResponse> {
+    // Cheap checks first, most passthrough traffic is never cached
+    if method != "GET" {
+        return None;
+    }
+    let headers_end = find_headers_end(response)?;
+    // A slice of the body must not be replayed as the whole of it
+    if response_status(response) == Some(206) {
+        debug!("Not caching {}{}: partial content", host, path);
+        return None;
+    }
+    if let Some(marker) = uncacheable_marker(&response[..headers_end]) {
+        debug!("Not caching {}{}: {}", host, path, marker);
+        return None;
+    }
+
+    let (status_line, headers, body) = split_response(response)?;
+
+    // A bare CR or LF inside a line would split the response when replayed
+    if !is_safe_header_line(&status_line) || !headers.iter().all(|h| is_safe_header_line(h)) {
+        warn!(
+            "Not caching {}{}: bare CR/LF in response headers",
+            host, path
+        );
+        return None;
+    }
+    let status_line = format!("{}\r\n", status_line);
+
+    // Overloaded origins are cached for as long as they ask clients to wait
+    let retry_after =
+        response_status(response).and_then(|status| retry_after_ttl(status, &headers));
+    let cacheable = match retry_after {
+        Some(_) => !CacheControl::parse(&headers).private,
+        None => is_cacheable(method, path, &headers),
+    };
+    if !cacheable {
+        return None;
+    }
+
+    if cache.query_policy().is_private(path) {
+        debug!("Not caching {}{}: private query parameter", host, path);
+        return None;
+    }
+
+    let vary = parse_vary(&headers);
+    if !cache.vary_policy().permits(&vary) {
+        debug!(
+            "Not caching {}{}: Vary {:?} not permitted",
+            host, path, vary
+        );
+        return None;
+    }
+
+    let Some(body) = within_content_length(&headers, body) else {
+        warn!(
+            "Not caching {}{}: body is {} bytes, Content-Length says more",
+            host,
+            path,
+            body.len()
+        );
+        return None;
+    };
+    let (headers, body) = match dechunk(headers, body, cache.config().gzip_transfer_coding) {
+        Some(dechunked) => dechunked,
+        None => {
+            debug!("Not caching {}{}: unusable chunked body", host, path);
+            return None;
+        }
+    };
+    // Connection headers describe the upstream hop, not the replayed response
+    let headers = strip_hop_by_hop(&headers);
+    let (headers, body) = match cache.html_injection() {
+        Some(snippet) => inject_after_head(headers, body, snippet),
+        None => (headers, body),
+    };
+    let (headers, body) = if cache.config().compress {
+        compress_response(headers, body, path)
+    } else {
+        (headers, body)
+    };
+
+    // Calculate TTL, responses stale on arrival are stored to be revalidated
+    let cache_control = CacheControl::parse(&headers);
+    let ttl = match retry_after {
+        Some(ttl) => ttl.min(cache.config().max_ttl),
+        None => cache.ttl_for(&headers, host, path),
+    };
+    let always_revalidate = cache_control.no_cache || ttl == 0;
+    let etag = header_value(&headers, "etag").map(str::to_string);
+    let last_modified = header_value(&headers, "last-modified").map(str::to_string);
+    if always_revalidate && etag.is_none() && last_modified.is_none() {
+        debug!(
+            "Not caching {}{}: stale on arrival without validators",
+            host, path
+        );
+        return None;
+    }
+    // Hashed from the body as stored, after any transformation above
+    let etag_synthesized = etag.is_none() && cache.config().synthesize_etags;
+    let (headers, etag) = if etag_synthesized {
+        let etag = body_etag(&body);
+        let mut headers = headers;
+        headers.push(format!("ETag: {}", etag));
+        (headers, Some(etag))
+    } else {
+        (headers, etag)
+    };
+    let stored_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    Some(CachedResponse {
+        status_line,
+        body,
+        expires: stored_at + retention(ttl, always_revalidate, cache),
+        stored_at,
+        // no-cache forbids any use without a successful revalidation
+        must_revalidate: cache_control.forbids_stale() || cache_control.no_cache,
+        always_revalidate,
+        pinned: false,
+        vary,
+        etag,
+        etag_synthesized,
+        last_modified,
+        headers,
+    })
+}
+
+/// Copy of a response with its freshness headers replaced by the rule's TTL
+///
+/// `Cache-Control`, `Expires` and `Pragma` give way to `Cache-Control:
+/// max-age=<ttl>`. Returns None, leaving the response to the normal rules,
+/// when it sets cookies and the rule doesn't strip them.
+fn force_cached_response(response: &[u8], rule: &ForceCacheRule) -> Option<Vec<u8>> {
+    let headers_end = find_headers_end(response)?;
+    let mut lines = response[..headers_end - 4]
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+    let status_line = lines.next()?;
+
+    let named = |line: &[u8], names: &[&str]| {
+        let name = line.split(|&b| b == b':').next().unwrap_or_default();
+        let name = String::from_utf8_lossy(name);
+        names.iter().any(|n| name.trim().eq_ignore_ascii_case(n))
+    };
+    let mut forced = Vec::with_capacity(response.len() + 32);
+    forced.extend_from_slice(status_line);
+    forced.extend_from_slice(b"\r\n");
+    for line in lines {
+        if named(line, &["set-cookie"]) {
+            if rule.set_cookie == SetCookiePolicy::Refuse {
+                return None;
+            }
+            continue;
+        }
+        if named(line, &["cache-control", "expires", "pragma"]) {
+            continue;
+        }
+        forced.extend_from_slice(line);
+        forced.extend_from_slice(b"\r\n");
+    }
+    forced.extend_from_slice(format!("Cache-Control: max-age={}\r\n\r\n", rule.ttl).as_bytes());
+    forced.extend_from_slice(&response[headers_end..]);
+    Some(forced)
+}
+
+/// How long to keep an entry with `ttl`
+///
+/// Entries revalidated on every use are kept for at least the default TTL,
+/// so a zero `max-age` doesn't leave them for the janitor straight away.
+fn retention<B: Cache>(ttl: u64, always_revalidate: bool, cache: &B) -> u64 {
+    if always_revalidate {
+        ttl.max(cache.config().default_ttl)
+    } else {
+        ttl
+    }
+}
+
+/// `body` cut to the response's `Content-Length`, so bytes read past the
+/// end of the response aren't stored as part of it
+///
+/// None if the body is shorter than declared, the connection closed before
+/// the response was complete. Chunked bodies are framed by their chunks and
+/// returned as they are.
+fn within_content_length<'a>(headers: &[String], body: &'a [u8]) -> Option<&'a [u8]> {
+    if header_value(headers, "transfer-encoding").is_some() {
+        return Some(body);
+    }
+    match header_value(headers, "content-length").and_then(|value| value.parse::<usize>().ok()) {
+        Some(length) if length < body.len() => {
+            debug!(
+                "Dropping {} bytes past the {} byte Content-Length",
+                body.len() - length,
+                length
+            );
+            Some(&body[..length])
+        }
+        Some(length) if length > body.len() => None,
+        _ => Some(body),
+    }
+}
+
+/// Replace a chunked body with its decoded bytes and a matching `Content-Length`
+///
+/// Returns None if the chunked stream is incomplete or malformed, or if other
+/// transfer codings are applied that we can't replay. With
+/// `gzip_transfer_coding`, a `gzip` coding under `chunked` is kept on the
+/// body as its `Content-Encoding`.
+fn dechunk(
+    headers: Vec<String>,
+    body: &[u8],
+    gzip_transfer_coding: bool,
+) -> Option<(Vec<String>, Bytes)> {
+    let Some(transfer_encoding) = header_value(&headers, "transfer-encoding") else {
+        return Some((headers, Bytes::copy_from_slice(body)));
+    };
+    // Only a bare chunked coding can be undone. Gzip under it stays on the
+    // body and becomes its content coding, if there isn't one already
+    let codings: Vec<String> = transfer_encoding
+        .split(',')
+        .map(|coding| coding.trim().to_ascii_lowercase())
+        .collect();
+    let gzipped = match codings.as_slice() {
+        [chunked] if chunked == "chunked" => false,
+        [gzip, chunked]
+            if gzip_transfer_coding
+                && (gzip == "gzip" || gzip == "x-gzip")
+                && chunked == "chunked"
+                && header_value(&headers, "content-encoding").is_none() =>
+        {
+            true
+        }
+        _ => return None,
+    };
+
+    let decoded = decode_chunked(body)?;
+    let framing = |header: &String| {
+        header.split_once(':').is_some_and(|(name, _)| {
+            let name = name.trim();
+            name.eq_ignore_ascii_case("transfer-encoding")
+                || name.eq_ignore_ascii_case("content-length")
+        })
+    };
+    let mut headers: Vec<String> = headers.into_iter().filter(|h| !framing(h)).collect();
+    if gzipped {
+        headers = mark_gzip_encoded(headers);
+    }
+    headers.push(format!("Content-Length: {}", decoded.len()));
+    Some((headers, Bytes::from(decoded)))
+}
+
+/// Find the offset just past the `\r\n\r\n` that terminates the header block
+fn find_headers_end(data: &[u8]) -> Option<usize> {
+    data.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// Scan a raw header block for markers that always prevent caching
+///
+/// Works on the bytes directly so rejected responses skip line splitting and
+/// per-header allocation entirely.
+fn uncacheable_marker(head: &[u8]) -> Option<&'static str> {
+    let contains = |needle: &[u8]| {
+        head.windows(needle.len())
+            .any(|window| window.eq_ignore_ascii_case(needle))
+    };
+    if contains(b"no-store") {
+        Some("no-store")
+    } else if contains(b"\r\nset-cookie:") {
+        Some("Set-Cookie")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of full header parses, to verify early bail-outs
+    static FULL_PARSES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Split a raw response into its status line, non-empty header lines and body
+fn split_response(response: &[u8]) -> Option<(String, Vec<String>, &[u8])> {
+    #[cfg(test)]
+    FULL_PARSES.with(|parses| parses.set(parses.get() + 1));
+    let headers_end = find_headers_end(response)?;
+    // Split strictly on CRLF so bare CR/LF stay visible to callers
+    let head = String::from_utf8_lossy(&response[..headers_end - 4]);
+    let mut lines = head.split("\r\n").map(str::to_string);
+    let status_line = lines.next()?;
+    let headers = lines.filter(|h| !h.is_empty()).collect();
+    Some((status_line, headers, &response[headers_end..]))
+}
+
+/// Parse the status code from the start of a raw response
+fn response_status(response: &[u8]) -> Option<u16> {
+    let line_end = response.windows(2).position(|w| w == b"\r\n")?;
+    let status_line = std::str::from_utf8(&response[..line_end]).ok()?;
+    status_line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Add validators from a stale entry to the client's request
+///
+/// Returns None if the client sent its own conditional headers, since an
+/// upstream `304` would then be addressed to the client rather than to us.
+fn build_conditional_request(
+    request: &[u8],
+    headers: &[String],
+    stale: &CachedResponse,
+) -> Option<Vec<u8>> {
+    if header_value(headers, "if-none-match").is_some()
+        || header_value(headers, "if-modified-since").is_some()
+    {
+        return None;
+    }
+    let headers_end = find_headers_end(request)?;
+
+    let mut conditional = Vec::with_capacity(request.len() + 128);
+    conditional.extend_from_slice(&request[..headers_end - 2]);
+    if let Some(etag) = stale.upstream_etag() {
+        conditional.extend_from_slice(format!("If-None-Match: {}\r\n", etag).as_bytes());
+    }
+    if let Some(last_modified) = &stale.last_modified {
+        conditional
+            .extend_from_slice(format!("If-Modified-Since: {}\r\n", last_modified).as_bytes());
+    }
+    conditional.extend_from_slice(b"\r\n");
+    conditional.extend_from_slice(&request[headers_end..]);
+    Some(conditional)
+}
+
+/// New expiry for an entry confirmed by a `304 Not Modified`
+///
+/// Freshness headers on the 304 replace the stored ones when present.
+fn revalidated_expiry<B: Cache>(
+    not_modified: &[u8],
+    stale: &CachedResponse,
+    host: &str,
+    path: &str,
+    cache: &B,
+) -> u64 {
+    let updated = split_response(not_modified)
+        .map(|(_, headers, _)| headers)
+        .filter(|h| {
+            header_value(h, "cache-control").is_some() || header_value(h, "expires").is_some()
+        });
+    let freshness = updated.as_deref().unwrap_or(&stale.headers);
+    let ttl = cache.ttl_for(freshness, host, path);
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        + retention(ttl, stale.always_revalidate, cache)
+}
+
+/// Whether a stale entry may replace an upstream 5xx (RFC 5861 `stale-if-error`)
+///
+/// The window counts from when the entry went stale. Entries that forbid
+/// serving stale never qualify.
+fn stale_if_error_permits(entry: &CachedResponse, config: &ProxyConfig, now: u64) -> bool {
+    if !config.serve_stale_on_server_error || !entry.may_serve_stale() {
+        return false;
+    }
+    CacheControl::parse(&entry.headers)
+        .stale_if_error
+        .or(config.default_stale_if_error)
+        .is_some_and(|window| now <= entry.expires.saturating_add(window))
+}
+
+/// Whether to add `Accept-Ranges: bytes` when serving an entry
+///
+/// Only complete `200` bodies can back a range, and an upstream
+/// `Accept-Ranges` (including `none`) is passed through untouched.
+fn advertises_ranges(cached: &CachedResponse) -> bool {
+    let full_body = cached.status_line.split_whitespace().nth(1) == Some("200");
+    full_body && header_value(&cached.headers, "accept-ranges").is_none()
+}
+
+/// Coding a gzip entry has to be turned into for the client, None if it
+/// can be served as stored
+///
+/// Clients ranking another of [`ENCODINGS`] above gzip, see
+/// [`preferred_encoding`], get it re-encoded, those refusing gzip get it
+/// decoded to `identity`.
+fn transcoding_for_client(
+    cached: &CachedResponse,
+    request_headers: &[String],
+) -> Option<&'static str> {
+    let encoding = header_value(&cached.headers, "content-encoding")?;
+    if !encoding.eq_ignore_ascii_case("gzip") {
+        return None;
+    }
+    // Without an Accept-Encoding any coding will do
+    let accept_encoding = header_value(request_headers, "accept-encoding")?;
+    match preferred_encoding(accept_encoding, ENCODINGS) {
+        Some("gzip") => None,
+        Some(coding) => Some(coding),
+        None => Some("identity"),
+    }
+}
+
+/// Copy of a gzip entry in `coding`, None if decoding fails
+///
+/// A re-encoded copy is other bytes than the stored one, so its ETag gets
+/// a `-<coding>` suffix rather than claiming to be the same representation.
+fn transcode_entry(cached: &CachedResponse, coding: &str) -> Option<CachedResponse> {
+    let Some((headers, body)) =
+        decompress_response(&cached.headers, &cached.body, MAX_RESPONSE_SIZE)
+    else {
+        warn!("Failed to decode gzip cache entry, serving it as stored");
+        return None;
+    };
+    let Some(encoded) = encode(&body, coding) else {
+        return Some(CachedResponse {
+            headers,
+            body,
+            ..cached.clone()
+        });
+    };
+    let etag = |value: &str| match value.strip_suffix('"') {
+        Some(opaque) => format!("{}-{}\"", opaque, coding),
+        None => format!("{}-{}", value, coding),
+    };
+    let mut headers: Vec<String> = without_headers(headers, &["content-length"])
+        .into_iter()
+        .map(|header| match header.split_once(':') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("etag") => {
+                format!("{}: {}", name, etag(value.trim()))
+            }
+            _ => header,
+        })
+        .collect();
+    headers.push(format!("Content-Encoding: {}", coding));
+    headers.push(format!("Content-Length: {}", encoded.len()));
+    Some(CachedResponse {
+        headers,
+        body: encoded,
+        etag: cached.etag.as_deref().map(etag),
+        ..cached.clone()
+    })
+}
+
+/// `cached` in the coding the client takes, see [`transcoding_for_client`]
+///
+/// Transcoding runs on the blocking pool, bodies can be megabytes and
+/// requests keep being served meanwhile. The entry is served as stored if
+/// transcoding fails.
+async fn entry_for_client(
+    cached: Arc<CachedResponse>,
+    request_headers: &[String],
+) -> Arc<CachedResponse> {
+    let Some(coding) = transcoding_for_client(&cached, request_headers) else {
+        return cached;
+    };
+    let entry = Arc::clone(&cached);
+    match tokio::task::spawn_blocking(move || transcode_entry(&entry, coding)).await {
+        Ok(Some(transcoded)) => Arc::new(transcoded),
+        _ => cached,
+    }
+}
+
+/// Answer a failed upstream fetch, with the stale copy if it may be served stale
+///
+/// The connection is always closed afterwards, whatever `reply` says.
+/// Returns the status and body bytes of the stale copy when one was served.
+async fn respond_upstream_failure<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    client: &mut C,
+    stale: Option<Arc<CachedResponse>>,
+    status: &[u8],
+    request_headers: &[String],
+    url: &str,
+    cache: &B,
+    reply: ReplyHeaders<'_>,
+) -> Option<(u16, usize)> {
+    match stale {
+        Some(entry) if entry.may_serve_stale() => {
+            info!("STALE: {} (upstream unavailable)", url);
+            let status = response_status(entry.status_line.as_bytes()).unwrap_or(200);
+            let reply = ReplyHeaders {
+                connection: Some("close"),
+                ..reply
+            };
+            match serve_cached_response(client, entry, request_headers, cache, reply, false).await {
+                Ok(sent) => Some(sent),
+                Err(_) => {
+                    debug!("Failed to serve stale response");
+                    Some((status, 0))
+                }
+            }
+        }
+        _ => {
+            send_error_response(client, status).await;
+            None
+        }
+    }
+}
+
+/// Wait before retry number `attempt`, doubling from `base`
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    base.saturating_mul(1 << attempt.min(16))
+}
+
+/// How the cache answered a request, for the cache key trace
+fn lookup_decision(lookup: Option<&CacheLookup>, bypass: bool) -> &'static str {
+    match lookup {
+        Some(CacheLookup::Fresh(_)) => "hit",
+        Some(CacheLookup::Stale(_)) => "stale",
+        Some(CacheLookup::Miss) => "miss",
+        None if bypass => "bypass",
+        // Methods other than GET and HEAD never consult the cache
+        None => "pass",
+    }
+}
+
+/// Status line for a gateway failure passed on from a shared fetch
+fn gateway_error(status: u16) -> &'static [u8] {
+    match status {
+        504 => b"HTTP/1.1 504 Gateway Timeout\r\n\r\n",
+        _ => b"HTTP/1.1 502 Bad Gateway\r\n\r\n",
+    }
+}
+
+/// Tell requests waiting on a coalesced fetch how it ended
+fn finish_flight(flight: Option<FlightGuard>, outcome: FlightOutcome) {
+    if let Some(flight) = flight {
+        flight.finish(outcome);
+    }
+}
+
+/// Record what [`respond_upstream_failure`] sent
+fn record_upstream_failure(
+    record: &mut AccessRecord,
+    status: &[u8],
+    sent_stale: Option<(u16, usize)>,
+) {
+    match sent_stale {
+        Some((status, bytes)) => {
+            record.status = status;
+            record.bytes = bytes;
+            record.cache_status = CacheStatus::Hit;
+        }
+        None => record.status = response_status(status).unwrap_or(502),
+    }
+}
+
+/// Whether the client's `If-None-Match` names the entry's `ETag`, see
+/// [`etag_matches`]
+fn client_has_current(cached: &CachedResponse, request_headers: &[String]) -> bool {
+    match (
+        cached.etag.as_deref(),
+        header_value(request_headers, "if-none-match"),
+    ) {
+        (Some(etag), Some(if_none_match)) => etag_matches(if_none_match, etag),
+        _ => false,
+    }
+}
+
+/// Response head telling a client its copy of the entry is current
+///
+/// Keeps the headers a `200` would carry that describe the cached copy,
+/// with its current `Age`, and the time it has been stored when `reply`
+/// sets `X-Cache`.
+fn not_modified_head(cached: &CachedResponse, reply: ReplyHeaders<'_>) -> String {
+    const KEPT: [&str; 6] = [
+        "cache-control",
+        "content-location",
+        "date",
+        "etag",
+        "expires",
+        "vary",
+    ];
+    let mut head = String::from("HTTP/1.1 304 Not Modified\r\n");
+    for header in &cached.headers {
+        let kept = KEPT.iter().any(|kept| is_named(header, kept));
+        if kept && !reply.replaces(header.as_bytes()) {
+            head.push_str(header);
+            head.push_str("\r\n");
+        }
+    }
+    head.push_str(&format!("Age: {}\r\n", current_age(cached)));
+    head.push_str(&reply.render(Some(cached_age(cached))));
+    head.push_str("\r\n");
+    head
+}
+
+/// Serve a cache entry, returning whether the connection can carry another request
+///
+/// A client already holding the entry, by its `If-None-Match`, gets a `304`.
+/// Only entries with a `Content-Length`, or answers to `HEAD` that carry no
+/// body at all, can be followed by another response. The status and size
+/// sent go into `record`.
+async fn reply_from_cache<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    client: &mut C,
+    cached: Arc<CachedResponse>,
+    request_headers: &[String],
+    cache: &B,
+    reply: ReplyHeaders<'_>,
+    head_only: bool,
+    record: &mut AccessRecord,
+) -> bool {
+    if client_has_current(&cached, request_headers) {
+        (record.status, record.bytes) = (304, 0);
+        return match client
+            .write_all(not_modified_head(&cached, reply).as_bytes())
+            .await
+        {
+            Ok(()) => true,
+            Err(e) => {
+                cache.record_client_write_error(&e);
+                debug!("Failed to send 304 to client: {}", e);
+                false
+            }
+        };
+    }
+    let framed = head_only || header_value(&cached.headers, "content-length").is_some();
+    let reply = ReplyHeaders {
+        connection: reply.connection.map(|c| if framed { c } else { "close" }),
+        ..reply
+    };
+    record.status = response_status(cached.status_line.as_bytes()).unwrap_or(200);
+    match serve_cached_response(
+        client,
+        Arc::clone(&cached),
+        request_headers,
+        cache,
+        reply,
+        head_only,
+    )
+    .await
+    {
+        Ok((status, bytes)) => {
+            cache.record_hit_bytes(&cached, bytes);
+            record.status = status;
+            record.bytes = bytes;
+            framed
+        }
+        Err(e) => {
+            debug!("Failed to serve cached response: {}", e);
+            false
+        }
+    }
+}
+
+/// Main client handler with reduced complexity
+///
+/// Serves requests until the client closes, stops asking for keep-alive,
+/// or reaches `max_requests_per_connection`. Each request gets one access
+/// log line once its response has been sent.
+async fn handle_client<B: Cache>(
+    client: TcpStream,
+    cache: B,
+    pool: ConnectionPool,
+    config: Arc<ProxyConfig>,
+) {
+    let peer = client.peer_addr().ok().map(|addr| addr.ip());
+    serve_connection(client, peer, cache, pool, config).await;
+}
+
+/// Serve requests from any client stream, `peer` being logged as its address
+async fn serve_connection<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    mut client: C,
+    peer: Option<IpAddr>,
+    cache: B,
+    pool: ConnectionPool,
+    config: Arc<ProxyConfig>,
+) {
+    let mut pending = BytesMut::with_capacity(8192);
+    let mut served = 0;
+
+    loop {
+        // Step 1: Read request
+        let budget = ReadBudget::new(&config, CONNECTION_TIMEOUT);
+        let buffer = match read_next_request(&mut client, &mut pending, budget).await {
+            Ok(Some(buf)) => buf,
+            Ok(None) => return,
+            Err(e) => {
+                // Idle keep-alive connections timing out is routine
+                if served == 0 {
+                    warn!("Failed to read request: {}", e);
+                } else {
+                    debug!("Failed to read request: {}", e);
+                }
+                if e == "Request too large" {
+                    send_error_response(
+                        &mut client,
+                        b"HTTP/1.1 413 Request Entity Too Large\r\n\r\n",
+                    )
+                    .await;
+                } else if e == HEAD_TIMED_OUT || e == TRANSFER_TOO_SLOW {
+                    send_error_response(&mut client, b"HTTP/1.1 408 Request Timeout\r\n\r\n").await;
+                }
+                return;
+            }
+        };
+
+        let parsed = parse_request(&buffer).ok();
+        let mut record = match &parsed {
+            Some((method, target, _)) => AccessRecord::new(peer, SystemTime::now(), method, target),
+            None => AccessRecord::new(peer, SystemTime::now(), "-", "-"),
+        };
+
+        // Clients over their rate are refused before anything else
+        if let (Some(limiter), Some(ip)) = (&config.rate_limiter, peer) {
+            if !limiter.check(ip) {
+                debug!("Rate limit exceeded by {}", ip);
+                send_error_response(&mut client, &too_many_requests(limiter.retry_after())).await;
+                record.status = 429;
+                log_access(&config, &record);
+                return;
+            }
+        }
+
+        // Methods are checked next, tunnels included
+        if let (Some(allowed), Some((method, _, _))) = (&config.allowed_methods, &parsed) {
+            if !allowed.contains(method) {
+                debug!("Method {} not allowed", method);
+                send_error_response(&mut client, &method_not_allowed(allowed)).await;
+                record.status = 405;
+                log_access(&config, &record);
+                return;
+            }
+        }
+
+        if let Some(answer) = parsed.as_ref().and_then(|(method, target, _)| {
+            proxy_local_answer(method, target, config.allowed_methods.as_deref())
+        }) {
+            debug!("Answering {} for the proxy itself", record.method);
+            send_error_response(&mut client, &answer).await;
+            record.status = response_status(&answer).unwrap_or(200);
+            log_access(&config, &record);
+            return;
+        }
+
+        // CONNECT tunnels carry opaque bytes and are never cached
+        if let Some((host, port)) = connect_target(&buffer) {
+            // Bytes sent ahead of the tunnel were left pending, pass them along
+            let mut buffer = buffer;
+            buffer.unsplit(pending);
+            let started = Instant::now();
+            (record.status, record.bytes) = tunnel_connect(client, &buffer, &host, port).await;
+            record.upstream_latency = Some(started.elapsed());
+            log_access(&config, &record);
+            return;
+        }
+
+        served += 1;
+        let keep_alive = served < config.max_requests_per_connection && client_keeps_alive(&buffer);
+        let reusable = handle_request(
+            &mut client,
+            &buffer,
+            &cache,
+            &pool,
+            &config,
+            keep_alive,
+            &mut record,
+        )
+        .await;
+        log_access(&config, &record);
+        if !reusable {
+            return;
+        }
+    }
+}
+
+/// Write `record` to the configured access log, if any, in the background
+fn log_access(config: &ProxyConfig, record: &AccessRecord) {
+    if let Some(log) = config.access_log.clone() {
+        let record = record.clone();
+        spawn_background(config, async move { log.log(&record) });
+    }
+}
+
+/// Spawn `task`, dropping it when the config has a
+/// [`ProxyConfig::task_limiter`] with no free slot, so the connection never
+/// waits on background work
+fn spawn_background<F>(config: &ProxyConfig, task: F)
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    match &config.task_limiter {
+        Some(limiter) => {
+            if limiter.try_spawn(task).is_none() {
+                debug!(
+                    "Dropped background task, {} running ({} dropped)",
+                    limiter.running(),
+                    limiter.dropped()
+                );
+            }
+        }
+        None => {
+            tokio::spawn(task);
+        }
+    }
+}
+
+/// A validated request and what answering it takes, shared by the steps of
+/// [`handle_request`]
+struct RequestContext<'a, B> {
+    cache: &'a B,
+    pool: &'a ConnectionPool,
+    config: &'a ProxyConfig,
+    method: String,
+    host: String,
+    port: u16,
+    path: String,
+    headers: Vec<String>,
+    /// The request as sent upstream, origin-form and without hop-by-hop headers
+    forwarded: Vec<u8>,
+    /// `Connection` value for every response, None leaves it out
+    connection: Option<&'static str>,
+    keep_alive: bool,
+    /// A `HEAD`, answered from the `GET` entry
+    head_only: bool,
+    /// The client asked to skip the cache with the bypass header
+    bypass: bool,
+}
+
+impl<'a, B: Cache> RequestContext<'a, B> {
+    fn new(
+        request: ValidRequest,
+        buffer: &[u8],
+        cache: &'a B,
+        pool: &'a ConnectionPool,
+        config: &'a ProxyConfig,
+        keep_alive: bool,
+    ) -> Self {
+        let ValidRequest {
+            method,
+            host,
+            port,
+            path,
+            headers,
+            absolute_form,
+        } = request;
+        let forwarded = if absolute_form {
+            without_hop_by_hop(&origin_form_request(buffer, &path, &authority(&host, port)))
+        } else {
+            without_hop_by_hop(buffer)
+        };
+        let bypass = config
+            .bypass_header
+            .as_deref()
+            .is_some_and(|name| header_value(&headers, name).is_some());
+        if bypass {
+            debug!("CACHE BYPASS: {}{}", host, path);
+        }
+        Self {
+            cache,
+            pool,
+            config,
+            connection: (config.max_requests_per_connection > 1).then_some(if keep_alive {
+                "keep-alive"
+            } else {
+                "close"
+            }),
+            keep_alive,
+            head_only: method == "HEAD",
+            method,
+            host,
+            port,
+            path,
+            headers,
+            forwarded,
+            bypass,
+        }
+    }
+
+    fn reply(&self, status: CacheStatus) -> ReplyHeaders<'static> {
+        ReplyHeaders {
+            connection: self.connection,
+            x_cache: self.config.cache_status_headers.then_some(status),
+            length_mismatch: self.config.length_mismatch,
+            server: self.config.server_header,
+        }
+    }
+
+    /// Force-cache rule covering the request, if any
+    fn force_rule(&self) -> Option<&'a ForceCacheRule> {
+        self.config
+            .force_cache
+            .iter()
+            .find(|rule| rule.matches(&self.host, &self.path))
+    }
+
+    /// Answer with `cached`, returning whether the connection can carry
+    /// another request
+    async fn answer_from_cache<C: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        client: &mut C,
+        cached: Arc<CachedResponse>,
+        status: CacheStatus,
+        head_only: bool,
+        record: &mut AccessRecord,
+    ) -> bool {
+        record.cache_status = status;
+        reply_from_cache(
+            client,
+            cached,
+            &self.headers,
+            self.cache,
+            self.reply(status),
+            head_only,
+            record,
+        )
+        .await
+            && self.keep_alive
+    }
+}
+
+/// Where [`lookup_cached`] left a request it couldn't answer from the cache
+struct CacheMiss {
+    /// Key the response is looked up and coalesced under
+    key: u64,
+    /// Key of the request's own entry when it is a cacheable CORS preflight
+    preflight_key: Option<u64>,
+    /// Expired entry to revalidate, or serve if upstream fails
+    stale: Option<Arc<CachedResponse>>,
+}
+
+/// Answer one request, returning whether the connection can carry another
+///
+/// With `max_requests_per_connection` above 1 every response says whether
+/// the connection stays open, `keep_alive` choosing which. What was sent is
+/// filled into `record` for the access log.
+async fn handle_request<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    client: &mut C,
+    buffer: &[u8],
+    cache: &B,
+    pool: &ConnectionPool,
+    config: &ProxyConfig,
+    keep_alive: bool,
+    record: &mut AccessRecord,
+) -> bool {
+    // Step 2: Parse and validate request
+    let request = match validate_request(buffer, config.max_request_line) {
+        Ok(request) => request,
+        Err(e) => {
+            debug!("Invalid request: {}", e);
+            send_error_response(client, e.status_line()).await;
+            record.status = response_status(e.status_line()).unwrap_or(400);
+            return false;
+        }
+    };
+    let ctx = RequestContext::new(request, buffer, cache, pool, config, keep_alive);
+
+    // Step 3: Check cache, concurrent misses for one URL share a single fetch
+    let miss = match lookup_cached(&ctx, client, record).await {
+        ControlFlow::Break(keep_alive) => return keep_alive,
+        ControlFlow::Continue(miss) => miss,
+    };
+    debug!("CACHE MISS: {}{}", ctx.host, ctx.path);
+    let flight = match join_flight(&ctx, client, record, &miss).await {
+        ControlFlow::Break(keep_alive) => return keep_alive,
+        ControlFlow::Continue(flight) => flight,
+    };
+
+    // Step 4: Forward request (conditionally for stale entries) and get response
+    let started = Instant::now();
+    let conditional = miss
+        .stale
+        .as_deref()
+        .and_then(|entry| build_conditional_request(&ctx.forwarded, &ctx.headers, entry));
+    let request = conditional.as_deref().unwrap_or(&ctx.forwarded);
+    let fetched = fetch_upstream(&ctx, client, request, miss.stale.is_some()).await;
+    record.upstream_latency = Some(started.elapsed());
+    let (mut upstream, fetched, teed) = match fetched {
+        Ok((upstream, Attempt::Read { fetched, teed })) => (upstream, fetched, teed),
+        Ok((_, Attempt::Relayed(status, bytes))) => {
+            debug!("STREAMED: {}{}", ctx.host, ctx.path);
+            (record.status, record.bytes) = (status, bytes);
+            return false;
+        }
+        Err(status) => {
+            finish_flight(
+                flight,
+                FlightOutcome::Failed(response_status(status).unwrap_or(502)),
+            );
+            let sent_stale = respond_upstream_failure(
+                client,
+                miss.stale,
+                status,
+                &ctx.headers,
+                &format!("{}{}", ctx.host, ctx.path),
+                cache,
+                ctx.reply(CacheStatus::Hit),
+            )
+            .await;
+            record_upstream_failure(record, status, sent_stale);
+            return false;
+        }
+    };
+
+    // Step 5a: Too large to cache, relay the rest as it arrives
+    if fetched.oversized {
+        let reply = ReplyHeaders {
+            connection: ctx.connection.map(|_| "close"),
+            ..ctx.reply(CacheStatus::Miss)
+        };
+        let (status, bytes) = relay_response(&mut upstream, client, &fetched.response, reply).await;
+        debug!("STREAMED: {}{} (too large to cache)", ctx.host, ctx.path);
+        (record.status, record.bytes) = (status, bytes);
+        return false;
+    }
+
+    // Step 5b: The stale copy is still valid, or better than a server error
+    if let Some((entry, status)) =
+        revalidate(&ctx, &miss, conditional.is_some(), &fetched.response).await
+    {
+        release_upstream(&ctx, upstream, &fetched).await;
+        return ctx
+            .answer_from_cache(client, entry, status, false, record)
+            .await;
+    }
+
+    // Step 6: Send response to client and return the connection to the pool
+    if !send_fetched(&ctx, client, upstream, &fetched, teed, record).await {
+        return false;
+    }
+
+    // Step 7: Cache response if applicable
+    store_response(&ctx, &fetched.response, flight, miss.preflight_key).await;
+    keep_alive && fetched.framed
+}
+
+/// Return `upstream` to the pool if `fetched` left it able to carry
+/// another request
+async fn release_upstream<B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    upstream: TcpStream,
+    fetched: &Fetched,
+) {
+    if fetched.reusable {
+        ctx.pool
+            .return_connection(ctx.host.clone(), ctx.port, upstream)
+            .await;
+    }
+}
+
+/// Send a `fetched` response to the client, unless it was already `teed`,
+/// and release its connection, returning false if the client went away
+///
+/// A body delimited by EOF ends the client connection too.
+async fn send_fetched<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    client: &mut C,
+    upstream: TcpStream,
+    fetched: &Fetched,
+    teed: bool,
+    record: &mut AccessRecord,
+) -> bool {
+    let response = &fetched.response;
+    record.status = response_status(response).unwrap_or(502);
+    record.bytes = response.len() - find_headers_end(response).unwrap_or(response.len());
+    let reply = ReplyHeaders {
+        connection: ctx
+            .connection
+            .map(|c| if fetched.framed { c } else { "close" }),
+        ..ctx.reply(CacheStatus::Miss)
+    };
+    if teed {
+        debug!("TEED: {}{}", ctx.host, ctx.path);
+    } else if let Err(e) = write_forwarded(client, response, reply).await {
+        ctx.cache.record_client_write_error(&e);
+        debug!("Failed to send response to client: {}", e);
+        return false;
+    }
+    release_upstream(ctx, upstream, fetched).await;
+    true
+}
+
+/// Answer the request from the cache if it can be, otherwise say what the
+/// fetch should start from
+///
+/// `GET` and `HEAD` requests are looked up, `HEAD` in the `GET` entry, and
+/// CORS preflights in their own entries keyed by origin. Returns whether
+/// the connection can carry another request once answered.
+async fn lookup_cached<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    client: &mut C,
+    record: &mut AccessRecord,
+) -> ControlFlow<bool, CacheMiss> {
+    let (cache, host, port, path) = (ctx.cache, ctx.host.as_str(), ctx.port, &ctx.path);
+    let key = cache.lookup_key(host, port, path, &ctx.headers).await;
+
+    let preflight_key = (ctx.config.cache_preflights && !ctx.bypass)
+        .then(|| cors::preflight(&ctx.method, &ctx.headers))
+        .flatten()
+        .map(|preflight| cache.preflight_key(host, port, path, &preflight));
+    if let Some(preflight_key) = preflight_key {
+        if let CacheLookup::Fresh(cached) = cache.lookup(preflight_key).await {
+            info!("CACHE HIT: {}{} (preflight)", host, path);
+            let hit = CacheStatus::Hit;
+            return ControlFlow::Break(
+                ctx.answer_from_cache(client, cached, hit, false, record)
+                    .await,
+            );
+        }
+    }
+
+    let lookup = if (ctx.method == "GET" || ctx.head_only) && !ctx.bypass {
+        Some(cache.lookup(key).await)
+    } else {
+        None
+    };
+    let lookup = match lookup {
+        Some(CacheLookup::Fresh(cached) | CacheLookup::Stale(cached))
+            if ctx.config.length_mismatch == LengthMismatch::Refetch
+                && length_mismatched(&cached) =>
+        {
+            warn!(
+                "Cached Content-Length for {}{} doesn't match its body, refetching",
+                host, path
+            );
+            Some(CacheLookup::Miss)
+        }
+        lookup => lookup,
+    };
+    let base_key = cache.base_key(host, port, path);
+    trace!(
+        target: CACHE_KEY_TARGET,
+        method = %ctx.method,
+        url = %format_args!("{}{}", authority(host, port), path),
+        key = base_key,
+        variant = ?(key != base_key).then_some(key),
+        decision = lookup_decision(lookup.as_ref(), ctx.bypass),
+        "cache key"
+    );
+
+    let stale = match lookup {
+        Some(CacheLookup::Fresh(cached)) => {
+            info!("CACHE HIT: {}{}", host, path);
+            let (hit, head_only) = (CacheStatus::Hit, ctx.head_only);
+            return ControlFlow::Break(
+                ctx.answer_from_cache(client, cached, hit, head_only, record)
+                    .await,
+            );
+        }
+        // Revalidating would need the full GET, pass the HEAD through
+        Some(CacheLookup::Stale(cached)) if !ctx.head_only => revalidatable(ctx, cached),
+        _ => None,
+    };
+    ControlFlow::Continue(CacheMiss {
+        key,
+        preflight_key,
+        stale,
+    })
+}
+
+/// `stale` if a 304 for it could be answered in an encoding the client accepts
+///
+/// A 304 can't turn the stored encoding into one the client accepts, only
+/// gzip can be decoded on the way out.
+fn revalidatable<B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    stale: Arc<CachedResponse>,
+) -> Option<Arc<CachedResponse>> {
+    let encoding = header_value(&stale.headers, "content-encoding").unwrap_or("identity");
+    if ctx.config.check_encoding_on_revalidate
+        && !encoding.eq_ignore_ascii_case("gzip")
+        && !accepts_encoding(&ctx.headers, encoding)
+    {
+        debug!(
+            "Stale {}{} has an unacceptable encoding, fetching in full",
+            ctx.host, ctx.path
+        );
+        return None;
+    }
+    Some(stale)
+}
+
+/// Lead the fetch of a missed `GET`, or wait on the request already
+/// fetching it and answer from what that stored
+///
+/// Returns the guard to finish once the response is stored, or whether the
+/// connection can carry another request once answered.
+async fn join_flight<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    client: &mut C,
+    record: &mut AccessRecord,
+    miss: &CacheMiss,
+) -> ControlFlow<bool, Option<FlightGuard>> {
+    let (host, path) = (&ctx.host, &ctx.path);
+    let wait = match (ctx.method == "GET" && miss.stale.is_none() && !ctx.bypass)
+        .then(|| ctx.cache.single_flight().join(miss.key))
+    {
+        Some(Flight::Lead(guard)) => return ControlFlow::Continue(Some(guard)),
+        Some(Flight::Follow(wait)) => wait,
+        Some(Flight::Bypass) | None => return ControlFlow::Continue(None),
+    };
+    match timeout(CONNECTION_TIMEOUT, wait.done()).await {
+        // Retrying an origin that just failed would only add to the herd
+        Ok(FlightOutcome::Failed(status)) => {
+            debug!("Shared fetch of {}{} failed with {}", host, path, status);
+            send_error_response(client, gateway_error(status)).await;
+            record.status = status;
+            return ControlFlow::Break(false);
+        }
+        Ok(_) => {}
+        Err(_) => debug!("Stopped waiting on the fetch of {}{}", host, path),
+    }
+    // The leader may have stored a Vary variant under another key
+    let key = ctx
+        .cache
+        .lookup_key(host, ctx.port, path, &ctx.headers)
+        .await;
+    if let CacheLookup::Fresh(cached) = ctx.cache.lookup(key).await {
+        info!("CACHE HIT: {}{} (coalesced)", host, path);
+        let hit = CacheStatus::Hit;
+        return ControlFlow::Break(
+            ctx.answer_from_cache(client, cached, hit, false, record)
+                .await,
+        );
+    }
+    ControlFlow::Continue(None)
+}
+
+/// An upstream response to one attempt of [`fetch_upstream`]
+enum Attempt {
+    /// Read in full, and already relayed to the client when `teed`
+    Read { fetched: Fetched, teed: bool },
+    /// Relayed to the client as it arrived, with its status and body bytes
+    Relayed(u16, usize),
+}
+
+/// Send `request` upstream and get the response, on a fresh connection
+/// again when an idempotent request fails before anything reached the client
+///
+/// A `revalidating` request's response is never teed, it's for the proxy
+/// rather than the client. Returns the connection with the response, or
+/// the error status to answer with.
+async fn fetch_upstream<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    client: &mut C,
+    request: &[u8],
+    revalidating: bool,
+) -> Result<(TcpStream, Attempt), &'static [u8]> {
+    let (host, port) = (ctx.host.as_str(), ctx.port);
+    let budget = ReadBudget::new(
+        ctx.config,
+        ctx.config.first_byte_timeout.unwrap_or(CONNECTION_TIMEOUT),
+    );
+    let retries = if ctx.method == "GET" || ctx.head_only {
+        ctx.config.upstream_retries
+    } else {
+        0
+    };
+    let mut attempt = 0;
+    loop {
+        let connected = if attempt == 0 {
+            ctx.pool.get_connection(host, port).await
+        } else {
+            ctx.pool.connect_fresh(host, port).await
+        };
+        let mut upstream = match connected {
+            Ok(stream) => stream,
+            Err(e) if attempt < retries && e.is_transient() => {
+                debug!("Connecting to {}:{} failed ({}), retrying", host, port, e);
+                tokio::time::sleep(retry_delay(ctx.config.retry_backoff, attempt)).await;
+                attempt += 1;
+                continue;
+            }
+            Err(e) => {
+                debug!("Failed to get connection from pool: {}", e);
+                return Err(match e {
+                    ConnectError::Refused => {
+                        info!("Upstream {}:{} refused connection", host, port);
+                        b"HTTP/1.1 502 Bad Gateway\r\n\r\n"
+                    }
+                    ConnectError::TimedOut => b"HTTP/1.1 504 Gateway Timeout\r\n\r\n",
+                    ConnectError::Failed(_) => b"HTTP/1.1 502 Bad Gateway\r\n\r\n",
+                });
+            }
+        };
+        let deadline_passed =
+            |e: &str| [FIRST_BYTE_TIMED_OUT, HEAD_TIMED_OUT, TRANSFER_TOO_SLOW].contains(&e);
+        match forward_once(ctx, &mut upstream, client, request, budget, revalidating).await {
+            Ok(response) => return Ok((upstream, response)),
+            // Deadlines already cost the client their full wait, don't repeat them
+            Err(e) if attempt < retries && !deadline_passed(e) => {
+                debug!("Upstream {}:{} failed ({}), retrying", host, port, e);
+                tokio::time::sleep(retry_delay(ctx.config.retry_backoff, attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                debug!("Failed to get upstream response: {}", e);
+                return Err(if deadline_passed(e) {
+                    b"HTTP/1.1 504 Gateway Timeout\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 502 Bad Gateway\r\n\r\n"
+                });
+            }
+        }
+    }
+}
+
+/// One attempt of [`fetch_upstream`], in the configured response mode
+async fn forward_once<C: AsyncRead + AsyncWrite + Unpin, B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    upstream: &mut TcpStream,
+    client: &mut C,
+    request: &[u8],
+    budget: ReadBudget,
+    revalidating: bool,
+) -> Result<Attempt, &'static str> {
+    let method = &ctx.method;
+    let forced = ctx.force_rule().is_some();
+    let streamed = match ctx.config.response_mode {
+        ResponseMode::Buffered => {
+            let fetched = forward_to_upstream(upstream, request, method, budget).await?;
+            return Ok(Attempt::Read {
+                fetched,
+                teed: false,
+            });
+        }
+        ResponseMode::Tee if !revalidating => {
+            let reply = ReplyHeaders {
+                connection: ctx.connection.map(|_| "close"),
+                ..ctx.reply(CacheStatus::Miss)
+            };
+            forward_tee(upstream, client, request, method, budget, forced, reply).await?
+        }
+        // Answers to a revalidation are for the proxy, not to be relayed as they come
+        _ => {
+            let reply = ctx.reply(CacheStatus::Miss);
+            forward_streaming(upstream, client, request, method, budget, forced, reply).await?
+        }
+    };
+    let read = |response, teed| Attempt::Read {
+        fetched: Fetched {
+            response,
+            reusable: false,
+            framed: false,
+            oversized: false,
+        },
+        teed,
+    };
+    Ok(match streamed {
+        Forwarded::Streamed(status, bytes) | Forwarded::Teed(status, bytes, None) => {
+            Attempt::Relayed(status, bytes)
+        }
+        // Already sent, what's left is caching the copy
+        Forwarded::Teed(_, _, Some(response)) => read(response, true),
+        Forwarded::Buffered(response) => read(response, false),
+    })
+}
+
+/// The stale entry to answer with instead of the upstream `response`, and
+/// the cache status to report
+///
+/// A 304 to a `conditional` request confirms the entry and refreshes its
+/// expiry. A 5xx is replaced by the entry when `stale-if-error` permits.
+async fn revalidate<B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    miss: &CacheMiss,
+    conditional: bool,
+    response: &[u8],
+) -> Option<(Arc<CachedResponse>, CacheStatus)> {
+    let (host, path) = (ctx.host.as_str(), &ctx.path);
+    let entry = miss.stale.as_ref()?;
+    let status = response_status(response);
+    if conditional && status == Some(304) {
+        let expires = revalidated_expiry(response, entry, host, path, ctx.cache);
+        ctx.cache.refresh_expiry(miss.key, expires).await;
+        info!("REVALIDATED: {}{}", host, path);
+        return Some((Arc::clone(entry), CacheStatus::Revalidated));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if !matches!(status, Some(500..=599)) || !stale_if_error_permits(entry, ctx.config, now) {
+        return None;
+    }
+    info!(
+        "STALE: {}{} (upstream returned {})",
+        host,
+        path,
+        status.unwrap_or(0)
+    );
+    Some((Arc::clone(entry), CacheStatus::Hit))
+}
+
+/// Store a fetched `response`, as a force-cache rule rewrote it, and the
+/// answer to a cacheable preflight under `preflight_key`
+///
+/// Requests coalesced on `flight` are told once the response is stored.
+async fn store_response<B: Cache>(
+    ctx: &RequestContext<'_, B>,
+    response: &[u8],
+    flight: Option<FlightGuard>,
+    preflight_key: Option<u64>,
+) {
+    let (cache, host, path) = (ctx.cache, ctx.host.as_str(), &ctx.path);
+    let forced = ctx
+        .force_rule()
+        .and_then(|rule| force_cached_response(response, rule));
+    let cacheable = forced.as_deref().unwrap_or(response);
+    if let Some(cached_response) = (!ctx.bypass || ctx.config.store_bypassed)
+        .then(|| parse_response_for_cache(cacheable, &ctx.method, host, path, cache))
+        .flatten()
+    {
+        let ttl = cached_response.expires.saturating_sub(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+
+        if cache
+            .put_variant(host, ctx.port, path, &ctx.headers, cached_response)
+            .await
+            .is_some()
+        {
+            info!("CACHED: {}{} (TTL: {}s)", host, path, ttl);
+            finish_flight(flight, FlightOutcome::Stored);
+        }
+    }
+    if let Some(key) = preflight_key {
+        if let Some(answer) = parse_preflight_for_cache(response, host, path) {
+            let ttl = answer.expires.saturating_sub(answer.stored_at);
+            if cache.put(key, answer).await {
+                info!("CACHED: {}{} (preflight, TTL: {}s)", host, path, ttl);
+            }
+        }
+    }
+}
+
+/// Cache entry for the answer to a CORS preflight, fresh for its
+/// `Access-Control-Max-Age`
+///
+/// Only successful answers are kept, and nothing marked `no-store` or
+/// setting cookies. Entries are never served stale.
+fn parse_preflight_for_cache(response: &[u8], host: &str, path: &str) -> Option<CachedResponse> {
+    let headers_end = find_headers_end(response)?;
+    if !matches!(response_status(response), Some(200 | 204)) {
+        return None;
+    }
+    if let Some(marker) = uncacheable_marker(&response[..headers_end]) {
+        debug!("Not caching preflight for {}{}: {}", host, path, marker);
+        return None;
+    }
+    let (status_line, headers, body) = split_response(response)?;
+    if !is_safe_header_line(&status_line) || !headers.iter().all(|h| is_safe_header_line(h)) {
+        return None;
+    }
+    let max_age = cors::max_age(&headers)?;
+    let body = within_content_length(&headers, body)?;
+    let (headers, body) = dechunk(headers, body, false)?;
+    let stored_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    Some(CachedResponse {
+        status_line: format!("{}\r\n", status_line),
+        headers: strip_hop_by_hop(&headers),
+        body,
+        expires: stored_at + max_age,
+        stored_at,
+        must_revalidate: true,
+        ..Default::default()
+    })
+}
+
+/// Interval between descriptor checks while accepting is paused
+const FD_RECHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Block until at least `min_free` descriptors are available
+async fn wait_for_fd_headroom(min_free: usize) {
+    let mut paused = false;
+
+    while let Some(headroom) = fd::current_fd_headroom() {
+        if headroom >= min_free {
+            break;
+        }
+        if !paused {
+            warn!(
+                "Only {} file descriptors left (minimum {}), pausing accept",
+                headroom, min_free
+            );
+            paused = true;
+        }
+        tokio::time::sleep(FD_RECHECK_INTERVAL).await;
+    }
+
+    if paused {
+        info!("File descriptor headroom restored, resuming accept");
+    }
+}
+
+/// How often the drain checks whether in-flight connections have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Counts a client connection as active until dropped
+///
+/// Held by the connection's task, so the count also drops if the handler panics.
+struct ActiveConnection(Arc<AtomicUsize>);
+
+impl ActiveConnection {
+    fn new(active_connections: &Arc<AtomicUsize>) -> Self {
+        active_connections.fetch_add(1, Ordering::Relaxed);
+        Self(Arc::clone(active_connections))
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The proxy as a library, serving requests from any byte stream
+///
+/// Holds the cache, the upstream pool and the config a connection is served
+/// with, so another server can hand over connections it accepted itself, or
+/// in-memory streams in tests. The cache is a [`ProxyCache`] unless another
+/// [`Cache`] backend is given. Clones share the cache and pool.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::config::ProxyConfig;
+/// use rustysquid::connection_pool::ConnectionPool;
+/// use rustysquid::proxy::CachingProxy;
+/// use rustysquid::ProxyCache;
+///
+/// async fn serve(proxy: &CachingProxy, stream: tokio::io::DuplexStream) {
+///     proxy.handle(stream).await;
+/// }
+///
+/// let proxy = CachingProxy::new(ProxyCache::new(), ConnectionPool::new(), ProxyConfig::default());
+/// assert_eq!(proxy.config().max_requests_per_connection, 1);
+/// ```
+#[derive(Clone)]
+pub struct CachingProxy<B = ProxyCache> {
+    cache: B,
+    pool: ConnectionPool,
+    config: Arc<ProxyConfig>,
+}
+
+impl<B: Cache + Clone> CachingProxy<B> {
+    pub fn new(cache: B, pool: ConnectionPool, config: ProxyConfig) -> Self {
+        Self {
+            cache,
+            pool,
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn cache(&self) -> &B {
+        &self.cache
+    }
+
+    pub fn pool(&self) -> &ConnectionPool {
+        &self.pool
+    }
+
+    pub fn config(&self) -> &ProxyConfig {
+        &self.config
+    }
+
+    /// Serve requests on `stream` until the client closes it or the
+    /// connection can't carry another request
+    ///
+    /// The stream has no peer address, access log lines show `-` for it.
+    /// `CONNECT` tunnels are relayed over the stream as they are for sockets.
+    pub async fn handle<S: AsyncRead + AsyncWrite + Unpin>(&self, stream: S) {
+        serve_connection(
+            stream,
+            None,
+            self.cache.clone(),
+            self.pool.clone(),
+            Arc::clone(&self.config),
+        )
+        .await;
+    }
+}
+
+/// Connection acceptor with proper connection limiting
+///
+/// Once `shutdown` completes the listener is closed and in-flight
+/// connections get up to `drain_timeout` to finish before this returns.
+pub async fn accept_connections<B: Cache + Clone + 'static>(
+    listener: TcpListener,
+    cache: B,
+    pool: ConnectionPool,
+    shared_config: SharedConfig,
+    active_connections: Arc<AtomicUsize>,
+    shutdown: impl std::future::Future<Output = ()>,
+) {
+    tokio::pin!(shutdown);
+    loop {
+        let next = async {
+            // Leave accepted connections enough descriptors for their upstreams
+            if let Some(min_free) = shared_config.load().min_free_fds {
+                wait_for_fd_headroom(min_free).await;
+            }
+            listener.accept().await
+        };
+        let accepted = tokio::select! {
+            _ = &mut shutdown => break,
+            accepted = next => accepted,
+        };
+
+        let (stream, addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+
+        // Clients outside the access list are closed before anything is read
+        if !shared_config.load().client_acl.permits(addr.ip()) {
+            debug!("Refusing connection from {}", addr);
+            drop(stream);
+            continue;
+        }
+
+        // Check connection limit
+        if active_connections.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+            debug!("Connection limit reached, rejecting {}", addr);
+            drop(stream);
+            continue;
+        }
+
+        // Handle client
+        let cache_clone = cache.clone();
+        let pool_clone = pool.clone();
+        // Each connection keeps the config current when it was accepted
+        let config_clone = shared_config.load();
+        let active = ActiveConnection::new(&active_connections);
+
+        tokio::spawn(async move {
+            let _active = active;
+            handle_client(stream, cache_clone, pool_clone, config_clone).await;
+        });
+    }
+
+    drop(listener);
+    drain_connections(&active_connections, shared_config.load().drain_timeout).await;
+}
+
+/// Replace the running config with a reloaded one, returns whether it was applied
+///
+/// An invalid config leaves the running one in place. Settings only read
+/// at startup are kept, see [`ProxyConfig::with_startup_settings`].
+fn reload_config(shared: &SharedConfig, reloaded: Result<ProxyConfig, EnvConfigError>) -> bool {
+    let reloaded = match reloaded {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Keeping the running configuration, reload failed: {}", e);
+            return false;
+        }
+    };
+    let running = shared.load();
+    if reloaded.listen_addr != running.listen_addr || reloaded.metrics_addr != running.metrics_addr
+    {
+        warn!("Listen address changes take effect after a restart");
+    }
+    shared.store(reloaded.with_startup_settings(&running));
+    info!("Configuration reloaded");
+    true
+}
+
+/// Give `cache` the TTL and query settings of a `reloaded` config that
+/// differ from the `running` one
+///
+/// Entries stay cached unless the query policy now keys them differently,
+/// returns how many that dropped.
+async fn reload_cache_policies(
+    cache: &ProxyCache,
+    running: &ProxyConfig,
+    reloaded: &ProxyConfig,
+) -> usize {
+    if reloaded.host_ttl_multipliers != running.host_ttl_multipliers {
+        cache.set_host_ttl_multipliers(reloaded.host_ttl_multipliers.clone());
+    }
+    if reloaded.extension_ttls != running.extension_ttls {
+        cache.set_extension_ttls(reloaded.extension_ttls.clone());
+    }
+    if reloaded.query_policy == running.query_policy {
+        return 0;
+    }
+    let cleared = cache.set_query_policy(reloaded.query_policy.clone()).await;
+    if cleared > 0 {
+        info!(
+            "Cleared {} cached entries keyed by the old query policy",
+            cleared
+        );
+    }
+    cleared
+}
+
+/// Reload the config from the environment and config file, then clear the
+/// cache, on every `SIGHUP`
+///
+/// Settings neither sets go back to their defaults, except those only read
+/// at startup, see [`reload_config`].
+#[cfg(unix)]
+pub fn spawn_config_reloader(shared: SharedConfig, cache: ProxyCache) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!(
+                "Failed to install SIGHUP handler, config reload disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let running = shared.load();
+            if reload_config(&shared, ProxyConfig::default().with_env()) {
+                reload_cache_policies(&cache, &running, &shared.load()).await;
+            }
+            let cleared = cache.len().await;
+            cache.clear().await;
+            info!("Cleared {} cached entries", cleared);
+        }
+    });
+}
+
+/// Wait up to `limit` for the active connection count to reach zero,
+/// returning how many were still in flight
+async fn drain_connections(active_connections: &AtomicUsize, limit: Duration) -> usize {
+    let in_flight = active_connections.load(Ordering::Relaxed);
+    if in_flight == 0 {
+        return 0;
+    }
+    info!("Waiting for {} active connections to finish", in_flight);
+
+    let drained = timeout(limit, async {
+        while active_connections.load(Ordering::Relaxed) > 0 {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+    })
+    .await;
+    let remaining = active_connections.load(Ordering::Relaxed);
+    match drained {
+        Ok(()) => info!("All connections finished"),
+        Err(_) => warn!(
+            "{} connections still in flight after {:?}, closing them",
+            remaining, limit
+        ),
+    }
+    remaining
+}
+
+/// Spawn a task refetching the scheduled URLs on every interval tick
+///
+/// The first tick fires immediately, so the URLs are also warmed on startup.
+/// With a `limiter` the fetches of a tick run in its background slots,
+/// otherwise one after another.
+pub fn spawn_refresher(
+    cache: ProxyCache,
+    pool: ConnectionPool,
+    schedule: RefreshSchedule,
+    limiter: Option<TaskLimiter>,
+) -> tokio::task::JoinHandle<()> {
+    let schedule = Arc::new(schedule);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(schedule.interval);
+        loop {
+            ticker.tick().await;
+            let Some(limiter) = &limiter else {
+                for url in &schedule.urls {
+                    refresh_url(&cache, &pool, url, &schedule).await;
+                }
+                continue;
+            };
+            let mut refreshes = Vec::with_capacity(schedule.urls.len());
+            for index in 0..schedule.urls.len() {
+                let (cache, pool, schedule) = (cache.clone(), pool.clone(), Arc::clone(&schedule));
+                refreshes.push(
+                    limiter
+                        .spawn(async move {
+                            refresh_url(&cache, &pool, &schedule.urls[index], &schedule).await
+                        })
+                        .await,
+                );
+            }
+            for refresh in refreshes {
+                let _ = refresh.await;
+            }
+        }
+    })
+}
+
+/// Refetch `url` and store the response as if a client without request
+/// headers had asked for it, returns whether the cache was updated
+///
+/// URLs not cached yet are always fetched, cached ones only once the
+/// schedule says they are due, see [`refresh_due`].
+async fn refresh_url(
+    cache: &ProxyCache,
+    pool: &ConnectionPool,
+    url: &str,
+    schedule: &RefreshSchedule,
+) -> bool {
+    let Some((host, port, path)) = parse_refresh_url(url) else {
+        warn!("Skipping malformed refresh URL {}", url);
+        return false;
+    };
+    let key = cache.lookup_key(&host, port, &path, &[]).await;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if let Some(entry) = cache.peek(key).await {
+        if !refresh_due(&entry, schedule, now) {
+            return false;
+        }
+    }
+
+    let refreshed = fetch_into_cache(cache, pool, &host, port, &path).await;
+    if refreshed {
+        debug!("REFRESHED: {}", url);
+    }
+    refreshed
+}
+
+/// Fetch `path` from `host:port` as a client without request headers would
+/// and store the response, returns whether the cache was updated
+///
+/// Responses go through the same checks as those fetched for clients, so
+/// uncacheable ones are dropped.
+async fn fetch_into_cache(
+    cache: &ProxyCache,
+    pool: &ConnectionPool,
+    host: &str,
+    port: u16,
+    path: &str,
+) -> bool {
+    let mut upstream = match pool.get_connection(host, port).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("Fetch of {}:{}{} failed: {}", host, port, path, e);
+            return false;
+        }
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path,
+        authority(host, port)
+    );
+    let budget = ReadBudget::new(&ProxyConfig::default(), CONNECTION_TIMEOUT);
+    let response = match forward_to_upstream(&mut upstream, request.as_bytes(), "GET", budget).await
+    {
+        Ok(fetched) if fetched.oversized => {
+            debug!(
+                "Fetch of {}:{}{} returned a response too large to cache",
+                host, port, path
+            );
+            return false;
+        }
+        Ok(fetched) => fetched.response,
+        Err(e) => {
+            debug!("Fetch of {}:{}{} failed: {}", host, port, path, e);
+            return false;
+        }
+    };
+    let Some(entry) = parse_response_for_cache(&response, "GET", host, path, cache) else {
+        debug!(
+            "Fetch of {}:{}{} returned an uncacheable response",
+            host, port, path
+        );
+        return false;
+    };
+    cache
+        .put_variant(host, port, path, &[], entry)
+        .await
+        .is_some()
+}
+
+/// URLs [`prefetch`] fetches at once
+const PREFETCH_CONCURRENCY: usize = 8;
+
+/// Fill the cache with `urls`, given as (host, port, path), before the
+/// first clients ask for them
+///
+/// Each URL is fetched and checked as a client request would be,
+/// [`PREFETCH_CONCURRENCY`] at a time. URLs that fail or return something
+/// uncacheable are skipped. Returns how many were cached.
+pub async fn prefetch(
+    urls: &[(String, u16, String)],
+    pool: &ConnectionPool,
+    cache: &ProxyCache,
+) -> usize {
+    let limiter = TaskLimiter::new(PREFETCH_CONCURRENCY);
+    let mut fetches = Vec::with_capacity(urls.len());
+    for (host, port, path) in urls.iter().cloned() {
+        let (cache, pool) = (cache.clone(), pool.clone());
+        fetches.push(
+            limiter
+                .spawn(async move { fetch_into_cache(&cache, &pool, &host, port, &path).await })
+                .await,
+        );
+    }
+    let mut cached = 0;
+    for fetch in fetches {
+        if fetch.await.unwrap_or(false) {
+            cached += 1;
+        }
+    }
+    info!("Prefetched {} of {} URLs", cached, urls.len());
+    cached
+}
+
+/// Whether a cached entry should be refetched at `now`
+///
+/// Entries stored with less than the schedule's `min_ttl` are never
+/// refetched, so short-lived content doesn't send the refresher to its
+/// origin every few seconds.
+fn refresh_due(entry: &CachedResponse, schedule: &RefreshSchedule, now: u64) -> bool {
+    let lifetime = entry.expires.saturating_sub(entry.stored_at);
+    if schedule.min_ttl.is_some_and(|min_ttl| lifetime < min_ttl) {
+        return false;
+    }
+    schedule
+        .remaining_fraction
+        .map_or(true, |fraction| needs_refresh(entry, fraction, now))
+}
+
+/// Whether less than `fraction` of the entry's lifetime remains at `now`
+fn needs_refresh(entry: &CachedResponse, fraction: f64, now: u64) -> bool {
+    let lifetime = entry.expires.saturating_sub(entry.stored_at);
+    let remaining = entry.expires.saturating_sub(now);
+    (remaining as f64) < lifetime as f64 * fraction
+}
+
+/// Split a `[http://]host[:port]/path` URL into its parts
+pub(crate) fn parse_refresh_url(url: &str) -> Option<(String, u16, String)> {
+    let rest = url.strip_prefix("http://").unwrap_or(url);
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let (host, port) = match split_authority(authority) {
+        (host, Some(port)) => (host, port.parse().ok()?),
+        (host, None) => (host, 80),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host.to_string(), port, path.to_string()))
+}
+
+/// Key `cache` like the one persisted in `dir` and reload its entries
+pub async fn load_cache(dir: &std::path::Path, cache: ProxyCache) -> ProxyCache {
+    let seed = DiskCache::new(dir)
+        .key_seed()
+        .unwrap_or_else(process_key_seed);
+    let cache = cache.with_key_seed(seed);
+    match cache.load_from_dir(dir).await {
+        Ok(stats) => info!(
+            "Loaded {} cache entries from {}, skipped {} ({} expired, {} corrupt, {} incompatible, {} refused)",
+            stats.loaded,
+            dir.display(),
+            stats.skipped(),
+            stats.expired,
+            stats.corrupt,
+            stats.incompatible,
+            stats.refused
+        ),
+        Err(e) => warn!("Failed to load cache from {}: {}", dir.display(), e),
+    }
+    cache
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_log::AccessLog;
+    use crate::acl::ClientAcl;
+    use crate::compress::unbrotli;
+    use crate::config::{ProxyCacheConfig, DEFAULT_MAX_REQUEST_LINE};
+    use crate::query::QueryPolicy;
+    use crate::rate_limit::RateLimiter;
+    use crate::vary::VaryPolicy;
+    use crate::{
+        backend::BoxFuture, cors::Preflight, create_cache_key, format_http_date,
+        single_flight::SingleFlight, ExtensionTtls, HostTtlMultipliers, CACHE_TTL,
+        MAX_REQUEST_HEADERS,
+    };
+    use std::net::SocketAddr;
+    use tokio::sync::Mutex;
+
+    /// Spawn an upstream answering each connection with the next scripted
+    /// response, recording the raw requests it receives
+    async fn spawn_upstream(responses: Vec<Vec<u8>>) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&requests);
+
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = BytesMut::new();
+                while find_headers_end(&buffer).is_none() {
+                    if stream.read_buf(&mut buffer).await.unwrap() == 0 {
+                        break;
+                    }
+                }
+                recorded
+                    .lock()
+                    .await
+                    .push(String::from_utf8_lossy(&buffer).to_string());
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+
+        (addr, requests)
+    }
+
+    /// Send `request` through `handle_client` and collect everything the client receives
+    async fn proxy_request(cache: &ProxyCache, request: &str) -> String {
+        proxy_request_via(cache, &ConnectionPool::new(), request).await
+    }
+
+    /// [`proxy_request`] sharing upstream connections through `pool`
+    async fn proxy_request_via(cache: &ProxyCache, pool: &ConnectionPool, request: &str) -> String {
+        proxy_request_with(cache, pool, ProxyConfig::default(), request).await
+    }
+
+    /// [`proxy_request_via`] with a non-default [`ProxyConfig`]
+    async fn proxy_request_with(
+        cache: &ProxyCache,
+        pool: &ConnectionPool,
+        config: ProxyConfig,
+        request: &str,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let handler = tokio::spawn(handle_client(
+            server,
+            cache.clone(),
+            pool.clone(),
+            Arc::new(config),
+        ));
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        handler.await.unwrap();
+        String::from_utf8_lossy(&received).to_string()
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn test_html_injection_on_cached_pages() {
+        let cache = ProxyCache::new().with_html_injection(r#"<base href="/site/">"#);
+        let page = |cache_control: &str| {
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nCache-Control: {}\r\n\
+                 Content-Length: 26\r\n\r\n<html><head></head></html>",
+                cache_control
+            )
+        };
+
+        let cached = parse_response_for_cache(
+            page("max-age=60").as_bytes(),
+            "GET",
+            "example.com",
+            "/index.html",
+            &cache,
+        )
+        .unwrap();
+        assert_eq!(
+            &cached.body[..],
+            &b"<html><head><base href=\"/site/\"></head></html>"[..]
+        );
+        assert_eq!(header_value(&cached.headers, "content-length"), Some("46"));
+
+        let untouched = parse_response_for_cache(
+            page("max-age=60, no-transform").as_bytes(),
+            "GET",
+            "example.com",
+            "/index.html",
+            &cache,
+        )
+        .unwrap();
+        assert_eq!(&untouched.body[..], &b"<html><head></head></html>"[..]);
+        assert_eq!(
+            header_value(&untouched.headers, "content-length"),
+            Some("26")
+        );
+    }
+
+    #[test]
+    fn test_overloaded_responses_cached_for_retry_after() {
+        let cache = ProxyCache::new();
+        let parse = |retry_after: &str| {
+            let response = format!(
+                "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\n\
+                 Cache-Control: max-age=3600\r\nContent-Length: 4\r\n\r\nbusy",
+                retry_after
+            );
+            parse_response_for_cache(
+                response.as_bytes(),
+                "GET",
+                "api.example.com",
+                "/items",
+                &cache,
+            )
+        };
+
+        let cached = parse("120").unwrap();
+        assert_eq!(cached.expires - cached.stored_at, 120);
+        let capped = ProxyCache::with_config(ProxyCacheConfig {
+            max_ttl: 60,
+            ..ProxyCacheConfig::default()
+        })
+        .unwrap();
+        let response = b"HTTP/1.1 503 Service Unavailable\r\nRetry-After: 120\r\n\r\n";
+        let cached =
+            parse_response_for_cache(response, "GET", "api.example.com", "/items", &capped)
+                .unwrap();
+        assert_eq!(cached.expires - cached.stored_at, 60);
+        assert_eq!(cached.status_line, "HTTP/1.1 503 Service Unavailable\r\n");
+        let cached = parse(&format_http_date(now() + 300)).unwrap();
+        assert!((299..=300).contains(&(cached.expires - cached.stored_at)));
+        // Already past, nothing to wait for
+        assert!(parse(&format_http_date(now() - 60)).is_none());
+
+        let too_many = b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 30\r\n\r\n";
+        let cached =
+            parse_response_for_cache(too_many, "GET", "api.example.com", "/items", &cache).unwrap();
+        assert_eq!(cached.expires - cached.stored_at, 30);
+        // Without Retry-After the path decides as before
+        let no_hint = b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\n\r\nbusy";
+        assert!(
+            parse_response_for_cache(no_hint, "GET", "api.example.com", "/items", &cache).is_none()
+        );
+        let private = b"HTTP/1.1 429 Too Many Requests\r\nRetry-After: 30\r\n\
+                        Cache-Control: private\r\n\r\n";
+        assert!(
+            parse_response_for_cache(private, "GET", "api.example.com", "/items", &cache).is_none()
+        );
+    }
+
+    #[test]
+    fn test_query_strings_and_cacheability() {
+        let cache = ProxyCache::new();
+        let by_extension = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody";
+        let fresh = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\n\r\nbody";
+        let cached = |response: &[u8], path: &str| {
+            parse_response_for_cache(response, "GET", "cdn.example.com", path, &cache).is_some()
+        };
+
+        // A cache-busting version still caches by extension
+        assert!(cached(by_extension, "/app.js?v=3"));
+        // Signed URLs are skipped even with explicit freshness
+        assert!(!cached(by_extension, "/image.jpg?token=secret&expires=123"));
+        assert!(!cached(fresh, "/video.mp4?Signature=abc"));
+
+        let cache = ProxyCache::new().with_query_policy(QueryPolicy {
+            private_params: vec!["session".to_string()],
+            ..QueryPolicy::default()
+        });
+        assert!(
+            parse_response_for_cache(by_extension, "GET", "a", "/a.css?token=1", &cache).is_some()
+        );
+        assert!(
+            parse_response_for_cache(by_extension, "GET", "a", "/a.css?session=1", &cache)
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identical_query_variants_collapse() {
+        let response = |body: &str| {
+            format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .into_bytes()
+        };
+        let (addr, requests) = spawn_upstream(vec![
+            response("banner"),
+            response("banner"),
+            response("other"),
+        ])
+        .await;
+        let cache = ProxyCache::new().with_query_policy(QueryPolicy {
+            collapse_identical_variants: true,
+            ..QueryPolicy::default()
+        });
+        let get = |query: &str| {
+            format!(
+                "GET /banner.png?utm_source={} HTTP/1.1\r\nHost: {}\r\n\r\n",
+                query, addr
+            )
+        };
+
+        proxy_request(&cache, &get("mail")).await;
+        proxy_request(&cache, &get("social")).await;
+        assert_eq!(requests.lock().await.len(), 2);
+
+        // A third query is answered by the collapsed entry
+        let third = proxy_request(&cache, &get("search")).await;
+        assert!(third.ends_with("\r\n\r\nbanner"), "{}", third);
+        assert_eq!(requests.lock().await.len(), 2);
+    }
+
+    #[test]
+    fn test_host_multiplier_extends_cached_ttl() {
+        let mut multipliers = HostTtlMultipliers::new();
+        multipliers.insert("cdn.example.com", 2.0);
+        let cache = ProxyCache::new().with_host_ttl_multipliers(multipliers);
+        let response = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\n\r\nbody";
+
+        let cached =
+            parse_response_for_cache(response, "GET", "cdn.example.com", "/app.js", &cache)
+                .unwrap();
+        assert!(cached.expires >= now() + 1199);
+
+        let cached =
+            parse_response_for_cache(response, "GET", "example.com", "/app.js", &cache).unwrap();
+        assert!(cached.expires <= now() + 600);
+    }
+
+    #[test]
+    fn test_extension_ttls_for_responses_without_freshness() {
+        let mut ttls = ExtensionTtls::new();
+        ttls.insert(".mp4", 86_400);
+        ttls.insert(".html", 60);
+        let cache = ProxyCache::new().with_extension_ttls(ttls);
+        let bare = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nbody";
+        let expires_in = |response: &[u8], path| {
+            parse_response_for_cache(response, "GET", "example.com", path, &cache)
+                .unwrap()
+                .expires
+                - now()
+        };
+
+        assert!((86_399..=86_400).contains(&expires_in(bare, "/media/intro.mp4")));
+        assert!((59..=60).contains(&expires_in(bare, "/index.html")));
+        // Unlisted extensions keep the default, headers still win
+        assert!((CACHE_TTL - 1..=CACHE_TTL).contains(&expires_in(bare, "/app.js")));
+        let fresh = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\n\r\nbody";
+        assert!((599..=600).contains(&expires_in(fresh, "/media/intro.mp4")));
+    }
+
+    #[test]
+    fn test_body_cached_up_to_content_length() {
+        let cache = ProxyCache::new();
+        // The start of the next response was read along with this one
+        let response = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 5\r\n\r\n\
+                         helloHTTP/1.1 200 OK\r\n";
+        let cached =
+            parse_response_for_cache(response, "GET", "example.com", "/app.js", &cache).unwrap();
+        assert_eq!(cached.body, Bytes::from("hello"));
+        assert_eq!(header_value(&cached.headers, "content-length"), Some("5"));
+
+        // Chunked framing wins over a Content-Length sent alongside it
+        let chunked = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 2\r\n\
+                        Transfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n";
+        let cached =
+            parse_response_for_cache(chunked, "GET", "example.com", "/app.js", &cache).unwrap();
+        assert_eq!(cached.body, Bytes::from("hello"));
+    }
+
+    #[test]
+    fn test_truncated_body_not_cached() {
+        let cache = ProxyCache::new();
+        // Upstream closed 3000 bytes into a 5000 byte body
+        let mut response =
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 5000\r\n\r\n"
+                .to_vec();
+        response.extend_from_slice(&[b'x'; 3000]);
+        assert!(
+            parse_response_for_cache(&response, "GET", "example.com", "/app.js", &cache).is_none()
+        );
+
+        response.extend_from_slice(&[b'x'; 2000]);
+        let cached =
+            parse_response_for_cache(&response, "GET", "example.com", "/app.js", &cache).unwrap();
+        assert_eq!(cached.body.len(), 5000);
+    }
+
+    #[test]
+    fn test_response_splitting_not_cached() {
+        let cache = ProxyCache::new();
+
+        // A bare LF smuggles a header that would be replayed as its own line
+        let response =
+            b"HTTP/1.1 200 OK\r\nX-Note: a\nInjected: evil\r\nContent-Length: 4\r\n\r\nbody";
+        assert!(
+            parse_response_for_cache(response, "GET", "example.com", "/a.js", &cache).is_none()
+        );
+
+        // So does a bare CR
+        let response = b"HTTP/1.1 200 OK\r\nX-Note: a\rInjected: evil\r\n\r\nbody";
+        assert!(
+            parse_response_for_cache(response, "GET", "example.com", "/a.js", &cache).is_none()
+        );
+
+        // Well-formed headers are kept intact
+        let response = b"HTTP/1.1 200 OK\r\nX-Note: a\r\n\r\nbody";
+        let cached =
+            parse_response_for_cache(response, "GET", "example.com", "/a.js", &cache).unwrap();
+        assert_eq!(cached.headers, vec!["X-Note: a".to_string()]);
+        assert!(cached.has_safe_headers());
+    }
+
+    #[test]
+    fn test_vary_recorded_on_cached_response() {
+        let cache = ProxyCache::new();
+        let response = b"HTTP/1.1 200 OK\r\nVary: Accept-Encoding\r\n\r\nbody";
+        let cached =
+            parse_response_for_cache(response, "GET", "example.com", "/app.js", &cache).unwrap();
+        assert_eq!(cached.vary, vec!["accept-encoding".to_string()]);
+
+        let response = b"HTTP/1.1 200 OK\r\nVary: *\r\n\r\nbody";
+        assert!(
+            parse_response_for_cache(response, "GET", "example.com", "/app.js", &cache).is_none()
+        );
+    }
+
+    #[test]
+    fn test_vary_cookie_not_cached_by_default() {
+        let response = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\nVary: Cookie\r\n\r\nbody";
+        let cache = ProxyCache::new();
+        assert!(
+            parse_response_for_cache(response, "GET", "example.com", "/app.js", &cache).is_none()
+        );
+
+        let cache = ProxyCache::new().with_vary_policy(VaryPolicy {
+            allow_cookie: true,
+            ..VaryPolicy::default()
+        });
+        let cached =
+            parse_response_for_cache(response, "GET", "example.com", "/app.js", &cache).unwrap();
+        assert_eq!(cached.vary, vec!["cookie".to_string()]);
+    }
+
+    #[test]
+    fn test_validators_recorded_on_cached_response() {
+        let cache = ProxyCache::new();
+        let response = b"HTTP/1.1 200 OK\r\nETag: \"abc\"\r\nLast-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\nbody";
+        let cached =
+            parse_response_for_cache(response, "GET", "example.com", "/a.png", &cache).unwrap();
+        assert_eq!(cached.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(
+            cached.last_modified.as_deref(),
+            Some("Sun, 06 Nov 1994 08:49:37 GMT")
+        );
+    }
+
+    #[test]
+    fn test_chunked_response_cached_decoded() {
+        let cache = ProxyCache::new();
+        let response =
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nTransfer-Encoding: chunked\r\n\r\n\
+            2\r\nh1\r\ne\r\n{ color: red }\r\n0\r\nX-Trailer: done\r\n\r\n";
+        let cached =
+            parse_response_for_cache(response, "GET", "example.com", "/site.css", &cache).unwrap();
+
+        assert_eq!(cached.body, Bytes::from("h1{ color: red }"));
+        assert_eq!(header_value(&cached.headers, "transfer-encoding"), None);
+        assert_eq!(header_value(&cached.headers, "content-length"), Some("16"));
+        assert_eq!(
+            header_value(&cached.headers, "content-type"),
+            Some("text/css")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_chunked_gzip_response_cached_encoded() {
+        let gzipped = crate::compress::gzip(b"body { color: red }");
+        let chunked = |head: &str| {
+            let (first, rest) = gzipped.split_at(10);
+            let mut response =
+                format!("HTTP/1.1 200 OK\r\nContent-Type: text/css\r\n{}\r\n", head).into_bytes();
+            for chunk in [first, rest] {
+                response.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+                response.extend_from_slice(chunk);
+                response.extend_from_slice(b"\r\n");
+            }
+            response.extend_from_slice(b"0\r\n\r\n");
+            response
+        };
+        let cache = ProxyCache::with_config(ProxyCacheConfig {
+            gzip_transfer_coding: true,
+            ..ProxyCacheConfig::default()
+        })
+        .unwrap();
+        let length = gzipped.len().to_string();
+
+        // Chunks come off first, the gzip body is stored as sent
+        for head in [
+            "Content-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n",
+            "Transfer-Encoding: gzip, chunked\r\n",
+        ] {
+            let cached =
+                parse_response_for_cache(&chunked(head), "GET", "example.com", "/a.css", &cache)
+                    .unwrap();
+            assert_eq!(&cached.body[..], &gzipped[..], "{}", head);
+            assert_eq!(
+                header_value(&cached.headers, "content-encoding"),
+                Some("gzip")
+            );
+            assert_eq!(header_value(&cached.headers, "transfer-encoding"), None);
+            assert_eq!(
+                header_value(&cached.headers, "content-length"),
+                Some(length.as_str())
+            );
+
+            let (mut writer, mut reader) = tokio::io::duplex(1 << 16);
+            let accepts_gzip = ["Accept-Encoding: gzip".to_string()];
+            let served = serve_cached_response(
+                &mut writer,
+                Arc::new(cached.clone()),
+                &accepts_gzip,
+                &cache,
+                ReplyHeaders::default(),
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(served, (200, gzipped.len()));
+            drop(writer);
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            let head_end = find_headers_end(&received).unwrap();
+            let head = String::from_utf8_lossy(&received[..head_end]);
+            assert!(head.contains(&format!("\r\nContent-Length: {}\r\n", length)));
+            assert!(!head.contains("Transfer-Encoding"));
+            assert_eq!(&received[head_end..], &gzipped[..]);
+            let decoded = serve_with_range(cached, &["Accept-Encoding: identity"]).await;
+            assert!(
+                decoded.ends_with("\r\n\r\nbody { color: red }"),
+                "{}",
+                decoded
+            );
+        }
+
+        // Without the option a gzip transfer coding is still passed through
+        let layered = chunked("Transfer-Encoding: gzip, chunked\r\n");
+        assert!(parse_response_for_cache(
+            &layered,
+            "GET",
+            "example.com",
+            "/a.css",
+            &ProxyCache::new()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_incomplete_chunked_response_not_cached() {
+        let cache = ProxyCache::new();
+        let truncated =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nbody\r\n7\r\n{ }";
+        assert!(
+            parse_response_for_cache(truncated, "GET", "example.com", "/site.css", &cache)
+                .is_none()
+        );
+        let layered = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n";
+        assert!(
+            parse_response_for_cache(layered, "GET", "example.com", "/site.css", &cache).is_none()
+        );
+    }
+
+    #[test]
+    fn test_uncacheable_responses_skip_full_parse() {
+        let cache = ProxyCache::new();
+        let parses = || FULL_PARSES.with(std::cell::Cell::get);
+        let before = parses();
+
+        let no_store =
+            b"HTTP/1.1 200 OK\r\nCache-Control: No-Store\r\nContent-Type: image/png\r\n\r\nbody";
+        assert!(
+            parse_response_for_cache(no_store, "GET", "example.com", "/a.png", &cache).is_none()
+        );
+        let cookie = b"HTTP/1.1 200 OK\r\nSet-Cookie: session=1\r\n\r\nbody";
+        assert!(parse_response_for_cache(cookie, "GET", "example.com", "/a.png", &cache).is_none());
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\r\nbody";
+        assert!(parse_response_for_cache(ok, "POST", "example.com", "/a.png", &cache).is_none());
+        assert_eq!(parses(), before);
+
+        assert!(parse_response_for_cache(ok, "GET", "example.com", "/a.png", &cache).is_some());
+        assert_eq!(parses(), before + 1);
+    }
+
+    #[test]
+    fn test_build_conditional_request() {
+        let request = b"GET /a.png HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let stale = CachedResponse {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some("Sun, 06 Nov 1994 08:49:37 GMT".to_string()),
+            ..Default::default()
+        };
+
+        let conditional =
+            build_conditional_request(request, &["Host: example.com".to_string()], &stale).unwrap();
+        assert_eq!(
+            String::from_utf8(conditional).unwrap(),
+            "GET /a.png HTTP/1.1\r\nHost: example.com\r\nIf-None-Match: \"abc\"\r\n\
+             If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n\r\n"
+        );
+
+        // Client-supplied validators are left alone
+        let headers = vec!["If-None-Match: \"xyz\"".to_string()];
+        assert!(build_conditional_request(request, &headers, &stale).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_synthesized_etag_answers_if_none_match() {
+        let (addr, requests) = spawn_upstream(vec![b"HTTP/1.1 200 OK\r\n\
+            Cache-Control: max-age=600\r\nContent-Length: 5\r\n\r\nhello"
+            .to_vec()])
+        .await;
+        let cache = ProxyCache::with_config(ProxyCacheConfig {
+            synthesize_etags: true,
+            ..ProxyCacheConfig::default()
+        })
+        .unwrap();
+        let request = |extra: &str| {
+            format!(
+                "GET /greeting.txt HTTP/1.1\r\nHost: {}\r\n{}\r\n",
+                addr, extra
+            )
+        };
+
+        proxy_request(&cache, &request("")).await;
+        let hit = proxy_request(&cache, &request("")).await;
+        let etag = body_etag(b"hello");
+        assert!(hit.contains(&format!("\r\nETag: {}\r\n", etag)), "{}", hit);
+
+        let not_modified =
+            proxy_request(&cache, &request(&format!("If-None-Match: W/{}\r\n", etag))).await;
+        assert!(not_modified.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(not_modified.contains("\r\nCache-Control: max-age=600\r\n"));
+        assert!(!not_modified.contains("Content-Length"));
+        assert!(not_modified.ends_with("\r\n\r\n"), "{}", not_modified);
+
+        let changed = proxy_request(&cache, &request("If-None-Match: \"other\"\r\n")).await;
+        assert!(changed.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(changed.ends_with("hello"));
+        assert_eq!(requests.lock().await.len(), 1);
+
+        // Upstream never sees a tag it didn't send
+        let entry = parse_response_for_cache(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            "GET",
+            "example.com",
+            "/a.png",
+            &cache,
+        )
+        .unwrap();
+        assert_eq!(entry.etag, Some(etag));
+        assert!(!entry.has_validators());
+        let conditional = build_conditional_request(
+            b"GET /a.png HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            &[],
+            &entry,
+        )
+        .unwrap();
+        assert!(!String::from_utf8(conditional)
+            .unwrap()
+            .contains("If-None-Match"));
+    }
+
+    #[tokio::test]
+    async fn test_stale_entry_revalidated_with_304() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 304 Not Modified\r\nCache-Control: max-age=600\r\n\r\n".to_vec(),
+        ])
+        .await;
+        let host = addr.ip().to_string();
+        let cache = ProxyCache::new();
+        let key = cache.lookup_key(&host, addr.port(), "/logo.png", &[]).await;
+        cache
+            .put(
+                key,
+                CachedResponse {
+                    status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+                    headers: vec!["ETag: \"v1\"".to_string(), "Content-Length: 6".to_string()],
+                    body: Bytes::from("cached"),
+                    expires: 1,
+                    etag: Some("\"v1\"".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        let response = proxy_request(
+            &cache,
+            &format!("GET /logo.png HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+        )
+        .await;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\ncached"));
+        assert!(requests.lock().await[0].contains("If-None-Match: \"v1\"\r\n"));
+        match cache.lookup(key).await {
+            CacheLookup::Fresh(entry) => assert!(entry.expires >= now() + 599),
+            other => panic!("expected refreshed entry, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bypass_header_forces_a_miss() {
+        let fresh = |body: &str| {
+            format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .into_bytes()
+        };
+        let (addr, requests) =
+            spawn_upstream(vec![fresh("old"), fresh("new"), fresh("newer")]).await;
+        let config = |store_bypassed| ProxyConfig {
+            bypass_header: Some("X-Bypass-Cache".to_string()),
+            store_bypassed,
+            ..ProxyConfig::default()
+        };
+        let (cache, pool) = (ProxyCache::new(), ConnectionPool::new());
+        let plain = format!("GET /app.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        let bypassing = format!(
+            "GET /app.js HTTP/1.1\r\nHost: {}\r\nx-bypass-cache: 1\r\n\r\n",
+            addr
+        );
+        let fetch = |config, request: &String| {
+            let (cache, pool, request) = (cache.clone(), pool.clone(), request.clone());
+            async move { proxy_request_with(&cache, &pool, config, &request).await }
+        };
+
+        assert!(fetch(config(true), &plain).await.ends_with("old"));
+        // A fresh entry exists, the header still goes upstream and stores the result
+        assert!(fetch(config(true), &bypassing).await.ends_with("new"));
+        assert!(fetch(config(true), &plain).await.ends_with("new"));
+        // Without storing, the cached copy is left alone
+        assert!(fetch(config(false), &bypassing).await.ends_with("newer"));
+        assert!(fetch(config(false), &plain).await.ends_with("new"));
+        assert_eq!(requests.lock().await.len(), 3);
+    }
+
+    #[test]
+    fn test_stale_on_arrival_stored_for_revalidation() {
+        let cache = ProxyCache::new();
+        let parse = |cache_control: &str, validator: &str| {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: {}\r\n{}Content-Length: 4\r\n\r\nbody",
+                cache_control, validator
+            );
+            parse_response_for_cache(response.as_bytes(), "GET", "example.com", "/app.js", &cache)
+        };
+        let etag = "ETag: \"v1\"\r\n";
+
+        // max-age=0 may still be served stale if upstream is down
+        let entry = parse("max-age=0", etag).unwrap();
+        assert!(entry.always_revalidate && !entry.must_revalidate);
+        assert!(!entry.is_fresh_at(entry.stored_at));
+        assert!(entry.expires >= entry.stored_at + cache.config().default_ttl);
+
+        // no-cache never may be used without revalidating, not even stale
+        let entry = parse(
+            "no-cache, max-age=600",
+            "Last-Modified: Tue, 01 Jul 2025 10:00:00 GMT\r\n",
+        )
+        .unwrap();
+        assert!(entry.always_revalidate && entry.must_revalidate);
+        // Whatever the path, no-cache means revalidate rather than don't store
+        let response = b"HTTP/1.1 200 OK\r\nCache-Control: no-cache\r\nETag: \"v1\"\r\n\r\n";
+        let entry =
+            parse_response_for_cache(response, "GET", "example.com", "/api/items", &cache).unwrap();
+        assert!(entry.always_revalidate && entry.must_revalidate);
+
+        // must-revalidate only applies once the entry expires
+        let entry = parse("max-age=600, must-revalidate", etag).unwrap();
+        assert!(!entry.always_revalidate && entry.must_revalidate);
+        assert!(entry.is_fresh_at(entry.stored_at));
+
+        // Without a validator there is nothing to revalidate with
+        assert!(parse("max-age=0", "").is_none());
+        assert!(parse("no-cache", "").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_no_cache_entry_revalidated_on_every_use() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: no-cache\r\nETag: \"v1\"\r\nContent-Length: 5\r\n\r\nhello"
+                .to_vec(),
+            b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n".to_vec(),
+            b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n".to_vec(),
+        ])
+        .await;
+        let cache = ProxyCache::new();
+        let request = format!("GET /app.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+
+        for _ in 0..3 {
+            let response = proxy_request(&cache, &request).await;
+            assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+        }
+        let requests = requests.lock().await;
+        assert_eq!(requests.len(), 3);
+        assert!(!requests[0].contains("If-None-Match"));
+        assert!(requests[1..]
+            .iter()
+            .all(|r| r.contains("If-None-Match: \"v1\"\r\n")));
+
+        // get never hands out the entry as fresh, but leaves it for lookup
+        let key = cache
+            .lookup_key(&addr.ip().to_string(), addr.port(), "/app.js", &[])
+            .await;
+        assert!(cache.get(key).await.is_none());
+        assert!(matches!(cache.lookup(key).await, CacheLookup::Stale(_)));
+    }
+
+    #[tokio::test]
+    async fn test_no_cache_without_validators_not_stored() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nCache-Control: no-cache\r\nContent-Length: 5\r\n\r\nhello";
+        // A synthesized ETag can't be revalidated upstream either
+        for synthesize_etags in [false, true] {
+            let (addr, requests) = spawn_upstream(vec![response.to_vec(), response.to_vec()]).await;
+            let cache = ProxyCache::with_config(ProxyCacheConfig {
+                synthesize_etags,
+                ..ProxyCacheConfig::default()
+            })
+            .unwrap();
+            let request = format!("GET /feed HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+
+            for _ in 0..2 {
+                let response = proxy_request(&cache, &request).await;
+                assert!(response.ends_with("\r\n\r\nhello"), "{}", response);
+                assert_eq!(cache.len().await, 0);
+            }
+            let requests = requests.lock().await;
+            assert_eq!(requests.len(), 2);
+            assert!(!requests[1].contains("If-None-Match"));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_revalidate_entry_never_served_stale() {
+        // Reserve a port with nothing listening so upstream connects fail
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let host = addr.ip().to_string();
+        let stale = |headers: Vec<String>| {
+            let must_revalidate = CacheControl::parse(&headers).forbids_stale();
+            CachedResponse {
+                status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+                headers,
+                body: Bytes::from("stale copy"),
+                expires: 1,
+                etag: Some("\"v1\"".to_string()),
+                must_revalidate,
+                ..Default::default()
+            }
+        };
+        let request = format!("GET /app.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+
+        let cache = ProxyCache::new();
+        let key = cache.lookup_key(&host, addr.port(), "/app.js", &[]).await;
+        cache
+            .put(key, stale(vec!["Cache-Control: max-age=60".to_string()]))
+            .await;
+        let response = proxy_request(&cache, &request).await;
+        assert!(response.ends_with("\r\n\r\nstale copy"));
+
+        let cache = ProxyCache::new();
+        cache
+            .put(
+                key,
+                stale(vec![
+                    "Cache-Control: max-age=60, proxy-revalidate".to_string()
+                ]),
+            )
+            .await;
+        let response = proxy_request(&cache, &request).await;
+        assert!(response.starts_with("HTTP/1.1 502 Bad Gateway"));
+    }
+
+    #[test]
+    fn test_gzip_entry_transcoded_to_brotli() {
+        let css = Bytes::from(".nav { margin: 0 auto; }\n".repeat(200));
+        let (headers, body) = compress_response(
+            vec!["Content-Type: text/css".to_string()],
+            css.clone(),
+            "/site.css",
+        );
+        let mut headers = headers;
+        headers.push("ETag: \"v1\"".to_string());
+        let cached = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers,
+            body,
+            etag: Some("\"v1\"".to_string()),
+            ..Default::default()
+        };
+        let accept = |value: &str| vec![format!("Accept-Encoding: {}", value)];
+
+        assert_eq!(transcoding_for_client(&cached, &accept("br")), Some("br"));
+        let transcoded = transcode_entry(&cached, "br").unwrap();
+        assert_eq!(
+            header_value(&transcoded.headers, "content-encoding"),
+            Some("br")
+        );
+        assert_eq!(
+            header_value(&transcoded.headers, "content-length"),
+            Some(transcoded.body.len().to_string().as_str())
+        );
+        assert_eq!(
+            header_value(&transcoded.headers, "vary"),
+            Some("Accept-Encoding")
+        );
+        assert_eq!(unbrotli(&transcoded.body, css.len()).unwrap(), css.to_vec());
+        // Other bytes, another strong validator
+        assert_eq!(header_value(&transcoded.headers, "etag"), Some("\"v1-br\""));
+        assert_eq!(transcoded.etag.as_deref(), Some("\"v1-br\""));
+
+        // Gzip is served as stored to clients that rank it first or say nothing
+        assert_eq!(
+            transcoding_for_client(&cached, &accept("gzip, br;q=0.9")),
+            None
+        );
+        assert_eq!(transcoding_for_client(&cached, &[]), None);
+        assert_eq!(
+            transcoding_for_client(&cached, &accept("identity")),
+            Some("identity")
+        );
+        let decoded = transcode_entry(&cached, "identity").unwrap();
+        assert_eq!(header_value(&decoded.headers, "content-encoding"), None);
+        assert_eq!(decoded.body, css);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_entry_decoded_for_client_without_gzip() {
+        let css = ".nav { margin: 0 auto; }\n".repeat(200);
+        let (addr, _) = spawn_upstream(vec![format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/css\r\nContent-Length: {}\r\n\r\n{}",
+            css.len(),
+            css
+        )
+        .into_bytes()])
+        .await;
+        let cache = ProxyCache::with_config(ProxyCacheConfig {
+            compress: true,
+            ..ProxyCacheConfig::default()
+        })
+        .unwrap();
+        let request = |accept: &str| {
+            format!(
+                "GET /site.css HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: {}\r\n\r\n",
+                addr, accept
+            )
+        };
+
+        // The miss is relayed as the origin sent it and stored gzipped
+        let response = proxy_request(&cache, &request("gzip")).await;
+        assert!(response.ends_with(&css));
+        assert!(cache.total_size() < css.len() / 4);
+
+        let response = proxy_request(&cache, &request("gzip, br;q=0.5")).await;
+        assert!(response.contains("\r\nContent-Encoding: gzip\r\n"));
+        assert!(!response.ends_with(&css));
+
+        // Clients preferring brotli get the stored gzip re-encoded
+        let response = proxy_request(&cache, &request("gzip, br")).await;
+        assert!(response.contains("\r\nContent-Encoding: br\r\n"));
+        assert_eq!(response.matches("Content-Encoding").count(), 1);
+        assert!(!response.ends_with(&css));
+
+        let response = proxy_request(&cache, &request("identity")).await;
+        assert!(!response.contains("Content-Encoding"));
+        assert!(response.contains(&format!("\r\nContent-Length: {}\r\n", css.len())));
+        assert!(response.ends_with(&css));
+    }
+
+    #[tokio::test]
+    async fn test_refresher_keeps_entry_fresh_without_clients() {
+        let refreshed =
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\nContent-Length: 2\r\n\r\nv2"
+                .to_vec();
+        let (addr, requests) = spawn_upstream(vec![refreshed; 3]).await;
+        let host = addr.ip().to_string();
+        let cache = ProxyCache::new();
+        let key = cache
+            .lookup_key(&host, addr.port(), "/status.json", &[])
+            .await;
+        let stored_at = now() - 100;
+        cache
+            .put(
+                key,
+                CachedResponse {
+                    status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+                    body: Bytes::from("v1"),
+                    expires: stored_at + 600,
+                    stored_at,
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        let refresher = spawn_refresher(
+            cache.clone(),
+            ConnectionPool::new(),
+            RefreshSchedule {
+                urls: vec![format!("http://{}/status.json", addr)],
+                interval: Duration::from_millis(20),
+                remaining_fraction: None,
+                min_ttl: None,
+            },
+            None,
+        );
+        for _ in 0..100 {
+            if requests.lock().await.len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        refresher.abort();
+
+        let entry = cache.peek(key).await.unwrap();
+        assert_eq!(entry.body, Bytes::from("v2"));
+        assert!(entry.stored_at > stored_at);
+        assert!(requests.lock().await.len() >= 2);
+        assert_eq!(cache.stats().hits + cache.stats().misses, 0);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_caches_cacheable_urls() {
+        // Every path gets the same response, only the extension decides
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec();
+        let (addr, requests) = spawn_upstream(vec![response; 3]).await;
+        let host = addr.ip().to_string();
+        let cache = ProxyCache::new();
+        let urls = vec![
+            (host.clone(), addr.port(), "/app.css".to_string()),
+            (host.clone(), addr.port(), "/app.js".to_string()),
+            (host.clone(), addr.port(), "/api/items".to_string()),
+            // Nothing listens on port 1
+            (host.clone(), 1, "/down.css".to_string()),
+        ];
+
+        assert_eq!(prefetch(&urls, &ConnectionPool::new(), &cache).await, 2);
+        assert_eq!(requests.lock().await.len(), 3);
+        assert_eq!(cache.len().await, 2);
+        for path in ["/app.css", "/app.js"] {
+            let key = cache.lookup_key(&host, addr.port(), path, &[]).await;
+            assert_eq!(&cache.get(key).await.unwrap().body[..], b"ok");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_lived_entries_not_refreshed() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=3600\r\nContent-Length: 2\r\n\r\nv2"
+                .to_vec(),
+        ])
+        .await;
+        let host = addr.ip().to_string();
+        let cache = ProxyCache::new();
+        let stored_at = now();
+        for (path, ttl) in [("/short", 30), ("/long", 3600)] {
+            let key = cache.lookup_key(&host, addr.port(), path, &[]).await;
+            let entry = CachedResponse {
+                status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+                body: Bytes::from("v1"),
+                expires: stored_at + ttl,
+                stored_at,
+                ..Default::default()
+            };
+            cache.put(key, entry).await;
+        }
+        let schedule = RefreshSchedule {
+            urls: Vec::new(),
+            interval: Duration::from_secs(1),
+            remaining_fraction: None,
+            min_ttl: Some(300),
+        };
+        let pool = ConnectionPool::new();
+
+        for _ in 0..3 {
+            let url = format!("http://{}/short", addr);
+            assert!(!refresh_url(&cache, &pool, &url, &schedule).await);
+        }
+        assert!(requests.lock().await.is_empty());
+
+        let url = format!("http://{}/long", addr);
+        assert!(refresh_url(&cache, &pool, &url, &schedule).await);
+        assert_eq!(requests.lock().await.len(), 1);
+        assert!(requests.lock().await[0].starts_with("GET /long "));
+
+        // Without a minimum every entry is due
+        let entry = CachedResponse {
+            stored_at,
+            expires: stored_at + 30,
+            ..Default::default()
+        };
+        let unlimited = RefreshSchedule {
+            min_ttl: None,
+            ..schedule
+        };
+        assert!(refresh_due(&entry, &unlimited, stored_at));
+    }
+
+    #[test]
+    fn test_refresh_schedule_helpers() {
+        let entry = CachedResponse {
+            stored_at: 1_000,
+            expires: 2_000,
+            ..Default::default()
+        };
+        assert!(!needs_refresh(&entry, 0.25, 1_700));
+        assert!(needs_refresh(&entry, 0.25, 1_800));
+        assert!(needs_refresh(&entry, 0.25, 2_500));
+
+        assert_eq!(
+            parse_refresh_url("http://cdn.example.com:8080/app.js"),
+            Some(("cdn.example.com".to_string(), 8080, "/app.js".to_string()))
+        );
+        assert_eq!(
+            parse_refresh_url("example.com"),
+            Some(("example.com".to_string(), 80, "/".to_string()))
+        );
+        assert_eq!(
+            parse_refresh_url("http://[::1]:8080/app.js"),
+            Some(("::1".to_string(), 8080, "/app.js".to_string()))
+        );
+        assert_eq!(
+            parse_refresh_url("[fd00::2]/app.js"),
+            Some(("fd00::2".to_string(), 80, "/app.js".to_string()))
+        );
+        assert!(parse_refresh_url("[::1]:http/").is_none());
+        assert!(parse_refresh_url("example.com:http/").is_none());
+        assert!(parse_refresh_url("http:///x").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_upstream_connection_is_reused() {
+        // One socket, two framed responses, and no second accept
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&accepts);
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let responses: [&[u8]; 2] = [
+                b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\none",
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\ntwo\r\n0\r\n\r\n",
+            ];
+            for response in responses {
+                let mut buffer = BytesMut::new();
+                while find_headers_end(&buffer).is_none() {
+                    if stream.read_buf(&mut buffer).await.unwrap() == 0 {
+                        return;
+                    }
+                }
+                stream.write_all(response).await.unwrap();
+            }
+            // Hold the socket open like a real keep-alive server
+            let _ = stream.read_buf(&mut BytesMut::new()).await;
+        });
+
+        let cache = ProxyCache::new();
+        let pool = ConnectionPool::new();
+        let request = |path: &str| format!("GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr);
+
+        let first = proxy_request_via(&cache, &pool, &request("/api/one")).await;
+        assert!(first.ends_with("\r\n\r\none"));
+        let key = (addr.ip().to_string(), addr.port());
+        assert_eq!(pool.stats().await.get(&key), Some(&1));
+
+        let second = proxy_request_via(&cache, &pool, &request("/api/two")).await;
+        assert!(second.ends_with("3\r\ntwo\r\n0\r\n\r\n"));
+        assert_eq!(accepts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests_capped_per_connection() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 5\r\n\r\nhello"
+                .to_vec(),
+        ])
+        .await;
+        let config = ProxyConfig {
+            max_requests_per_connection: 3,
+            ..ProxyConfig::default()
+        };
+        // Five requests in one write, the first a miss and the rest cache hits
+        let pipelined = format!("GET /page HTTP/1.1\r\nHost: {}\r\n\r\n", addr).repeat(5);
+
+        let received = proxy_request_with(
+            &ProxyCache::new(),
+            &ConnectionPool::new(),
+            config,
+            &pipelined,
+        )
+        .await;
+        let responses: Vec<&str> = received.split("HTTP/1.1 200 OK\r\n").skip(1).collect();
+        assert_eq!(responses.len(), 3, "{}", received);
+        for response in &responses[..2] {
+            assert!(
+                response.contains("Connection: keep-alive\r\n"),
+                "{}",
+                response
+            );
+            assert!(response.ends_with("\r\n\r\nhello"));
+        }
+        assert!(responses[2].contains("Connection: close\r\n"));
+        assert!(responses[2].ends_with("\r\n\r\nhello"));
+        assert_eq!(requests.lock().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests_after_body_kept() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 201 Created\r\nContent-Length: 7\r\n\r\ncreated".to_vec(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nnext".to_vec(),
+        ])
+        .await;
+        // The GET follows the POST body in the same write
+        let pipelined = format!(
+            "POST /submit HTTP/1.1\r\nHost: {0}\r\nContent-Length: 4\r\n\r\nping\
+             GET /next HTTP/1.1\r\nHost: {0}\r\n\r\n",
+            addr
+        );
+
+        let config = ProxyConfig {
+            max_requests_per_connection: 2,
+            ..ProxyConfig::default()
+        };
+
+        let received = proxy_request_with(
+            &ProxyCache::new(),
+            &ConnectionPool::new(),
+            config,
+            &pipelined,
+        )
+        .await;
+        let created = received.find("HTTP/1.1 201 Created\r\n").unwrap();
+        let next = received.find("HTTP/1.1 200 OK\r\n").unwrap();
+        assert!(created < next, "{}", received);
+        assert!(received[..next].ends_with("\r\n\r\ncreated"));
+        assert!(received.ends_with("\r\n\r\nnext"));
+
+        let requests = requests.lock().await;
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("POST /submit HTTP/1.1\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\nping"), "{}", requests[0]);
+        assert!(requests[1].starts_with("GET /next HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_head_served_from_cached_get() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 5\r\n\r\nhello"
+                .to_vec(),
+        ])
+        .await;
+        let cache = ProxyCache::new();
+        let get = proxy_request(
+            &cache,
+            &format!("GET /image.jpg HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+        )
+        .await;
+        assert!(get.ends_with("\r\n\r\nhello"));
+
+        let head = proxy_request(
+            &cache,
+            &format!("HEAD /image.jpg HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("\r\nContent-Length: 5\r\n"));
+        assert!(head.ends_with("\r\n\r\n"), "{:?}", head);
+        assert_eq!(requests.lock().await.len(), 1);
+    }
+
+    /// Log sink that stays readable after it is handed over
+    #[derive(Clone, Default)]
+    struct LogSink(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_access_log_line_per_request() {
+        let (addr, _) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 5\r\n\r\nhello"
+                .to_vec(),
+        ])
+        .await;
+        let sink = LogSink::default();
+        let config = ProxyConfig {
+            max_requests_per_connection: 3,
+            access_log: Some(AccessLog::new(sink.clone())),
+            ..ProxyConfig::default()
+        };
+        let requests = format!(
+            "GET /a.js HTTP/1.1\r\nHost: {0}\r\n\r\nHEAD /a.js HTTP/1.1\r\nHost: {0}\r\n\r\nGET /a.js HTTP/1.1\r\n\r\n",
+            addr
+        );
+        proxy_request_with(
+            &ProxyCache::new(),
+            &ConnectionPool::new(),
+            config,
+            &requests,
+        )
+        .await;
+
+        let log = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 3, "{}", log);
+        assert!(lines[0].starts_with("127.0.0.1 - - ["), "{}", lines[0]);
+        assert!(lines[0].contains("] \"GET /a.js HTTP/1.1\" 200 5 MISS "));
+        assert!(
+            !lines[0].ends_with(" -"),
+            "no upstream latency: {}",
+            lines[0]
+        );
+        assert!(
+            lines[1].ends_with("\"HEAD /a.js HTTP/1.1\" 200 - HIT -"),
+            "{}",
+            lines[1]
+        );
+        // The missing Host is refused before anything is looked up
+        assert!(
+            lines[2].ends_with("\"GET /a.js HTTP/1.1\" 400 - MISS -"),
+            "{}",
+            lines[2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hop_by_hop_headers_not_forwarded_or_cached() {
+        let (addr, requests) = spawn_upstream(vec![b"HTTP/1.1 200 OK\r\n\
+            Cache-Control: max-age=60\r\n\
+            Connection: X-Upstream-Hop\r\n\
+            X-Upstream-Hop: 1\r\n\
+            Keep-Alive: timeout=5\r\n\
+            Content-Length: 2\r\n\r\nok"
+            .to_vec()])
+        .await;
+        let cache = ProxyCache::new();
+        proxy_request(
+            &cache,
+            &format!(
+                "GET /a.js HTTP/1.1\r\nHost: {}\r\nConnection: X-Custom\r\nX-Custom: secret\r\n\
+                 Proxy-Authorization: Basic Zm9vOmJhcg==\r\nTE: trailers\r\nAccept: */*\r\n\r\n",
+                addr
+            ),
+        )
+        .await;
+
+        let forwarded = requests.lock().await[0].to_ascii_lowercase();
+        for name in ["connection:", "x-custom:", "proxy-authorization:", "te:"] {
+            assert!(
+                !forwarded.contains(name),
+                "{} forwarded: {}",
+                name,
+                forwarded
+            );
+        }
+        assert!(forwarded.contains("\r\naccept: */*\r\n"));
+
+        let key = cache
+            .lookup_key("127.0.0.1", addr.port(), "/a.js", &[])
+            .await;
+        let entry = cache.peek(key).await.unwrap();
+        assert_eq!(
+            entry.headers,
+            ["Cache-Control: max-age=60", "Content-Length: 2"]
+        );
+    }
+
+    #[test]
+    fn test_without_hop_by_hop_keeps_body_framing() {
+        let request = b"POST /upload HTTP/1.1\r\nHost: a\r\nConnection: keep-alive\r\n\
+            Transfer-Encoding: chunked\r\nX-Raw: \xff\r\n\r\n2\r\nok\r\n0\r\n\r\n";
+        assert_eq!(
+            without_hop_by_hop(request),
+            b"POST /upload HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\
+              X-Raw: \xff\r\n\r\n2\r\nok\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_force_cached_response_rewrites_freshness() {
+        let rule = |set_cookie| ForceCacheRule {
+            host: "a".to_string(),
+            path_prefix: "/".to_string(),
+            ttl: 600,
+            set_cookie,
+        };
+        let private = b"HTTP/1.1 200 OK\r\nCache-Control: private, no-store\r\n\
+            Pragma: no-cache\r\nExpires: 0\r\nContent-Length: 2\r\n\r\nok";
+        assert_eq!(
+            force_cached_response(private, &rule(SetCookiePolicy::Refuse)).unwrap(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nCache-Control: max-age=600\r\n\r\nok"
+        );
+
+        let cookie = b"HTTP/1.1 200 OK\r\nSet-Cookie: id=1\r\nContent-Length: 2\r\n\r\nok";
+        assert_eq!(
+            force_cached_response(cookie, &rule(SetCookiePolicy::Refuse)),
+            None
+        );
+        assert_eq!(
+            force_cached_response(cookie, &rule(SetCookiePolicy::Strip)).unwrap(),
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nCache-Control: max-age=600\r\n\r\nok"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_force_cache_rule_set_cookie_policy() {
+        let response = b"HTTP/1.1 200 OK\r\nCache-Control: private\r\n\
+            Set-Cookie: session=abc\r\nContent-Length: 2\r\n\r\nok";
+        for response_mode in [
+            ResponseMode::Buffered,
+            ResponseMode::Streaming,
+            ResponseMode::Tee,
+        ] {
+            for set_cookie in [SetCookiePolicy::Refuse, SetCookiePolicy::Strip] {
+                let (addr, requests) =
+                    spawn_upstream(vec![response.to_vec(), response.to_vec()]).await;
+                let config = ProxyConfig {
+                    response_mode,
+                    force_cache: vec![ForceCacheRule {
+                        host: "127.0.0.1".to_string(),
+                        path_prefix: "/static/".to_string(),
+                        ttl: 600,
+                        set_cookie,
+                    }],
+                    ..ProxyConfig::default()
+                };
+                let cache = ProxyCache::new();
+                let request = format!("GET /static/a.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+                let pool = ConnectionPool::new();
+                let fetch = || proxy_request_with(&cache, &pool, config.clone(), &request);
+
+                // The client fetching it from upstream still gets its cookie
+                assert!(fetch().await.contains("\r\nSet-Cookie: session=abc\r\n"));
+                let second = fetch().await;
+                match set_cookie {
+                    SetCookiePolicy::Refuse => {
+                        assert_eq!(cache.len().await, 0);
+                        assert_eq!(requests.lock().await.len(), 2);
+                    }
+                    SetCookiePolicy::Strip => {
+                        assert_eq!(requests.lock().await.len(), 1, "{:?}", response_mode);
+                        assert!(second.starts_with("HTTP/1.1 200 OK\r\n"));
+                        assert!(!second.contains("Set-Cookie"), "{}", second);
+                        assert!(second.contains("\r\nCache-Control: max-age=600\r\n"));
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_large_responses_streamed_before_fully_read() {
+        const LEN: usize = 12 * 1024 * 1024;
+        const FIRST: usize = 1024 * 1024;
+        for response_mode in [
+            ResponseMode::Buffered,
+            ResponseMode::Streaming,
+            ResponseMode::Tee,
+        ] {
+            let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = upstream.local_addr().unwrap();
+            let (client_started, started) = tokio::sync::oneshot::channel::<()>();
+            // Upstream holds back most of the body until the client has seen some
+            let origin = tokio::spawn(async move {
+                let (mut stream, _) = upstream.accept().await.unwrap();
+                let mut request = BytesMut::new();
+                while find_headers_end(&request).is_none() {
+                    stream.read_buf(&mut request).await.unwrap();
+                }
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: {}\r\n\r\n",
+                    LEN
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&vec![b'a'; FIRST]).await.unwrap();
+                started.await.unwrap();
+                stream.write_all(&vec![b'b'; LEN - FIRST]).await.unwrap();
+            });
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let cache = ProxyCache::new();
+            let config = ProxyConfig {
+                response_mode,
+                ..ProxyConfig::default()
+            };
+            let handler = tokio::spawn(handle_client(
+                server,
+                cache.clone(),
+                ConnectionPool::new(),
+                Arc::new(config),
+            ));
+            client
+                .write_all(format!("GET /video.mp4 HTTP/1.1\r\nHost: {}\r\n\r\n", addr).as_bytes())
+                .await
+                .unwrap();
+
+            let mut received = Vec::new();
+            while find_headers_end(&received).map_or(true, |end| received.len() == end) {
+                let read = timeout(Duration::from_secs(5), client.read_buf(&mut received))
+                    .await
+                    .unwrap_or_else(|_| panic!("{:?} waited for the whole body", response_mode));
+                assert!(read.unwrap() > 0);
+            }
+            assert!(received.starts_with(b"HTTP/1.1 200 OK\r\n"));
+            client_started.send(()).unwrap();
+
+            client.read_to_end(&mut received).await.unwrap();
+            handler.await.unwrap();
+            origin.await.unwrap();
+            let body = &received[find_headers_end(&received).unwrap()..];
+            assert_eq!(body.len(), LEN, "{:?}", response_mode);
+            assert!(body[FIRST..].iter().all(|&b| b == b'b'));
+            assert_eq!(cache.len().await, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expect_continue_and_interim_responses() {
+        for response_mode in [
+            ResponseMode::Buffered,
+            ResponseMode::Streaming,
+            ResponseMode::Tee,
+        ] {
+            let created =
+                b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok";
+            let (addr, requests) = spawn_upstream(vec![created.to_vec()]).await;
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (server, _) = listener.accept().await.unwrap();
+            let config = ProxyConfig {
+                response_mode,
+                ..ProxyConfig::default()
+            };
+            let handler = tokio::spawn(handle_client(
+                server,
+                ProxyCache::new(),
+                ConnectionPool::new(),
+                Arc::new(config.clone()),
+            ));
+
+            // The body only follows the go-ahead
+            let head = format!(
+                "POST /upload HTTP/1.1\r\nHost: {}\r\nExpect: 100-continue\r\nContent-Length: 4\r\n\r\n",
+                addr
+            );
+            client.write_all(head.as_bytes()).await.unwrap();
+            let mut received = Vec::new();
+            while !received.ends_with(b"\r\n\r\n") {
+                let read = timeout(Duration::from_secs(5), client.read_buf(&mut received))
+                    .await
+                    .expect("no 100 Continue before the body");
+                assert!(read.unwrap() > 0);
+            }
+            assert_eq!(received, b"HTTP/1.1 100 Continue\r\n\r\n");
+            client.write_all(b"data").await.unwrap();
+            client.read_to_end(&mut received).await.unwrap();
+            handler.await.unwrap();
+
+            // Upstream's own interim response isn't relayed a second time
+            let received = String::from_utf8(received).unwrap();
+            assert_eq!(
+                received.matches("100 Continue").count(),
+                1,
+                "{:?}",
+                response_mode
+            );
+            assert!(received.contains("\r\n\r\nHTTP/1.1 201 Created\r\n"));
+            assert!(received.ends_with("\r\n\r\nok"));
+            assert!(requests.lock().await[0].contains("Content-Length: 4\r\n"));
+
+            // Nor is one cached in place of the final response
+            let cacheable = b"HTTP/1.1 103 Early Hints\r\nLink: </app.css>\r\n\r\n\
+                HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 2\r\n\r\nok";
+            let (addr, _) = spawn_upstream(vec![cacheable.to_vec()]).await;
+            let cache = ProxyCache::new();
+            let request = format!("GET /app.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+            let response =
+                proxy_request_with(&cache, &ConnectionPool::new(), config, &request).await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            let host = addr.ip().to_string();
+            let key = cache.lookup_key(&host, addr.port(), "/app.js", &[]).await;
+            let cached = cache.get(key).await.unwrap();
+            assert_eq!(cached.status_line, "HTTP/1.1 200 OK\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tee_relays_while_caching() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let (client_started, started) = tokio::sync::oneshot::channel::<()>();
+        let (test_done, done) = tokio::sync::oneshot::channel::<()>();
+        // Upstream holds back half the body, then keeps the connection open
+        let origin = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request = BytesMut::new();
+            while find_headers_end(&request).is_none() {
+                stream.read_buf(&mut request).await.unwrap();
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 10\r\n\r\nhello",
+                )
+                .await
+                .unwrap();
+            started.await.unwrap();
+            stream.write_all(b"world").await.unwrap();
+            let _ = done.await;
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let cache = ProxyCache::new();
+        let config = ProxyConfig {
+            response_mode: ResponseMode::Tee,
+            ..ProxyConfig::default()
+        };
+        let handler = tokio::spawn(handle_client(
+            server,
+            cache.clone(),
+            ConnectionPool::new(),
+            Arc::new(config.clone()),
+        ));
+        let request = format!("GET /page.html HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        let mut received = Vec::new();
+        while !received.ends_with(b"hello") {
+            let read = timeout(Duration::from_secs(5), client.read_buf(&mut received))
+                .await
+                .expect("the first half waited for the rest");
+            assert!(read.unwrap() > 0);
+        }
+        client_started.send(()).unwrap();
+        client.read_to_end(&mut received).await.unwrap();
+        handler.await.unwrap();
+        assert!(received.ends_with(b"\r\n\r\nhelloworld"));
+
+        // Cached once the body was complete, without waiting for upstream to close
+        assert_eq!(cache.len().await, 1);
+        let pool = ConnectionPool::new();
+        let cached = proxy_request_with(&cache, &pool, config.clone(), &request).await;
+        assert!(cached.ends_with("helloworld"));
+        test_done.send(()).unwrap();
+        origin.await.unwrap();
+
+        // A copy outgrowing the limit is dropped, the client still gets it all
+        let len = MAX_RESPONSE_SIZE + 1024 * 1024;
+        let mut large = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\r\n".to_vec();
+        large.resize(large.len() + len, b'a');
+        let (addr, _) = spawn_upstream(vec![large]).await;
+        let request = format!("GET /large.mp4 HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        let cache = ProxyCache::new();
+        let response = proxy_request_with(&cache, &ConnectionPool::new(), config, &request).await;
+        assert_eq!(response.len() - response.find("\r\n\r\n").unwrap() - 4, len);
+        assert_eq!(cache.len().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cache_status_headers_on_second_request() {
+        // An upstream cache's own X-Cache header doesn't reach the client
+        let response = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\
+            X-Cache: HIT from origin\r\nContent-Length: 2\r\n\r\nok";
+        for response_mode in [
+            ResponseMode::Buffered,
+            ResponseMode::Streaming,
+            ResponseMode::Tee,
+        ] {
+            let (addr, _) = spawn_upstream(vec![response.to_vec()]).await;
+            let config = ProxyConfig {
+                response_mode,
+                cache_status_headers: true,
+                ..ProxyConfig::default()
+            };
+            let cache = ProxyCache::new();
+            let pool = ConnectionPool::new();
+            let request = format!("GET /status HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+
+            let first = proxy_request_with(&cache, &pool, config.clone(), &request).await;
+            assert!(first.contains("\r\nX-Cache: MISS\r\n"), "{}", first);
+            assert!(!first.contains("from origin"));
+            assert!(!first.contains("X-Cache-Age"));
+
+            let second = proxy_request_with(&cache, &pool, config, &request).await;
+            assert!(second.contains("\r\nX-Cache: HIT\r\n"), "{}", second);
+            assert!(second.contains("\r\nX-Cache-Age: 0\r\n"), "{}", second);
+            assert!(second.ends_with("\r\n\r\nok"));
+        }
+
+        // Off by default
+        let (addr, _) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec()
+        ])
+        .await;
+        let request = format!("GET /plain HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        assert!(!proxy_request(&ProxyCache::new(), &request)
+            .await
+            .contains("X-Cache"));
+    }
+
+    #[tokio::test]
+    async fn test_server_header_on_proxied_responses() {
+        let server = format!("\r\nServer: rustysquid/{}\r\n", env!("CARGO_PKG_VERSION"));
+        let response = b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\
+            Server: origin/1.0\r\nContent-Length: 2\r\n\r\nok";
+        for response_mode in [
+            ResponseMode::Buffered,
+            ResponseMode::Streaming,
+            ResponseMode::Tee,
+        ] {
+            let (addr, _) = spawn_upstream(vec![response.to_vec()]).await;
+            let config = ProxyConfig {
+                response_mode,
+                server_header: true,
+                ..ProxyConfig::default()
+            };
+            let cache = ProxyCache::new();
+            let pool = ConnectionPool::new();
+            let request = format!("GET /server HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+
+            let miss = proxy_request_with(&cache, &pool, config.clone(), &request).await;
+            assert!(miss.contains(&server), "{}", miss);
+            assert!(!miss.contains("origin/1.0"), "{}", miss);
+
+            let hit = proxy_request_with(&cache, &pool, config, &request).await;
+            assert!(hit.contains(&server), "{}", hit);
+            assert!(!hit.contains("origin/1.0"), "{}", hit);
+            assert!(hit.ends_with("\r\n\r\nok"));
+        }
+
+        // Off by default, the upstream's header passes through
+        let (addr, _) = spawn_upstream(vec![response.to_vec()]).await;
+        let request = format!("GET /server HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        let plain = proxy_request(&ProxyCache::new(), &request).await;
+        assert!(plain.contains("\r\nServer: origin/1.0\r\n"), "{}", plain);
+        assert!(!plain.contains("rustysquid"));
+    }
+
+    #[tokio::test]
+    async fn test_cache_key_trace_per_request() {
+        let (addr, _) = spawn_upstream(vec![b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\n\
+            Vary: Accept-Encoding\r\nContent-Length: 2\r\n\r\nok"
+            .to_vec()])
+        .await;
+        let sink = LogSink::default();
+        let writer = sink.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter("rustysquid::cache_key=trace")
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        // The current-thread runtime keeps the handler on this thread
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let cache = ProxyCache::new();
+        let request = format!(
+            "GET /a.js HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: gzip\r\n\r\n",
+            addr
+        );
+        proxy_request(&cache, &request).await;
+        proxy_request(&cache, &request).await;
+
+        let log = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2, "{}", log);
+        let base_key = cache.base_key("127.0.0.1", addr.port(), "/a.js");
+        let variant = cache
+            .lookup_key(
+                "127.0.0.1",
+                addr.port(),
+                "/a.js",
+                &["Accept-Encoding: gzip".to_string()],
+            )
+            .await;
+        assert_ne!(variant, base_key);
+        for (line, variant, decision) in [
+            (lines[0], "None".to_string(), "miss"),
+            (lines[1], format!("Some({})", variant), "hit"),
+        ] {
+            assert!(
+                line.contains(" TRACE rustysquid::cache_key: cache key "),
+                "{}",
+                line
+            );
+            assert!(
+                line.contains(&format!("url=127.0.0.1:{}/a.js ", addr.port())),
+                "{}",
+                line
+            );
+            assert!(line.contains(&format!("key={} ", base_key)), "{}", line);
+            assert!(line.contains(&format!("variant={} ", variant)), "{}", line);
+            assert!(
+                line.contains(&format!("decision=\"{}\"", decision)),
+                "{}",
+                line
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_method_lists_allowed_ones() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec(),
+        ])
+        .await;
+        let config = || ProxyConfig {
+            allowed_methods: Some(vec![
+                "GET".to_string(),
+                "HEAD".to_string(),
+                "POST".to_string(),
+            ]),
+            ..ProxyConfig::default()
+        };
+        let pool = ConnectionPool::new();
+        let cache = ProxyCache::new();
+
+        for request in [
+            format!("DELETE /a HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+            format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", addr, addr),
+        ] {
+            let response = proxy_request_with(&cache, &pool, config(), &request).await;
+            assert_eq!(
+                response,
+                "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, HEAD, POST\r\n\r\n"
+            );
+        }
+        assert!(requests.lock().await.is_empty());
+
+        let allowed = format!(
+            "POST /a HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\n\r\n",
+            addr
+        );
+        let response = proxy_request_with(&cache, &pool, config(), &allowed).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_options_and_trace_for_the_proxy() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 204 No Content\r\nAllow: GET, OPTIONS\r\n\r\n".to_vec(),
+        ])
+        .await;
+        let cache = ProxyCache::new();
+        let pool = ConnectionPool::new();
+        let response =
+            proxy_request_via(&cache, &pool, "OPTIONS * HTTP/1.1\r\nHost: proxy\r\n\r\n").await;
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nAllow: GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS, CONNECT\r\n\
+             Content-Length: 0\r\n\r\n"
+        );
+        // Never reflected, whatever the target
+        for trace in [
+            format!("TRACE http://{}/ HTTP/1.1\r\nHost: {}\r\n\r\n", addr, addr),
+            "TRACE * HTTP/1.1\r\nHost: proxy\r\n\r\n".to_string(),
+        ] {
+            let response = proxy_request_via(&cache, &pool, &trace).await;
+            assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+            assert!(response.contains("\r\nAllow: GET, HEAD,"));
+        }
+        assert!(requests.lock().await.is_empty());
+
+        // OPTIONS for an origin is the origin's to answer
+        let response = proxy_request_via(
+            &cache,
+            &pool,
+            &format!(
+                "OPTIONS http://{}/api HTTP/1.1\r\nHost: {}\r\n\r\n",
+                addr, addr
+            ),
+        )
+        .await;
+        assert!(
+            response.starts_with("HTTP/1.1 204 No Content\r\n"),
+            "{}",
+            response
+        );
+        assert_eq!(requests.lock().await.len(), 1);
+
+        // Configured methods are the ones advertised, TRACE never is
+        let config = ProxyConfig {
+            allowed_methods: Some(vec!["GET".to_string(), "TRACE".to_string()]),
+            ..ProxyConfig::default()
+        };
+        let response = proxy_request_with(
+            &cache,
+            &pool,
+            config,
+            "TRACE / HTTP/1.1\r\nHost: proxy\r\n\r\n",
+        )
+        .await;
+        assert_eq!(
+            response,
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upstream_closing_silently_gets_502() {
+        for response_mode in [
+            ResponseMode::Buffered,
+            ResponseMode::Streaming,
+            ResponseMode::Tee,
+        ] {
+            // Accepts, reads the request and closes without a byte
+            let (addr, requests) = spawn_upstream(vec![Vec::new()]).await;
+            let config = ProxyConfig {
+                response_mode,
+                ..ProxyConfig::default()
+            };
+            let response = proxy_request_with(
+                &ProxyCache::new(),
+                &ConnectionPool::new(),
+                config,
+                &format!("GET /a.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+            )
+            .await;
+            assert_eq!(
+                response, "HTTP/1.1 502 Bad Gateway\r\n\r\n",
+                "{:?}",
+                response_mode
+            );
+            assert_eq!(requests.lock().await.len(), 1);
+        }
+    }
+
+    /// Upstream that reads one request and answers after `delay`
+    async fn spawn_slow_upstream(delay: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = BytesMut::new();
+                    let _ = stream.read_buf(&mut request).await;
+                    tokio::time::sleep(delay).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await;
+                });
+            }
+        });
+        addr
+    }
+
+    /// Sends `data` a byte at a time, `interval` apart, then holds the
+    /// connection open
+    async fn dribble<W: AsyncWrite + Unpin>(stream: &mut W, data: &[u8], interval: Duration) {
+        for byte in data {
+            if stream.write_all(&[*byte]).await.is_err() {
+                return;
+            }
+            tokio::time::sleep(interval).await;
+        }
+        std::future::pending::<()>().await;
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_head_dropped_at_deadline() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let config = ProxyConfig {
+            head_timeout: Duration::from_millis(300),
+            ..ProxyConfig::default()
+        };
+        let handler = tokio::spawn(handle_client(
+            server,
+            ProxyCache::new(),
+            ConnectionPool::new(),
+            Arc::new(config),
+        ));
+
+        // Every byte lands well inside the per-read timeout, but the head
+        // never finishes
+        let (mut reader, mut writer) = client.into_split();
+        let started = Instant::now();
+        tokio::spawn(async move {
+            let head = b"GET /a.js HTTP/1.1\r\nHost: example.com\r\nX-Padding: aaaaaaaaaa";
+            dribble(&mut writer, head, Duration::from_millis(20)).await;
+        });
+        let mut received = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), reader.read_to_end(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), handler)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(250), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_slow_upstream_head_gets_504() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = BytesMut::new();
+                    let _ = stream.read_buf(&mut request).await;
+                    dribble(
+                        &mut stream,
+                        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nX-Slow: aaaaaaaaaaaaaaaa",
+                        Duration::from_millis(20),
+                    )
+                    .await;
+                });
+            }
+        });
+        for response_mode in [
+            ResponseMode::Buffered,
+            ResponseMode::Streaming,
+            ResponseMode::Tee,
+        ] {
+            let config = ProxyConfig {
+                response_mode,
+                head_timeout: Duration::from_millis(300),
+                ..ProxyConfig::default()
+            };
+            let started = Instant::now();
+            let response = proxy_request_with(
+                &ProxyCache::new(),
+                &ConnectionPool::new(),
+                config,
+                &format!("GET /a.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+            )
+            .await;
+            assert!(
+                response.starts_with("HTTP/1.1 504 Gateway Timeout\r\n"),
+                "{:?}: {}",
+                response_mode,
+                response
+            );
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn test_read_budget_minimum_rate() {
+        let config = ProxyConfig {
+            min_transfer_rate: Some(1000),
+            ..ProxyConfig::default()
+        };
+        let budget = ReadBudget::new(&config, CONNECTION_TIMEOUT);
+        let started = Instant::now() - Duration::from_secs(2);
+        // Nothing is checked before the first byte or inside the grace period
+        assert_eq!(budget.check(None, 0, false), Ok(()));
+        assert_eq!(budget.check(Some(Instant::now()), 1, false), Ok(()));
+        assert_eq!(budget.check(Some(started), 2500, true), Ok(()));
+        assert_eq!(
+            budget.check(Some(started), 1500, true),
+            Err(TRANSFER_TOO_SLOW)
+        );
+        let stalled = Instant::now() - config.head_timeout;
+        assert_eq!(
+            budget.check(Some(stalled), 1_000_000, false),
+            Err(HEAD_TIMED_OUT)
+        );
+        assert_eq!(budget.next_read(None, false), CONNECTION_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_framed_responses_read_without_waiting_for_close() {
+        // Upstream keeps every connection open after answering, so only
+        // the framing can end the read
+        let fetch = |response: &'static [u8]| async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = BytesMut::new();
+                while find_headers_end(&buffer).is_none() {
+                    stream.read_buf(&mut buffer).await.unwrap();
+                }
+                stream.write_all(response).await.unwrap();
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            });
+            let mut upstream = TcpStream::connect(addr).await.unwrap();
+            let budget = ReadBudget::new(&ProxyConfig::default(), CONNECTION_TIMEOUT);
+            timeout(
+                Duration::from_secs(2),
+                forward_to_upstream(&mut upstream, b"GET / HTTP/1.1\r\n\r\n", "GET", budget),
+            )
+            .await
+            .expect("read should stop at the framing boundary")
+            .unwrap()
+        };
+
+        let exact = fetch(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello").await;
+        assert!(exact.framed && exact.reusable);
+        assert!(exact.response.ends_with(b"\r\n\r\nhello"));
+
+        let chunked =
+            fetch(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n")
+                .await;
+        assert!(chunked.framed && chunked.reusable);
+        assert!(chunked.response.ends_with(b"0\r\n\r\n"));
+
+        // Extra bytes are cut off, and the connection is out of step
+        let overlong = fetch(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhello").await;
+        assert!(overlong.framed && !overlong.reusable);
+        assert!(overlong.response.ends_with(b"\r\n\r\nhe"));
+    }
+
+    #[tokio::test]
+    async fn test_first_byte_deadline_gets_504() {
+        for response_mode in [
+            ResponseMode::Buffered,
+            ResponseMode::Streaming,
+            ResponseMode::Tee,
+        ] {
+            let config = ProxyConfig {
+                response_mode,
+                first_byte_timeout: Some(Duration::from_millis(100)),
+                ..ProxyConfig::default()
+            };
+            // A hung origin fails fast, one answering inside the deadline
+            // is unaffected
+            for (delay, status_line) in [
+                (Duration::from_secs(2), "HTTP/1.1 504 Gateway Timeout\r\n"),
+                (Duration::from_millis(10), "HTTP/1.1 200 OK\r\n"),
+            ] {
+                let addr = spawn_slow_upstream(delay).await;
+                let started = Instant::now();
+                let response = proxy_request_with(
+                    &ProxyCache::new(),
+                    &ConnectionPool::new(),
+                    config.clone(),
+                    &format!("GET /a.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+                )
+                .await;
+                assert!(
+                    response.starts_with(status_line),
+                    "{:?}: {}",
+                    response_mode,
+                    response
+                );
+                assert!(started.elapsed() < Duration::from_secs(1));
+            }
+        }
+    }
+
+    #[test]
+    fn test_request_errors_map_to_status() {
+        let status = |request: &str| {
+            let error = validate_request(request.as_bytes(), DEFAULT_MAX_REQUEST_LINE).unwrap_err();
+            let line = String::from_utf8_lossy(error.status_line()).to_string();
+            (error, line[9..12].to_string())
+        };
+        assert_eq!(
+            status("GARBAGE\r\n\r\n"),
+            (RequestError::Malformed, "400".to_string())
+        );
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nAccept: */*\r\n\r\n"),
+            (RequestError::MissingHost, "400".to_string())
+        );
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nHost:\r\n\r\n"),
+            (RequestError::MissingHost, "400".to_string())
+        );
+        assert_eq!(
+            status("GET / HTTP/1.1\r\nHost: a.example\r\nhost: b.example\r\n\r\n"),
+            (RequestError::DuplicateHost, "400".to_string())
+        );
+        assert_eq!(
+            status("POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip\r\n\r\n"),
+            (RequestError::Unsupported, "501".to_string())
+        );
+        let crowded = format!(
+            "GET / HTTP/1.1\r\nHost: a\r\n{}\r\n",
+            "X-Filler: 1\r\n".repeat(MAX_REQUEST_HEADERS)
+        );
+        assert_eq!(
+            status(&crowded),
+            (RequestError::TooManyHeaders, "431".to_string())
+        );
+        assert!(validate_request(
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            DEFAULT_MAX_REQUEST_LINE
+        )
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_request_line_gets_414() {
+        // 64 bytes exactly is still accepted, one more is not
+        let line = |target_len: usize| format!("GET /{} HTTP/1.1", "a".repeat(target_len - 1));
+        assert_eq!(line(51).len(), 64);
+        let request = |line: String| format!("{}\r\nHost: a.example\r\n\r\n", line);
+        assert!(validate_request(request(line(51)).as_bytes(), 64).is_ok());
+        assert_eq!(
+            validate_request(request(line(52)).as_bytes(), 64).unwrap_err(),
+            RequestError::UriTooLong
+        );
+
+        let response = proxy_request_with(
+            &ProxyCache::new(),
+            &ConnectionPool::new(),
+            ProxyConfig {
+                max_request_line: 64,
+                ..ProxyConfig::default()
+            },
+            &request(line(4096)),
+        )
+        .await;
+        assert_eq!(response, "HTTP/1.1 414 URI Too Long\r\n\r\n");
+    }
+
+    #[test]
+    fn test_absolute_form_requests_validated() {
+        let request = validate_request(
+            b"GET http://cdn.example.com/a.js HTTP/1.1\r\nHost: other.example\r\n\r\n",
+            DEFAULT_MAX_REQUEST_LINE,
+        )
+        .unwrap();
+        assert_eq!(
+            (request.host.as_str(), request.port),
+            ("cdn.example.com", 80)
+        );
+        assert_eq!(request.path, "/a.js");
+        assert!(request.absolute_form);
+        assert_eq!(
+            header_value(&request.headers, "host"),
+            Some("cdn.example.com")
+        );
+
+        // No Host header is needed when the target names the origin
+        let request = validate_request(
+            b"GET http://cdn.example.com:8080/a.js?v=1 HTTP/1.1\r\n\r\n",
+            DEFAULT_MAX_REQUEST_LINE,
+        )
+        .unwrap();
+        assert_eq!(
+            (request.host.as_str(), request.port),
+            ("cdn.example.com", 8080)
+        );
+        assert_eq!(request.path, "/a.js?v=1");
+        assert_eq!(
+            header_value(&request.headers, "host"),
+            Some("cdn.example.com:8080")
+        );
+
+        let request = validate_request(
+            b"GET /a.js HTTP/1.1\r\nHost: a.example\r\n\r\n",
+            DEFAULT_MAX_REQUEST_LINE,
+        )
+        .unwrap();
+        assert!(!request.absolute_form);
+        assert_eq!(
+            validate_request(
+                b"GET https://a.example/ HTTP/1.1\r\nHost: a.example\r\n\r\n",
+                DEFAULT_MAX_REQUEST_LINE
+            )
+            .unwrap_err(),
+            RequestError::Malformed
+        );
+    }
+
+    #[test]
+    fn test_origin_form_request_rewrite() {
+        let rewritten = origin_form_request(
+            b"POST http://a.example:81/form?x=1 HTTP/1.1\r\nHost: b.example\r\nContent-Length: 2\r\n\r\nhi",
+            "/form?x=1",
+            "a.example:81",
+        );
+        assert_eq!(
+            String::from_utf8(rewritten).unwrap(),
+            "POST /form?x=1 HTTP/1.1\r\nHost: a.example:81\r\nContent-Length: 2\r\n\r\nhi"
+        );
+        assert_eq!(authority("a.example", 80), "a.example");
+        assert_eq!(authority("2001:db8::1", 80), "[2001:db8::1]");
+        assert_eq!(authority("::1", 8080), "[::1]:8080");
+    }
+
+    #[tokio::test]
+    async fn test_absolute_form_uses_uri_authority() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 2\r\n\r\nok".to_vec(),
+        ])
+        .await;
+        let cache = ProxyCache::new();
+        let response = proxy_request(
+            &cache,
+            &format!(
+                "GET http://{}/a.js HTTP/1.1\r\nHost: unrelated.example\r\n\r\n",
+                addr
+            ),
+        )
+        .await;
+        assert!(response.ends_with("\r\n\r\nok"));
+        let forwarded = requests.lock().await[0].clone();
+        assert!(
+            forwarded.starts_with("GET /a.js HTTP/1.1\r\n"),
+            "{}",
+            forwarded
+        );
+        assert!(forwarded.contains(&format!("\r\nHost: {}\r\n", addr)));
+        assert!(!forwarded.contains("unrelated.example"));
+
+        // The same resource in origin-form is a cache hit
+        let hit = proxy_request(
+            &cache,
+            &format!("GET /a.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+        )
+        .await;
+        assert!(hit.ends_with("\r\n\r\nok"));
+        assert_eq!(cache.stats().hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_misses_share_one_fetch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&accepts);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buffer = BytesMut::new();
+                    while find_headers_end(&buffer).is_none() {
+                        if stream.read_buf(&mut buffer).await.unwrap() == 0 {
+                            return;
+                        }
+                    }
+                    // Slow enough for every client to miss first
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+                        .await;
+                });
+            }
+        });
+
+        let cache = ProxyCache::new();
+        let request = format!("GET /a.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        let clients: Vec<_> = (0..5)
+            .map(|_| {
+                let (cache, request) = (cache.clone(), request.clone());
+                tokio::spawn(async move { proxy_request(&cache, &request).await })
+            })
+            .collect();
+        for client in clients {
+            assert!(client.await.unwrap().ends_with("\r\n\r\nok"));
+        }
+        assert_eq!(accepts.load(Ordering::SeqCst), 1);
+        assert!(cache.single_flight().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hits_count_bytes_saved() {
+        let response = |len: usize| {
+            format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: {}\r\n\r\n{}",
+                len,
+                "x".repeat(len)
+            )
+            .into_bytes()
+        };
+        let (addr, requests) = spawn_upstream(vec![response(100), response(250)]).await;
+        let cache = ProxyCache::new();
+        let get = |method: &str, path: &str| {
+            format!("{} {} HTTP/1.1\r\nHost: {}\r\n\r\n", method, path, addr)
+        };
+
+        // Misses cost upstream traffic and save nothing
+        proxy_request(&cache, &get("GET", "/small")).await;
+        proxy_request(&cache, &get("GET", "/large")).await;
+        assert_eq!(cache.stats().hit_bytes, 0);
+        assert_eq!(cache.stats().origin_bytes_saved, 0);
+
+        for path in ["/small", "/small", "/large"] {
+            let response = proxy_request(&cache, &get("GET", path)).await;
+            assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        }
+        assert_eq!(requests.lock().await.len(), 2);
+        let stats = cache.stats();
+        assert_eq!(stats.hit_bytes, 100 + 100 + 250);
+        assert!(stats.origin_bytes_saved > stats.hit_bytes);
+
+        // A HEAD hit saves the stored head but sends no body
+        proxy_request(&cache, &get("HEAD", "/small")).await;
+        let after_head = cache.stats();
+        assert_eq!(after_head.hit_bytes, stats.hit_bytes);
+        assert!(after_head.origin_bytes_saved > stats.origin_bytes_saved);
+    }
+
+    #[tokio::test]
+    async fn test_preflight_cached_for_max_age() {
+        let answer = |origin: &str| {
+            format!(
+                "HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Origin: {}\r\n\
+                 Access-Control-Allow-Methods: PUT\r\nAccess-Control-Max-Age: 600\r\n\r\n",
+                origin
+            )
+            .into_bytes()
+        };
+        let (addr, requests) = spawn_upstream(vec![
+            answer("https://app.example"),
+            answer("https://other.example"),
+            answer("https://app.example"),
+        ])
+        .await;
+        let cache = ProxyCache::new();
+        let config = |cache_preflights| ProxyConfig {
+            cache_preflights,
+            ..ProxyConfig::default()
+        };
+        let preflight = |origin: &str| {
+            format!(
+                "OPTIONS /api/item HTTP/1.1\r\nHost: {}\r\nOrigin: {}\r\n\
+                 Access-Control-Request-Method: PUT\r\n\r\n",
+                addr, origin
+            )
+        };
+        let send = |request: String, cache_preflights| {
+            let cache = cache.clone();
+            async move {
+                proxy_request_with(
+                    &cache,
+                    &ConnectionPool::new(),
+                    config(cache_preflights),
+                    &request,
+                )
+                .await
+            }
+        };
+
+        let first = send(preflight("https://app.example"), true).await;
+        assert!(first.starts_with("HTTP/1.1 204"));
+        let second = send(preflight("https://app.example"), true).await;
+        assert!(second.starts_with("HTTP/1.1 204"));
+        assert!(second.contains("Access-Control-Allow-Origin: https://app.example\r\n"));
+        assert_eq!(requests.lock().await.len(), 1);
+
+        // Another origin gets its own answer
+        let other = send(preflight("https://other.example"), true).await;
+        assert!(other.contains("Access-Control-Allow-Origin: https://other.example\r\n"));
+        assert_eq!(requests.lock().await.len(), 2);
+
+        // Without the option preflights always go upstream
+        send(preflight("https://app.example"), false).await;
+        assert_eq!(requests.lock().await.len(), 3);
+    }
+
+    #[test]
+    fn test_preflight_entries_expire_with_max_age() {
+        let response =
+            b"HTTP/1.1 200 OK\r\nAccess-Control-Max-Age: 30\r\nContent-Length: 0\r\n\r\n";
+        let entry = parse_preflight_for_cache(response, "api", "/").unwrap();
+        assert_eq!(entry.expires - entry.stored_at, 30);
+        assert!(!entry.may_serve_stale());
+
+        for uncached in [
+            &b"HTTP/1.1 204 No Content\r\n\r\n"[..],
+            b"HTTP/1.1 403 Forbidden\r\nAccess-Control-Max-Age: 30\r\n\r\n",
+            b"HTTP/1.1 204 No Content\r\nAccess-Control-Max-Age: 30\r\nCache-Control: no-store\r\n\r\n",
+        ] {
+            assert!(parse_preflight_for_cache(uncached, "api", "/").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_requests_retry_flaky_upstream() {
+        // Every other connection is closed without an answer
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&accepts);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let _ = stream.read_buf(&mut BytesMut::new()).await;
+                if accepted.fetch_add(1, Ordering::SeqCst) % 2 == 1 {
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                        .await;
+                }
+            }
+        });
+        let request = |method: &str| {
+            format!(
+                "{} /flaky HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\n\r\n",
+                method, addr
+            )
+        };
+
+        let response = proxy_request(&ProxyCache::new(), &request("GET")).await;
+        assert!(response.ends_with("\r\n\r\nok"));
+        assert_eq!(accepts.load(Ordering::SeqCst), 2);
+
+        // A POST might have been acted on, it is never sent twice
+        let response = proxy_request(&ProxyCache::new(), &request("POST")).await;
+        assert_eq!(response, "HTTP/1.1 502 Bad Gateway\r\n\r\n");
+        assert_eq!(accepts.load(Ordering::SeqCst), 3);
+
+        accepts.store(0, Ordering::SeqCst);
+        let no_retries = ProxyConfig {
+            upstream_retries: 0,
+            ..ProxyConfig::default()
+        };
+        let response = proxy_request_with(
+            &ProxyCache::new(),
+            &ConnectionPool::new(),
+            no_retries,
+            &request("GET"),
+        )
+        .await;
+        assert_eq!(response, "HTTP/1.1 502 Bad Gateway\r\n\r\n");
+        assert_eq!(accepts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_retry_delay_doubles() {
+        let base = Duration::from_millis(50);
+        assert_eq!(retry_delay(base, 0), base);
+        assert_eq!(retry_delay(base, 2), Duration::from_millis(200));
+        assert_eq!(retry_delay(Duration::MAX, 3), Duration::MAX);
+    }
+
+    #[tokio::test]
+    async fn test_failed_fetches_leave_no_flights() {
+        let cache = ProxyCache::new();
+        let clients: Vec<_> = (0..4)
+            .map(|i| {
+                let cache = cache.clone();
+                tokio::spawn(async move {
+                    let path = if i % 2 == 0 { "/a.js" } else { "/b.js" };
+                    let request = format!("GET {} HTTP/1.1\r\nHost: 127.0.0.1:1\r\n\r\n", path);
+                    proxy_request(&cache, &request).await
+                })
+            })
+            .collect();
+        for client in clients {
+            assert!(client.await.unwrap().starts_with("HTTP/1.1 502"));
+        }
+        assert!(cache.single_flight().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_shared_fetch_fails_every_follower() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepts = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&accepts);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let _ = stream.read_buf(&mut BytesMut::new()).await;
+                    // Hang long enough for every client to follow, then
+                    // close without answering
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                });
+            }
+        });
+
+        let cache = ProxyCache::new();
+        let request = format!("GET /a.js HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+        let clients: Vec<_> = (0..5)
+            .map(|_| {
+                let (cache, request) = (cache.clone(), request.clone());
+                tokio::spawn(async move { proxy_request(&cache, &request).await })
+            })
+            .collect();
+        for client in clients {
+            assert_eq!(client.await.unwrap(), "HTTP/1.1 502 Bad Gateway\r\n\r\n");
+        }
+        // Only the leader reached upstream, retries included
+        let attempts = 1 + ProxyConfig::default().upstream_retries as usize;
+        assert_eq!(accepts.load(Ordering::SeqCst), attempts);
+        assert!(cache.single_flight().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_host_rejected_before_forwarding() {
+        let response = proxy_request(
+            &ProxyCache::new(),
+            "GET / HTTP/1.1\r\nHost: 127.0.0.1:1\r\nHost: 127.0.0.1:2\r\n\r\n",
+        )
+        .await;
+        assert_eq!(response, "HTTP/1.1 400 Bad Request\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_config_applies_to_new_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = SharedConfig::new(ProxyConfig::default());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(accept_connections(
+            listener,
+            ProxyCache::new(),
+            ConnectionPool::new(),
+            shared.clone(),
+            Arc::new(AtomicUsize::new(0)),
+            async {
+                let _ = stopped.await;
+            },
+        ));
+        let delete = |client: TcpStream| async move {
+            let mut client = client;
+            client
+                .write_all(b"DELETE /item HTTP/1.1\r\nHost: 127.0.0.1:1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = delete(TcpStream::connect(addr).await.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.1 502"));
+
+        // A connection accepted before the reload keeps the old policy
+        let before = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let reloaded = ProxyConfig {
+            listen_addr: "127.0.0.1:9".parse().unwrap(),
+            allowed_methods: Some(vec!["GET".to_string()]),
+            ..ProxyConfig::default()
+        };
+        assert!(reload_config(&shared, Ok(reloaded)));
+        assert!(delete(before).await.starts_with("HTTP/1.1 502"));
+
+        let response = delete(TcpStream::connect(addr).await.unwrap()).await;
+        assert!(response.starts_with("HTTP/1.1 405"));
+        assert!(response.contains("Allow: GET\r\n"));
+        // The listener can't move, the address stays as it was started
+        assert_eq!(
+            shared.load().listen_addr,
+            ProxyConfig::default().listen_addr
+        );
+
+        // A broken reload keeps the running config
+        let invalid = Err(EnvConfigError::SharedPort(3128));
+        assert!(!reload_config(&shared, invalid));
+        assert!(shared.load().allowed_methods.is_some());
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clients_outside_acl_closed_on_accept() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shared = SharedConfig::new(ProxyConfig {
+            client_acl: ClientAcl {
+                deny: ClientAcl::parse_list("127.0.0.0/8").unwrap(),
+                ..ClientAcl::default()
+            },
+            ..ProxyConfig::default()
+        });
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(accept_connections(
+            listener,
+            ProxyCache::new(),
+            ConnectionPool::new(),
+            shared,
+            Arc::new(AtomicUsize::new(0)),
+            async {
+                let _ = stopped.await;
+            },
+        ));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let _ = client
+            .write_all(b"OPTIONS * HTTP/1.1\r\nHost: proxy\r\n\r\n")
+            .await;
+        let mut response = Vec::new();
+        let _ = timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .unwrap();
+        assert!(response.is_empty());
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_background_tasks_throttled_while_connections_accepted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sink = LogSink::default();
+        let limiter = TaskLimiter::new(1);
+        let shared = SharedConfig::new(ProxyConfig {
+            access_log: Some(AccessLog::new(sink.clone())),
+            task_limiter: Some(limiter.clone()),
+            ..ProxyConfig::default()
+        });
+        // Other background work holds the only slot
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let busy = limiter
+            .spawn(async {
+                let _ = released.await;
+            })
+            .await;
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(accept_connections(
+            listener,
+            ProxyCache::new(),
+            ConnectionPool::new(),
+            shared,
+            Arc::new(AtomicUsize::new(0)),
+            async {
+                let _ = stopped.await;
+            },
+        ));
+
+        // Connections are still answered and closed, their log writes dropped
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"OPTIONS * HTTP/1.1\r\nHost: proxy\r\n\r\n")
+                .await
+                .unwrap();
+            let mut status = [0; 15];
+            timeout(Duration::from_secs(5), client.read_exact(&mut status))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&status, b"HTTP/1.1 200 OK");
+            let mut rest = Vec::new();
+            timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(sink.0.lock().unwrap().is_empty());
+        assert_eq!(limiter.running(), 1);
+        assert_eq!(limiter.dropped(), 3);
+
+        // Once the slot is free the next write goes through
+        release.send(()).unwrap();
+        busy.await.unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"OPTIONS * HTTP/1.1\r\nHost: proxy\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        for _ in 0..100 {
+            if limiter.running() == 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let log = String::from_utf8(sink.0.lock().unwrap().clone()).unwrap();
+        assert_eq!(log.lines().count(), 1, "{}", log);
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_clients_over_rate_limit_get_429() {
+        let config = Arc::new(ProxyConfig {
+            rate_limiter: Some(RateLimiter::new(0.01, 2)),
+            ..ProxyConfig::default()
+        });
+        let send = |peer: Option<IpAddr>| {
+            let config = Arc::clone(&config);
+            async move {
+                let (mut client, proxy_side) = tokio::io::duplex(4096);
+                client
+                    .write_all(b"OPTIONS * HTTP/1.1\r\nHost: proxy\r\n\r\n")
+                    .await
+                    .unwrap();
+                serve_connection(
+                    proxy_side,
+                    peer,
+                    ProxyCache::new(),
+                    ConnectionPool::new(),
+                    config,
+                )
+                .await;
+                let mut response = Vec::new();
+                client.read_to_end(&mut response).await.unwrap();
+                String::from_utf8(response).unwrap()
+            }
+        };
+
+        let noisy = Some("192.168.1.20".parse().unwrap());
+        for _ in 0..2 {
+            assert!(send(noisy).await.starts_with("HTTP/1.1 200 OK\r\n"));
+        }
+        assert_eq!(
+            send(noisy).await,
+            "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 100\r\n\r\n"
+        );
+        // Other clients are unaffected, as are streams without an address
+        let quiet = Some("192.168.1.21".parse().unwrap());
+        assert!(send(quiet).await.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(send(None).await.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reload_rebuilds_config_from_defaults() {
+        let vars = |pairs: &'static [(&str, &str)]| {
+            ProxyConfig::default().with_vars(|name| {
+                pairs
+                    .iter()
+                    .find(|(var, _)| *var == name)
+                    .map(|(_, value)| value.to_string())
+            })
+        };
+        let running = ProxyConfig {
+            pool_connections_per_host: 3,
+            ..vars(&[
+                ("RUSTYSQUID_IGNORE_QUERY", "true"),
+                ("RUSTYSQUID_EXTENSION_TTLS", "js=3600"),
+            ])
+            .unwrap()
+        };
+        let cache = ProxyCache::new()
+            .with_extension_ttls(running.extension_ttls.clone())
+            .with_query_policy(running.query_policy.clone());
+        let shared = SharedConfig::new(running);
+
+        // Settings taken out of the environment go back to their defaults
+        let running = shared.load();
+        assert!(reload_config(
+            &shared,
+            vars(&[("RUSTYSQUID_PRIVATE_QUERY_PARAMS", "session")])
+        ));
+        let reloaded = shared.load();
+        assert!(!reloaded.query_policy.ignore_query);
+        assert_eq!(reloaded.query_policy.private_params, vec!["session"]);
+        assert_eq!(reloaded.extension_ttls, ExtensionTtls::default());
+        // Startup settings stay as they were
+        assert_eq!(reloaded.pool_connections_per_host, 3);
+
+        reload_cache_policies(&cache, &running, &reloaded).await;
+        assert!(!cache.query_policy().ignore_query);
+        assert_eq!(cache.extension_ttls().get("/app.js"), None);
+
+        // A broken reload keeps what is running
+        assert!(!reload_config(
+            &shared,
+            vars(&[("RUSTYSQUID_IGNORE_QUERY", "maybe")])
+        ));
+        assert_eq!(shared.load().query_policy.private_params, vec!["session"]);
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_cache_unless_rekeyed() {
+        let cache = ProxyCache::new();
+        let key = cache
+            .lookup_key("example.com", 80, "/app.js?v=1", &[])
+            .await;
+        let entry = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            body: Bytes::from("ok"),
+            expires: now() + 600,
+            stored_at: now(),
+            ..Default::default()
+        };
+        cache.put(key, entry).await;
+        let running = ProxyConfig::default();
+
+        let mut multipliers = HostTtlMultipliers::new();
+        multipliers.insert("example.com", 2.0);
+        let mut ttls = ExtensionTtls::new();
+        ttls.insert("js", 3600);
+        let reloaded = ProxyConfig {
+            host_ttl_multipliers: multipliers.clone(),
+            extension_ttls: ttls.clone(),
+            query_policy: QueryPolicy {
+                private_params: vec!["session".to_string()],
+                ..QueryPolicy::default()
+            },
+            ..ProxyConfig::default()
+        };
+        assert_eq!(reload_cache_policies(&cache, &running, &reloaded).await, 0);
+        assert_eq!(cache.len().await, 1);
+        assert_eq!(*cache.host_ttl_multipliers(), multipliers);
+        assert_eq!(*cache.extension_ttls(), ttls);
+        assert_eq!(cache.query_policy().private_params, vec!["session"]);
+
+        // Keys without the query string no longer find the entry
+        let rekeyed = ProxyConfig {
+            query_policy: QueryPolicy {
+                ignore_query: true,
+                ..reloaded.query_policy.clone()
+            },
+            ..reloaded.clone()
+        };
+        assert_eq!(reload_cache_policies(&cache, &reloaded, &rekeyed).await, 1);
+        assert!(cache.is_empty().await);
+        assert!(cache.query_policy().ignore_query);
+    }
+
+    #[tokio::test]
+    async fn test_sighup_clears_cache() {
+        let (upstream, _) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\nContent-Length: 2\r\n\r\nok"
+                .to_vec(),
+        ])
+        .await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cache = ProxyCache::new();
+        // Only read at startup, the reload keeps it
+        let shared = SharedConfig::new(ProxyConfig {
+            pool_connections_per_host: 3,
+            ..ProxyConfig::default()
+        });
+        spawn_config_reloader(shared.clone(), cache.clone());
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(accept_connections(
+            listener,
+            cache.clone(),
+            ConnectionPool::new(),
+            shared.clone(),
+            Arc::new(AtomicUsize::new(0)),
+            async {
+                let _ = stopped.await;
+            },
+        ));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(format!("GET http://{}/ok.txt HTTP/1.1\r\n\r\n", upstream).as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("ok"), "{}", response);
+        assert_eq!(cache.len().await, 1);
+
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &std::process::id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+        timeout(Duration::from_secs(5), async {
+            while !cache.is_empty().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(shared.load().pool_connections_per_host, 3);
+
+        stop.send(()).unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let active = Arc::new(AtomicUsize::new(0));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(accept_connections(
+            listener,
+            ProxyCache::new(),
+            ConnectionPool::new(),
+            SharedConfig::new(ProxyConfig {
+                drain_timeout: Duration::from_secs(5),
+                ..ProxyConfig::default()
+            }),
+            Arc::clone(&active),
+            async {
+                let _ = stopped.await;
+            },
+        ));
+
+        // Half a request keeps the connection in flight
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        while active.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!server.is_finished());
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        // The in-flight request still gets its full response
+        client
+            .write_all(b"Host: 127.0.0.1:1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(response, "HTTP/1.1 502 Bad Gateway\r\n\r\n");
+        timeout(Duration::from_secs(2), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(active.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_gives_up_after_timeout() {
+        let limit = Duration::from_millis(150);
+        assert_eq!(drain_connections(&AtomicUsize::new(0), limit).await, 0);
+
+        let started = std::time::Instant::now();
+        assert_eq!(drain_connections(&AtomicUsize::new(2), limit).await, 2);
+        assert!(started.elapsed() >= limit);
+    }
+
+    #[test]
+    fn test_client_keeps_alive() {
+        assert!(client_keeps_alive(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"));
+        assert!(!client_keeps_alive(
+            b"GET / HTTP/1.1\r\nHost: a\r\nConnection: Close\r\n\r\n"
+        ));
+        assert!(!client_keeps_alive(b"GET / HTTP/1.0\r\nHost: a\r\n\r\n"));
+        assert!(client_keeps_alive(
+            b"GET / HTTP/1.0\r\nHost: a\r\nConnection: keep-alive\r\n\r\n"
+        ));
+        // A request body is part of the request, a pipelined one is not
+        let post = b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\n\r\nbodyGET";
+        assert_eq!(request_length(post), Some(post.len() - 3));
+        assert_eq!(request_length(&post[..post.len() - 5]), None);
+    }
+
+    #[test]
+    fn test_response_framing() {
+        let framing = |response: &[u8], method| response_framing(response, method).unwrap();
+        assert_eq!(
+            framing(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", "GET"),
+            (38, BodyFraming::Length(5), true)
+        );
+        assert_eq!(
+            framing(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", "HEAD").1,
+            BodyFraming::Empty
+        );
+        assert_eq!(
+            framing(
+                b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n",
+                "GET"
+            ),
+            (48, BodyFraming::Empty, false)
+        );
+        assert_eq!(
+            framing(
+                b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+                "GET"
+            )
+            .1,
+            BodyFraming::Chunked
+        );
+        // Unframed bodies and HTTP/1.0 without keep-alive end at close
+        assert_eq!(
+            framing(b"HTTP/1.1 200 OK\r\n\r\n", "GET"),
+            (19, BodyFraming::UntilClose, false)
+        );
+        assert!(!framing(b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n", "GET").2);
+        assert!(response_framing(b"HTTP/1.1 200 OK\r\nContent-Le", "GET").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cached_full_body_advertises_ranges() {
+        let cache = ProxyCache::new();
+        let serve = |status_line: &str, headers: Vec<String>| {
+            let cache = cache.clone();
+            let cached = Arc::new(CachedResponse {
+                status_line: status_line.to_string(),
+                headers,
+                body: Bytes::from("0123456789"),
+                expires: u64::MAX,
+                ..Default::default()
+            });
+            async move {
+                let (mut writer, mut reader) = tokio::io::duplex(4096);
+                serve_cached_response(
+                    &mut writer,
+                    cached,
+                    &[],
+                    &cache,
+                    ReplyHeaders::default(),
+                    false,
+                )
+                .await
+                .unwrap();
+                drop(writer);
+                let mut received = String::new();
+                reader.read_to_string(&mut received).await.unwrap();
+                received
+            }
+        };
+
+        let full = serve(
+            "HTTP/1.1 200 OK\r\n",
+            vec!["Content-Length: 10".to_string()],
+        )
+        .await;
+        assert!(full.contains("\r\nAccept-Ranges: bytes\r\n\r\n0123456789"));
+
+        let refused = serve(
+            "HTTP/1.1 200 OK\r\n",
+            vec!["Accept-Ranges: none".to_string()],
+        )
+        .await;
+        assert!(refused.contains("Accept-Ranges: none"));
+        assert!(!refused.contains("bytes"));
+
+        let partial = serve("HTTP/1.1 206 Partial Content\r\n", vec![]).await;
+        assert!(!partial.contains("Accept-Ranges"));
+    }
+
+    #[tokio::test]
+    async fn test_cached_responses_carry_increasing_age() {
+        let age_of = |response: &str| -> u64 {
+            let ages: Vec<&str> = response
+                .split("\r\n")
+                .filter_map(|line| line.strip_prefix("Age: "))
+                .collect();
+            assert_eq!(ages.len(), 1, "{}", response);
+            ages[0].parse().unwrap()
+        };
+        let entry = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec!["Age: 10".to_string(), "Content-Length: 5".to_string()],
+            body: Bytes::from("hello"),
+            expires: u64::MAX,
+            stored_at: now() - 5,
+            ..Default::default()
+        };
+
+        // Upstream's age plus the time stored replaces the stored header
+        let first = age_of(&serve_with_range(entry.clone(), &[]).await);
+        assert!((15..=16).contains(&first), "{}", first);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let second = age_of(&serve_with_range(entry.clone(), &[]).await);
+        assert!(second > first);
+
+        let partial = serve_with_range(entry.clone(), &["Range: bytes=0-1"]).await;
+        assert!(age_of(&partial) >= second);
+        let fresh = CachedResponse {
+            headers: vec!["Content-Length: 5".to_string()],
+            stored_at: now(),
+            ..entry
+        };
+        assert!(age_of(&serve_with_range(fresh.clone(), &[]).await) <= 1);
+        assert!(age_of(&not_modified_head(&fresh, ReplyHeaders::default())) <= 1);
+    }
+
+    #[tokio::test]
+    async fn test_length_mismatch_reframed_on_serve() {
+        let mismatched = || CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec![
+                "Content-Type: text/plain".to_string(),
+                "Content-Length: 20".to_string(),
+            ],
+            body: Bytes::from("0123456789"),
+            expires: u64::MAX,
+            ..Default::default()
+        };
+        let serve = |length_mismatch| async move {
+            let (mut writer, mut reader) = tokio::io::duplex(4096);
+            let reply = ReplyHeaders {
+                length_mismatch,
+                ..ReplyHeaders::default()
+            };
+            let sent = serve_cached_response(
+                &mut writer,
+                Arc::new(mismatched()),
+                &[],
+                &ProxyCache::new(),
+                reply,
+                false,
+            )
+            .await
+            .unwrap();
+            assert_eq!(sent, (200, 10));
+            drop(writer);
+            let mut received = String::new();
+            reader.read_to_string(&mut received).await.unwrap();
+            received
+        };
+
+        let recomputed = serve(LengthMismatch::Recompute).await;
+        assert!(!recomputed.contains("Content-Length: 20"));
+        assert!(recomputed.ends_with("Content-Length: 10\r\n\r\n0123456789"));
+
+        let chunked = serve(LengthMismatch::Chunked).await;
+        assert!(!chunked.contains("Content-Length"));
+        assert!(chunked.ends_with("Transfer-Encoding: chunked\r\n\r\na\r\n0123456789\r\n0\r\n\r\n"));
+
+        // Refetching happens at lookup, anything served regardless is recomputed
+        let refetch = serve(LengthMismatch::Refetch).await;
+        assert!(refetch.ends_with("Content-Length: 10\r\n\r\n0123456789"));
+
+        // Matching entries go out untouched
+        let matching = serve_with_range(
+            CachedResponse {
+                headers: vec!["Content-Length: 10".to_string()],
+                ..mismatched()
+            },
+            &[],
+        )
+        .await;
+        assert_eq!(matching.matches("Content-Length").count(), 1);
+        assert!(!matching.contains("chunked"));
+    }
+
+    #[tokio::test]
+    async fn test_length_mismatch_refetches_entry() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 5\r\n\r\nfresh"
+                .to_vec(),
+        ])
+        .await;
+        let host = addr.ip().to_string();
+        let cache = ProxyCache::new();
+        let key = cache.lookup_key(&host, addr.port(), "/data", &[]).await;
+        cache
+            .put(
+                key,
+                CachedResponse {
+                    status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+                    headers: vec!["Content-Length: 99".to_string()],
+                    body: Bytes::from("short"),
+                    expires: now() + 60,
+                    ..Default::default()
+                },
+            )
+            .await;
+        let config = || ProxyConfig {
+            length_mismatch: LengthMismatch::Refetch,
+            ..ProxyConfig::default()
+        };
+        let request = format!("GET /data HTTP/1.1\r\nHost: {}\r\n\r\n", addr);
+
+        let response = proxy_request_with(&cache, &ConnectionPool::new(), config(), &request).await;
+        assert!(response.ends_with("Content-Length: 5\r\n\r\nfresh"));
+        assert_eq!(requests.lock().await.len(), 1);
+
+        // The refetched copy replaced the bad entry and is served from cache
+        let response = proxy_request_with(&cache, &ConnectionPool::new(), config(), &request).await;
+        assert!(response.ends_with("\r\n\r\nfresh"));
+        assert_eq!(requests.lock().await.len(), 1);
+    }
+
+    async fn serve_with_range(cached: CachedResponse, request_headers: &[&str]) -> String {
+        let request_headers: Vec<String> = request_headers.iter().map(|h| h.to_string()).collect();
+        let (mut writer, mut reader) = tokio::io::duplex(1 << 16);
+        serve_cached_response(
+            &mut writer,
+            Arc::new(cached),
+            &request_headers,
+            &ProxyCache::new(),
+            ReplyHeaders::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        drop(writer);
+        let mut received = String::new();
+        reader.read_to_string(&mut received).await.unwrap();
+        received
+    }
+
+    fn video(len: usize) -> CachedResponse {
+        let body: String = (0..len).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec![
+                "Content-Type: video/mp4".to_string(),
+                format!("Content-Length: {}", len),
+                "ETag: \"v1\"".to_string(),
+            ],
+            body: Bytes::from(body),
+            expires: u64::MAX,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_range_served_from_cache() {
+        let entry = video(2048);
+        let expected = String::from_utf8(entry.body[0..1024].to_vec()).unwrap();
+        let response = serve_with_range(entry, &["Range: bytes=0-1023"]).await;
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("\r\nContent-Range: bytes 0-1023/2048\r\n"));
+        assert!(response.contains("\r\nContent-Length: 1024\r\n"));
+        assert!(!response.contains("Content-Length: 2048"));
+        assert!(response.ends_with(&format!("\r\n\r\n{}", expected)));
+
+        // An end past the body is clamped to the last byte
+        let response = serve_with_range(video(100), &["Range: bytes=90-500"]).await;
+        assert!(response.contains("\r\nContent-Range: bytes 90-99/100\r\n"));
+        let response = serve_with_range(video(100), &["Range: bytes=-10"]).await;
+        assert!(response.contains("\r\nContent-Range: bytes 90-99/100\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_open_ended_range_served_from_cache() {
+        let entry = video(2048);
+        let expected = String::from_utf8(entry.body[500..].to_vec()).unwrap();
+        let response = serve_with_range(entry, &["Range: bytes=500-"]).await;
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        assert!(response.contains("\r\nContent-Range: bytes 500-2047/2048\r\n"));
+        assert!(response.contains("\r\nContent-Length: 1548\r\n"));
+        assert!(response.ends_with(&format!("\r\n\r\n{}", expected)));
+    }
+
+    #[tokio::test]
+    async fn test_unsatisfiable_range_gets_416() {
+        let response = serve_with_range(video(2048), &["Range: bytes=4096-"]).await;
+        assert_eq!(
+            response,
+            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */2048\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ranges_falling_back_to_full_body() {
+        for headers in [
+            &["Range: bytes=0-9, 20-29"][..],
+            &["Range: bytes=9-0"],
+            &["Range: items=0-9"],
+            &["Range: bytes=0-9", "If-Range: \"v0\""],
+        ] {
+            let response = serve_with_range(video(64), headers).await;
+            assert!(
+                response.starts_with("HTTP/1.1 200 OK\r\n"),
+                "{:?}: {}",
+                headers,
+                response
+            );
+            assert!(response.contains("\r\nContent-Length: 64\r\n"));
+        }
+        let response = serve_with_range(video(64), &["Range: bytes=0-9", "If-Range: \"v1\""]).await;
+        assert!(response.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+    }
+
+    #[test]
+    fn test_partial_content_not_cached() {
+        let response = b"HTTP/1.1 206 Partial Content\r\nCache-Control: max-age=600\r\nContent-Range: bytes 0-3/10\r\nContent-Length: 4\r\n\r\nabcd";
+        assert!(parse_response_for_cache(
+            response,
+            "GET",
+            "example.com",
+            "/a.mp4",
+            &ProxyCache::new()
+        )
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_disconnect_mid_serve_is_counted() {
+        let cache = ProxyCache::new();
+        let cached = Arc::new(CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec!["Content-Length: 4096".to_string()],
+            body: Bytes::from(vec![b'x'; 4096]),
+            expires: u64::MAX,
+            ..Default::default()
+        });
+
+        // The reader takes part of the response, then the client goes away
+        let (mut writer, mut reader) = tokio::io::duplex(128);
+        let client = tokio::spawn(async move {
+            let mut partial = [0u8; 32];
+            reader.read_exact(&mut partial).await.unwrap();
+            drop(reader);
+            partial
+        });
+        let result = serve_cached_response(
+            &mut writer,
+            Arc::clone(&cached),
+            &[],
+            &cache,
+            ReplyHeaders::default(),
+            false,
+        )
+        .await;
+        assert_eq!(&client.await.unwrap()[..15], b"HTTP/1.1 200 OK");
+        assert_eq!(result, Err("Failed to write body"));
+        assert_eq!(cache.stats().client_disconnects, 1);
+        assert_eq!(cache.stats().client_write_errors, 0);
+
+        // A fully read response counts nothing
+        let (mut writer, mut reader) = tokio::io::duplex(8192);
+        serve_cached_response(
+            &mut writer,
+            cached,
+            &[],
+            &cache,
+            ReplyHeaders::default(),
+            false,
+        )
+        .await
+        .unwrap();
+        drop(writer);
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert!(received.ends_with(&[b'x'; 4096]));
+        assert_eq!(cache.stats().client_disconnects, 1);
+    }
+
+    #[tokio::test]
+    async fn test_stale_served_on_upstream_503() {
+        let (addr, _) = spawn_upstream(vec![
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\n\r\ndown".to_vec(),
+        ])
+        .await;
+        let host = addr.ip().to_string();
+        let cache = ProxyCache::new();
+        let key = cache.lookup_key(&host, addr.port(), "/feed", &[]).await;
+        cache
+            .put(
+                key,
+                CachedResponse {
+                    status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+                    headers: vec![
+                        "Cache-Control: max-age=60, stale-if-error=600".to_string(),
+                        "Content-Length: 10".to_string(),
+                    ],
+                    body: Bytes::from("stale feed"),
+                    expires: now() - 30,
+                    etag: Some("\"v1\"".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await;
+
+        let response = proxy_request(
+            &cache,
+            &format!("GET /feed HTTP/1.1\r\nHost: {}\r\n\r\n", addr),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nstale feed"));
+    }
+
+    #[test]
+    fn test_stale_if_error_window() {
+        let entry = |cache_control: &str| CachedResponse {
+            headers: vec![format!("Cache-Control: {}", cache_control)],
+            expires: 1_000,
+            must_revalidate: CacheControl::parse(&[format!("Cache-Control: {}", cache_control)])
+                .forbids_stale(),
+            ..Default::default()
+        };
+        let config = ProxyConfig::default();
+        assert!(stale_if_error_permits(
+            &entry("stale-if-error=60"),
+            &config,
+            1_060
+        ));
+        assert!(!stale_if_error_permits(
+            &entry("stale-if-error=60"),
+            &config,
+            1_061
+        ));
+        assert!(!stale_if_error_permits(
+            &entry("max-age=60"),
+            &config,
+            1_001
+        ));
+        assert!(!stale_if_error_permits(
+            &entry("must-revalidate, stale-if-error=60"),
+            &config,
+            1_001
+        ));
+
+        let operator = ProxyConfig {
+            default_stale_if_error: Some(300),
+            ..ProxyConfig::default()
+        };
+        assert!(stale_if_error_permits(
+            &entry("max-age=60"),
+            &operator,
+            1_300
+        ));
+        let disabled = ProxyConfig {
+            serve_stale_on_server_error: false,
+            ..operator
+        };
+        assert!(!stale_if_error_permits(
+            &entry("stale-if-error=60"),
+            &disabled,
+            1_001
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_revalidation_respects_client_accept_encoding() {
+        let (addr, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 304 Not Modified\r\n\r\n".to_vec(),
+            b"HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 5\r\n\r\nplain".to_vec(),
+        ])
+        .await;
+        let host = addr.ip().to_string();
+        let stale_br = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            headers: vec![
+                "Content-Encoding: br".to_string(),
+                "ETag: \"v1\"".to_string(),
+            ],
+            body: Bytes::from("brotli"),
+            expires: 1,
+            etag: Some("\"v1\"".to_string()),
+            ..Default::default()
+        };
+
+        // A br client gets the stored br body on 304
+        let cache = ProxyCache::new();
+        let key = cache.lookup_key(&host, addr.port(), "/app.js", &[]).await;
+        cache.put(key, stale_br.clone()).await;
+        let response = proxy_request(
+            &cache,
+            &format!(
+                "GET /app.js HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: br\r\n\r\n",
+                addr
+            ),
+        )
+        .await;
+        assert!(response.ends_with("\r\n\r\nbrotli"));
+        assert!(requests.lock().await[0].contains("If-None-Match"));
+
+        // An identity-only client triggers an unconditional fetch
+        let cache = ProxyCache::new();
+        cache.put(key, stale_br).await;
+        let response = proxy_request(
+            &cache,
+            &format!(
+                "GET /app.js HTTP/1.1\r\nHost: {}\r\nAccept-Encoding: identity\r\n\r\n",
+                addr
+            ),
+        )
+        .await;
+        assert!(response.ends_with("\r\n\r\nplain"));
+        assert!(!requests.lock().await[1].contains("If-None-Match"));
+    }
+
+    #[tokio::test]
+    async fn test_connect_tunnels_both_ways() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let cache = ProxyCache::new();
+        tokio::spawn(handle_client(
+            server,
+            cache.clone(),
+            ConnectionPool::new(),
+            Arc::new(ProxyConfig::default()),
+        ));
+
+        client
+            .write_all(
+                format!(
+                    "CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+                    echo_addr, echo_addr
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+        let mut reply = vec![0u8; established.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, established);
+
+        client.write_all(b"ping").await.unwrap();
+        let mut echoed = [0u8; 4];
+        client.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+        assert!(cache.is_empty().await);
+    }
+
+    #[tokio::test]
+    async fn test_streaming_applies_backpressure_to_slow_client() {
+        const BODY_SIZE: usize = 32 * 1024 * 1024;
+
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let written = Arc::new(AtomicUsize::new(0));
+        let upstream_written = Arc::clone(&written);
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut request = BytesMut::new();
+            while find_headers_end(&request).is_none() {
+                stream.read_buf(&mut request).await.unwrap();
+            }
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nCache-Control: no-store\r\nContent-Length: {}\r\n\r\n",
+                BODY_SIZE
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            let chunk = vec![b'x'; 64 * 1024];
+            for _ in 0..BODY_SIZE / chunk.len() {
+                stream.write_all(&chunk).await.unwrap();
+                upstream_written.fetch_add(chunk.len(), Ordering::Relaxed);
+            }
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.set_recv_buffer_size(16 * 1024).unwrap();
+        let mut client = socket
+            .connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let config = ProxyConfig {
+            response_mode: ResponseMode::Streaming,
+            ..ProxyConfig::default()
+        };
+        let handler = tokio::spawn(handle_client(
+            server,
+            ProxyCache::new(),
+            ConnectionPool::new(),
+            Arc::new(config),
+        ));
+        client
+            .write_all(format!("GET /video HTTP/1.1\r\nHost: {}\r\n\r\n", upstream_addr).as_bytes())
+            .await
+            .unwrap();
+
+        // While the client isn't reading, upstream stalls well short of the body
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(written.load(Ordering::Relaxed) < BODY_SIZE / 2);
+
+        let mut received = 0;
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            match client.read(&mut buf).await.unwrap() {
+                0 => break,
+                n => received += n,
+            }
+        }
+        handler.await.unwrap();
+        assert_eq!(written.load(Ordering::Relaxed), BODY_SIZE);
+        assert!(received > BODY_SIZE);
+    }
+
+    #[test]
+    fn test_connect_target() {
+        let connect = b"CONNECT example.com:8443 HTTP/1.1\r\nHost: example.com:8443\r\n\r\n";
+        assert_eq!(
+            connect_target(connect),
+            Some(("example.com".to_string(), 8443))
+        );
+        let get = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        assert_eq!(connect_target(get), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_accept_pauses_without_fd_headroom() {
+        // Current headroom always satisfies a zero margin
+        timeout(Duration::from_secs(1), wait_for_fd_headroom(0))
+            .await
+            .unwrap();
+        // No process can have this many free descriptors
+        assert!(
+            timeout(Duration::from_millis(250), wait_for_fd_headroom(usize::MAX))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_host_multiplier_never_overrides_no_store() {
+        let mut multipliers = HostTtlMultipliers::new();
+        multipliers.insert("cdn.example.com", 2.0);
+        let cache = ProxyCache::new().with_host_ttl_multipliers(multipliers);
+        let response = b"HTTP/1.1 200 OK\r\nCache-Control: no-store\r\n\r\nbody";
+
+        assert!(
+            parse_response_for_cache(response, "GET", "cdn.example.com", "/app.js", &cache)
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_caching_proxy_serves_in_memory_streams() {
+        let (upstream, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\nContent-Length: 5\r\n\r\nhello"
+                .to_vec(),
+        ])
+        .await;
+        let proxy = CachingProxy::new(
+            ProxyCache::new(),
+            ConnectionPool::new(),
+            ProxyConfig::default(),
+        );
+        let request = format!(
+            "GET http://{}/greeting.txt HTTP/1.1\r\nHost: {}\r\n\r\n",
+            upstream, upstream
+        );
+
+        for _ in 0..2 {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let handler = tokio::spawn({
+                let proxy = proxy.clone();
+                async move { proxy.handle(server).await }
+            });
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            handler.await.unwrap();
+
+            let received = String::from_utf8_lossy(&received);
+            assert!(received.starts_with("HTTP/1.1 200 OK\r\n"), "{}", received);
+            assert!(received.ends_with("hello"), "{}", received);
+        }
+
+        // The second request was answered from the cache
+        assert_eq!(requests.lock().await.len(), 1);
+        assert_eq!(proxy.cache().len().await, 1);
+    }
+
+    /// Backend keeping entries in a plain map, without `Vary` support
+    #[derive(Clone)]
+    struct MapCache {
+        entries: Arc<std::sync::Mutex<std::collections::HashMap<u64, Arc<CachedResponse>>>>,
+        single_flight: SingleFlight,
+        config: ProxyCacheConfig,
+        vary_policy: VaryPolicy,
+        query_policy: Arc<QueryPolicy>,
+        hits: Arc<AtomicUsize>,
+    }
+
+    impl MapCache {
+        fn new() -> Self {
+            Self {
+                entries: Arc::default(),
+                single_flight: SingleFlight::new(8),
+                config: ProxyCacheConfig::default(),
+                vary_policy: VaryPolicy::default(),
+                query_policy: Arc::default(),
+                hits: Arc::default(),
+            }
+        }
+
+        fn entries(
+            &self,
+        ) -> std::sync::MutexGuard<'_, std::collections::HashMap<u64, Arc<CachedResponse>>>
+        {
+            self.entries.lock().unwrap()
+        }
+    }
+
+    impl Cache for MapCache {
+        fn get(&self, key: u64) -> BoxFuture<'_, Option<Arc<CachedResponse>>> {
+            let entry = self.entries().get(&key).cloned();
+            Box::pin(async move { entry.filter(|entry| entry.is_fresh_at(now())) })
+        }
+
+        fn put(&self, key: u64, entry: CachedResponse) -> BoxFuture<'_, bool> {
+            self.entries().insert(key, Arc::new(entry));
+            Box::pin(async { true })
+        }
+
+        fn clear(&self) -> BoxFuture<'_, ()> {
+            self.entries().clear();
+            Box::pin(async {})
+        }
+
+        fn len(&self) -> BoxFuture<'_, usize> {
+            let len = self.entries().len();
+            Box::pin(async move { len })
+        }
+
+        fn total_size(&self) -> usize {
+            self.entries().values().map(|entry| entry.body.len()).sum()
+        }
+
+        fn lookup(&self, key: u64) -> BoxFuture<'_, CacheLookup> {
+            let lookup = match self.entries().get(&key) {
+                Some(entry) if entry.is_fresh_at(now()) => CacheLookup::Fresh(Arc::clone(entry)),
+                Some(entry) if entry.has_validators() => CacheLookup::Stale(Arc::clone(entry)),
+                _ => CacheLookup::Miss,
+            };
+            Box::pin(async move { lookup })
+        }
+
+        fn peek(&self, key: u64) -> BoxFuture<'_, Option<Arc<CachedResponse>>> {
+            let entry = self.entries().get(&key).cloned();
+            Box::pin(async move { entry })
+        }
+
+        fn refresh_expiry(&self, key: u64, expires: u64) -> BoxFuture<'_, bool> {
+            let refreshed = match self.entries().get_mut(&key) {
+                Some(entry) => {
+                    Arc::make_mut(entry).expires = expires;
+                    true
+                }
+                None => false,
+            };
+            Box::pin(async move { refreshed })
+        }
+
+        fn base_key(&self, host: &str, port: u16, path: &str) -> u64 {
+            create_cache_key(host, port, path)
+        }
+
+        fn lookup_key<'a>(
+            &'a self,
+            host: &'a str,
+            port: u16,
+            path: &'a str,
+            _request_headers: &'a [String],
+        ) -> BoxFuture<'a, u64> {
+            Box::pin(async move { self.base_key(host, port, path) })
+        }
+
+        fn preflight_key(
+            &self,
+            host: &str,
+            port: u16,
+            path: &str,
+            preflight: &Preflight<'_>,
+        ) -> u64 {
+            let path = format!(
+                "OPTIONS {} {} {} {}",
+                preflight.origin, preflight.method, preflight.request_headers, path
+            );
+            create_cache_key(host, port, &path)
+        }
+
+        fn put_variant<'a>(
+            &'a self,
+            host: &'a str,
+            port: u16,
+            path: &'a str,
+            _request_headers: &'a [String],
+            entry: CachedResponse,
+        ) -> BoxFuture<'a, Option<u64>> {
+            let key = entry.vary.is_empty().then(|| {
+                let key = self.base_key(host, port, path);
+                self.entries().insert(key, Arc::new(entry));
+                key
+            });
+            Box::pin(async move { key })
+        }
+
+        fn single_flight(&self) -> &SingleFlight {
+            &self.single_flight
+        }
+
+        fn config(&self) -> &ProxyCacheConfig {
+            &self.config
+        }
+
+        fn ttl_for(&self, headers: &[String], _host: &str, _path: &str) -> u64 {
+            crate::calculate_ttl(headers)
+        }
+
+        fn vary_policy(&self) -> &VaryPolicy {
+            &self.vary_policy
+        }
+
+        fn query_policy(&self) -> Arc<QueryPolicy> {
+            Arc::clone(&self.query_policy)
+        }
+
+        fn html_injection(&self) -> Option<&str> {
+            None
+        }
+
+        fn record_client_write_error(&self, _error: &std::io::Error) {}
+
+        fn record_hit_bytes(&self, _cached: &CachedResponse, _body_bytes: usize) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_served_from_another_backend() {
+        let (upstream, requests) = spawn_upstream(vec![
+            b"HTTP/1.1 200 OK\r\nCache-Control: max-age=600\r\nContent-Length: 5\r\n\r\nhello"
+                .to_vec(),
+        ])
+        .await;
+        let cache = MapCache::new();
+        let proxy = CachingProxy::new(cache.clone(), ConnectionPool::new(), ProxyConfig::default());
+        let request = format!(
+            "GET http://{}/greeting.txt HTTP/1.1\r\nHost: {}\r\n\r\n",
+            upstream, upstream
+        );
+
+        for _ in 0..2 {
+            let (mut client, server) = tokio::io::duplex(64 * 1024);
+            let handler = tokio::spawn({
+                let proxy = proxy.clone();
+                async move { proxy.handle(server).await }
+            });
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            handler.await.unwrap();
+            assert!(received.ends_with(b"\r\n\r\nhello"));
+        }
+
+        // The response was stored in the map and the second request answered from it
+        assert_eq!(requests.lock().await.len(), 1);
+        assert_eq!(cache.len().await, 1);
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 1);
+        let key = create_cache_key(&upstream.ip().to_string(), upstream.port(), "/greeting.txt");
+        assert!(cache.peek(key).await.is_some());
+    }
+}
@@ -0,0 +1,210 @@
+use lru::LruCache;
+use std::num::NonZeroUsize;
+
+/// Query parameters that usually make a URL signed, per-user or short-lived
+pub const DEFAULT_PRIVATE_PARAMS: &[&str] = &["token", "sig", "signature", "expires"];
+
+/// Paths whose latest query variant is remembered for [`QueryVariants`]
+const TRACKED_PATHS: usize = 1024;
+
+/// How query strings affect caching
+///
+/// Extension-based caching in [`crate::is_cacheable`] looks at the path
+/// without its query, so `/app.js?v=3` is cached like `/app.js`. A query
+/// naming any of `private_params` is never cached, whatever the extension
+/// or freshness headers, since such URLs are typically signed for one user
+/// and would only fill the cache with entries nobody else can hit.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::query::QueryPolicy;
+///
+/// let policy = QueryPolicy::default();
+/// assert!(policy.is_private("/image.jpg?token=secret&expires=123"));
+/// assert!(!policy.is_private("/app.js?v=3"));
+///
+/// let policy = QueryPolicy { ignore_query: true, ..QueryPolicy::default() };
+/// assert_eq!(policy.key_path("/app.js?v=3"), "/app.js");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryPolicy {
+    /// Build cache keys from the path alone, so every query string shares
+    /// one entry. Only suitable for origins whose queries never change the body.
+    pub ignore_query: bool,
+    /// Parameter names (case-insensitive) that make a URL uncacheable
+    pub private_params: Vec<String>,
+    /// Once two query variants of a path are cached with identical bodies,
+    /// key later requests for that path without their query, as with
+    /// `ignore_query` but only for paths shown to ignore it
+    pub collapse_identical_variants: bool,
+}
+
+impl Default for QueryPolicy {
+    fn default() -> Self {
+        Self {
+            ignore_query: false,
+            collapse_identical_variants: false,
+            private_params: DEFAULT_PRIVATE_PARAMS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl QueryPolicy {
+    /// Path cache keys are built from
+    pub fn key_path<'a>(&self, path: &'a str) -> &'a str {
+        if self.ignore_query {
+            split_query(path).0
+        } else {
+            path
+        }
+    }
+
+    /// Whether the query string names one of `private_params`
+    pub fn is_private(&self, path: &str) -> bool {
+        let Some(query) = split_query(path).1 else {
+            return false;
+        };
+        query
+            .split('&')
+            .map(|pair| pair.split_once('=').map_or(pair, |(name, _)| name))
+            .any(|name| {
+                self.private_params
+                    .iter()
+                    .any(|private| private.eq_ignore_ascii_case(name))
+            })
+    }
+}
+
+/// Recently cached query variants per path, to spot paths whose query
+/// doesn't change the body
+///
+/// Paths are identified by the key of their URL without the query. Only the
+/// latest variant of each path is kept, and only for the most recently
+/// cached paths.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::query::QueryVariants;
+///
+/// let mut variants = QueryVariants::default();
+/// assert!(!variants.record(1, "utm_source=a", 0xfeed));
+/// // The same body under another query collapses the path
+/// assert!(variants.record(1, "utm_source=b", 0xfeed));
+/// assert!(variants.is_collapsed(1));
+/// assert!(!variants.is_collapsed(2));
+/// ```
+pub struct QueryVariants {
+    latest: LruCache<u64, (String, u64)>,
+    collapsed: LruCache<u64, ()>,
+}
+
+impl Default for QueryVariants {
+    fn default() -> Self {
+        let capacity = NonZeroUsize::new(TRACKED_PATHS).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            latest: LruCache::new(capacity),
+            collapsed: LruCache::new(capacity),
+        }
+    }
+}
+
+impl QueryVariants {
+    /// Whether the path keyed `path_key` was found to ignore its query
+    pub fn is_collapsed(&self, path_key: u64) -> bool {
+        self.collapsed.contains(&path_key)
+    }
+
+    /// Note a variant of `path_key` cached for `query` with a body hashing
+    /// to `body_hash`, returns true if this collapses the path
+    pub fn record(&mut self, path_key: u64, query: &str, body_hash: u64) -> bool {
+        if self.is_collapsed(path_key) {
+            return false;
+        }
+        let identical = self
+            .latest
+            .peek(&path_key)
+            .is_some_and(|(seen, hash)| seen != query && *hash == body_hash);
+        if identical {
+            self.latest.pop(&path_key);
+            self.collapsed.put(path_key, ());
+        } else {
+            self.latest.put(path_key, (query.to_string(), body_hash));
+        }
+        identical
+    }
+}
+
+/// Split a request path at its `?`
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::query::split_query;
+///
+/// assert_eq!(split_query("/a.jpg?w=100"), ("/a.jpg", Some("w=100")));
+/// assert_eq!(split_query("/a.jpg"), ("/a.jpg", None));
+/// ```
+pub fn split_query(path: &str) -> (&str, Option<&str>) {
+    match path.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_private_params() {
+        let policy = QueryPolicy::default();
+        assert!(policy.is_private("/a.mp4?Expires=1700000000&Signature=abc"));
+        assert!(policy.is_private("/a.jpg?w=100&sig"));
+        // Only whole parameter names count, not values or prefixes
+        assert!(!policy.is_private("/a.jpg?w=token"));
+        assert!(!policy.is_private("/a.jpg?tokens=1"));
+        assert!(!policy.is_private("/token.jpg"));
+
+        let policy = QueryPolicy {
+            private_params: vec!["session".to_string()],
+            ..QueryPolicy::default()
+        };
+        assert!(policy.is_private("/a.css?session=1"));
+        assert!(!policy.is_private("/a.css?token=1"));
+    }
+
+    #[test]
+    fn test_query_variants_collapse_on_identical_bodies() {
+        let mut variants = QueryVariants::default();
+        // The same query stored again, or another query with another body,
+        // says nothing about the query being ignored
+        assert!(!variants.record(1, "v=1", 10));
+        assert!(!variants.record(1, "v=1", 10));
+        assert!(!variants.record(1, "v=2", 20));
+        assert!(!variants.is_collapsed(1));
+        assert!(variants.record(1, "v=3", 20));
+        assert!(variants.is_collapsed(1));
+        assert!(!variants.record(1, "v=4", 20));
+
+        // Paths are tracked apart
+        assert!(!variants.record(2, "v=1", 20));
+        assert!(!variants.is_collapsed(2));
+    }
+
+    #[test]
+    fn test_key_path() {
+        let policy = QueryPolicy::default();
+        assert_eq!(policy.key_path("/a.js?v=2"), "/a.js?v=2");
+        let policy = QueryPolicy {
+            ignore_query: true,
+            ..QueryPolicy::default()
+        };
+        assert_eq!(policy.key_path("/a.js?v=2"), "/a.js");
+        assert_eq!(policy.key_path("/a.js"), "/a.js");
+    }
+}
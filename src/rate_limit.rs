@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often [`RateLimiter::check`] drops the buckets of clients gone quiet
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One client's allowance, refilled at the limiter's rate up to its burst
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Tokens held at `now`, never more than `burst`
+    fn refilled(&self, now: Instant, rate: f64, burst: f64) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * rate).min(burst)
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    clients: HashMap<IpAddr, TokenBucket>,
+    swept: Instant,
+}
+
+/// Caps how many requests each client address may make per second
+///
+/// Every address gets a token bucket holding up to `burst` requests, refilled
+/// at `requests_per_second`. Buckets that have refilled completely are
+/// dropped now and then, so addresses seen once don't pile up. Clones share
+/// the same buckets.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::rate_limit::RateLimiter;
+///
+/// let limiter = RateLimiter::new(1.0, 2);
+/// let client = "192.168.1.20".parse().unwrap();
+/// assert!(limiter.check(client));
+/// assert!(limiter.check(client));
+/// assert!(!limiter.check(client));
+/// // Other clients have their own allowance
+/// assert!(limiter.check("192.168.1.21".parse().unwrap()));
+/// ```
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+impl RateLimiter {
+    /// Allow `requests_per_second` per client on average, and up to `burst`
+    /// (at least one) at once
+    ///
+    /// # Panics
+    ///
+    /// If `requests_per_second` isn't a positive number.
+    pub fn new(requests_per_second: f64, burst: u32) -> Self {
+        assert!(
+            requests_per_second.is_finite() && requests_per_second > 0.0,
+            "rate limit must be a positive number of requests per second"
+        );
+        Self {
+            rate: requests_per_second,
+            burst: f64::from(burst.max(1)),
+            buckets: Arc::new(Mutex::new(Buckets {
+                clients: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    /// Take one request from `ip`'s allowance, false if it has none left
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    /// Whole seconds until a client that was refused has a request to spend
+    pub fn retry_after(&self) -> u64 {
+        (1.0 / self.rate).ceil().max(1.0) as u64
+    }
+
+    /// Client addresses currently tracked
+    pub fn clients(&self) -> usize {
+        self.lock().clients.len()
+    }
+
+    fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        let (rate, burst) = (self.rate, self.burst);
+        let mut buckets = self.lock();
+        if now.saturating_duration_since(buckets.swept) >= SWEEP_INTERVAL {
+            // A full bucket is what a new client starts with, nothing is lost
+            buckets
+                .clients
+                .retain(|_, bucket| bucket.refilled(now, rate, burst) < burst);
+            buckets.swept = now;
+        }
+        let bucket = buckets.clients.entry(ip).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = bucket.refilled(now, rate, burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        // Every update leaves the buckets consistent, a poisoned lock is still usable
+        self.buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_steady_rate_allowed() {
+        let limiter = RateLimiter::new(4.0, 1);
+        let start = Instant::now();
+        // One request every 250ms is exactly the rate, indefinitely
+        for i in 0..100 {
+            let now = start + Duration::from_millis(250 * i);
+            assert!(limiter.check_at(ip("10.0.0.1"), now), "request {}", i);
+        }
+        assert!(!limiter.check_at(ip("10.0.0.1"), start + Duration::from_millis(24_900)));
+    }
+
+    #[test]
+    fn test_burst_rejected_then_refilled() {
+        let limiter = RateLimiter::new(2.0, 5);
+        let now = Instant::now();
+        let client = ip("10.0.0.1");
+        for _ in 0..5 {
+            assert!(limiter.check_at(client, now));
+        }
+        assert!(!limiter.check_at(client, now));
+        // Refused requests don't cost anything
+        assert!(!limiter.check_at(client, now + Duration::from_millis(400)));
+        assert!(limiter.check_at(client, now + Duration::from_millis(500)));
+        assert!(!limiter.check_at(client, now + Duration::from_millis(500)));
+        // Another client isn't held up, and a long pause refills only up to the burst
+        assert!(limiter.check_at(ip("10.0.0.2"), now));
+        let later = now + Duration::from_secs(60);
+        for _ in 0..5 {
+            assert!(limiter.check_at(client, later));
+        }
+        assert!(!limiter.check_at(client, later));
+        assert_eq!(limiter.retry_after(), 1);
+        assert_eq!(RateLimiter::new(0.1, 1).retry_after(), 10);
+    }
+
+    #[test]
+    fn test_idle_clients_swept() {
+        let limiter = RateLimiter::new(1.0, 10);
+        let start = Instant::now();
+        for i in 0..10 {
+            limiter.check_at(ip(&format!("10.0.0.{}", i)), start);
+        }
+        let busy = ip("10.0.1.1");
+        for _ in 0..10 {
+            limiter.check_at(busy, start + SWEEP_INTERVAL - Duration::from_secs(5));
+        }
+        assert_eq!(limiter.clients(), 11);
+
+        // Only the busy client is still refilling, the rest start over
+        assert!(limiter.check_at(busy, start + SWEEP_INTERVAL));
+        assert_eq!(limiter.clients(), 1);
+        for _ in 0..4 {
+            assert!(limiter.check_at(busy, start + SWEEP_INTERVAL));
+        }
+        assert!(!limiter.check_at(busy, start + SWEEP_INTERVAL));
+    }
+}
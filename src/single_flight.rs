@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::watch;
+
+/// Default cap on concurrent coalesced fetches
+pub const MAX_IN_FLIGHT: usize = 256;
+
+/// Running fetches, each with the channel its outcome is reported on
+type FlightMap = HashMap<u64, watch::Receiver<Option<FlightOutcome>>>;
+type Flights = Arc<Mutex<FlightMap>>;
+
+/// Coalesces concurrent cache misses for the same key into one upstream fetch
+///
+/// The first miss for a key leads the fetch and holds a [`FlightGuard`],
+/// later misses follow and wait for the guard to drop, then act on the
+/// [`FlightOutcome`] it reported. Guards remove their key when dropped, so
+/// the map only ever holds fetches that are still running, whether they
+/// end in success, error or a panic. Once `max_in_flight` keys are being
+/// fetched, further misses bypass coalescing instead of growing the map.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::single_flight::{Flight, SingleFlight};
+///
+/// let flights = SingleFlight::new(8);
+/// let leader = match flights.join(1) {
+///     Flight::Lead(guard) => guard,
+///     _ => unreachable!(),
+/// };
+/// assert!(matches!(flights.join(1), Flight::Follow(_)));
+/// drop(leader);
+/// assert!(flights.is_empty());
+/// ```
+#[derive(Clone)]
+pub struct SingleFlight {
+    flights: Flights,
+    max_in_flight: usize,
+}
+
+/// How a leading fetch ended, as reported to its followers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlightOutcome {
+    /// The response was cached, followers find it there
+    Stored,
+    /// Upstream failed, followers answer with this status instead of
+    /// each retrying the origin
+    Failed(u16),
+    /// Nothing to share, the response wasn't cacheable or the leader gave
+    /// up, so followers fetch on their own
+    Abandoned,
+}
+
+/// Role of a request in fetching a missing key, see [`SingleFlight::join`]
+pub enum Flight {
+    /// First miss, fetch and cache the response, then drop the guard
+    Lead(FlightGuard),
+    /// Another request is fetching the key, wait for it with [`FlightWait::done`]
+    Follow(FlightWait),
+    /// Too many fetches in flight, fetch independently
+    Bypass,
+}
+
+/// Held by the request fetching a key, removes it from the map when dropped
+///
+/// Dropping it without [`FlightGuard::finish`] reports
+/// [`FlightOutcome::Abandoned`].
+pub struct FlightGuard {
+    key: u64,
+    flights: Flights,
+    done: watch::Sender<Option<FlightOutcome>>,
+}
+
+impl FlightGuard {
+    /// End the fetch, telling every follower how it went
+    pub fn finish(self, outcome: FlightOutcome) {
+        // send_replace stores the value even when nobody is following yet
+        self.done.send_replace(Some(outcome));
+    }
+}
+
+/// Handle for waiting on another request's fetch
+pub struct FlightWait(watch::Receiver<Option<FlightOutcome>>);
+
+impl FlightWait {
+    /// Resolves with the outcome once the leading fetch has finished
+    pub async fn done(mut self) -> FlightOutcome {
+        // Outcomes are sent just before the guard drops, so wait for the drop
+        while self.0.changed().await.is_ok() {}
+        let outcome = *self.0.borrow();
+        outcome.unwrap_or(FlightOutcome::Abandoned)
+    }
+}
+
+impl SingleFlight {
+    /// Coalesce at most `max_in_flight` distinct keys at a time
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            flights: Arc::new(Mutex::new(HashMap::new())),
+            max_in_flight,
+        }
+    }
+
+    /// Lead the fetch for `key`, follow one already running, or bypass
+    pub fn join(&self, key: u64) -> Flight {
+        let mut flights = lock(&self.flights);
+        if let Some(done) = flights.get(&key) {
+            return Flight::Follow(FlightWait(done.clone()));
+        }
+        if flights.len() >= self.max_in_flight {
+            return Flight::Bypass;
+        }
+        let (done, waiting) = watch::channel(None);
+        flights.insert(key, waiting);
+        Flight::Lead(FlightGuard {
+            key,
+            flights: Arc::clone(&self.flights),
+            done,
+        })
+    }
+
+    /// Number of keys currently being fetched
+    pub fn len(&self) -> usize {
+        lock(&self.flights).len()
+    }
+
+    /// Whether no fetch is in flight
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for SingleFlight {
+    fn default() -> Self {
+        Self::new(MAX_IN_FLIGHT)
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        lock(&self.flights).remove(&self.key);
+    }
+}
+
+/// The map stays consistent across a panic, a poisoned lock is still usable
+fn lock(flights: &Mutex<FlightMap>) -> MutexGuard<'_, FlightMap> {
+    flights
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn lead(flights: &SingleFlight, key: u64) -> FlightGuard {
+        match flights.join(key) {
+            Flight::Lead(guard) => guard,
+            _ => panic!("expected to lead {}", key),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_followers_wake_when_leader_finishes() {
+        let flights = SingleFlight::new(8);
+        let guard = lead(&flights, 7);
+        let Flight::Follow(wait) = flights.join(7) else {
+            panic!("expected to follow");
+        };
+        let follower = tokio::spawn(wait.done());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!follower.is_finished());
+
+        drop(guard);
+        let outcome = tokio::time::timeout(Duration::from_secs(1), follower)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(outcome, FlightOutcome::Abandoned);
+        assert!(flights.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_every_follower_sees_the_outcome() {
+        for outcome in [FlightOutcome::Stored, FlightOutcome::Failed(504)] {
+            let flights = SingleFlight::new(8);
+            let guard = lead(&flights, 3);
+            let followers: Vec<_> = (0..5)
+                .map(|_| match flights.join(3) {
+                    Flight::Follow(wait) => tokio::spawn(wait.done()),
+                    _ => panic!("expected to follow"),
+                })
+                .collect();
+
+            guard.finish(outcome);
+            assert!(flights.is_empty());
+            for follower in followers {
+                assert_eq!(follower.await.unwrap(), outcome);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_map_empties_after_success_and_failure() {
+        let flights = SingleFlight::new(8);
+        let fetch = |key: u64, fail: bool| {
+            let flights = flights.clone();
+            tokio::spawn(async move {
+                let _guard = lead(&flights, key);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                if fail {
+                    return Err("upstream refused");
+                }
+                Ok(key)
+            })
+        };
+        let tasks: Vec<_> = (0..6).map(|key| fetch(key, key % 2 == 0)).collect();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(flights.len(), 6);
+        for task in tasks {
+            let _ = task.await.unwrap();
+        }
+        assert!(flights.is_empty());
+
+        // A panicking leader still releases its key
+        let panicking = flights.clone();
+        let _ = tokio::spawn(async move {
+            let _guard = lead(&panicking, 99);
+            panic!("fetch failed");
+        })
+        .await;
+        assert!(flights.is_empty());
+    }
+
+    #[test]
+    fn test_misses_bypass_once_cap_reached() {
+        let flights = SingleFlight::new(2);
+        let _first = lead(&flights, 1);
+        let _second = lead(&flights, 2);
+        assert!(matches!(flights.join(3), Flight::Bypass));
+        // Keys already in flight can still be followed
+        assert!(matches!(flights.join(1), Flight::Follow(_)));
+        assert_eq!(flights.len(), 2);
+    }
+}
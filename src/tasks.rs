@@ -0,0 +1,151 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+/// Caps how many tasks spawned through it run at once
+///
+/// [`TaskLimiter::spawn`] waits for a free slot before spawning, so a burst
+/// queues up in the caller instead of piling tasks onto the runtime.
+/// [`TaskLimiter::try_spawn`] drops the task instead, for callers that
+/// mustn't wait. Clones share the same slots.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::tasks::TaskLimiter;
+///
+/// # tokio_test::block_on(async {
+/// let limiter = TaskLimiter::new(2);
+/// let task = limiter.spawn(async { 1 + 1 }).await;
+/// assert_eq!(task.await.unwrap(), 2);
+/// assert_eq!(limiter.running(), 0);
+/// # });
+/// ```
+#[derive(Clone, Debug)]
+pub struct TaskLimiter {
+    permits: Arc<Semaphore>,
+    limit: usize,
+    dropped: Arc<AtomicUsize>,
+}
+
+impl TaskLimiter {
+    /// Allow `limit` tasks at a time, at least one
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(limit)),
+            limit,
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Tasks allowed to run at once
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Tasks spawned through this limiter that haven't finished
+    pub fn running(&self) -> usize {
+        self.limit - self.permits.available_permits()
+    }
+
+    /// Tasks [`TaskLimiter::try_spawn`] dropped for want of a slot
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Spawn `task` once fewer than `limit` are running
+    ///
+    /// The slot is held until the task finishes, panics and aborts included.
+    pub async fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let permit = Arc::clone(&self.permits)
+            .acquire_owned()
+            .await
+            .expect("task semaphore is never closed");
+        tokio::spawn(async move {
+            let _permit = permit;
+            task.await
+        })
+    }
+
+    /// Spawn `task` if fewer than `limit` are running, otherwise drop it
+    ///
+    /// Returns None when the task was dropped, see [`TaskLimiter::dropped`].
+    pub fn try_spawn<F>(&self, task: F) -> Option<JoinHandle<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let Ok(permit) = Arc::clone(&self.permits).try_acquire_owned() else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        Some(tokio::spawn(async move {
+            let _permit = permit;
+            task.await
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_spawned_tasks_throttled_to_limit() {
+        let limiter = TaskLimiter::new(3);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for _ in 0..10 {
+            let (running, peak) = (Arc::clone(&running), Arc::clone(&peak));
+            tasks.push(
+                limiter
+                    .spawn(async move {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .await,
+            );
+            assert!(limiter.running() <= 3);
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 3);
+        assert_eq!(limiter.running(), 0);
+
+        // A panicking task gives its slot back
+        let limiter = TaskLimiter::new(1);
+        let _ = limiter.spawn(async { panic!("task failed") }).await.await;
+        assert_eq!(limiter.running(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_try_spawn_drops_tasks_without_a_slot() {
+        let limiter = TaskLimiter::new(1);
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        let busy = limiter
+            .try_spawn(async {
+                let _ = released.await;
+            })
+            .unwrap();
+        assert!(limiter.try_spawn(async {}).is_none());
+        assert_eq!(limiter.dropped(), 1);
+
+        release.send(()).unwrap();
+        busy.await.unwrap();
+        assert_eq!(limiter.try_spawn(async { 2 }).unwrap().await.unwrap(), 2);
+        assert_eq!(limiter.dropped(), 1);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{cache_key_hasher, process_key_seed};
+
+/// Which `Vary` request headers may produce cacheable variants
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::vary::VaryPolicy;
+///
+/// let policy = VaryPolicy::default();
+/// assert!(policy.permits(&["accept-encoding".to_string()]));
+/// assert!(!policy.permits(&["*".to_string()]));
+/// assert!(!policy.permits(&["cookie".to_string()]));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VaryPolicy {
+    /// Cache per-credential variants for `Vary: Authorization`
+    ///
+    /// The credential itself is only ever fed to the key hash, never stored.
+    pub allow_authorization: bool,
+    /// Cache per-session variants for `Vary: Cookie`
+    ///
+    /// Off by default: every session produces its own variant, so such
+    /// responses are refused like `Vary: *` and only fill the cache.
+    pub allow_cookie: bool,
+}
+
+impl Default for VaryPolicy {
+    fn default() -> Self {
+        Self {
+            allow_authorization: true,
+            allow_cookie: false,
+        }
+    }
+}
+
+impl VaryPolicy {
+    /// Whether a response varying on `vary` (as returned by [`parse_vary`]) may be cached
+    pub fn permits(&self, vary: &[String]) -> bool {
+        vary.iter().all(|name| match name.as_str() {
+            "*" => false,
+            "authorization" => self.allow_authorization,
+            "cookie" => self.allow_cookie,
+            _ => true,
+        })
+    }
+}
+
+/// Collect the header names listed in all `Vary` response headers
+///
+/// Names are lowercased, de-duplicated and sorted so the same set always
+/// produces the same variant key.
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::vary::parse_vary;
+///
+/// let headers = vec!["Vary: Accept-Encoding, Authorization".to_string()];
+/// assert_eq!(parse_vary(&headers), vec!["accept-encoding", "authorization"]);
+/// ```
+pub fn parse_vary(response_headers: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = response_headers
+        .iter()
+        .filter_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            name.trim().eq_ignore_ascii_case("vary").then_some(value)
+        })
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Create a cache key that also covers the request header values named by `vary`
+///
+/// Header values are hashed into the key and never retained, so varying on
+/// `Authorization` isolates per-user variants without storing credentials.
+/// With an empty `vary` list this equals [`crate::create_cache_key`].
+///
+/// # Examples
+///
+/// ```
+/// use rustysquid::create_cache_key;
+/// use rustysquid::vary::create_vary_cache_key;
+///
+/// let vary = vec!["accept-encoding".to_string()];
+/// let gzip = vec!["Accept-Encoding: gzip".to_string()];
+///
+/// let plain = create_vary_cache_key("example.com", 80, "/app.js", &[], &vary);
+/// let zipped = create_vary_cache_key("example.com", 80, "/app.js", &gzip, &vary);
+/// assert_ne!(plain, zipped);
+///
+/// let base = create_vary_cache_key("example.com", 80, "/app.js", &gzip, &[]);
+/// assert_eq!(base, create_cache_key("example.com", 80, "/app.js"));
+/// ```
+pub fn create_vary_cache_key(
+    host: &str,
+    port: u16,
+    path: &str,
+    request_headers: &[String],
+    vary: &[String],
+) -> u64 {
+    create_vary_cache_key_with_seed(process_key_seed(), host, port, path, request_headers, vary)
+}
+
+/// [`create_vary_cache_key`] with an explicit hash seed
+pub fn create_vary_cache_key_with_seed(
+    seed: u64,
+    host: &str,
+    port: u16,
+    path: &str,
+    request_headers: &[String],
+    vary: &[String],
+) -> u64 {
+    let mut hasher = cache_key_hasher(seed, host, port, path);
+    for name in vary {
+        hasher.update(b"\0");
+        hasher.update(name.as_bytes());
+        let mut values = request_headers.iter().filter_map(|header| {
+            let (header_name, value) = header.split_once(':')?;
+            header_name
+                .trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim())
+        });
+        match values.next() {
+            Some(first) => {
+                hasher.update(b"=");
+                hasher.update(first.as_bytes());
+                for value in values {
+                    hasher.update(b",");
+                    hasher.update(value.as_bytes());
+                }
+            }
+            // Distinguish an absent header from an empty one
+            None => hasher.update(b"\x01"),
+        }
+    }
+    hasher.digest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorization_variants_are_isolated() {
+        let vary = parse_vary(&["Vary: Authorization".to_string()]);
+        let alice = vec!["Authorization: Bearer alice-secret".to_string()];
+        let bob = vec!["Authorization: Bearer bob-secret".to_string()];
+
+        let alice_key = create_vary_cache_key("api.example.com", 443, "/me", &alice, &vary);
+        let bob_key = create_vary_cache_key("api.example.com", 443, "/me", &bob, &vary);
+        let anonymous = create_vary_cache_key("api.example.com", 443, "/me", &[], &vary);
+
+        assert_ne!(alice_key, bob_key);
+        assert_ne!(alice_key, anonymous);
+        assert_eq!(
+            alice_key,
+            create_vary_cache_key("api.example.com", 443, "/me", &alice, &vary)
+        );
+    }
+
+    #[test]
+    fn test_credentials_never_stored_in_key_material() {
+        let vary = parse_vary(&["Vary: authorization".to_string()]);
+        let request = vec!["Authorization: Basic c2VjcmV0".to_string()];
+        let key = create_vary_cache_key("example.com", 80, "/private", &request, &vary);
+
+        // Only the header name and the opaque u64 key are retained
+        assert_eq!(vary, vec!["authorization".to_string()]);
+        let stored = format!("{vary:?}{key}");
+        assert!(!stored.contains("c2VjcmV0"));
+    }
+
+    #[test]
+    fn test_policy_can_refuse_authorization_variants() {
+        let vary = parse_vary(&["Vary: Accept-Encoding, Authorization".to_string()]);
+        assert!(VaryPolicy::default().permits(&vary));
+
+        let strict = VaryPolicy {
+            allow_authorization: false,
+            ..VaryPolicy::default()
+        };
+        assert!(!strict.permits(&vary));
+        assert!(strict.permits(&["accept-encoding".to_string()]));
+    }
+
+    #[test]
+    fn test_cookie_variants_refused_unless_allowed() {
+        let vary = parse_vary(&["Vary: Accept-Encoding, Cookie".to_string()]);
+        assert!(!VaryPolicy::default().permits(&vary));
+
+        let lenient = VaryPolicy {
+            allow_cookie: true,
+            ..VaryPolicy::default()
+        };
+        assert!(lenient.permits(&vary));
+        assert!(!lenient.permits(&["*".to_string()]));
+    }
+
+    #[test]
+    fn test_parse_vary_normalizes_names() {
+        let headers = vec![
+            "Vary: Accept-Encoding,  User-Agent".to_string(),
+            "vary: accept-encoding".to_string(),
+            "Content-Type: text/html".to_string(),
+        ];
+        assert_eq!(parse_vary(&headers), vec!["accept-encoding", "user-agent"]);
+        assert!(parse_vary(&[]).is_empty());
+    }
+}
@@ -2,7 +2,6 @@ use bytes::Bytes;
 /// Integration tests for RustySquid - increases code coverage
 /// Tests end-to-end proxy functionality and edge cases
 use rustysquid::*;
-use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // Test the full request-response cycle
@@ -13,7 +12,7 @@ async fn test_full_request_cycle() {
     // Test request parsing
     let request = b"GET /test HTTP/1.1\r\nHost: example.com\r\nUser-Agent: test\r\n\r\n";
     let parsed = parse_request(request);
-    assert!(parsed.is_some());
+    assert!(parsed.is_ok());
 
     let (method, path, headers) = parsed.unwrap();
     assert_eq!(method, "GET");
@@ -41,6 +40,7 @@ async fn test_full_request_cycle() {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     };
 
     assert!(cache.put(key, response.clone()).await);
@@ -57,7 +57,7 @@ async fn test_http_methods() {
         let request = format!("{} /test HTTP/1.1\r\nHost: test.com\r\n\r\n", method);
         let parsed = parse_request(request.as_bytes());
 
-        assert!(parsed.is_some(), "Failed to parse {} request", method);
+        assert!(parsed.is_ok(), "Failed to parse {} request", method);
         let (parsed_method, _, _) = parsed.unwrap();
         assert_eq!(parsed_method, method);
 
@@ -68,7 +68,7 @@ async fn test_http_methods() {
     // CONNECT method test separately (different format)
     let connect_request = b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n";
     let parsed = parse_request(connect_request);
-    assert!(parsed.is_some());
+    assert!(parsed.is_ok());
 }
 
 // Test various content types and extensions
@@ -109,8 +109,8 @@ async fn test_cache_control_headers() {
         ),
         (
             vec!["Cache-Control: no-cache".to_string()],
-            false,
-            "no-cache",
+            true,
+            "no-cache, stored for revalidation",
         ),
         (
             vec!["Cache-Control: no-store".to_string()],
@@ -179,14 +179,19 @@ fn test_host_extraction_edge_cases() {
         Some(("192.168.1.1".to_string(), 8080))
     );
 
-    // IPv6 address (simplified test) - our implementation doesn't handle IPv6 brackets specially
-    // This is acceptable for a basic proxy
-    let ipv6_result = extract_host(&["Host: [::1]".to_string()]);
-    assert!(ipv6_result.is_some() || ipv6_result.is_none()); // Accept either behavior
-
-    // IPv6 with port - complex parsing not required for basic proxy
-    let ipv6_port_result = extract_host(&["Host: [::1]:8080".to_string()]);
-    assert!(ipv6_port_result.is_some() || ipv6_port_result.is_none());
+    // IPv6 literals, with and without a port
+    assert_eq!(
+        extract_host(&["Host: [::1]".to_string()]),
+        Some(("::1".to_string(), 80))
+    );
+    assert_eq!(
+        extract_host(&["Host: [::1]:8080".to_string()]),
+        Some(("::1".to_string(), 8080))
+    );
+    assert_eq!(
+        extract_host(&["Host: [2001:db8::1]:443".to_string()]),
+        Some(("2001:db8::1".to_string(), 443))
+    );
 
     // Missing Host header
     assert_eq!(extract_host(&[]), None);
@@ -202,24 +207,40 @@ fn test_host_extraction_edge_cases() {
 #[test]
 fn test_request_parsing_edge_cases() {
     // Minimal valid request
-    assert!(parse_request(b"GET / HTTP/1.1\r\n\r\n").is_some());
+    assert!(parse_request(b"GET / HTTP/1.1\r\n\r\n").is_ok());
 
     // With multiple headers
     let request =
         b"GET /path HTTP/1.1\r\nHost: example.com\r\nUser-Agent: test\r\nAccept: */*\r\n\r\n";
     let parsed = parse_request(request);
-    assert!(parsed.is_some());
+    assert!(parsed.is_ok());
     let (_, _, headers) = parsed.unwrap();
     assert_eq!(headers.len(), 3);
 
     // Invalid requests
-    assert!(parse_request(b"").is_none());
-    assert!(parse_request(b"INVALID REQUEST").is_none());
-    assert!(parse_request(b"GET").is_none());
-    assert!(parse_request(b"GET /\r\n\r\n").is_none()); // Missing HTTP version
+    assert!(parse_request(b"").is_err());
+    assert!(parse_request(b"INVALID REQUEST").is_err());
+    assert!(parse_request(b"GET").is_err());
+    assert!(parse_request(b"GET /\r\n\r\n").is_err()); // Missing HTTP version
 
     // HTTP/1.0
-    assert!(parse_request(b"GET / HTTP/1.0\r\n\r\n").is_some());
+    assert!(parse_request(b"GET / HTTP/1.0\r\n\r\n").is_ok());
+
+    // Typed errors for truncated heads and header floods
+    assert_eq!(parse_request(b"GET"), Err(ParseError::Incomplete));
+    let crowded = format!(
+        "GET / HTTP/1.1\r\n{}\r\n",
+        "X-Filler: 1\r\n".repeat(MAX_REQUEST_HEADERS + 1)
+    );
+    assert_eq!(
+        parse_request(crowded.as_bytes()),
+        Err(ParseError::TooManyHeaders)
+    );
+    let full = format!(
+        "GET / HTTP/1.1\r\n{}\r\n",
+        "X-Filler: 1\r\n".repeat(MAX_REQUEST_HEADERS)
+    );
+    assert!(parse_request(full.as_bytes()).is_ok());
 }
 
 // Test cache memory limits
@@ -233,6 +254,7 @@ async fn test_cache_memory_limits() {
         headers: vec![],
         body: Bytes::from(vec![0u8; MAX_ENTRY_SIZE + 1]),
         expires: u64::MAX,
+        ..Default::default()
     };
 
     assert!(
@@ -246,6 +268,7 @@ async fn test_cache_memory_limits() {
         headers: vec![],
         body: Bytes::from(vec![0u8; MAX_ENTRY_SIZE - 100]),
         expires: u64::MAX,
+        ..Default::default()
     };
 
     assert!(
@@ -270,6 +293,7 @@ async fn test_cache_eviction() {
             headers: vec![],
             body: Bytes::from(format!("body {}", i)),
             expires: u64::MAX,
+            ..Default::default()
         };
         cache.put(i as u64, response).await;
     }
@@ -282,6 +306,7 @@ async fn test_cache_eviction() {
         headers: vec![],
         body: Bytes::from("new body"),
         expires: u64::MAX,
+        ..Default::default()
     };
 
     cache.put(CACHE_SIZE as u64, new_response).await;
@@ -312,6 +337,7 @@ async fn test_concurrent_cache_access() {
                 headers: vec![],
                 body: Bytes::from(format!("body {}", i)),
                 expires: u64::MAX,
+                ..Default::default()
             };
 
             // Perform multiple operations
@@ -350,6 +376,7 @@ async fn test_cache_expiration_handling() {
         headers: vec![],
         body: Bytes::from("expired"),
         expires: now - 1,
+        ..Default::default()
     };
 
     // Expires in 1 hour
@@ -358,6 +385,7 @@ async fn test_cache_expiration_handling() {
         headers: vec![],
         body: Bytes::from("valid"),
         expires: now + 3600,
+        ..Default::default()
     };
 
     // Never expires (far future)
@@ -366,6 +394,7 @@ async fn test_cache_expiration_handling() {
         headers: vec![],
         body: Bytes::from("permanent"),
         expires: u64::MAX,
+        ..Default::default()
     };
 
     cache.put(1, expired).await;
@@ -399,6 +428,7 @@ async fn test_memory_pressure() {
         headers: vec![],
         body: Bytes::from("test"),
         expires: u64::MAX,
+        ..Default::default()
     };
 
     assert!(cache.put(1, response).await);
@@ -416,6 +446,7 @@ async fn test_cache_clear() {
             headers: vec![],
             body: Bytes::from(format!("body {}", i)),
             expires: u64::MAX,
+            ..Default::default()
         };
         cache.put(i, response).await;
     }
@@ -437,6 +468,7 @@ async fn test_cache_clear() {
         headers: vec![],
         body: Bytes::from("new"),
         expires: u64::MAX,
+        ..Default::default()
     };
 
     assert!(cache.put(100, response).await);
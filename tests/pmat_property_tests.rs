@@ -79,7 +79,7 @@ proptest! {
         let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\n\r\n", method, path, host);
         let result = parse_request(request.as_bytes());
 
-        prop_assert!(result.is_some(), "Valid request must parse");
+        prop_assert!(result.is_ok(), "Valid request must parse");
         let (parsed_method, parsed_path, headers) = result.unwrap();
         prop_assert_eq!(parsed_method, method);
         prop_assert_eq!(parsed_path, path);
@@ -96,7 +96,7 @@ proptest! {
         prop_assume!(!request_str.starts_with("GET ") && !request_str.starts_with("POST "));
 
         let result = parse_request(&garbage);
-        prop_assert!(result.is_none(), "Malformed request must be rejected");
+        prop_assert!(result.is_err(), "Malformed request must be rejected");
     }
 
     /// Property: Request size limits are enforced
@@ -131,14 +131,19 @@ proptest! {
     /// Property: Cache-Control headers are always respected
     #[test]
     fn prop_cache_control_respected(
-        directive in prop::sample::select(vec!["no-cache", "no-store", "private", "max-age=3600", "public"])
+        directive in prop::sample::select(vec!["no-cache", "no-store", "private", "max-age=3600", "public"]),
+        path in prop::sample::select(vec!["/index.html", "/api/items"])
     ) {
         let headers = vec![format!("Cache-Control: {}", directive)];
-        let should_cache = !directive.contains("no-cache") &&
-                          !directive.contains("no-store") &&
-                          !directive.contains("private");
+        // no-cache responses are stored and revalidated on every use, so
+        // like max-age it makes any path cacheable
+        let should_cache = match directive {
+            "no-store" | "private" => false,
+            "no-cache" | "max-age=3600" => true,
+            _ => path == "/index.html",
+        };
 
-        let result = is_cacheable("GET", "/index.html", &headers);
+        let result = is_cacheable("GET", path, &headers);
         prop_assert_eq!(result, should_cache, "Cache-Control directives must be respected");
     }
 
@@ -199,6 +204,7 @@ async fn prop_cache_capacity_invariant() {
             headers: vec![],
             body: Bytes::from(format!("body {}", i)),
             expires: u64::MAX,
+            ..Default::default()
         };
         cache.put(i as u64, response).await;
     }
@@ -224,6 +230,7 @@ async fn prop_cache_memory_invariant() {
             headers: vec![],
             body: Bytes::from(vec![0u8; large_size]),
             expires: u64::MAX,
+            ..Default::default()
         };
         cache.put(i as u64, response).await;
     }
@@ -247,6 +254,7 @@ async fn prop_cache_expiration_invariant() {
         headers: vec![],
         body: Bytes::from("expired"),
         expires: 0, // Already expired
+        ..Default::default()
     };
 
     cache.put(1, expired_response).await;
@@ -265,6 +273,7 @@ async fn prop_cache_expiration_invariant() {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     };
 
     cache.put(2, valid_response.clone()).await;
@@ -303,6 +312,7 @@ async fn prop_concurrent_cache_safety() {
                 headers: vec![],
                 body: Bytes::from(format!("body{}", i)),
                 expires: u64::MAX,
+                ..Default::default()
             };
 
             // Concurrent put and get operations
@@ -418,6 +428,7 @@ async fn prop_zero_copy_cache_operations() {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     };
 
     let key = 12345u64;
@@ -458,6 +469,7 @@ async fn prop_arc_reference_count_behavior() {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     };
 
     let key = 54321u64;
@@ -495,6 +507,7 @@ async fn prop_zero_allocations_in_cache_hit_path() {
     use std::sync::atomic::{AtomicUsize, Ordering};
 
     // Custom allocator to track allocations
+    #[allow(dead_code)]
     struct TrackingAllocator {
         allocations: AtomicUsize,
     }
@@ -521,6 +534,7 @@ async fn prop_zero_allocations_in_cache_hit_path() {
             .unwrap()
             .as_secs()
             + 3600,
+        ..Default::default()
     };
 
     let key = 98765u64;
@@ -549,7 +563,7 @@ async fn prop_zero_allocations_in_cache_hit_path() {
 // ----------------------------------------------------------------------------
 
 #[quickcheck]
-fn qc_connection_pool_stats_deterministic(host: String, port: u16) -> bool {
+fn qc_connection_pool_stats_deterministic(host: String, _port: u16) -> bool {
     if host.is_empty() || host.len() > 253 {
         return true; // Skip invalid hosts
     }
@@ -649,7 +663,9 @@ async fn prop_connection_pool_thread_safety() {
 
     // Property: Pool remains in valid state after concurrent access
     let final_stats = pool.stats().await;
-    assert!(final_stats.len() >= 0, "Pool should remain in valid state");
+    for count in final_stats.values() {
+        assert!(*count > 0, "Pool should not retain empty host entries");
+    }
 }
 
 #[tokio::test]
@@ -661,9 +677,9 @@ async fn prop_connection_pool_resource_cleanup() {
         pool.cleanup_stale_connections().await;
         let stats = pool.stats().await;
 
-        // Pool should never contain negative or invalid counts
+        // Pool should never retain empty host entries after cleanup
         for ((_host, _port), count) in stats.iter() {
-            assert!(*count >= 0, "Connection counts must be non-negative");
+            assert!(*count > 0, "Empty pools must be removed by cleanup");
         }
     }
 
@@ -692,7 +708,7 @@ fn test_pmat_zero_tolerance_compliance() {
     let has_satd = std::fs::read_dir(src_dir)
         .unwrap()
         .filter_map(|entry| entry.ok())
-        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == "rs"))
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "rs"))
         .any(|entry| {
             let content = std::fs::read_to_string(entry.path()).unwrap();
             content.contains("TODO") || content.contains("FIXME") || content.contains("HACK")
@@ -1,7 +1,6 @@
 use bytes::Bytes;
 use proptest::prelude::*;
 use rustysquid::*;
-use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // Property: Cache keys should be deterministic
@@ -70,15 +69,17 @@ proptest! {
     }
 }
 
-// Property: Cache should respect no-cache headers
+// Property: Cache should respect no-store headers, no-cache only asks for
+// revalidation so those responses are still stored
 proptest! {
     #[test]
-    fn prop_no_cache_header_respected(
+    fn prop_no_store_header_respected(
         path in "/[a-z0-9/]{1,50}\\.(jpg|css|js)"
     ) {
+        let headers = vec!["Cache-Control: no-store".to_string()];
+        prop_assert!(!is_cacheable("GET", &path, &headers));
         let headers = vec!["Cache-Control: no-cache".to_string()];
-        let result = is_cacheable("GET", &path, &headers);
-        prop_assert!(!result);
+        prop_assert!(is_cacheable("GET", &path, &headers));
     }
 }
 
@@ -124,6 +125,7 @@ async fn prop_cache_operations() {
                 .unwrap()
                 .as_secs()
                 + 3600,
+            ..Default::default()
         };
         cache.put(key, response).await;
         assert!(cache.len().await > 0);
@@ -135,6 +137,44 @@ async fn prop_cache_operations() {
     assert_eq!(cache.len().await, 0);
 }
 
+// Property: The in-memory cache behaves the same through the Cache trait
+async fn check_cache_backend<C: rustysquid::backend::Cache + ?Sized>(cache: &C) {
+    let expires = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        + 3600;
+    let mut stored = 0;
+    for i in 0..50 {
+        let response = CachedResponse {
+            status_line: "HTTP/1.1 200 OK\r\n".to_string(),
+            body: Bytes::from(format!("body{i}")),
+            expires,
+            ..Default::default()
+        };
+        let key = create_cache_key(&format!("backend{i}.com"), 80, "/");
+        assert!(cache.put(key, response).await);
+        stored += 1;
+        assert_eq!(cache.len().await, stored);
+        assert_eq!(
+            cache.get(key).await.unwrap().body,
+            Bytes::from(format!("body{i}"))
+        );
+    }
+    assert!(cache.total_size() > 0);
+
+    cache.clear().await;
+    assert!(cache.is_empty().await);
+    assert_eq!(cache.total_size(), 0);
+}
+
+#[tokio::test]
+async fn prop_cache_trait_matches_in_memory_cache() {
+    check_cache_backend(&ProxyCache::new()).await;
+    let boxed: Box<dyn rustysquid::backend::Cache> = Box::new(ProxyCache::new());
+    check_cache_backend(boxed.as_ref()).await;
+}
+
 // Property: Cache size never exceeds MAX_CACHE_BYTES
 #[tokio::test]
 async fn prop_cache_size_never_exceeds_limit() {
@@ -151,6 +191,7 @@ async fn prop_cache_size_never_exceeds_limit() {
                 .unwrap()
                 .as_secs()
                 + 3600,
+            ..Default::default()
         };
 
         let key = create_cache_key(&format!("test{i}.com"), 80, "/");
@@ -184,6 +225,7 @@ proptest! {
                     .unwrap()
                     .as_secs()
                     + 3600,
+                    ..Default::default()
             };
 
             let key = create_cache_key("test.com", 80, "/oversized");
@@ -220,6 +262,7 @@ async fn prop_cache_concurrent_safety() {
                     .unwrap()
                     .as_secs()
                     + 3600,
+                ..Default::default()
             };
             cache_clone.put(key, response.clone()).await;
             let retrieved = cache_clone.get(key).await;